@@ -0,0 +1,69 @@
+// =============================================================================
+// File        : embed.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 17:48:20.
+// =============================================================================
+
+//! Runs a spy constructed in code, without a config file.
+//!
+//! ```sh
+//! cargo run --example embed
+//! ```
+
+use std::{env, fs, thread, time::Duration};
+
+use anyhow::{bail, Result};
+use spyrun::{Control, Engine, InputPolicy, Settings, Spy};
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let dir = env::temp_dir().join("spyrun_embed");
+    fs::remove_dir_all(&dir).unwrap_or_default();
+    fs::create_dir_all(&dir)?;
+    let input = dir.join("input");
+    let output = dir.join("output");
+
+    #[cfg(windows)]
+    let (cmd, arg) = ("cmd", ["/c", "echo embedded {{ event_name }}"]);
+    #[cfg(not(windows))]
+    let (cmd, arg) = ("sh", ["-c", "echo embedded {{ event_name }}"]);
+    let spy = Spy::builder("embed")
+        .input(&input)
+        .input_policy(InputPolicy::Create)
+        .output(&output)
+        .event("Create")
+        .pattern(r"\.txt$", cmd, &arg)
+        .build()?;
+
+    let engine = Engine::start(Settings::with_spys(&dir, vec![spy])?)?;
+    // e.g. from a signal handler of the embedder.
+    let control = engine.control();
+
+    // Wait for the watcher to start, then drop a file.
+    thread::sleep(Duration::from_secs(1));
+    fs::write(input.join("hello.txt"), "hello")?;
+
+    let mut stdout = None;
+    for _ in 0..100 {
+        thread::sleep(Duration::from_millis(100));
+        stdout = fs::read_dir(&output)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().contains("_stdout_"))
+            .filter_map(|e| fs::read_to_string(e.path()).ok())
+            .find(|s| !s.trim().is_empty());
+        if stdout.is_some() {
+            break;
+        }
+    }
+
+    control.send(Control::Stop)?;
+    engine.join()?;
+    match stdout {
+        Some(stdout) => println!("{}", stdout.trim()),
+        None => bail!("The command was not executed"),
+    }
+    Ok(())
+}
@@ -0,0 +1,238 @@
+// =============================================================================
+// File        : cfg_diff.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 20:12:44.
+// =============================================================================
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use crypto_hash::{hex_digest, Algorithm};
+
+/// What the backup config differs from the primary one, logged when the backup is used.
+///
+/// The files are compared as raw TOML (not rendered), so a primary with a template error is still compared.
+pub fn backup_report(primary: &Path, backup: &Path) -> Vec<String> {
+    let mut lines = vec![describe("primary", primary), describe("backup", backup)];
+    let primary_str = match fs::read_to_string(primary) {
+        Ok(s) => s,
+        Err(e) => {
+            lines.push(format!(
+                "the primary config can't be read, no diff: {}, e: {}",
+                primary.display(),
+                e
+            ));
+            return lines;
+        }
+    };
+    let primary_value = match toml::from_str::<toml::Value>(&primary_str) {
+        Ok(v) => v,
+        Err(e) => {
+            lines.push(format!(
+                "the primary config is not valid TOML, no diff: {}",
+                e.message()
+            ));
+            return lines;
+        }
+    };
+    let backup_value = match fs::read_to_string(backup)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| Ok(toml::from_str::<toml::Value>(&s)?))
+    {
+        Ok(v) => v,
+        Err(e) => {
+            lines.push(format!("the backup config can't be parsed, no diff: {}", e));
+            return lines;
+        }
+    };
+    let changes = diff(&backup_value, &primary_value);
+    if changes.is_empty() {
+        lines.push("no difference in the raw TOML (only in the rendering)".to_string());
+    }
+    lines.extend(changes);
+    lines
+}
+
+fn describe(label: &str, path: &Path) -> String {
+    match stat(path) {
+        Ok((modified, hash)) => format!(
+            "{}: {}, modified: {}, sha256: {}",
+            label,
+            path.display(),
+            modified,
+            hash
+        ),
+        Err(e) => format!("{}: {}, e: {}", label, path.display(), e),
+    }
+}
+
+fn stat(path: &Path) -> Result<(String, String)> {
+    let modified: DateTime<Local> = fs::metadata(path)?.modified()?.into();
+    let hash = hex_digest(Algorithm::SHA256, &fs::read(path)?);
+    Ok((modified.format("%Y-%m-%d %H:%M:%S").to_string(), hash))
+}
+
+/// The changes from `backup` to `primary`: the spys added / removed by name, then the keys added / removed / changed.
+pub fn diff(backup: &toml::Value, primary: &toml::Value) -> Vec<String> {
+    let (mut old, mut new) = (BTreeMap::new(), BTreeMap::new());
+    flatten("", backup, &mut old);
+    flatten("", primary, &mut new);
+    let (old_spys, new_spys) = (spy_names(backup), spy_names(primary));
+    let added_spys = new_spys.difference(&old_spys).collect::<Vec<_>>();
+    let removed_spys = old_spys.difference(&new_spys).collect::<Vec<_>>();
+    // The keys of the added / removed spys are in their own lines.
+    let in_spy = |key: &str, names: &[&String]| {
+        names
+            .iter()
+            .any(|name| key.starts_with(&format!("spys[{}]", name)))
+    };
+    let mut lines = vec![];
+    lines.extend(added_spys.iter().map(|name| format!("spy added: {}", name)));
+    lines.extend(
+        removed_spys
+            .iter()
+            .map(|name| format!("spy removed: {}", name)),
+    );
+    for (key, value) in new.iter() {
+        match old.get(key) {
+            None if !in_spy(key, &added_spys) => lines.push(format!("added: {} = {}", key, value)),
+            Some(old_value) if old_value != value => {
+                lines.push(format!("changed: {}: {} -> {}", key, old_value, value))
+            }
+            _ => {}
+        }
+    }
+    for (key, value) in old.iter() {
+        if !new.contains_key(key) && !in_spy(key, &removed_spys) {
+            lines.push(format!("removed: {} (was {})", key, value));
+        }
+    }
+    lines
+}
+
+fn spy_names(value: &toml::Value) -> BTreeSet<String> {
+    value
+        .get("spys")
+        .and_then(|spys| spys.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|spy| spy.get("name").and_then(|name| name.as_str()))
+        .map(String::from)
+        .collect()
+}
+
+/// The leaf values by dotted key. The spys are keyed by name, e.g. `spys[csv].throttle`.
+fn flatten(prefix: &str, value: &toml::Value, out: &mut BTreeMap<String, String>) {
+    let join = |key: &str| match prefix {
+        "" => key.to_string(),
+        _ => format!("{}.{}", prefix, key),
+    };
+    match value {
+        toml::Value::Table(table) => {
+            for (k, v) in table {
+                flatten(&join(k), v, out);
+            }
+        }
+        toml::Value::Array(spys) if prefix == "spys" => {
+            for (i, spy) in spys.iter().enumerate() {
+                let name = spy
+                    .get("name")
+                    .and_then(|name| name.as_str())
+                    .map(String::from)
+                    .unwrap_or_else(|| i.to_string());
+                flatten(&format!("spys[{}]", name), spy, out);
+            }
+        }
+        v => {
+            out.insert(prefix.to_string(), v.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, write},
+    };
+
+    use anyhow::Result;
+
+    use super::backup_report;
+
+    #[test]
+    fn test_backup_report() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_backup_report");
+        create_dir_all(&tmp)?;
+        let (primary, backup) = (tmp.join("spyrun.toml"), tmp.join("spyrun_backup.toml"));
+        write(
+            &backup,
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+max_threads = 2
+
+[[spys]]
+name = 'a'
+output = 'out'
+
+[[spys]]
+name = 'b'
+input = 'b'
+"#,
+        )?;
+        // A template error, but valid as TOML.
+        write(
+            &primary,
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+max_threads = 4
+metrics_addr = '{{ broken'
+
+[[spys]]
+name = 'a'
+output = 'out2'
+
+[[spys]]
+name = 'c'
+input = 'c'
+"#,
+        )?;
+        let lines = backup_report(&primary, &backup);
+        assert!(lines[0].starts_with("primary: "), "{:?}", lines);
+        assert!(lines[0].contains("sha256: "), "{:?}", lines);
+        assert!(lines[1].starts_with("backup: "), "{:?}", lines);
+        assert_eq!(
+            lines[2..],
+            [
+                "spy added: c",
+                "spy removed: b",
+                "changed: cfg.max_threads: 2 -> 4",
+                "added: cfg.metrics_addr = \"{{ broken\"",
+                "changed: spys[a].output: \"out\" -> \"out2\"",
+            ]
+        );
+
+        let lines = backup_report(&tmp.join("missing.toml"), &backup);
+        assert!(
+            lines[2].starts_with("the primary config can't be read, no diff: "),
+            "{:?}",
+            lines
+        );
+
+        write(&primary, "[cfg\n")?;
+        let lines = backup_report(&primary, &backup);
+        assert!(
+            lines[2].starts_with("the primary config is not valid TOML"),
+            "{:?}",
+            lines
+        );
+        Ok(())
+    }
+}
@@ -6,31 +6,87 @@
 
 use std::{
     collections::HashMap,
-    fmt,
-    fs::{create_dir_all, OpenOptions},
-    path::PathBuf,
-    process::{Command, ExitStatus},
-    sync::{Arc, Mutex},
+    env, fmt,
+    fs::{self, create_dir_all, File, OpenOptions},
+    io,
+    io::Write as _,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, LazyLock, Mutex},
     thread,
     time::{Duration, Instant},
 };
 
-use anyhow::Result;
+use anyhow::{bail, Context as _, Result};
 use chrono::Local;
+use crypto_hash::{hex_digest, Algorithm};
 use log_derive::logfn;
 use tera::Context;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, info_span, warn, Span};
 
-use crate::util::{insert_file_context, new_tera};
+use crate::{
+    janitor::Writing,
+    mover::available_path,
+    settings::{Guard, OutputMode, ShellKind},
+    util::{
+        expand_env, insert_file_context, new_exec_id, new_tera, render, scrub, to_posix_path,
+        RenderDeadline, DEFAULT_RENDER_TIMEOUT,
+    },
+    Vars,
+};
+
+/// The max size of the result file written by a command.
+const RESULT_FILE_MAX_BYTES: u64 = 64 * 1024;
+
+/// Retry count and first wait to open an output file when file descriptors are exhausted.
+const OPEN_RETRY_COUNT: u32 = 5;
+const OPEN_RETRY_WAIT: Duration = Duration::from_millis(100);
+
+#[cfg(windows)]
+const DEFAULT_SCRIPT_EXT: &str = "ps1";
+#[cfg(not(windows))]
+const DEFAULT_SCRIPT_EXT: &str = "sh";
 
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+/// The range of `nice`, the same as Unix.
+const NICE_MIN: i32 = -20;
+const NICE_MAX: i32 = 19;
+
+#[cfg(windows)]
+const IDLE_PRIORITY_CLASS: u32 = 0x00000040;
+#[cfg(windows)]
+const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x00004000;
+#[cfg(windows)]
+const NORMAL_PRIORITY_CLASS: u32 = 0x00000020;
+#[cfg(windows)]
+const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x00008000;
+#[cfg(windows)]
+const HIGH_PRIORITY_CLASS: u32 = 0x00000080;
+
+/// Serializes the appends to the same per_key / per_day output file.
+static OUTPUT_LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Hash, PartialEq, Eq, Clone)]
 pub struct CommandInfo {
+    exec_id: String,
     name: String,
     event_path: PathBuf,
     cmd: String,
     arg: Vec<String>,
     input: String,
     output: String,
+    expand_env_in_args: bool,
+    limitkey: String,
+    output_mode: OutputMode,
+    event_kind: String,
+    export_env: bool,
+    output_status_prefix: bool,
+    script: Option<String>,
+    script_ext: String,
+    keep_script: bool,
+    merge_stderr: bool,
+    nice: Option<i32>,
+    shell_kind: ShellKind,
 }
 
 impl fmt::Display for CommandInfo {
@@ -40,94 +96,506 @@ impl fmt::Display for CommandInfo {
     }
 }
 
+/// The rendered fields scrubbed, it is a span field of the execution.
+impl fmt::Debug for CommandInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CommandInfo")
+            .field("exec_id", &self.exec_id)
+            .field("name", &self.name)
+            .field("event_path", &self.event_path)
+            .field("cmd", &scrub(&self.cmd))
+            .field(
+                "arg",
+                &self.arg.iter().map(|a| scrub(a)).collect::<Vec<_>>(),
+            )
+            .field("input", &scrub(&self.input))
+            .field("output", &scrub(&self.output))
+            .field("limitkey", &scrub(&self.limitkey))
+            .field("event_kind", &self.event_kind)
+            .field("script", &self.script.as_deref().map(scrub))
+            .finish_non_exhaustive()
+    }
+}
+
+impl CommandInfo {
+    /// The key of `throttle`, `debounce` and `failure_cooldown_secs`.
+    pub fn limitkey(&self) -> &str {
+        &self.limitkey
+    }
+}
+
+/// The settings of a pattern (or its spy) for its executions. Passed beside the template context,
+/// so a var of the same name never changes them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecOptions {
+    pub output_mode: OutputMode,
+    pub expand_env_in_args: bool,
+    pub export_env: bool,
+    pub condition: Option<String>,
+    pub guard: Option<Guard>,
+    pub nice: Option<i32>,
+    pub shell_kind: ShellKind,
+    pub script: Option<String>,
+    pub script_ext: Option<String>,
+    pub keep_script: bool,
+    pub merge_stderr: bool,
+    pub output_status_prefix: bool,
+    /// The `ps()` / `psf()` of the templates of an execution together, see `RenderDeadline`.
+    pub render_timeout: Duration,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        Self {
+            output_mode: OutputMode::default(),
+            expand_env_in_args: false,
+            export_env: true,
+            condition: None,
+            guard: None,
+            nice: None,
+            shell_kind: ShellKind::default(),
+            script: None,
+            script_ext: None,
+            keep_script: false,
+            merge_stderr: false,
+            output_status_prefix: false,
+            render_timeout: DEFAULT_RENDER_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Status {
+    Exited(ExitStatus),
+    /// `Command::spawn` failed, e.g. the rendered cmd was not found.
+    FailedToStart {
+        kind: io::ErrorKind,
+        cmd: String,
+    },
+    /// A template field failed to render, e.g. a tera function got a wrong argument.
+    RenderFailed {
+        field: String,
+        error: String,
+    },
+}
+
+impl Status {
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            Status::Exited(status) => status.code(),
+            Status::FailedToStart { .. } | Status::RenderFailed { .. } => None,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        matches!(self, Status::Exited(status) if status.success())
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Exited(status) => write!(f, "{}", status),
+            Status::FailedToStart { kind, cmd } => {
+                write!(f, "failed to start: {}, kind: {:?}", cmd, kind)
+            }
+            Status::RenderFailed { field, error } => {
+                write!(f, "failed to render `{}`: {}", field, error)
+            }
+        }
+    }
+}
+
+impl fmt::Display for CommandResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.skipped {
+            Some(reason) => write!(f, "skipped: {:?}", reason),
+            None => write!(
+                f,
+                "status: {}, duration: {:?}, stdout: {}, stderr: {}, result: {}",
+                self.status,
+                self.duration,
+                self.stdout.display(),
+                self.stderr.display(),
+                self.result()
+            ),
+        }
+    }
+}
+
+/// Why a command was not executed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SkipReason {
+    Debounce,
+    Throttle,
+    Condition,
+    Guard,
+    /// The limitkey failed within `failure_cooldown_secs`.
+    Cooldown,
+    /// The limitkey is suspended by `loop_guard`.
+    LoopGuard,
+    /// The event path failed `max_failures` times.
+    Quarantine,
+    /// Not the latest file of a `latest_only` pattern.
+    LatestOnly,
+    /// The event is of a spy with a higher `priority`.
+    Owner,
+    /// A walked file older than `max_age_secs`.
+    MaxAge,
+}
+
+impl SkipReason {
+    /// The name in the `skip_marker`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::Debounce => "debounce",
+            SkipReason::Throttle => "throttle",
+            SkipReason::Condition => "condition",
+            SkipReason::Guard => "guard",
+            SkipReason::Cooldown => "cooldown",
+            SkipReason::LoopGuard => "loop_guard",
+            SkipReason::Quarantine => "quarantine",
+            SkipReason::LatestOnly => "latest_only",
+            SkipReason::Owner => "owner",
+            SkipReason::MaxAge => "max_age",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct CommandResult {
-    status: ExitStatus,
+    status: Status,
     stdout: PathBuf,
     stderr: PathBuf,
-    skipped: bool,
+    skipped: Option<SkipReason>,
+    duration: Duration,
+    result: Vec<(String, String)>,
 }
 
-#[tracing::instrument]
+/// The context of the errors of `render_field`, to tell the field that failed.
+#[derive(Debug)]
+struct RenderField {
+    field: String,
+    template: String,
+}
+
+impl fmt::Display for RenderField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Failed to render `{}`: {}", self.field, self.template)
+    }
+}
+
+// Not `logfn`, it would log a decrypted secret.
+fn render_field(field: &str, template: &str, context: &Context) -> Result<String> {
+    new_tera(field, template)
+        .and_then(|tera| render(&tera, field, context))
+        .with_context(|| RenderField {
+            field: field.to_string(),
+            template: template.to_string(),
+        })
+}
+
+/// The span every log record of one command execution is emitted in.
+pub fn exec_span(
+    name: &str,
+    exec_id: &str,
+    event_path: &Path,
+    pattern_index: usize,
+    pattern_name: &str,
+) -> Span {
+    info_span!(
+        "exec",
+        spy_name = %name,
+        exec_id = %exec_id,
+        event_path = %event_path.display(),
+        pattern_index,
+        pattern_name = %pattern_name
+    )
+}
+
+impl CommandResult {
+    pub fn skip(reason: SkipReason) -> Self {
+        Self {
+            status: Status::Exited(ExitStatus::default()),
+            stdout: PathBuf::new(),
+            stderr: PathBuf::new(),
+            skipped: Some(reason),
+            duration: Duration::ZERO,
+            result: vec![],
+        }
+    }
+
+    pub fn skipped(&self) -> bool {
+        self.skipped.is_some()
+    }
+
+    pub fn skip_reason(&self) -> Option<SkipReason> {
+        self.skipped
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Not started, by `Command::spawn` or a template field that failed to render.
+    pub fn failed_to_start(&self) -> bool {
+        matches!(
+            self.status,
+            Status::FailedToStart { .. } | Status::RenderFailed { .. }
+        )
+    }
+
+    /// The failed result of `e` if a template field failed to render, otherwise `e` as is.
+    fn render_failed(e: anyhow::Error) -> Result<Self> {
+        let Some(field) = e.downcast_ref::<RenderField>().map(|r| r.field.clone()) else {
+            return Err(e);
+        };
+        error!("{:?}", e);
+        Ok(Self {
+            status: Status::RenderFailed {
+                field,
+                error: format!("{:#}", e),
+            },
+            stdout: PathBuf::new(),
+            stderr: PathBuf::new(),
+            skipped: None,
+            duration: Duration::ZERO,
+            result: vec![],
+        })
+    }
+
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+
+    pub fn code(&self) -> Option<i32> {
+        self.status.code()
+    }
+
+    /// The fields of the result file as `key=value`, separated by spaces.
+    pub fn result(&self) -> String {
+        self.result
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Logs the finished command, as WARN with "slow command" if it took longer than `slow_threshold`.
+    pub fn log_finished(&self, slow_threshold: Option<Duration>) {
+        if self.skipped() || self.failed_to_start() {
+            return;
+        }
+        match slow_threshold.filter(|threshold| self.duration > *threshold) {
+            Some(threshold) => warn!(
+                "slow command ! Finished status: {}, duration: {:?} > {:?}, stdout: {}, stderr: {}, result: {}",
+                self.status,
+                self.duration,
+                threshold,
+                self.stdout.display(),
+                self.stderr.display(),
+                self.result()
+            ),
+            None => info!(
+                "Finished status: {}, duration: {:?}, stdout: {}, stderr: {}, result: {}",
+                self.status,
+                self.duration,
+                self.stdout.display(),
+                self.stderr.display(),
+                self.result()
+            ),
+        }
+    }
+}
+
+/// min / max / avg of the command durations.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DurationStats {
+    pub count: u32,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl DurationStats {
+    pub fn add(&mut self, duration: Duration) {
+        self.min = match self.count {
+            0 => duration,
+            _ => self.min.min(duration),
+        };
+        self.max = self.max.max(duration);
+        self.total += duration;
+        self.count += 1;
+    }
+
+    pub fn avg(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => self.total / n,
+        }
+    }
+}
+
+impl fmt::Display for DurationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "count: {}, min: {:?}, max: {:?}, avg: {:?}",
+            self.count,
+            self.min,
+            self.max,
+            self.avg()
+        )
+    }
+}
+
+#[tracing::instrument(skip(context))]
 #[logfn(Trace)]
 pub fn render_command(cmd_info: CommandInfo, context: Context) -> Result<CommandInfo> {
+    let template = cmd_info.clone();
     let mut context = context.clone();
-    insert_file_context(&cmd_info.event_path, "event", &mut context).unwrap();
-    let tera = new_tera("spy_name", &cmd_info.name)?;
-    let spy_name = tera.render("spy_name", &context)?;
+    insert_file_context(&cmd_info.event_path, "event", &mut context)?;
+    let spy_name = render_field("spy_name", &cmd_info.name, &context)?;
     context.insert("spy_name", &spy_name);
-    let tera = new_tera("cmd", &cmd_info.cmd)?;
-    let cmd = tera.render("cmd", &context)?;
+    let cmd = render_field("cmd", &cmd_info.cmd, &context)?;
     context.insert("cmd", &cmd);
-    let arg = &cmd_info
+    let arg = cmd_info
         .arg
         .iter()
+        .map(|s| render_field("arg", s, &context))
         .map(|s| {
-            let tera = new_tera("arg", s).unwrap();
-            tera.render("arg", &context).unwrap()
+            s.map(|s| match cmd_info.expand_env_in_args {
+                true => expand_env(&s),
+                false => s,
+            })
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>>>()?;
     context.insert("arg", &arg.join(" "));
-    let tera = new_tera("input", &cmd_info.input)?;
-    let input = tera.render("input", &context)?;
+    let input = render_field("input", &cmd_info.input, &context)?;
     context.insert("input", &input);
-    let tera = new_tera("output", &cmd_info.output)?;
-    let output = tera.render("output", &context)?;
+    let output = render_field("output", &cmd_info.output, &context)?;
+    if output.trim().is_empty() {
+        bail!(
+            "[{}] `output` rendered empty: {}, the output files would be written to the current directory",
+            &spy_name,
+            &cmd_info.output
+        );
+    }
     context.insert("output", &output);
     create_dir_all(&output)?;
 
-    Ok(CommandInfo {
+    let rendered = CommandInfo {
+        exec_id: cmd_info.exec_id,
         name: cmd_info.name,
         event_path: cmd_info.event_path,
         cmd,
-        arg: arg.to_vec(),
+        arg,
         input,
         output,
-    })
+        expand_env_in_args: cmd_info.expand_env_in_args,
+        limitkey: cmd_info.limitkey,
+        output_mode: cmd_info.output_mode,
+        event_kind: cmd_info.event_kind,
+        export_env: cmd_info.export_env,
+        output_status_prefix: cmd_info.output_status_prefix,
+        script: cmd_info
+            .script
+            .map(|script| render_field("script", &script, &context))
+            .transpose()?,
+        script_ext: cmd_info.script_ext,
+        keep_script: cmd_info.keep_script,
+        merge_stderr: cmd_info.merge_stderr,
+        nice: cmd_info.nice,
+        shell_kind: cmd_info.shell_kind,
+    };
+    log_rendered(&template, &rendered);
+    Ok(rendered)
 }
 
-#[tracing::instrument]
+/// Logs the command as written in the config at debug, and as rendered at info with the fields changed by the rendering.
+fn log_rendered(template: &CommandInfo, rendered: &CommandInfo) {
+    debug!(
+        "Command template: cmd: {}, arg: {:?}, input: {}, output: {}",
+        &template.cmd, &template.arg, &template.input, &template.output
+    );
+    let mut fields = vec![
+        ("cmd".to_string(), &template.cmd, &rendered.cmd),
+        ("input".to_string(), &template.input, &rendered.input),
+        ("output".to_string(), &template.output, &rendered.output),
+    ];
+    fields.extend(
+        template
+            .arg
+            .iter()
+            .zip(&rendered.arg)
+            .enumerate()
+            .map(|(i, (t, r))| (format!("arg[{}]", i), t, r)),
+    );
+    let changed = fields
+        .iter()
+        .filter(|(_, t, r)| t != r)
+        .map(|(name, _, _)| name.as_str())
+        .collect::<Vec<_>>();
+    let arg = rendered.arg.iter().map(|r| scrub(r)).collect::<Vec<_>>();
+    info!(
+        "Command rendered: cmd: {}, arg: {:?}, input: {}, output: {}, changed: [{}]",
+        scrub(&rendered.cmd),
+        arg,
+        scrub(&rendered.input),
+        scrub(&rendered.output),
+        changed.join(", ")
+    );
+}
+
+/// Marks `limitkey` as the latest event to debounce. Returns the mark for `debounce_check`.
+pub fn debounce_mark(limitkey: &str, cache: &Arc<Mutex<HashMap<String, Instant>>>) -> Instant {
+    let now = Instant::now();
+    cache.lock().unwrap().insert(limitkey.to_string(), now);
+    now
+}
+
+/// Executes the command after the debounce window, unless a later event has marked its limitkey.
+#[tracing::instrument(skip(cache))]
 #[logfn(Trace)]
-pub fn debounce_command(
+pub fn debounce_check(
     cmd_info: CommandInfo,
-    threshold: Duration,
-    limitkey: &str,
-    context: Context,
+    marked: Instant,
     cache: &Arc<Mutex<HashMap<String, Instant>>>,
 ) -> Result<CommandResult> {
-    let now = Instant::now();
-    let mut lock = cache.lock().unwrap();
-    lock.insert(limitkey.to_string(), now);
-    drop(lock);
-
-    thread::sleep(threshold);
-
     let lock = cache.lock().unwrap();
-    let executed = lock.get(limitkey).unwrap();
-    if executed > &now {
+    let executed = lock.get(&cmd_info.limitkey).unwrap();
+    if executed > &marked {
         debug!(
             "Debounce ! Skip execute limitkey: {}",
-            &limitkey.to_string(),
+            scrub(&cmd_info.limitkey)
         );
-        return Ok(CommandResult {
-            status: ExitStatus::default(),
-            stdout: PathBuf::new(),
-            stderr: PathBuf::new(),
-            skipped: true,
-        });
+        return Ok(CommandResult::skip(SkipReason::Debounce));
     }
     drop(lock);
 
     exec(cmd_info)
 }
 
-#[tracing::instrument]
+/// Debounces on the calling thread. The watcher waits on a `Timer` instead, see `debounce_mark`.
+#[tracing::instrument(skip(limitkey, cache), fields(limitkey = %scrub(limitkey)))]
+#[logfn(Trace)]
+pub fn debounce_command(
+    cmd_info: CommandInfo,
+    threshold: Duration,
+    limitkey: &str,
+    cache: &Arc<Mutex<HashMap<String, Instant>>>,
+) -> Result<CommandResult> {
+    let marked = debounce_mark(limitkey, cache);
+    thread::sleep(threshold);
+    debounce_check(cmd_info, marked, cache)
+}
+
+#[tracing::instrument(skip(limitkey, cache), fields(limitkey = %scrub(limitkey)))]
 #[logfn(Trace)]
 pub fn throttle_command(
     cmd_info: CommandInfo,
     threshold: Duration,
     limitkey: &str,
-    context: Context,
     cache: &Arc<Mutex<HashMap<String, Instant>>>,
 ) -> Result<CommandResult> {
     let now = Instant::now();
@@ -136,16 +604,8 @@ pub fn throttle_command(
     if let Some(executed) = executed {
         if now.duration_since(*executed) < threshold {
             drop(lock);
-            debug!(
-                "Throttle ! Skip execute limitkey: {}",
-                &limitkey.to_string(),
-            );
-            return Ok(CommandResult {
-                status: ExitStatus::default(),
-                stdout: PathBuf::default(),
-                stderr: PathBuf::default(),
-                skipped: true,
-            });
+            debug!("Throttle ! Skip execute limitkey: {}", scrub(limitkey));
+            return Ok(CommandResult::skip(SkipReason::Throttle));
         }
     }
     lock.insert(limitkey.to_string(), now);
@@ -154,92 +614,776 @@ pub fn throttle_command(
     exec(cmd_info)
 }
 
+/// Whether `limitkey` failed within `cooldown`, see `record_failure`.
+#[logfn(Trace)]
+pub fn in_cooldown(
+    limitkey: &str,
+    cooldown: Duration,
+    failures: &Arc<Mutex<HashMap<String, Instant>>>,
+) -> bool {
+    let in_cooldown = failures
+        .lock()
+        .unwrap()
+        .get(limitkey)
+        .is_some_and(|failed| failed.elapsed() < cooldown);
+    if in_cooldown {
+        debug!("Cooldown ! Skip execute limitkey: {}", scrub(limitkey));
+    }
+    in_cooldown
+}
+
+/// Records the failure time of `limitkey` for `in_cooldown`, or clears it after a success.
+#[logfn(Trace)]
+pub fn record_failure(
+    limitkey: &str,
+    success: bool,
+    failures: &Arc<Mutex<HashMap<String, Instant>>>,
+) {
+    let mut lock = failures.lock().unwrap();
+    match success {
+        true => lock.remove(limitkey),
+        false => lock.insert(limitkey.to_string(), Instant::now()),
+    };
+}
+
+/// The paths tried to find `cmd`: itself if it has a directory, otherwise `cmd` in each PATH entry.
+#[logfn(Trace)]
+fn cmd_candidates(cmd: &str) -> Vec<PathBuf> {
+    let path = Path::new(cmd);
+    if path.components().count() > 1 {
+        return vec![path.to_path_buf()];
+    }
+    #[cfg(windows)]
+    let exts = env::var("PATHEXT")
+        .unwrap_or_default()
+        .split(';')
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| format!("{}{}", cmd, ext.to_lowercase()))
+        .collect::<Vec<_>>();
+    env::split_paths(&env::var_os("PATH").unwrap_or_default())
+        .flat_map(|dir| {
+            let candidates = vec![dir.join(cmd)];
+            #[cfg(windows)]
+            let candidates =
+                [candidates, exts.iter().map(|name| dir.join(name)).collect()].concat();
+            candidates
+        })
+        .collect()
+}
+
+/// EMFILE / ENFILE, or ERROR_TOO_MANY_OPEN_FILES on Windows.
+fn is_too_many_open_files(e: &io::Error) -> bool {
+    #[cfg(windows)]
+    let codes = [4];
+    #[cfg(not(windows))]
+    let codes = [23, 24];
+    e.raw_os_error().is_some_and(|code| codes.contains(&code))
+}
+
+/// Opens an output file to append, retrying with backoff while file descriptors are exhausted.
+fn open_output(path: &Path) -> Result<File> {
+    let mut wait = OPEN_RETRY_WAIT;
+    for i in 0..=OPEN_RETRY_COUNT {
+        match OpenOptions::new().append(true).create(true).open(path) {
+            Err(e) if is_too_many_open_files(&e) && i < OPEN_RETRY_COUNT => {
+                warn!(
+                    "Too many open files, retry {}/{} after {:?}: {}",
+                    i + 1,
+                    OPEN_RETRY_COUNT,
+                    wait,
+                    path.display()
+                );
+                thread::sleep(wait);
+                wait *= 2;
+            }
+            Err(e) if is_too_many_open_files(&e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Too many open files: {}. Consider lowering `cfg.max_threads`",
+                        path.display()
+                    )
+                });
+            }
+            r => return Ok(r?),
+        }
+    }
+    unreachable!()
+}
+
+fn failed_to_start(cmd_info: &CommandInfo, e: io::Error) -> Status {
+    if is_too_many_open_files(&e) {
+        error!("Too many open files. Consider lowering `cfg.max_threads`");
+    }
+    let searched = cmd_candidates(&cmd_info.cmd)
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+    let lookup = match Path::new(&cmd_info.cmd).components().count() > 1 {
+        true => "searched",
+        false => "searched PATH",
+    };
+    error!(
+        "Failed to start cmd: {}, kind: {:?}, e: {}, {}: [{}]",
+        scrub(&cmd_info.cmd),
+        e.kind(),
+        e,
+        lookup,
+        scrub(&searched.join(", "))
+    );
+    Status::FailedToStart {
+        kind: e.kind(),
+        cmd: cmd_info.cmd.clone(),
+    }
+}
+
+/// The stdout / stderr paths of `cmd_info` for its output mode. Both are the stdout path if
+/// `merge_stderr` is set.
+fn output_paths(cmd_info: &CommandInfo) -> (PathBuf, PathBuf) {
+    let output = PathBuf::from(&cmd_info.output);
+    let name = &cmd_info.name;
+    let (stdout_path, stderr_path) = match cmd_info.output_mode {
+        OutputMode::PerExec => {
+            let now = Local::now().format("%Y%m%d_%H%M%S%3f").to_string();
+            let exec_id = &cmd_info.exec_id;
+            (
+                output.join(format!("{}_stdout_{}_{}.log", name, now, exec_id)),
+                output.join(format!("{}_stderr_{}_{}.log", name, now, exec_id)),
+            )
+        }
+        OutputMode::PerKey => {
+            let hash = hex_digest(Algorithm::SHA256, cmd_info.limitkey.as_bytes());
+            let hash = &hash[..16];
+            (
+                output.join(format!("{}_{}_stdout.log", name, hash)),
+                output.join(format!("{}_{}_stderr.log", name, hash)),
+            )
+        }
+        OutputMode::PerDay => {
+            let today = Local::now().format("%Y%m%d").to_string();
+            (
+                output.join(format!("{}_stdout_{}.log", name, today)),
+                output.join(format!("{}_stderr_{}.log", name, today)),
+            )
+        }
+    };
+    if cmd_info.merge_stderr {
+        (stdout_path.clone(), stdout_path)
+    } else {
+        (stdout_path, stderr_path)
+    }
+}
+
+/// Appends a header line with the exec_id and `content` to `path` at once.
+fn append_output(path: &Path, exec_id: &str, content: &[u8]) -> Result<()> {
+    let lock = OUTPUT_LOCKS
+        .lock()
+        .unwrap()
+        .entry(path.to_path_buf())
+        .or_default()
+        .clone();
+    let _lock = lock.lock().unwrap();
+    let mut file = open_output(path)?;
+    let header = format!(
+        "===== exec_id: {} {} =====\n",
+        exec_id,
+        Local::now().format("%Y-%m-%d %H:%M:%S%.3f")
+    );
+    file.write_all(&[header.as_bytes(), content].concat())?;
+    Ok(())
+}
+
+fn result_path(cmd_info: &CommandInfo) -> PathBuf {
+    env::temp_dir().join(format!("spyrun_result_{}.json", cmd_info.exec_id))
+}
+
+/// Reads and removes the result file. Only the scalar fields of a JSON object are taken.
+fn read_result(path: &Path) -> Vec<(String, String)> {
+    if !path.is_file() {
+        return vec![];
+    }
+    let result = (|| -> Result<Vec<(String, String)>> {
+        let len = fs::metadata(path)?.len();
+        if len > RESULT_FILE_MAX_BYTES {
+            bail!("too large: {} > {} bytes", len, RESULT_FILE_MAX_BYTES);
+        }
+        let json: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(json
+            .into_iter()
+            .filter_map(|(k, v)| match v {
+                serde_json::Value::String(s) => Some((k, s)),
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => {
+                    Some((k, v.to_string()))
+                }
+                _ => None,
+            })
+            .collect())
+    })();
+    fs::remove_file(path).unwrap_or_default();
+    result.unwrap_or_else(|e| {
+        warn!("Ignore the result file: {}, e: {:?}", path.display(), e);
+        vec![]
+    })
+}
+
+/// Renames `path` to `{prefix}_{file name}` in the same directory, avoiding existing files.
+fn prefix_file_name(path: &Path, prefix: &str) -> Result<PathBuf> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let renamed = available_path(&path.with_file_name(format!("{}_{}", prefix, name)));
+    fs::rename(path, &renamed)?;
+    Ok(renamed)
+}
+
+/// Writes the rendered `script` to a temp file, and returns the cmd and arg to run it by `script_ext`.
+fn materialize_script(cmd_info: &CommandInfo) -> io::Result<(String, Vec<String>, PathBuf)> {
+    let path = env::temp_dir().join(format!(
+        "spyrun_script_{}.{}",
+        cmd_info.exec_id, cmd_info.script_ext
+    ));
+    fs::write(&path, cmd_info.script.as_deref().unwrap_or_default())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    }
+    let script_path = path.to_string_lossy().to_string();
+    let (cmd, mut arg) = match cmd_info.script_ext.as_str() {
+        "ps1" => (
+            "powershell".to_string(),
+            ["-NoProfile", "-ExecutionPolicy", "Bypass", "-File"]
+                .map(String::from)
+                .to_vec(),
+        ),
+        "cmd" | "bat" => ("cmd".to_string(), vec!["/c".to_string()]),
+        "sh" | "bash" => ("bash".to_string(), vec![]),
+        // Run by its shebang.
+        _ => (script_path.clone(), vec![]),
+    };
+    if cmd != script_path {
+        arg.push(script_path);
+    }
+    arg.extend(cmd_info.arg.iter().cloned());
+    Ok((cmd, arg, path))
+}
+
+/// Clamps `nice` to `NICE_MIN..=NICE_MAX` with a warning.
+fn clamp_nice(nice: i64) -> i32 {
+    let clamped = nice.clamp(NICE_MIN as i64, NICE_MAX as i64) as i32;
+    if clamped as i64 != nice {
+        warn!("nice {} is out of range, use {}", nice, clamped);
+    }
+    clamped
+}
+
+/// Runs the child with `nice`. The result is checked by `check_nice` after spawn.
+#[cfg(unix)]
+fn set_nice(command: &mut Command, nice: i32) {
+    use std::os::unix::process::CommandExt as _;
+    // SAFETY: setpriority is async-signal-safe. The error is reported by `check_nice`.
+    unsafe {
+        command.pre_exec(move || {
+            libc::setpriority(libc::PRIO_PROCESS, 0, nice);
+            Ok(())
+        });
+    }
+}
+
+/// Runs the child in the priority class nearest to `nice`.
+#[cfg(windows)]
+fn set_nice(command: &mut Command, nice: i32) {
+    use std::os::windows::process::CommandExt as _;
+    let class = match nice {
+        15.. => IDLE_PRIORITY_CLASS,
+        5..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+        -4..=4 => NORMAL_PRIORITY_CLASS,
+        -14..=-5 => ABOVE_NORMAL_PRIORITY_CLASS,
+        _ => HIGH_PRIORITY_CLASS,
+    };
+    command.creation_flags(class);
+}
+
+/// Sets `nice` again to the spawned child to log it if the OS rejects it, e.g. a negative `nice`
+/// without privilege.
+#[cfg(unix)]
+fn check_nice(child: &Child, nice: Option<i32>) {
+    let Some(nice) = nice else {
+        return;
+    };
+    // SAFETY: setpriority only takes integers.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, child.id() as libc::id_t, nice) } != 0 {
+        let e = io::Error::last_os_error();
+        // The child may already have exited.
+        if e.raw_os_error() != Some(libc::ESRCH) {
+            warn!(
+                "Failed to set nice {} to pid {}, e: {}",
+                nice,
+                child.id(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn check_nice(_child: &Child, _nice: Option<i32>) {}
+
+/// The command of `cmd_info`, with the `SPYRUN_*` env vars unless `export_env` is false.
+/// The program and arguments to run `cmd` with `shell_kind`.
+fn shell_command(shell_kind: ShellKind, cmd: &str, arg: &[String]) -> (String, Vec<String>) {
+    let translate = |root: &str, s: &str| to_posix_path(s, root).unwrap_or_else(|| s.to_string());
+    match shell_kind {
+        ShellKind::Native => (cmd.to_string(), arg.to_vec()),
+        ShellKind::Wsl => (
+            "wsl.exe".to_string(),
+            ["--".to_string(), translate("/mnt/", cmd)]
+                .into_iter()
+                .chain(arg.iter().map(|a| translate("/mnt/", a)))
+                .collect(),
+        ),
+        ShellKind::GitBash => (
+            cmd.to_string(),
+            arg.iter().map(|a| translate("/", a)).collect(),
+        ),
+    }
+}
+
+fn command(cmd_info: &CommandInfo) -> Command {
+    let (cmd, arg) = shell_command(cmd_info.shell_kind, &cmd_info.cmd, &cmd_info.arg);
+    let mut command = Command::new(cmd);
+    command.args(arg);
+    if let Some(nice) = cmd_info.nice {
+        set_nice(&mut command, nice);
+    }
+    if cmd_info.export_env {
+        command
+            .env("SPYRUN_EVENT_PATH", &cmd_info.event_path)
+            .env("SPYRUN_EVENT_KIND", &cmd_info.event_kind)
+            .env("SPYRUN_SPY_NAME", &cmd_info.name)
+            .env("SPYRUN_INPUT", &cmd_info.input)
+            .env("SPYRUN_OUTPUT", &cmd_info.output)
+            .env("SPYRUN_RESULT_FILE", result_path(cmd_info));
+    }
+    command
+}
+
 #[tracing::instrument]
 #[logfn(Debug)]
 pub fn exec(cmd_info: CommandInfo) -> Result<CommandResult> {
-    let now = Local::now().format("%Y%m%d_%H%M%S%3f").to_string();
-    let stdout_path =
-        PathBuf::from(&cmd_info.output).join(format!("{}_stdout_{}.log", &cmd_info.name, now));
-    let stderr_path =
-        PathBuf::from(&cmd_info.output).join(format!("{}_stderr_{}.log", &cmd_info.name, now));
-    let stdout_file = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(&stdout_path)?;
-    let stderr_file = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(&stderr_path)?;
-    info!(
-        "Execute cmd: {}, arg: {}, stdout: {}, stderr: {}",
-        &cmd_info.cmd,
-        &cmd_info.arg.join(" "),
+    let (cmd_info, script_path) = match cmd_info
+        .script
+        .as_ref()
+        .map(|_| materialize_script(&cmd_info))
+    {
+        Some(Ok((cmd, arg, path))) => (
+            CommandInfo {
+                cmd,
+                arg,
+                ..cmd_info
+            },
+            Some(path),
+        ),
+        Some(Err(e)) => {
+            error!("Failed to write script, kind: {:?}, e: {}", e.kind(), e);
+            return Ok(CommandResult {
+                status: Status::FailedToStart {
+                    kind: e.kind(),
+                    cmd: "script".to_string(),
+                },
+                stdout: PathBuf::new(),
+                stderr: PathBuf::new(),
+                skipped: None,
+                duration: Duration::ZERO,
+                result: vec![],
+            });
+        }
+        None => (cmd_info, None),
+    };
+    let (stdout_path, stderr_path) = output_paths(&cmd_info);
+    let _writing = Writing::new(
+        &cmd_info.output,
+        &[stdout_path.clone(), stderr_path.clone()],
+    );
+    // The arguments are in the `Command rendered` line, scrubbed.
+    debug!(
+        "Execute cmd: {}, stdout: {}, stderr: {}",
+        scrub(&cmd_info.cmd),
         stdout_path.display(),
         stderr_path.display()
     );
+    let start = Instant::now();
+    let status = match cmd_info.output_mode {
+        OutputMode::PerExec => {
+            let stdout_file = open_output(&stdout_path)?;
+            // Share the same file handle, so the two streams keep their order.
+            let stderr_file = if cmd_info.merge_stderr {
+                stdout_file.try_clone()?
+            } else {
+                open_output(&stderr_path)?
+            };
+            let child = command(&cmd_info)
+                .stdout(stdout_file)
+                .stderr(stderr_file)
+                .spawn();
+            match child {
+                Ok(mut child) => {
+                    check_nice(&child, cmd_info.nice);
+                    Status::Exited(child.wait()?)
+                }
+                Err(e) => failed_to_start(&cmd_info, e),
+            }
+        }
+        // Capture the output and append it at once, so concurrent executions never interleave.
+        OutputMode::PerKey | OutputMode::PerDay => match command(&cmd_info)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|child| {
+                check_nice(&child, cmd_info.nice);
+                child.wait_with_output()
+            }) {
+            Ok(output) if cmd_info.merge_stderr => {
+                let content = [output.stdout, output.stderr].concat();
+                append_output(&stdout_path, &cmd_info.exec_id, &content)?;
+                Status::Exited(output.status)
+            }
+            Ok(output) => {
+                append_output(&stdout_path, &cmd_info.exec_id, &output.stdout)?;
+                append_output(&stderr_path, &cmd_info.exec_id, &output.stderr)?;
+                Status::Exited(output.status)
+            }
+            Err(e) => failed_to_start(&cmd_info, e),
+        },
+    };
+    let duration = start.elapsed();
+    if let Some(script_path) = script_path {
+        if cmd_info.keep_script {
+            info!("Keep script: {}", script_path.display());
+        } else {
+            fs::remove_file(&script_path).unwrap_or_default();
+        }
+    }
+    // The appended output files of per_key / per_day are shared by the executions.
+    let (stdout_path, stderr_path) =
+        if cmd_info.output_status_prefix && cmd_info.output_mode == OutputMode::PerExec {
+            let prefix = if status.success() { "ok" } else { "err" };
+            let stdout_path = prefix_file_name(&stdout_path, prefix)?;
+            if cmd_info.merge_stderr {
+                (stdout_path.clone(), stdout_path)
+            } else {
+                (stdout_path, prefix_file_name(&stderr_path, prefix)?)
+            }
+        } else {
+            (stdout_path, stderr_path)
+        };
     Ok(CommandResult {
-        status: Command::new(&cmd_info.cmd)
-            .args(&cmd_info.arg)
-            .stdout(stdout_file)
-            .stderr(stderr_file)
-            .spawn()?
-            .wait()?,
+        status,
         stdout: stdout_path,
         stderr: stderr_path,
-        skipped: false,
+        skipped: None,
+        duration,
+        result: read_result(&result_path(&cmd_info)),
     })
 }
 
-#[tracing::instrument]
+/// Renders `condition` with the event context. A template (`{{ ... }}` / `{% ... %}`) must render
+/// `true` or `false`, anything else is evaluated as an expression, e.g. `event_size > 0`.
+#[logfn(Trace)]
+fn is_condition_met(
+    field: &str,
+    condition: &str,
+    event_path: &Path,
+    context: &Context,
+) -> Result<bool> {
+    let mut context = context.clone();
+    insert_file_context(event_path, "event", &mut context)?;
+    let template = if condition.contains("{{") || condition.contains("{%") {
+        condition.to_string()
+    } else {
+        format!("{{% if {} %}}true{{% else %}}false{{% endif %}}", condition)
+    };
+    let rendered = render_field(field, &template, &context)?;
+    match rendered.trim().to_lowercase().as_str() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => bail!(
+            "`{}` must render true or false: {}, rendered: {}",
+            field,
+            condition,
+            rendered
+        ),
+    }
+}
+
+/// Whether `guard` passes: the expression is true, or the command exits with zero.
+#[logfn(Trace)]
+fn is_guard_passed(guard: &Guard, event_path: &Path, context: &Context) -> Result<bool> {
+    let (cmd, arg) = match guard {
+        Guard::Expr(expr) => return is_condition_met("guard", expr, event_path, context),
+        Guard::Cmd { cmd, arg } => (cmd, arg),
+    };
+    let mut context = context.clone();
+    insert_file_context(event_path, "event", &mut context)?;
+    let cmd = render_field("guard", cmd, &context)?;
+    let arg = arg
+        .iter()
+        .map(|a| render_field("guard", a, &context))
+        .collect::<Result<Vec<_>>>()?;
+    match Command::new(&cmd)
+        .args(&arg)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Ok(status) => {
+            let arg = arg.iter().map(|a| scrub(a)).collect::<Vec<_>>();
+            debug!(
+                "Guard cmd: {}, arg: {:?}, status: {}",
+                scrub(&cmd),
+                arg,
+                status
+            );
+            Ok(status.success())
+        }
+        Err(e) => {
+            warn!("Guard failed to start: {}, e: {}", scrub(&cmd), e);
+            Ok(false)
+        }
+    }
+}
+
+/// The templates of a command, rendered with the event context.
+#[derive(Debug, Clone, Default)]
+pub struct CommandTemplate<'a> {
+    /// The spy name, the prefix of the output files.
+    pub name: &'a str,
+    pub input: &'a str,
+    pub output: &'a str,
+    pub cmd: &'a str,
+    pub arg: Vec<String>,
+    /// Rendered by `prepare_command` only. Empty means the whole command.
+    pub limitkey: &'a str,
+}
+
+/// The `CommandInfo` of the event with the settings of `options`, rendered with `context`.
+#[logfn(Trace)]
+pub fn render_command_info(
+    event_path: &Path,
+    template: CommandTemplate,
+    options: &ExecOptions,
+    context: &Context,
+) -> Result<CommandInfo> {
+    let exec_id = context
+        .get("exec_id")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(new_exec_id);
+    render_command(
+        CommandInfo {
+            exec_id,
+            name: template.name.to_string(),
+            event_path: event_path.to_path_buf(),
+            cmd: template.cmd.to_string(),
+            arg: template.arg,
+            input: template.input.to_string(),
+            output: template.output.to_string(),
+            expand_env_in_args: options.expand_env_in_args,
+            limitkey: String::new(),
+            output_mode: options.output_mode,
+            event_kind: context
+                .get("event_kind")
+                .and_then(|v| v.as_str())
+                .map(String::from)
+                .unwrap_or_default(),
+            export_env: options.export_env,
+            output_status_prefix: options.output_status_prefix,
+            script: options.script.clone(),
+            script_ext: options
+                .script_ext
+                .as_deref()
+                .unwrap_or(DEFAULT_SCRIPT_EXT)
+                .to_string(),
+            keep_script: options.keep_script,
+            merge_stderr: options.merge_stderr,
+            nice: options.nice.map(|nice| clamp_nice(nice as i64)),
+            shell_kind: options.shell_kind,
+        },
+        context.clone(),
+    )
+}
+
+/// A command to execute once, without the tera types, for embedding.
+#[derive(Debug, Clone, Default)]
+pub struct ExecRequest {
+    pub event_path: PathBuf,
+    /// The spy name, the prefix of the output files.
+    pub name: String,
+    pub input: String,
+    pub output: String,
+    pub cmd: String,
+    pub arg: Vec<String>,
+    /// The vars to render `cmd`, `arg` and `output` with, in addition to the event vars.
+    pub vars: Vars,
+}
+
+impl ExecRequest {
+    /// Executes the command and waits for it. `debounce` and `throttle` are not applied.
+    pub fn execute(&self) -> Result<CommandResult> {
+        let mut context = Context::new();
+        self.vars.iter().for_each(|(k, v)| context.insert(k, v));
+        let template = CommandTemplate {
+            name: &self.name,
+            input: &self.input,
+            output: &self.output,
+            cmd: &self.cmd,
+            arg: self.arg.clone(),
+            ..Default::default()
+        };
+        let cmd_info = render_command_info(
+            &self.event_path,
+            template,
+            &ExecOptions::default(),
+            &context,
+        )?;
+        exec(cmd_info)
+    }
+}
+
+/// The command of an event before debounce and throttle.
+#[derive(Debug)]
+pub enum Prepared {
+    /// Skipped by `condition` or `guard`.
+    Skip(CommandResult),
+    /// A template field failed to render.
+    Failed(CommandResult),
+    /// Rendered with the limitkey.
+    Ready(CommandInfo),
+}
+
+/// Checks `condition` and `guard`, then renders the command and its limitkey.
+#[tracing::instrument(skip(context))]
+#[logfn(Trace)]
+pub fn prepare_command(
+    event_path: &PathBuf,
+    template: CommandTemplate,
+    options: &ExecOptions,
+    context: &Context,
+) -> Result<Prepared> {
+    prepare(event_path, template, options, context)
+        .or_else(|e| CommandResult::render_failed(e).map(Prepared::Failed))
+}
+
+fn prepare(
+    event_path: &PathBuf,
+    template: CommandTemplate,
+    options: &ExecOptions,
+    context: &Context,
+) -> Result<Prepared> {
+    let _deadline = RenderDeadline::new(options.render_timeout);
+    if let Some(condition) = options.condition.as_deref() {
+        if !is_condition_met("condition", condition, event_path, context)? {
+            debug!("Condition ! Skip execute condition: {}", condition);
+            return Ok(Prepared::Skip(CommandResult::skip(SkipReason::Condition)));
+        }
+    }
+    if let Some(guard) = options.guard.as_ref() {
+        if !is_guard_passed(guard, event_path, context)? {
+            debug!("Guard ! Skip execute guard: {:?}", guard);
+            return Ok(Prepared::Skip(CommandResult::skip(SkipReason::Guard)));
+        }
+    }
+    let limitkey = template.limitkey;
+    let cmd_info = render_command_info(event_path, template, options, context)?;
+    // The event vars and `spy_name` as the command, e.g. for the templates of `limit_scope`.
+    let mut key_context = context.clone();
+    insert_file_context(event_path, "event", &mut key_context)?;
+    key_context.insert("spy_name", &cmd_info.name);
+    let limitkey = render_field("limitkey", limitkey, &key_context)?;
+    let limitkey = if limitkey.is_empty() {
+        cmd_info.to_string()
+    } else {
+        limitkey
+    };
+    Ok(Prepared::Ready(CommandInfo {
+        limitkey,
+        ..cmd_info
+    }))
+}
+
+#[tracing::instrument(skip(context, cache))]
 #[logfn(Trace)]
 pub fn execute_command(
     event_path: &PathBuf,
-    name: &str,
-    input: &str,
-    output: &str,
-    cmd: &str,
-    arg: Vec<String>,
+    template: CommandTemplate,
     debounce: Duration,
     throttle: Duration,
-    limitkey: &str,
+    options: &ExecOptions,
     context: Context,
     cache: &Arc<Mutex<HashMap<String, Instant>>>,
 ) -> Result<CommandResult> {
-    let cmd_info = render_command(
-        CommandInfo {
-            name: name.to_string(),
-            event_path: event_path.clone(),
-            cmd: cmd.to_string(),
-            arg: arg.clone(),
-            input: input.to_string(),
-            output: output.to_string(),
-        },
-        context.clone(),
-    )?;
-    let tera = new_tera("limitkey", limitkey)?;
-    let limitkey = tera.render("limitkey", &context)?;
+    let cmd_info = match prepare_command(event_path, template, options, &context)? {
+        Prepared::Skip(result) | Prepared::Failed(result) => return Ok(result),
+        Prepared::Ready(cmd_info) => cmd_info,
+    };
+    let limitkey = cmd_info.limitkey.clone();
     if debounce > Duration::from_millis(0) {
-        if limitkey.is_empty() {
-            let limitkey = cmd_info.to_string();
-            return debounce_command(cmd_info, debounce, &limitkey, context.clone(), cache);
-        }
-        return debounce_command(cmd_info, debounce, &limitkey, context.clone(), cache);
-    }
-    if throttle > Duration::from_millis(0) {
-        if limitkey.is_empty() {
-            let limitkey = cmd_info.to_string();
-            return throttle_command(cmd_info, throttle, &limitkey, context.clone(), cache);
-        }
-        return throttle_command(cmd_info, throttle, &limitkey, context.clone(), cache);
+        return debounce_command(cmd_info, debounce, &limitkey, cache);
     }
-    panic!("`debounce` or `throttle` must set ! (one must be greater than 0)");
+    // A zero throttle executes immediately, and still records the limitkey.
+    throttle_command(cmd_info, throttle, &limitkey, cache)
 }
 
 #[cfg(test)]
 mod tests {
     use std::env;
 
+    use tracing_subscriber::{fmt::Layer, prelude::*, Registry};
+
     use super::*;
+    use crate::state;
+
+    #[cfg(windows)]
+    #[test]
+    fn test_shell_command() {
+        assert_eq!(
+            to_posix_path(r"C:\Users\spy\a b.sh", "/mnt/").as_deref(),
+            Some("/mnt/c/Users/spy/a b.sh")
+        );
+        assert_eq!(
+            to_posix_path(r"\\?\D:\work", "/mnt/").as_deref(),
+            Some("/mnt/d/work")
+        );
+        assert_eq!(to_posix_path("E:/x", "/").as_deref(), Some("/e/x"));
+        assert_eq!(to_posix_path("C:", "/mnt/").as_deref(), Some("/mnt/c"));
+        assert_eq!(to_posix_path("-c", "/mnt/"), None);
+        assert_eq!(to_posix_path("dir\\a.sh", "/mnt/"), None);
+        assert_eq!(to_posix_path("C:a.sh", "/mnt/"), None);
+
+        let arg = vec!["-c".to_string(), r"C:\watch\a.sh".to_string()];
+        assert_eq!(
+            shell_command(ShellKind::Wsl, "bash", &arg),
+            (
+                "wsl.exe".to_string(),
+                vec![
+                    "--".to_string(),
+                    "bash".to_string(),
+                    "-c".to_string(),
+                    "/mnt/c/watch/a.sh".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            shell_command(ShellKind::GitBash, "bash", &arg),
+            (
+                "bash".to_string(),
+                vec!["-c".to_string(), "/c/watch/a.sh".to_string()]
+            )
+        );
+        assert_eq!(
+            shell_command(ShellKind::Native, "bash", &arg),
+            ("bash".to_string(), arg.clone())
+        );
+    }
 
     #[test]
     fn test_execute_command_with_throttle() -> Result<()> {
@@ -276,14 +1420,17 @@ mod tests {
             handles.push(thread::spawn(move || {
                 let result = execute_command(
                     &event_path,
-                    name,
-                    input,
-                    output.to_str().unwrap(),
-                    cmd,
-                    arg,
+                    CommandTemplate {
+                        name,
+                        input,
+                        output: output.to_str().unwrap(),
+                        cmd,
+                        arg,
+                        ..Default::default()
+                    },
                     Duration::from_millis(0),
                     throttle,
-                    "",
+                    &ExecOptions::default(),
                     context,
                     &cache,
                 )
@@ -292,12 +1439,12 @@ mod tests {
                     assert_eq!(result.status.code(), Some(0));
                     assert_ne!(result.stdout.to_str().unwrap(), "");
                     assert_ne!(result.stderr.to_str().unwrap(), "");
-                    assert!(!result.skipped);
+                    assert!(!result.skipped());
                 } else {
                     assert_eq!(result.status.code(), Some(0));
                     assert_eq!(result.stdout.to_str().unwrap(), "");
                     assert_eq!(result.stderr.to_str().unwrap(), "");
-                    assert!(result.skipped);
+                    assert!(result.skipped());
                 }
             }));
             thread::sleep(Duration::from_millis(100));
@@ -341,14 +1488,17 @@ mod tests {
             handles.push(thread::spawn(move || {
                 let result = execute_command(
                     &event_path,
-                    name,
-                    input,
-                    output.to_str().unwrap(),
-                    cmd,
-                    arg,
+                    CommandTemplate {
+                        name,
+                        input,
+                        output: output.to_str().unwrap(),
+                        cmd,
+                        arg,
+                        ..Default::default()
+                    },
                     Duration::from_millis(0),
                     throttle,
-                    "",
+                    &ExecOptions::default(),
                     context,
                     &cache,
                 )
@@ -356,7 +1506,7 @@ mod tests {
                 assert_eq!(result.status.code(), Some(0));
                 assert_ne!(result.stdout.to_str().unwrap(), "");
                 assert_ne!(result.stderr.to_str().unwrap(), "");
-                assert!(!result.skipped);
+                assert!(!result.skipped());
             }));
             thread::sleep(Duration::from_millis(200));
         }
@@ -404,14 +1554,17 @@ mod tests {
             handles.push(thread::spawn(move || {
                 let result = execute_command(
                     &event_path,
-                    name,
-                    input,
-                    output.to_str().unwrap(),
-                    cmd,
-                    arg,
+                    CommandTemplate {
+                        name,
+                        input,
+                        output: output.to_str().unwrap(),
+                        cmd,
+                        arg,
+                        ..Default::default()
+                    },
                     debounce,
                     Duration::from_millis(0),
-                    "",
+                    &ExecOptions::default(),
                     context,
                     &cache,
                 )
@@ -420,12 +1573,12 @@ mod tests {
                     assert_eq!(result.status.code(), Some(0));
                     assert_eq!(result.stdout.to_str().unwrap(), "");
                     assert_eq!(result.stderr.to_str().unwrap(), "");
-                    assert!(result.skipped);
+                    assert!(result.skipped());
                 } else {
                     assert_eq!(result.status.code(), Some(0));
                     assert_ne!(result.stdout.to_str().unwrap(), "");
                     assert_ne!(result.stderr.to_str().unwrap(), "");
-                    assert!(!result.skipped);
+                    assert!(!result.skipped());
                 }
             }));
             thread::sleep(Duration::from_millis(100));
@@ -469,14 +1622,17 @@ mod tests {
             handles.push(thread::spawn(move || {
                 let result = execute_command(
                     &event_path,
-                    name,
-                    input,
-                    output.to_str().unwrap(),
-                    cmd,
-                    arg,
+                    CommandTemplate {
+                        name,
+                        input,
+                        output: output.to_str().unwrap(),
+                        cmd,
+                        arg,
+                        ..Default::default()
+                    },
                     debounce,
                     Duration::from_millis(0),
-                    "",
+                    &ExecOptions::default(),
                     context,
                     &cache,
                 )
@@ -484,7 +1640,7 @@ mod tests {
                 assert_eq!(result.status.code(), Some(0));
                 assert_ne!(result.stdout.to_str().unwrap(), "");
                 assert_ne!(result.stderr.to_str().unwrap(), "");
-                assert!(!result.skipped);
+                assert!(!result.skipped());
             }));
             thread::sleep(Duration::from_millis(200));
         }
@@ -496,4 +1652,1060 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_execute_command_with_throttle_state() -> Result<()> {
+        let tmp = env::current_dir()?.join("test");
+        let state_file = tmp.join("test_throttle_state").join("state.toml");
+        let output = tmp.join("test_throttle_state");
+        std::fs::remove_file(&state_file).unwrap_or_default();
+        #[cfg(windows)]
+        let cmd = "cmd";
+        #[cfg(not(windows))]
+        let cmd = "/bin/sh";
+        #[cfg(windows)]
+        let arg = vec!["/c", "echo", "test_throttle_state"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        #[cfg(not(windows))]
+        let arg = vec!["-c", "echo", "test_throttle_state"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let run = |cache: &Arc<Mutex<HashMap<String, Instant>>>| {
+            execute_command(
+                &PathBuf::from("event"),
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: output.to_str().unwrap(),
+                    cmd,
+                    arg: arg.clone(),
+                    ..Default::default()
+                },
+                Duration::from_millis(0),
+                Duration::from_secs(60),
+                &ExecOptions::default(),
+                Context::new(),
+                cache,
+            )
+        };
+
+        assert!(state::load(&state_file)?.is_empty());
+        let cache = Arc::new(Mutex::new(HashMap::new()));
+        let saver = state::Saver::new(
+            &state_file,
+            HashMap::from([("test".to_string(), cache.clone())]),
+        );
+        assert!(!saver.save()?);
+        assert!(!run(&cache)?.skipped());
+        assert!(saver.save()?);
+        // Unchanged, so not written again.
+        assert!(!saver.save()?);
+
+        // restart: a fresh process only has what was persisted.
+        let caches = state::load(&state_file)?;
+        assert!(run(&caches["test"])?.skipped());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_with_render_error() -> Result<()> {
+        let tmp = env::current_dir()?.join("test");
+        let output = tmp.join("test_execute_command_with_render_error");
+        let run = |arg: &str, limitkey: &str| {
+            execute_command(
+                &PathBuf::from("event"),
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: output.to_str().unwrap(),
+                    cmd: "echo",
+                    arg: vec![arg.to_string()],
+                    limitkey,
+                },
+                Duration::from_millis(0),
+                Duration::from_millis(100),
+                &ExecOptions::default(),
+                Context::new(),
+                &Arc::new(Mutex::new(HashMap::new())),
+            )
+        };
+        // A failed result naming the field, not an error of the execution.
+        let failed = |result: CommandResult| {
+            assert!(result.failed_to_start());
+            assert!(!result.success());
+            match result.status {
+                Status::RenderFailed { field, error } => (field, error),
+                status => panic!("{:?}", status),
+            }
+        };
+
+        let (field, error) = failed(run("{{ env(arg=1) }}", "")?);
+        assert_eq!(field, "arg");
+        assert!(error.contains("env: arg must be a string"), "{}", error);
+
+        let (field, error) = failed(run("echo", "{{ unknown }}")?);
+        assert_eq!(field, "limitkey");
+        assert!(error.contains("unknown"), "{}", error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_without_debounce_and_throttle() -> Result<()> {
+        let output = env::current_dir()?
+            .join("test")
+            .join("test_execute_command_without_debounce_and_throttle");
+        let cache = Arc::new(Mutex::new(HashMap::new()));
+        let run = || {
+            execute_command(
+                &PathBuf::from("event"),
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: output.to_str().unwrap(),
+                    cmd: "echo",
+                    arg: vec![],
+                    ..Default::default()
+                },
+                Duration::ZERO,
+                Duration::ZERO,
+                &ExecOptions::default(),
+                Context::new(),
+                &cache,
+            )
+        };
+
+        // Executed immediately, each time.
+        assert!(run()?.success());
+        assert!(run()?.success());
+        assert_eq!(cache.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_with_empty_output() -> Result<()> {
+        let mut context = Context::new();
+        context.insert("out_dir", " ");
+        let result = execute_command(
+            &PathBuf::from("event"),
+            CommandTemplate {
+                name: "empty_output",
+                input: "input",
+                output: "{{ out_dir }}",
+                cmd: "echo",
+                arg: vec![],
+                ..Default::default()
+            },
+            Duration::from_millis(0),
+            Duration::from_millis(100),
+            &ExecOptions::default(),
+            context,
+            &Arc::new(Mutex::new(HashMap::new())),
+        );
+        let message = format!("{:?}", result.unwrap_err());
+        assert!(
+            message.contains("[empty_output] `output` rendered empty: {{ out_dir }}"),
+            "{}",
+            message
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_command_with_expand_env_in_args() -> Result<()> {
+        env::set_var("SPYRUN_EXPAND_HOME", "/home/spyrun");
+        let tmp = env::current_dir()?.join("test");
+        let cmd_info = |expand_env_in_args| CommandInfo {
+            exec_id: new_exec_id(),
+            name: "test".to_string(),
+            event_path: PathBuf::from("event"),
+            cmd: "echo".to_string(),
+            arg: vec![
+                "$SPYRUN_EXPAND_HOME/x".to_string(),
+                "${SPYRUN_EXPAND_HOME}".to_string(),
+                "%SPYRUN_EXPAND_HOME%".to_string(),
+                "$SPYRUN_EXPAND_UNSET".to_string(),
+            ],
+            input: "input".to_string(),
+            output: tmp
+                .join("test_expand_env_in_args")
+                .to_string_lossy()
+                .to_string(),
+            expand_env_in_args,
+            limitkey: String::new(),
+            output_mode: OutputMode::PerExec,
+            event_kind: String::new(),
+            export_env: true,
+            output_status_prefix: false,
+            script: None,
+            script_ext: DEFAULT_SCRIPT_EXT.to_string(),
+            keep_script: false,
+            merge_stderr: false,
+            nice: None,
+            shell_kind: ShellKind::Native,
+        };
+
+        let rendered = render_command(cmd_info(true), Context::new())?;
+        assert_eq!(
+            rendered.arg,
+            [
+                "/home/spyrun/x",
+                "/home/spyrun",
+                "/home/spyrun",
+                "$SPYRUN_EXPAND_UNSET"
+            ]
+        );
+
+        let rendered = render_command(cmd_info(false), Context::new())?;
+        assert_eq!(rendered.arg, cmd_info(false).arg);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_log_levels() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_command_log_levels");
+        std::fs::create_dir_all(&tmp)?;
+        let log_path = tmp.join("test.log");
+        let layer = Layer::default()
+            .with_writer(Mutex::new(std::fs::File::create(&log_path)?))
+            .with_ansi(false);
+        let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+        #[cfg(windows)]
+        let (cmd, shell) = ("cmd", "/c");
+        #[cfg(not(windows))]
+        let (cmd, shell) = ("/bin/sh", "-c");
+        let arg = vec![
+            shell.to_string(),
+            "echo {{ event_name }}".to_string(),
+            // `Alice` encrypted.
+            "{{ dec(arg='EzB4qO+2K66gKXPBNRl7owf4EGpo') }}".to_string(),
+            "--pass={{ password }}".to_string(),
+        ];
+        // A var decrypted when the vars are rendered, the arg template has no `dec()`.
+        let tera = new_tera("password", "{{ dec(arg=enc(arg='s3cret')) }}")?;
+        let mut context = Context::new();
+        context.insert("password", &render(&tera, "password", &Context::new())?);
+        let cache = Arc::new(Mutex::new(HashMap::new()));
+        tracing::dispatcher::with_default(&dispatch, || -> Result<()> {
+            // The second is throttled.
+            for _ in 0..2 {
+                execute_command(
+                    &PathBuf::from("event.txt"),
+                    CommandTemplate {
+                        name: "test",
+                        input: "input",
+                        output: tmp.to_str().unwrap(),
+                        cmd,
+                        arg: arg.clone(),
+                        ..Default::default()
+                    },
+                    Duration::from_millis(0),
+                    Duration::from_secs(10),
+                    &ExecOptions::default(),
+                    context.clone(),
+                    &cache,
+                )?;
+            }
+            Ok(())
+        })?;
+
+        let log = std::fs::read_to_string(&log_path)?;
+        let level = |message: &str| {
+            let line = log
+                .lines()
+                .find(|l| l.contains(message))
+                .unwrap_or_else(|| panic!("{} not found: {}", message, log));
+            ["TRACE", "DEBUG", "INFO", "WARN", "ERROR"]
+                .into_iter()
+                .find(|level| line.contains(&format!(" {} ", level)))
+                .unwrap()
+        };
+        assert_eq!(level("Command template: "), "DEBUG");
+        assert_eq!(level("Command rendered: "), "INFO");
+        assert_eq!(level("Execute cmd: "), "DEBUG");
+        assert_eq!(level("Throttle ! Skip execute limitkey: "), "DEBUG");
+        assert!(!log.contains(" WARN "), "{}", log);
+
+        let rendered = log
+            .lines()
+            .find(|l| l.contains("Command rendered: "))
+            .unwrap();
+        assert!(rendered.contains("echo event.txt"), "{}", rendered);
+        assert!(
+            rendered.contains("changed: [arg[1], arg[2], arg[3]]"),
+            "{}",
+            rendered
+        );
+        // The decrypted secret is scrubbed, the template has only the ciphertext.
+        assert!(rendered.contains("\"***\""), "{}", rendered);
+        // Also through a var, only the secret in the field.
+        assert!(rendered.contains("\"--pass=***\""), "{}", rendered);
+        // Nor in the span fields or the limitkey of any line.
+        assert!(!log.contains("Alice"), "{}", log);
+        assert!(!log.contains("s3cret"), "{}", log);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_in_exec_span() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_exec_span");
+        std::fs::create_dir_all(&tmp)?;
+        let log_path = tmp.join("test.log");
+        let layer = Layer::default()
+            .with_writer(Mutex::new(std::fs::File::create(&log_path)?))
+            .with_ansi(false);
+        let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", vec!["/c".to_string(), "echo".to_string()]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("/bin/sh", vec!["-c".to_string(), "echo".to_string()]);
+
+        let handles = (0..2)
+            .map(|i| {
+                let dispatch = dispatch.clone();
+                let output = tmp.clone();
+                let arg = arg.clone();
+                thread::spawn(move || {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        let exec_id = new_exec_id();
+                        let mut context = Context::new();
+                        context.insert("exec_id", &exec_id);
+                        let event_path = PathBuf::from(format!("event{}", i));
+                        let span = exec_span("test", &exec_id, &event_path, i, &i.to_string());
+                        let _enter = span.enter();
+                        let result = execute_command(
+                            &event_path,
+                            CommandTemplate {
+                                name: "test",
+                                input: "input",
+                                output: output.to_str().unwrap(),
+                                cmd,
+                                arg,
+                                ..Default::default()
+                            },
+                            Duration::from_millis(0),
+                            Duration::from_millis(100),
+                            &ExecOptions::default(),
+                            context,
+                            &Arc::new(Mutex::new(HashMap::new())),
+                        )
+                        .unwrap();
+                        assert!(result.stdout.to_string_lossy().contains(&exec_id));
+                        exec_id
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let exec_ids = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_ne!(exec_ids[0], exec_ids[1]);
+
+        let log = std::fs::read_to_string(&log_path)?;
+        for exec_id in exec_ids {
+            let lines = log
+                .lines()
+                .filter(|l| l.contains("Execute cmd"))
+                .filter(|l| l.contains(&format!("exec_id={}", exec_id)))
+                .count();
+            assert_eq!(lines, 1, "{}", log);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slow_command() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_slow_command");
+        std::fs::create_dir_all(&tmp)?;
+        let log_path = tmp.join("test.log");
+        #[cfg(windows)]
+        let (cmd, arg) = (
+            "powershell",
+            vec![
+                "-Command".to_string(),
+                "Start-Sleep -Milliseconds 300".to_string(),
+            ],
+        );
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("sleep", vec!["0.3".to_string()]);
+
+        let mut stats = DurationStats::default();
+        let layer = Layer::default()
+            .with_writer(Mutex::new(std::fs::File::create(&log_path)?))
+            .with_ansi(false);
+        tracing::subscriber::with_default(Registry::default().with(layer), || -> Result<()> {
+            let result = execute_command(
+                &PathBuf::from("event"),
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: tmp.to_str().unwrap(),
+                    cmd,
+                    arg,
+                    ..Default::default()
+                },
+                Duration::from_millis(0),
+                Duration::from_millis(1),
+                &ExecOptions::default(),
+                Context::new(),
+                &Arc::new(Mutex::new(HashMap::new())),
+            )?;
+            assert!(result.duration() >= Duration::from_millis(300));
+            result.log_finished(Some(Duration::from_millis(100)));
+            stats.add(result.duration());
+            Ok(())
+        })?;
+        stats.add(Duration::from_millis(100));
+        stats.add(Duration::from_millis(200));
+
+        let log = std::fs::read_to_string(&log_path)?;
+        let line = log.lines().find(|l| l.contains("slow command")).unwrap();
+        assert!(line.contains("WARN"), "{}", line);
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.min, Duration::from_millis(100));
+        assert!(stats.min <= stats.avg() && stats.avg() <= stats.max);
+        assert!(stats.max >= Duration::from_millis(300));
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_failed_to_start() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_failed_to_start");
+        std::fs::create_dir_all(&tmp)?;
+        let log_path = tmp.join("test.log");
+        let layer = Layer::default()
+            .with_writer(Mutex::new(std::fs::File::create(&log_path)?))
+            .with_ansi(false);
+        let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+        let result = tracing::dispatcher::with_default(&dispatch, || {
+            execute_command(
+                &PathBuf::from("event"),
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: tmp.to_str().unwrap(),
+                    cmd: "spyrun_not_found_command",
+                    arg: vec![],
+                    ..Default::default()
+                },
+                Duration::from_millis(0),
+                Duration::from_millis(1),
+                &ExecOptions::default(),
+                Context::new(),
+                &Arc::new(Mutex::new(HashMap::new())),
+            )
+        })?;
+        assert!(result.failed_to_start());
+        assert!(!result.skipped());
+        assert_eq!(
+            result.status,
+            Status::FailedToStart {
+                kind: io::ErrorKind::NotFound,
+                cmd: "spyrun_not_found_command".to_string(),
+            }
+        );
+        assert_eq!(result.status.code(), None);
+        let candidates = cmd_candidates("spyrun_not_found_command");
+        assert!(candidates
+            .iter()
+            .all(|p| p.ends_with("spyrun_not_found_command")
+                || p.file_stem()
+                    .is_some_and(|s| s == "spyrun_not_found_command")));
+        // Each PATH entry tried is in the log.
+        let log = std::fs::read_to_string(&log_path)?;
+        let line = log
+            .lines()
+            .find(|l| l.contains("Failed to start cmd: spyrun_not_found_command"))
+            .unwrap_or_else(|| panic!("not found: {}", log));
+        assert!(line.contains("searched PATH: ["), "{}", line);
+        let first = env::split_paths(&env::var_os("PATH").unwrap())
+            .next()
+            .unwrap()
+            .join("spyrun_not_found_command");
+        assert!(line.contains(&first.display().to_string()), "{}", line);
+        assert_eq!(candidates[0], first);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_per_day() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_per_day");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", vec!["/c".to_string(), "echo".to_string()]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("echo", vec![]);
+
+        let handles = (0..2)
+            .map(|i| {
+                let tmp = tmp.clone();
+                let mut arg = arg.clone();
+                arg.push(format!("run{}", i));
+                let options = ExecOptions {
+                    output_mode: OutputMode::PerDay,
+                    ..Default::default()
+                };
+                thread::spawn(move || {
+                    execute_command(
+                        &PathBuf::from(format!("event{}", i)),
+                        CommandTemplate {
+                            name: "test",
+                            input: "input",
+                            output: tmp.to_str().unwrap(),
+                            cmd,
+                            arg,
+                            ..Default::default()
+                        },
+                        Duration::from_millis(0),
+                        Duration::from_millis(1),
+                        &options,
+                        Context::new(),
+                        &Arc::new(Mutex::new(HashMap::new())),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+
+        let stdout = tmp.join(format!("test_stdout_{}.log", Local::now().format("%Y%m%d")));
+        assert!(results.iter().all(|r| r.stdout == stdout));
+        let log = std::fs::read_to_string(&stdout)?;
+        assert_eq!(log.matches("===== exec_id: ").count(), 2, "{}", log);
+        assert!(log.contains("run0"), "{}", log);
+        assert!(log.contains("run1"), "{}", log);
+        // Each block is the header followed by its own output.
+        let lines = log.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("===== exec_id: "));
+        assert!(lines[1].trim().starts_with("run"));
+        assert!(lines[2].starts_with("===== exec_id: "));
+        assert!(lines[3].trim().starts_with("run"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_export_env() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_export_env");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        let event_path = tmp.join("event.txt");
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", vec!["/c", "echo", "%SPYRUN_EVENT_PATH%"]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("/bin/sh", vec!["-c", "echo $SPYRUN_EVENT_PATH"]);
+        let arg = arg.into_iter().map(String::from).collect::<Vec<_>>();
+
+        let run = |export_env: bool| {
+            let options = ExecOptions {
+                export_env,
+                ..Default::default()
+            };
+            execute_command(
+                &event_path,
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: tmp.to_str().unwrap(),
+                    cmd,
+                    arg: arg.clone(),
+                    limitkey: &export_env.to_string(),
+                },
+                Duration::from_millis(0),
+                Duration::from_millis(1),
+                &options,
+                Context::new(),
+                &Arc::new(Mutex::new(HashMap::new())),
+            )
+        };
+        let result = run(true)?;
+        let stdout = std::fs::read_to_string(&result.stdout)?;
+        assert_eq!(stdout.trim(), event_path.to_string_lossy());
+
+        let result = run(false)?;
+        let stdout = std::fs::read_to_string(&result.stdout)?;
+        assert_ne!(stdout.trim(), event_path.to_string_lossy());
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_result_file() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_result_file");
+        std::fs::create_dir_all(&tmp)?;
+        let log_path = tmp.join("test.log");
+        #[cfg(windows)]
+        let (cmd, arg) = (
+            "powershell",
+            vec![
+                "-Command".to_string(),
+                r#"Set-Content -Path $env:SPYRUN_RESULT_FILE -Value '{"records": 42, "detail": [1]}'"#
+                    .to_string(),
+            ],
+        );
+        #[cfg(not(windows))]
+        let (cmd, arg) = (
+            "/bin/sh",
+            vec![
+                "-c".to_string(),
+                r#"echo '{"records": 42, "detail": [1]}' > "$SPYRUN_RESULT_FILE""#.to_string(),
+            ],
+        );
+
+        let layer = Layer::default()
+            .with_writer(Mutex::new(std::fs::File::create(&log_path)?))
+            .with_ansi(false);
+        let result =
+            tracing::subscriber::with_default(Registry::default().with(layer), || -> Result<_> {
+                let mut context = Context::new();
+                context.insert("exec_id", "result_file");
+                let result = execute_command(
+                    &PathBuf::from("event"),
+                    CommandTemplate {
+                        name: "test",
+                        input: "input",
+                        output: tmp.to_str().unwrap(),
+                        cmd,
+                        arg,
+                        ..Default::default()
+                    },
+                    Duration::from_millis(0),
+                    Duration::from_millis(1),
+                    &ExecOptions::default(),
+                    context,
+                    &Arc::new(Mutex::new(HashMap::new())),
+                )?;
+                result.log_finished(None);
+                Ok(result)
+            })?;
+
+        assert_eq!(result.result(), "records=42");
+        assert!(!env::temp_dir()
+            .join("spyrun_result_result_file.json")
+            .exists());
+        let log = std::fs::read_to_string(&log_path)?;
+        let line = log.lines().find(|l| l.contains("Finished")).unwrap();
+        assert!(line.contains("result: records=42"), "{}", line);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_result_malformed() -> Result<()> {
+        let path = env::temp_dir().join("spyrun_result_malformed.json");
+        std::fs::write(&path, "{records: ")?;
+        assert!(read_result(&path).is_empty());
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_too_many_open_files() {
+        #[cfg(windows)]
+        let code = 4;
+        #[cfg(not(windows))]
+        let code = 24;
+        assert!(is_too_many_open_files(&io::Error::from_raw_os_error(code)));
+        assert!(!is_too_many_open_files(&io::Error::from(
+            io::ErrorKind::NotFound
+        )));
+    }
+
+    #[test]
+    fn test_execute_command_burst() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_burst");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", vec!["/c".to_string(), "echo".to_string()]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("echo", vec![]);
+
+        // Every execution holds two output files open while it runs.
+        let handles = (0..64)
+            .map(|i| {
+                let tmp = tmp.clone();
+                let arg = arg.clone();
+                thread::spawn(move || {
+                    execute_command(
+                        &PathBuf::from(format!("event{}", i)),
+                        CommandTemplate {
+                            name: "test",
+                            input: "input",
+                            output: tmp.to_str().unwrap(),
+                            cmd,
+                            arg,
+                            ..Default::default()
+                        },
+                        Duration::from_millis(0),
+                        Duration::from_millis(1),
+                        &ExecOptions::default(),
+                        Context::new(),
+                        &Arc::new(Mutex::new(HashMap::new())),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(results.len(), 64);
+        assert!(results.iter().all(|r| r.status.code() == Some(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_output_status_prefix() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_output_status_prefix");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", "/c");
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("/bin/sh", "-c");
+
+        let run = |code: &str| {
+            // `exit N` as one argument, the script of `-c`.
+            let arg = vec![arg.to_string(), format!("exit {}", code)];
+            let options = ExecOptions {
+                output_status_prefix: true,
+                ..Default::default()
+            };
+            execute_command(
+                &PathBuf::from("event"),
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: tmp.to_str().unwrap(),
+                    cmd,
+                    arg,
+                    limitkey: code,
+                },
+                Duration::from_millis(0),
+                Duration::from_millis(1),
+                &options,
+                Context::new(),
+                &Arc::new(Mutex::new(HashMap::new())),
+            )
+        };
+        let name = |p: &PathBuf| p.file_name().unwrap().to_string_lossy().to_string();
+
+        let result = run("1")?;
+        assert!(name(&result.stdout).starts_with("err_test_stdout_"));
+        assert!(name(&result.stderr).starts_with("err_test_stderr_"));
+        assert!(result.stdout.is_file());
+
+        let result = run("0")?;
+        assert!(name(&result.stdout).starts_with("ok_test_stdout_"));
+        assert!(result.stdout.is_file());
+        assert_eq!(std::fs::read_dir(&tmp)?.count(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exec_request() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_exec_request");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        #[cfg(windows)]
+        let (cmd, arg) = (
+            "cmd",
+            vec!["/c", "echo {{ greeting }} {{ event_name }}& exit 2"],
+        );
+        #[cfg(not(windows))]
+        let (cmd, arg) = (
+            "/bin/sh",
+            vec!["-c", "echo {{ greeting }} {{ event_name }}; exit 2"],
+        );
+        let request = ExecRequest {
+            event_path: PathBuf::from("input").join("data.csv"),
+            name: "embed".to_string(),
+            input: "input".to_string(),
+            output: tmp.join("{{ event_stem }}").to_string_lossy().to_string(),
+            cmd: cmd.to_string(),
+            arg: arg.iter().map(|s| s.to_string()).collect(),
+            vars: HashMap::from([("greeting".to_string(), "hello".to_string())]),
+        };
+
+        let result = request.execute()?;
+        assert_eq!(result.code(), Some(2));
+        assert!(result.stdout.starts_with(tmp.join("data")));
+        assert_eq!(
+            std::fs::read_to_string(&result.stdout)?.trim(),
+            "hello data.csv"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_merge_stderr() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_merge_stderr");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        #[cfg(windows)]
+        let (cmd, arg) = (
+            "cmd",
+            vec!["/c", "echo out1& echo err1 1>&2& echo out2& echo err2 1>&2"],
+        );
+        #[cfg(not(windows))]
+        let (cmd, arg) = (
+            "/bin/sh",
+            vec!["-c", "echo out1; echo err1 >&2; echo out2; echo err2 >&2"],
+        );
+
+        let options = ExecOptions {
+            merge_stderr: true,
+            ..Default::default()
+        };
+        let result = execute_command(
+            &PathBuf::from("event"),
+            CommandTemplate {
+                name: "test",
+                input: "input",
+                output: tmp.to_str().unwrap(),
+                cmd,
+                arg: arg.iter().map(|s| s.to_string()).collect(),
+                limitkey: "merge_stderr",
+            },
+            Duration::from_millis(0),
+            Duration::from_millis(1),
+            &options,
+            Context::new(),
+            &Arc::new(Mutex::new(HashMap::new())),
+        )?;
+        assert!(result.success());
+        assert_eq!(result.stdout, result.stderr);
+        assert_eq!(std::fs::read_dir(&tmp)?.count(), 1);
+        let lines = std::fs::read_to_string(&result.stdout)?
+            .lines()
+            .map(|l| l.trim().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["out1", "err1", "out2", "err2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_with_condition() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_condition");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(&tmp)?;
+        let empty = tmp.join("empty.txt");
+        let data = tmp.join("data.txt");
+        std::fs::write(&empty, "")?;
+        std::fs::write(&data, "data")?;
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", vec!["/c".to_string(), "echo".to_string()]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("echo", vec![]);
+
+        let run = |event_path: &PathBuf, condition: &str| {
+            let options = ExecOptions {
+                condition: Some(condition.to_string()),
+                ..Default::default()
+            };
+            execute_command(
+                event_path,
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: tmp.to_str().unwrap(),
+                    cmd,
+                    arg: arg.clone(),
+                    limitkey: "test",
+                },
+                Duration::from_millis(0),
+                Duration::from_millis(1),
+                &options,
+                Context::new(),
+                &Arc::new(Mutex::new(HashMap::new())),
+            )
+        };
+        assert!(run(&empty, "event_size > 0")?.skipped());
+        assert!(!run(&data, "event_size > 0")?.skipped());
+
+        let lock = "{{ not file_exists(path=event_dir ~ '/' ~ event_stem ~ '.lock') }}";
+        assert!(!run(&data, lock)?.skipped());
+        std::fs::write(tmp.join("data.lock"), "")?;
+        assert!(run(&data, lock)?.skipped());
+
+        assert!(run(&data, "{{ event_name }}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_with_guard() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_guard");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(&tmp)?;
+        let data = tmp.join("data.txt");
+        std::fs::write(&data, "data")?;
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", vec!["/c".to_string(), "echo".to_string()]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("echo", vec![]);
+        #[cfg(windows)]
+        let exit = |code: &str| Guard::Cmd {
+            cmd: "cmd".to_string(),
+            arg: vec!["/c".to_string(), format!("exit {}", code)],
+        };
+        #[cfg(not(windows))]
+        let exit = |code: &str| Guard::Cmd {
+            cmd: "/bin/sh".to_string(),
+            arg: vec!["-c".to_string(), format!("exit {}", code)],
+        };
+
+        let run = |guard: Guard| {
+            let options = ExecOptions {
+                guard: Some(guard),
+                ..Default::default()
+            };
+            execute_command(
+                &data,
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: tmp.to_str().unwrap(),
+                    cmd,
+                    arg: arg.clone(),
+                    limitkey: &new_exec_id(),
+                },
+                Duration::from_millis(0),
+                Duration::from_millis(1),
+                &options,
+                Context::new(),
+                &Arc::new(Mutex::new(HashMap::new())),
+            )
+        };
+        let result = run(exit("1"))?;
+        assert_eq!(result.skip_reason(), Some(SkipReason::Guard));
+        let result = run(exit("0"))?;
+        assert_eq!(result.skip_reason(), None);
+        assert!(result.success());
+
+        let result = run(Guard::Cmd {
+            cmd: "not_found_guard_cmd".to_string(),
+            arg: vec![],
+        })?;
+        assert_eq!(result.skip_reason(), Some(SkipReason::Guard));
+
+        let result = run(Guard::Expr("event_size == 0".to_string()))?;
+        assert_eq!(result.skip_reason(), Some(SkipReason::Guard));
+        let result = run(Guard::Expr("event_name == 'data.txt'".to_string()))?;
+        assert!(!result.skipped());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_execute_command_nice() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_nice");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+
+        let run = |nice: i32, output_mode: OutputMode| -> Result<String> {
+            let options = ExecOptions {
+                nice: Some(nice),
+                output_mode,
+                ..Default::default()
+            };
+            let result = execute_command(
+                &PathBuf::from("event"),
+                CommandTemplate {
+                    name: "test",
+                    input: "input",
+                    output: tmp.to_str().unwrap(),
+                    cmd: "nice",
+                    arg: vec![],
+                    limitkey: &new_exec_id(),
+                },
+                Duration::from_millis(0),
+                Duration::from_millis(1),
+                &options,
+                Context::new(),
+                &Arc::new(Mutex::new(HashMap::new())),
+            )?;
+            assert!(result.success());
+            let stdout = std::fs::read_to_string(&result.stdout)?;
+            Ok(stdout.lines().last().unwrap_or_default().trim().to_string())
+        };
+        assert_eq!(run(10, OutputMode::PerExec)?, "10");
+        assert_eq!(run(5, OutputMode::PerKey)?, "5");
+        // Clamped to the max.
+        assert_eq!(run(100, OutputMode::PerExec)?, "19");
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_command_script() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_script");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        #[cfg(windows)]
+        let script = "Write-Output 'event: {{ event_path }}'";
+        #[cfg(not(windows))]
+        let script = "echo \"event: {{ event_path }}\"";
+        let event_path = tmp.join("event.txt");
+
+        let mut context = Context::new();
+        context.insert("exec_id", "script");
+        let options = ExecOptions {
+            script: Some(script.to_string()),
+            ..Default::default()
+        };
+        let result = execute_command(
+            &event_path,
+            CommandTemplate {
+                name: "test",
+                input: "input",
+                output: tmp.to_str().unwrap(),
+                cmd: "",
+                arg: vec![],
+                ..Default::default()
+            },
+            Duration::from_millis(0),
+            Duration::from_millis(1),
+            &options,
+            context.clone(),
+            &Arc::new(Mutex::new(HashMap::new())),
+        )?;
+        assert!(result.success(), "{:?}", result);
+        let stdout = std::fs::read_to_string(&result.stdout)?;
+        assert!(stdout.contains("event: "), "{}", stdout);
+        assert!(stdout.contains("event.txt"), "{}", stdout);
+        let script_path =
+            env::temp_dir().join(format!("spyrun_script_script.{}", DEFAULT_SCRIPT_EXT));
+        assert!(!script_path.exists());
+
+        // A var of the same name is only a var, the cmd still runs.
+        context.insert("script", script);
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", vec!["/c".to_string(), "echo cmd".to_string()]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("echo", vec!["cmd".to_string()]);
+        let result = execute_command(
+            &event_path,
+            CommandTemplate {
+                name: "test",
+                input: "input",
+                output: tmp.to_str().unwrap(),
+                cmd,
+                arg,
+                limitkey: "var",
+            },
+            Duration::from_millis(0),
+            Duration::from_millis(1),
+            &ExecOptions::default(),
+            context,
+            &Arc::new(Mutex::new(HashMap::new())),
+        )?;
+        let stdout = std::fs::read_to_string(&result.stdout)?;
+        assert_eq!(stdout.trim(), "cmd");
+        Ok(())
+    }
 }
@@ -0,0 +1,210 @@
+// =============================================================================
+// File        : dedup.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 10:48:05.
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{
+    field::{Field, Value, Visit},
+    span, Event, Metadata, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+#[derive(Debug, Clone)]
+struct Seen {
+    metadata: &'static Metadata<'static>,
+    message: String,
+    since: Instant,
+    suppressed: usize,
+}
+
+/// Wraps a layer and drops the events identical (same target and message) to one already written
+/// within `window`. A summary line is written once the window of a suppressed event ends.
+#[derive(Debug)]
+pub struct Dedup<L> {
+    inner: L,
+    window: Duration,
+    seen: Mutex<HashMap<u64, Seen>>,
+}
+
+impl<L> Dedup<L> {
+    pub fn new(inner: L, window: Duration) -> Self {
+        Self {
+            inner,
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.0, "{:?}", value).unwrap();
+        }
+    }
+}
+
+fn emit_summary<S, L>(inner: &L, seen: &Seen, ctx: Context<'_, S>)
+where
+    S: Subscriber,
+    L: Layer<S>,
+{
+    let fields = seen.metadata.fields();
+    if let Some(field) = fields.field("message") {
+        let message = format!(
+            "suppressed {} duplicates: {}",
+            seen.suppressed, seen.message
+        );
+        let values = [(&field, Some(&message.as_str() as &dyn Value))];
+        inner.on_event(&Event::new(seen.metadata, &fields.value_set(&values)), ctx);
+    }
+}
+
+impl<S, L> Layer<S> for Dedup<L>
+where
+    S: Subscriber,
+    L: Layer<S>,
+{
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.inner.on_layer(subscriber);
+    }
+
+    fn register_callsite(
+        &self,
+        metadata: &'static Metadata<'static>,
+    ) -> tracing::subscriber::Interest {
+        self.inner.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.enabled(metadata, ctx)
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, span: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        self.inner.on_record(span, values, ctx);
+    }
+
+    fn on_follows_from(&self, span: &span::Id, follows: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_follows_from(span, follows, ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let mut hasher = DefaultHasher::new();
+        event.metadata().target().hash(&mut hasher);
+        visitor.0.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(s) = seen.get_mut(&key) {
+            if now.duration_since(s.since) < self.window {
+                s.suppressed += 1;
+                return;
+            }
+        }
+        let mut expired = vec![];
+        seen.retain(|_, s| {
+            let keep = now.duration_since(s.since) < self.window;
+            if !keep && s.suppressed > 0 {
+                expired.push(s.clone());
+            }
+            keep
+        });
+        seen.insert(
+            key,
+            Seen {
+                metadata: event.metadata(),
+                message: visitor.0,
+                since: now,
+                suppressed: 0,
+            },
+        );
+        drop(seen);
+
+        expired
+            .iter()
+            .for_each(|s| emit_summary(&self.inner, s, ctx.clone()));
+        self.inner.on_event(event, ctx);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &span::Id, new: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_id_change(old, new, ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, read_to_string, File},
+        sync::Mutex,
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use tracing::{info, warn};
+    use tracing_subscriber::{fmt::Layer, prelude::*, Registry};
+
+    use super::Dedup;
+
+    #[test]
+    fn test_dedup() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_dedup");
+        create_dir_all(&tmp)?;
+        let log_path = tmp.join("test.log");
+        let layer = Layer::default()
+            .with_writer(Mutex::new(File::create(&log_path)?))
+            .with_ansi(false);
+        let subscriber = Registry::default().with(Dedup::new(layer, Duration::from_millis(200)));
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                warn!("stop watch error");
+            }
+            info!("another message");
+            thread::sleep(Duration::from_millis(300));
+            info!("after window");
+        });
+
+        let log = read_to_string(&log_path)?;
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4, "{}", log);
+        assert_eq!(log.matches("stop watch error").count(), 2, "{}", log);
+        assert!(
+            log.contains("suppressed 99 duplicates: stop watch error"),
+            "{}",
+            log
+        );
+        Ok(())
+    }
+}
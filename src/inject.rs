@@ -0,0 +1,183 @@
+// =============================================================================
+// File        : inject.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 19:02:37.
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+};
+
+use anyhow::{anyhow, bail, Result};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tracing::{error, info};
+
+use crate::message::{parse_event_kind, Message, MessageSender};
+
+/// The extension of the request files in `cfg.inject_dir`.
+const REQUEST_EXT: &str = "inject";
+
+/// The channels of the running spys by name.
+pub type Senders = HashMap<String, MessageSender>;
+
+/// Parses `INJECT <spy_name> <kind> <path>` to the spy name and the event. The path may contain spaces.
+pub fn parse(line: &str) -> Result<(String, Event)> {
+    let mut fields = line.trim().splitn(4, char::is_whitespace);
+    let (Some("INJECT"), Some(name), Some(kind), Some(path)) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        bail!("Expected `INJECT <spy_name> <kind> <path>`: {}", line);
+    };
+    let Some(event_kind) = parse_event_kind(kind) else {
+        bail!("Unknown event kind: {}", kind);
+    };
+    let event = Event::new(event_kind).add_path(PathBuf::from(path.trim()));
+    Ok((name.to_string(), event))
+}
+
+/// Sends the event of `line` to its spy, and waits for the exec_id or the skip reason.
+pub fn inject(line: &str, senders: &Senders) -> Result<String> {
+    let (name, event) = parse(line)?;
+    let tx = senders
+        .get(&name)
+        .ok_or_else(|| anyhow!("Unknown spy: {}", &name))?;
+    let (reply, rx) = mpsc::channel();
+    tx.send(Message::Inject(event, reply))
+        .map_err(|_| anyhow!("[{}] is stopped", &name))?;
+    rx.recv()
+        .map_err(|_| anyhow!("[{}] stopped before handling the event", &name))
+}
+
+/// Injects the lines of the request file `path`, and writes the results to `<stem>.result`.
+/// Renamed to `<stem>.processing` first, so a request is handled once.
+pub fn process_file(path: &Path, senders: &Senders) -> Result<PathBuf> {
+    let processing = path.with_extension("processing");
+    fs::rename(path, &processing)?;
+    let results = fs::read_to_string(&processing)?
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|line| match inject(line, senders) {
+            Ok(result) => format!("{} => {}\n", line.trim(), result),
+            Err(e) => format!("{} => error: {}\n", line.trim(), e),
+        })
+        .collect::<String>();
+    let result_path = path.with_extension("result");
+    fs::write(&result_path, results)?;
+    fs::remove_file(&processing)?;
+    info!("injected: {}", result_path.display());
+    Ok(result_path)
+}
+
+fn is_request(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == REQUEST_EXT) && path.is_file()
+}
+
+fn spawn_process(path: PathBuf, senders: Senders) {
+    thread::spawn(move || {
+        if let Err(e) = process_file(&path, &senders) {
+            // Also when another event of the same file has taken it.
+            error!("Failed to inject: {}, e: {:?}", path.display(), e);
+        }
+    });
+}
+
+/// Watches `dir` for the `*.inject` files, and handles the existing ones.
+pub fn watch(dir: &Path, senders: Senders) -> Result<RecommendedWatcher> {
+    fs::create_dir_all(dir)?;
+    let watched = senders.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |res: Result<Event, notify::Error>| match res {
+            Ok(event) => event
+                .paths
+                .into_iter()
+                .filter(|path| is_request(path))
+                .for_each(|path| spawn_process(path, watched.clone())),
+            Err(e) => error!("inject dir watch error: {:?}", e),
+        })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!("watching inject dir {}", dir.display());
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_request(&path) {
+            spawn_process(path, senders.clone());
+        }
+    }
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, env, fs, sync::mpsc, thread};
+
+    use anyhow::Result;
+    use notify::{
+        event::{CreateKind, ModifyKind, RenameMode},
+        EventKind,
+    };
+
+    use super::{inject, parse, process_file, MessageSender, Senders};
+    use crate::message::Message;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let (name, event) = parse("INJECT csv Create /data/in/a b.csv")?;
+        assert_eq!(name, "csv");
+        assert_eq!(event.kind, EventKind::Create(CreateKind::Any));
+        assert_eq!(event.paths[0].to_string_lossy(), "/data/in/a b.csv");
+        let (_, event) = parse("INJECT csv Modify(Name(To)) /data/in/a.csv")?;
+        assert_eq!(
+            event.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+        assert!(parse("INJECT csv Create").is_err());
+        assert!(parse("INJECT csv Unknown /a.csv").is_err());
+        assert!(parse("EJECT csv Create /a.csv").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_file() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_inject");
+        fs::remove_dir_all(&tmp).unwrap_or_default();
+        fs::create_dir_all(&tmp)?;
+        // A spy replying the path it received, by the controls.
+        let (tx, rx) = mpsc::channel();
+        let tx = MessageSender::new(mpsc::channel().0, tx);
+        let spy = thread::spawn(move || {
+            for message in rx {
+                if let Message::Inject(event, reply) = message {
+                    let path = event.paths[0].to_string_lossy().to_string();
+                    reply.send(format!("exec_id: {}", path)).unwrap();
+                }
+            }
+        });
+        let senders: Senders = HashMap::from([("csv".to_string(), tx)]);
+        assert_eq!(
+            inject("INJECT csv Create a.csv", &senders)?,
+            "exec_id: a.csv"
+        );
+        let e = inject("INJECT unknown Create a.csv", &senders).unwrap_err();
+        assert!(e.to_string().contains("Unknown spy: unknown"), "{}", e);
+
+        let request = tmp.join("replay.inject");
+        fs::write(
+            &request,
+            "# replay\nINJECT csv Create b.csv\n\nINJECT other Create c.csv\n",
+        )?;
+        let result = fs::read_to_string(process_file(&request, &senders)?)?;
+        assert_eq!(
+            result,
+            "INJECT csv Create b.csv => exec_id: b.csv\nINJECT other Create c.csv => error: Unknown spy: other\n"
+        );
+        assert!(!request.exists());
+        assert!(!tmp.join("replay.processing").exists());
+
+        drop(senders);
+        spy.join().unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+// =============================================================================
+// File        : instance.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 11:36:52.
+// =============================================================================
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Result};
+use log_derive::logfn;
+use single_instance::SingleInstance;
+use tracing::{info, warn};
+
+use crate::util::process_exists;
+
+/// The single instance lock and its pid sidecar file, removed when dropped.
+pub struct Lock {
+    _instance: Option<SingleInstance>,
+    pid_path: PathBuf,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.pid_path).unwrap_or_default();
+    }
+}
+
+#[logfn(Trace)]
+fn read_pid(pid_path: &Path) -> Option<u32> {
+    fs::read_to_string(pid_path).ok()?.trim().parse().ok()
+}
+
+fn locked(instance: Option<SingleInstance>, pid_path: &Path) -> Result<Lock> {
+    fs::write(pid_path, process::id().to_string())?;
+    Ok(Lock {
+        _instance: instance,
+        pid_path: pid_path.to_path_buf(),
+    })
+}
+
+/// Acquire the single instance lock `name`.
+///
+/// If the lock is held, the pid in `pid_path` is checked and the lock is taken over only if that
+/// process no longer exists, or `force` is set.
+pub fn acquire(name: &str, pid_path: &Path, force: bool) -> Result<Lock> {
+    let instance = SingleInstance::new(name)?;
+    if instance.is_single() {
+        info!("Acquired the instance lock: {}", name);
+        return locked(Some(instance), pid_path);
+    }
+
+    match read_pid(pid_path) {
+        Some(pid) if process_exists(pid) => {
+            if !force {
+                bail!("Another instance is already running. pid: {}", pid);
+            }
+            warn!(
+                "--force: take over the instance lock of the running pid: {}",
+                pid
+            );
+        }
+        Some(pid) => warn!("The instance lock is stale. pid: {} is not running", pid),
+        None => {
+            if !force {
+                bail!(
+                    "Another instance is already running. pid file: {}",
+                    pid_path.display()
+                );
+            }
+            warn!(
+                "--force: take over the instance lock without pid file: {}",
+                pid_path.display()
+            );
+        }
+    }
+    drop(instance);
+
+    // The lock is a file on non Windows. Recreate it to take it over.
+    #[cfg(not(windows))]
+    {
+        fs::remove_file(name).unwrap_or_default();
+        let instance = SingleInstance::new(name)?;
+        if instance.is_single() {
+            info!("Took over the instance lock: {}", name);
+            return locked(Some(instance), pid_path);
+        }
+    }
+    warn!("Start without the instance lock: {}", name);
+    locked(None, pid_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use anyhow::Result;
+    use single_instance::SingleInstance;
+
+    use super::acquire;
+
+    fn lock_name(name: &str) -> String {
+        let name = format!("spyrun_test_{}", name);
+        #[cfg(not(windows))]
+        let name = env::temp_dir().join(name).to_string_lossy().to_string();
+        name
+    }
+
+    #[test]
+    fn test_acquire_with_stale_pid() -> Result<()> {
+        let name = lock_name("stale");
+        let pid_path = env::temp_dir().join("spyrun_test_stale.pid");
+        let _held = SingleInstance::new(&name)?;
+        // No process has this pid.
+        fs::write(&pid_path, u32::MAX.to_string())?;
+
+        let lock = acquire(&name, &pid_path, false)?;
+        assert_eq!(fs::read_to_string(&pid_path)?, process::id().to_string());
+        drop(lock);
+        assert!(!pid_path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_acquire_with_running_pid() -> Result<()> {
+        let name = lock_name("running");
+        let pid_path = env::temp_dir().join("spyrun_test_running.pid");
+        let _held = SingleInstance::new(&name)?;
+        fs::write(&pid_path, process::id().to_string())?;
+
+        let message = format!("{:?}", acquire(&name, &pid_path, false).err().unwrap());
+        assert!(message.contains("Another instance"), "{}", message);
+
+        let _lock = acquire(&name, &pid_path, true)?;
+        Ok(())
+    }
+}
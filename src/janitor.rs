@@ -0,0 +1,150 @@
+// =============================================================================
+// File        : janitor.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 10:02:18.
+// =============================================================================
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use log_derive::logfn;
+use tracing::{debug, info};
+
+/// Output directories used by executed commands.
+static OUTPUT_DIRS: LazyLock<Mutex<HashSet<PathBuf>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Output files currently written by running commands.
+static WRITING: LazyLock<Mutex<HashSet<PathBuf>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Marks output files as being written until dropped.
+#[derive(Debug)]
+pub struct Writing(Vec<PathBuf>);
+
+impl Writing {
+    pub fn new<P: AsRef<Path>>(dir: P, paths: &[PathBuf]) -> Self {
+        OUTPUT_DIRS
+            .lock()
+            .unwrap()
+            .insert(dir.as_ref().to_path_buf());
+        WRITING.lock().unwrap().extend(paths.iter().cloned());
+        Self(paths.to_vec())
+    }
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        let mut writing = WRITING.lock().unwrap();
+        self.0.iter().for_each(|p| {
+            writing.remove(p);
+        });
+    }
+}
+
+pub fn output_dirs() -> Vec<PathBuf> {
+    OUTPUT_DIRS.lock().unwrap().iter().cloned().collect()
+}
+
+fn is_output_log(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".log") && (name.contains("_stdout") || name.contains("_stderr"))
+}
+
+/// Remove the oldest output logs in `dirs` until their total size is at most `max_bytes`.
+///
+/// Files still being written are counted but never removed.
+#[logfn(Debug)]
+pub fn prune(dirs: &[PathBuf], max_bytes: u64) -> Result<usize> {
+    let mut files = vec![];
+    for dir in dirs.iter().filter(|d| d.is_dir()) {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && is_output_log(&entry.path()) {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((modified, metadata.len(), entry.path()));
+            }
+        }
+    }
+
+    let mut total = files.iter().map(|(_, len, _)| len).sum::<u64>();
+    debug!("[janitor] total: {}, max: {}", total, max_bytes);
+    if total <= max_bytes {
+        return Ok(0);
+    }
+
+    files.sort();
+    let writing = WRITING.lock().unwrap().clone();
+    let mut removed = 0;
+    for (_, len, path) in files.into_iter().filter(|(_, _, p)| !writing.contains(p)) {
+        if total <= max_bytes {
+            break;
+        }
+        match fs::remove_file(&path) {
+            Ok(_) => {
+                total -= len;
+                removed += 1;
+                info!("[janitor] removed: {}", path.display());
+            }
+            Err(e) => debug!("[janitor] failed to remove: {}, e: {:?}", path.display(), e),
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, read_dir, remove_dir_all, write},
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::Result;
+
+    use super::{prune, Writing};
+
+    #[test]
+    fn test_prune() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_prune");
+        remove_dir_all(&tmp).unwrap_or_default();
+        let dirs = vec![tmp.join("a"), tmp.join("b")];
+        for dir in &dirs {
+            create_dir_all(dir)?;
+        }
+        for i in 0..10 {
+            let dir = &dirs[i % 2];
+            write(dir.join(format!("spy_stdout_{:03}_id.log", i)), [0u8; 100])?;
+            write(dir.join(format!("spy_stderr_{:03}_id.log", i)), [0u8; 100])?;
+            thread::sleep(Duration::from_millis(10));
+        }
+        write(dirs[0].join("other.log"), [0u8; 1000])?;
+        let newest = dirs[1].join("spy_stdout_009_id.log");
+        let oldest = dirs[0].join("spy_stdout_000_id.log");
+        let writing = Writing::new(&dirs[0], std::slice::from_ref(&oldest));
+
+        let removed = prune(&dirs, 1000)?;
+        assert_eq!(removed, 10);
+        let files = dirs
+            .iter()
+            .flat_map(|d| read_dir(d).unwrap())
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 11);
+        assert!(oldest.is_file());
+        assert!(newest.is_file());
+        assert!(dirs[0].join("other.log").is_file());
+
+        drop(writing);
+        assert_eq!(prune(&dirs, 900)?, 1);
+        assert!(!oldest.is_file());
+        Ok(())
+    }
+}
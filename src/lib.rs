@@ -0,0 +1,4014 @@
+// =============================================================================
+// File        : lib.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 17:10:12.
+// =============================================================================
+
+mod cfg_diff;
+mod command;
+mod dedup;
+mod inject;
+mod instance;
+mod janitor;
+mod logger;
+mod message;
+mod metrics;
+mod mover;
+mod pool;
+mod quarantine;
+mod queue;
+mod ready;
+mod service;
+mod settings;
+mod source;
+mod spy;
+mod state;
+mod timer;
+mod util;
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    env,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Local;
+use clap::Parser;
+use command::{
+    debounce_check, debounce_command, debounce_mark, exec, exec_span, execute_command, in_cooldown,
+    prepare_command, record_failure, render_command_info, throttle_command, CommandTemplate,
+    DurationStats, ExecOptions, Prepared,
+};
+pub use command::{CommandResult, ExecRequest, SkipReason, Status};
+use crypto_hash::{hex_digest, Algorithm};
+use go_defer::defer;
+use log_derive::logfn;
+use message::{
+    event_kind_to_detail, event_kind_to_string, string_to_event_kind, Message, MessageSender,
+    RenameAsCreate,
+};
+pub use message::{Control, SpyEvent};
+use metrics::{SpyMetrics, SpyStats};
+use mover::move_file;
+use normalize_path::NormalizePath;
+pub use notify::RecursiveMode;
+use notify::{Event, RecommendedWatcher, Watcher};
+use path_slash::PathBufExt as _;
+use quarantine::{Quarantine, DEFAULT_QUARANTINE_TTL};
+pub use queue::EventSender;
+use queue::Queue;
+use rayon::{prelude::*, ThreadPool};
+use ready::Startup;
+pub use service::run as run_service;
+use settings::{startup_order, Init, InitPhase, Matcher, StartupMode};
+pub use settings::{
+    ExportFormat, Guard, InputPolicy, LimitScope, LoopGuard, OutputMode, PathsRelativeTo, Pattern,
+    PatternType, PausePolicy, QueuePolicy, Settings, ShellKind, Spy, SpyBuilder, StopAction, Walk,
+    WalkOnError, WatchBackend, When,
+};
+pub use source::{EventSource, Handle, NotifySource, PollSource, TestSource};
+use spy::{WALK_EVENT_INFO, WALK_OLD_EVENT_INFO};
+use tera::Context;
+use timer::Timer;
+use tracing::{debug, error, info, trace, warn};
+use util::{
+    hostname, insert_default_context, insert_file_context, new_exec_id, new_tera, render, scrub,
+    RenderDeadline, DEFAULT_RENDER_TIMEOUT,
+};
+
+/// The sender of the control channel of `run`.
+pub type ControlSender = mpsc::Sender<Control>;
+/// The receiver of the control channel of `run`.
+pub type ControlReceiver = mpsc::Receiver<Control>;
+/// The template vars given in code, e.g. to `run_sync`, by name.
+pub type Vars = HashMap<String, String>;
+
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+const JANITOR_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_AFTER_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the events of a `skip_marker` removed are still skipped by default, plus the poll interval.
+const DEFAULT_SKIP_MARKER_TTL: Duration = Duration::from_secs(1);
+
+/// The `skip_marker` written, by `flag_key`: None while the file exists, else when its removal was seen.
+type Markers = Arc<Mutex<HashMap<String, Option<Instant>>>>;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Cli {
+    /// Sets a custom config file
+    #[arg(short, long, value_name = "FILE", default_value = "spyrun.toml")]
+    config: PathBuf,
+
+    /// Stops after N commands are executed
+    #[arg(long, value_name = "N")]
+    max_events: Option<usize>,
+
+    /// Runs as a Windows service
+    #[arg(long)]
+    pub service: bool,
+
+    /// Takes over the instance lock even if another instance is running
+    #[arg(long)]
+    force: bool,
+
+    /// Logs only errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Walks the spys and writes the matched paths to FILE as NDJSON without executing commands
+    #[arg(long, value_name = "FILE")]
+    walk_report: Option<PathBuf>,
+
+    /// Prints the rendered command for a sample event without executing it
+    #[arg(long, requires = "sample_path")]
+    render: bool,
+
+    /// Executes the command for a sample event once and prints the result
+    #[arg(long, requires = "sample_path", conflicts_with = "render")]
+    run_pattern: bool,
+
+    /// The event path of the sample event for --render and --run-pattern
+    #[arg(long, value_name = "PATH")]
+    sample_path: Option<PathBuf>,
+
+    /// The event kind of the sample event for --render and --run-pattern
+    #[arg(long, value_name = "KIND", default_value = "Create")]
+    sample_kind: String,
+
+    /// Only the spy NAME is matched with the sample event
+    #[arg(long, value_name = "NAME")]
+    spy: Option<String>,
+
+    /// Prints the effective settings, with the inherited values of the spys filled in
+    #[arg(long)]
+    export_config: bool,
+
+    /// The format of --export-config
+    #[arg(long, value_enum, default_value_t = ExportFormat::Toml)]
+    format: ExportFormat,
+}
+
+#[derive(Debug, Clone)]
+struct MaxEvents {
+    max: usize,
+    count: Arc<AtomicUsize>,
+    tx_stop: ControlSender,
+}
+
+impl MaxEvents {
+    #[tracing::instrument]
+    fn executed(&self) {
+        let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        debug!("executed count: {}/{}", count, self.max);
+        if count == self.max {
+            info!("Reached max events: {}", self.max);
+            if let Err(e) = self.tx_stop.send(Control::Stop) {
+                error!("max events stop error: {:?}", e);
+            }
+        }
+    }
+}
+
+/// The received events to handle, held while paused.
+#[derive(Debug)]
+struct Pause {
+    policy: PausePolicy,
+    paused: bool,
+    pending: VecDeque<Event>,
+    dropped: u64,
+}
+
+impl Pause {
+    fn new(policy: PausePolicy) -> Self {
+        Self {
+            policy,
+            paused: false,
+            pending: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Whether `event` is kept, false if dropped while paused.
+    fn push(&mut self, event: Event) -> bool {
+        if self.paused && self.policy == PausePolicy::Drop {
+            debug!("Paused ! Drop event: {:?}", &event.paths);
+            self.dropped += 1;
+            return false;
+        }
+        self.pending.push_back(event);
+        true
+    }
+
+    /// Whether `next` has an event.
+    fn is_ready(&self) -> bool {
+        !self.paused && !self.pending.is_empty()
+    }
+
+    /// The next pending event, none while paused.
+    fn next(&mut self) -> Option<Event> {
+        match self.paused {
+            true => None,
+            false => self.pending.pop_front(),
+        }
+    }
+}
+
+/// Where to reply the result of an injected event, see `Message::Inject`. None for the watched events.
+#[derive(Debug, Default)]
+struct Reply(Option<mpsc::Sender<String>>);
+
+impl Reply {
+    fn send<S: Into<String>>(self, result: S) {
+        if let Some(tx) = self.0 {
+            if tx.send(result.into()).is_err() {
+                debug!("inject requester is gone");
+            }
+        }
+    }
+}
+
+/// The fires by limitkey for `cfg.loop_guard`, shared by the commands of a spy.
+#[derive(Debug)]
+struct LoopDetector {
+    guard: LoopGuard,
+    fires: HashMap<String, Vec<Instant>>,
+    suspended: HashMap<String, Instant>,
+}
+
+impl LoopDetector {
+    fn new(guard: LoopGuard) -> Self {
+        Self {
+            guard,
+            fires: HashMap::new(),
+            suspended: HashMap::new(),
+        }
+    }
+
+    /// Records a fire of `key` and returns false while the key is suspended.
+    #[tracing::instrument(skip_all)]
+    fn fire(&mut self, key: &str) -> bool {
+        let now = Instant::now();
+        self.suspended.retain(|_, until| now < *until);
+        if self.suspended.contains_key(key) {
+            return false;
+        }
+        let window = Duration::from_millis(self.guard.window);
+        self.fires.retain(|_, fires| {
+            fires.retain(|fired| now.duration_since(*fired) < window);
+            !fires.is_empty()
+        });
+        let fires = self.fires.entry(key.to_string()).or_default();
+        fires.push(now);
+        if fires.len() > self.guard.max {
+            error!(
+                "Loop detected ! {} fired {} times within {} ms. Suspend {} ms.",
+                scrub(key),
+                fires.len(),
+                self.guard.window,
+                self.guard.cooldown
+            );
+            self.fires.remove(key);
+            self.suspended.insert(
+                key.to_string(),
+                now + Duration::from_millis(self.guard.cooldown),
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// The vars of the process: `cmd_*`, `cmd_line`, `now`, `cwd` and `hostname`.
+#[tracing::instrument]
+#[logfn(Debug)]
+fn build_cmd_map() -> Result<Context> {
+    let cmd_file = env::current_exe()?;
+    debug!("{:?}", &cmd_file);
+
+    let mut context = Context::new();
+
+    context.insert("cmd_line", &env::args().collect::<Vec<String>>().join(" "));
+    context.insert("now", &Local::now().format("%Y%m%d%H%M%S%3f").to_string());
+    context.insert("cwd", &env::current_dir()?.to_slash_lossy());
+    context.insert("hostname", &hostname());
+    // context.insert("cwd", &env::current_dir()?);
+
+    insert_file_context(&cmd_file, "cmd", &mut context)?;
+
+    Ok(context)
+}
+
+// Called for every event, so the event kind is checked first and the span is only for trace.
+// The matcher is compiled by `Settings::rebuild` and `Spy::builder`, a spy without it matches nothing.
+#[tracing::instrument(level = "trace", skip_all)]
+#[logfn(Trace)]
+fn find_pattern(event: &notify::Event, spy: &Spy) -> Option<(usize, Pattern)> {
+    let matcher = spy.matcher.as_deref()?;
+    if !matcher.subscribes(&event.kind) {
+        return None;
+    }
+    let event_path = event.paths.last()?;
+    let pattern_index = matcher.find(&event_path.to_string_lossy())?;
+    trace!(
+        "event_kind: {:?}, event_path: {}",
+        &event.kind,
+        &event_path.to_string_lossy()
+    );
+    spy.patterns
+        .as_ref()?
+        .get(pattern_index)
+        .map(|p| (pattern_index, p.clone()))
+}
+
+#[tracing::instrument]
+#[logfn(Trace)]
+fn is_watched(event_path: &Path, spy: &Spy) -> bool {
+    let input = Path::new(spy.input.as_ref().unwrap()).normalize();
+    let event_path = event_path.normalize();
+    match spy.recursive {
+        RecursiveMode::Recursive => event_path.starts_with(&input),
+        RecursiveMode::NonRecursive => {
+            event_path == input || event_path.parent() == Some(input.as_path())
+        }
+    }
+}
+
+#[tracing::instrument]
+#[logfn(Trace)]
+fn find_owner<'a>(event: &notify::Event, spys: &'a [Spy]) -> Option<&'a Spy> {
+    let event_path = event.paths.last()?;
+    spys.iter()
+        .filter(|spy| find_pattern(event, spy).is_some() && is_watched(event_path, spy))
+        .min_by_key(|spy| Reverse(spy.priority.unwrap_or_default()))
+}
+
+/// The spys to watch. The disabled spys are skipped, but still usable as `base`.
+fn enabled_spys(spys: &[Spy]) -> Vec<Spy> {
+    spys.iter()
+        .filter(|spy| {
+            if !spy.is_enabled() {
+                info!("[{}] disabled, skip watching", &spy.name);
+            }
+            let events = spy.events.as_deref().unwrap_or_default();
+            if spy.is_enabled() && events.len() > 1 && events.iter().any(|e| e == "Any") {
+                warn!(
+                    "[{}] events {:?} has Any with others, Any already matches every kind",
+                    &spy.name, events
+                );
+            }
+            spy.is_enabled()
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether `event_path` is the newest by mtime among the files in its directory matching `pattern`.
+/// The files with the same mtime are all the newest. A path without mtime (e.g. removed) is not.
+fn is_latest(event_path: &Path, pattern: &Pattern) -> bool {
+    let mtime = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    let (Some(modified), Ok(re)) = (mtime(event_path), pattern.regex()) else {
+        return false;
+    };
+    let Some(entries) = event_path.parent().and_then(|dir| fs::read_dir(dir).ok()) else {
+        return true;
+    };
+    !entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.is_file() && re.is_match(&path.to_string_lossy()))
+        .any(|path| mtime(&path).is_some_and(|m| m > modified))
+}
+
+/// Writes `{spy, path, pattern}` per line to `report` for the walked paths matching the patterns.
+#[tracing::instrument]
+#[logfn(Debug)]
+fn walk_report(spys: &[Spy], report: &Path) -> Result<usize> {
+    let mut file = File::create(report)?;
+    let mut count = 0;
+    for spy in spys.iter().filter(|spy| spy.walk.is_some()) {
+        let (tx, rx) = mpsc::channel();
+        let handle = spy.walk(tx)?;
+        for msg in rx {
+            if let Message::Event(event) = msg {
+                if let Some((_, pattern)) = find_pattern(&event, spy) {
+                    let line = serde_json::json!({
+                        "spy": &spy.name,
+                        "path": event.paths.last().unwrap().to_slash_lossy(),
+                        "pattern": &pattern.pattern,
+                    });
+                    writeln!(file, "{}", line)?;
+                    count += 1;
+                }
+            }
+        }
+        handle.join().unwrap()?;
+    }
+    Ok(count)
+}
+
+/// Applies `input_policy` to the spys whose `input` does not exist. The ignored spys are removed.
+#[tracing::instrument]
+#[logfn(Debug)]
+fn check_inputs(spys: Vec<Spy>) -> Result<Vec<Spy>> {
+    let mut checked = vec![];
+    for spy in spys {
+        let input = Path::new(spy.input.as_ref().unwrap()).normalize();
+        if input.exists() {
+            checked.push(spy);
+            continue;
+        }
+        match spy.input_policy.unwrap_or_default() {
+            InputPolicy::Error => bail!("[{}] input not found: {}", &spy.name, input.display()),
+            InputPolicy::Create => {
+                info!("[{}] create input: {}", &spy.name, input.display());
+                fs::create_dir_all(&input)?;
+                checked.push(spy);
+            }
+            InputPolicy::Wait => {
+                info!("[{}] wait for input: {}", &spy.name, input.display());
+                checked.push(spy);
+            }
+            InputPolicy::Ignore => {
+                warn!(
+                    "[{}] input not found, skip watching: {}",
+                    &spy.name,
+                    input.display()
+                );
+            }
+        }
+    }
+    Ok(checked)
+}
+
+/// The settings of `spy` for the commands, e.g. `{{ spy_throttle }}`, and the whole spy as `spy_config_json`.
+///
+/// The templates in the settings, e.g. `{{ enc(...) }}` in `arg`, are kept as they are in the JSON.
+fn insert_spy_context(context: &mut Context, spy: &Spy) -> Result<()> {
+    context.insert("spy_throttle", &(spy.throttle_of(None).as_millis() as u64));
+    context.insert("spy_debounce", &(spy.debounce_of(None).as_millis() as u64));
+    context.insert(
+        "spy_recursive",
+        &(spy.recursive == RecursiveMode::Recursive),
+    );
+    context.insert(
+        "spy_events",
+        &spy.events.as_deref().unwrap_or_default().join(","),
+    );
+    context.insert(
+        "spy_patterns_count",
+        &spy.patterns.as_ref().map_or(0, |p| p.len()),
+    );
+    context.insert("spy_config_json", &serde_json::to_string(spy)?);
+    Ok(())
+}
+
+/// The settings of `pattern`, or `spy` if unset, for `execute_command`.
+fn exec_options(spy: &Spy, pattern: &Pattern) -> ExecOptions {
+    ExecOptions {
+        output_mode: pattern.output_mode.or(spy.output_mode).unwrap_or_default(),
+        expand_env_in_args: pattern
+            .expand_env_in_args
+            .or(spy.expand_env_in_args)
+            .unwrap_or_default(),
+        export_env: spy.export_env.unwrap_or(true),
+        condition: pattern.condition.clone(),
+        guard: pattern.guard.clone(),
+        nice: pattern.nice.or(spy.nice),
+        shell_kind: pattern.shell_kind.or(spy.shell_kind).unwrap_or_default(),
+        script: pattern.script.clone(),
+        script_ext: pattern.script_ext.clone(),
+        keep_script: pattern.keep_script.unwrap_or_default(),
+        merge_stderr: pattern
+            .merge_stderr
+            .or(spy.merge_stderr)
+            .unwrap_or_default(),
+        output_status_prefix: spy.output_status_prefix.unwrap_or_default(),
+        render_timeout: spy
+            .render_timeout
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_RENDER_TIMEOUT),
+    }
+}
+
+/// The matched pattern of the event, with its capture groups as `match_<name>` and `match_<number>`.
+/// Inserted before `limitkey` is rendered, so it can be keyed by a part of the file name.
+fn insert_pattern_context(
+    context: &mut Context,
+    pattern_index: usize,
+    pattern: &Pattern,
+    event_path: &Path,
+) {
+    context.insert("matched_pattern", &pattern.pattern);
+    context.insert("pattern_index", &pattern_index);
+    context.insert("pattern_name", &pattern.name(pattern_index));
+    let Ok(re) = pattern.regex() else {
+        return;
+    };
+    let path = event_path.to_string_lossy();
+    let Some(captures) = re.captures(&path) else {
+        return;
+    };
+    for (i, name) in re.capture_names().enumerate().skip(1) {
+        let value = captures.get(i).map(|m| m.as_str()).unwrap_or_default();
+        context.insert(format!("match_{}", i), value);
+        if let Some(name) = name {
+            context.insert(format!("match_{}", name), value);
+        }
+    }
+}
+
+/// The `progress_file` or `skip_marker` (by `field`) rendered for the event.
+fn event_file_path(
+    field: &str,
+    template: &str,
+    event_path: &Path,
+    context: &Context,
+) -> Result<PathBuf> {
+    let mut context = context.clone();
+    insert_file_context(event_path, "event", &mut context)?;
+    let tera = new_tera(field, template)?;
+    Ok(PathBuf::from(render(&tera, field, &context)?))
+}
+
+/// Writes why the command of the event was skipped to the `skip_marker`, for the external systems waiting for the output.
+/// The marker is added to `markers` before written, not to trigger the spy.
+fn write_skip_marker(
+    template: &str,
+    reason: SkipReason,
+    limitkey: Option<&str>,
+    event_path: &Path,
+    context: &Context,
+    markers: &Markers,
+) -> Result<()> {
+    let path = event_file_path("skip_marker", template, event_path, context)?;
+    markers.lock().unwrap().insert(flag_key(&path), None);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let marker = serde_json::json!({
+        "reason": reason.as_str(),
+        "limitkey": limitkey.map(scrub),
+        "event_path": event_path.to_string_lossy(),
+        "timestamp": Local::now().to_rfc3339(),
+    });
+    fs::write(&path, serde_json::to_string_pretty(&marker)?)?;
+    Ok(())
+}
+
+/// The first spy and pattern matching a sample event, with the context to render its command.
+struct Sample<'a> {
+    spy: &'a Spy,
+    pattern_index: usize,
+    pattern: Pattern,
+    event_kind: String,
+    options: ExecOptions,
+    context: Context,
+}
+
+impl<'a> Sample<'a> {
+    fn find(spys: &'a [Spy], context: &Context, path: &Path, kind: &str) -> Result<Self> {
+        let event = Event::new(string_to_event_kind(kind)).add_path(path.to_path_buf());
+        let Some((spy, (pattern_index, pattern))) = spys
+            .iter()
+            .find_map(|spy| find_pattern(&event, spy).map(|found| (spy, found)))
+        else {
+            bail!("No pattern matches: {}, kind: {}", path.display(), kind);
+        };
+        let event_kind = event_kind_to_string(event.kind);
+        let mut context = context.clone();
+        context.insert("event_kind", &event_kind);
+        context.insert("event_kind_detail", &event_kind_to_detail(event.kind));
+        insert_pattern_context(&mut context, pattern_index, &pattern, path);
+        let options = exec_options(spy, &pattern);
+        Ok(Self {
+            spy,
+            pattern_index,
+            pattern,
+            event_kind,
+            options,
+            context,
+        })
+    }
+
+    fn command(&self) -> (&str, Vec<String>) {
+        let (cmd, arg) = self.pattern.command(&self.event_kind);
+        (cmd, arg.to_vec())
+    }
+}
+
+/// Renders the command of the first pattern matching a sample event, without executing it.
+fn render_sample(spys: &[Spy], context: &Context, path: &Path, kind: &str) -> Result<String> {
+    let sample = Sample::find(spys, context, path, kind)?;
+    let spy = sample.spy;
+    let (cmd, arg) = sample.command();
+    let cmd_info = render_command_info(
+        path,
+        spy.command_template(cmd, arg),
+        &sample.options,
+        &sample.context,
+    )?;
+    Ok(format!(
+        "[{}] pattern: {}, {}",
+        spy.name,
+        sample.pattern.name(sample.pattern_index),
+        cmd_info
+    ))
+}
+
+/// Executes the command of the first pattern matching a sample event once, and waits for it.
+///
+/// `debounce` and `throttle` are not applied, but `condition` and `guard` are.
+fn run_sample(spys: &[Spy], context: &Context, path: &Path, kind: &str) -> Result<CommandResult> {
+    let sample = Sample::find(spys, context, path, kind)?;
+    let spy = sample.spy;
+    let pattern_name = sample.pattern.name(sample.pattern_index);
+    let exec_id = new_exec_id();
+    let mut context = sample.context.clone();
+    context.insert("exec_id", &exec_id);
+    let span = exec_span(
+        &spy.name,
+        &exec_id,
+        path,
+        sample.pattern_index,
+        &pattern_name,
+    );
+    let _enter = span.enter();
+    info!("[{}] run pattern: {}", &spy.name, &pattern_name);
+    let (cmd, arg) = sample.command();
+    let result = execute_command(
+        &path.to_path_buf(),
+        spy.command_template(cmd, arg),
+        Duration::from_secs(0),
+        Duration::from_secs(1),
+        &sample.options,
+        context,
+        &Arc::new(Mutex::new(HashMap::new())),
+    )?;
+    result.log_finished(None);
+    Ok(result)
+}
+
+/// `walk` for the events of the initial walk or scan (and rescan), `watch` for the others.
+fn event_source(event: &Event) -> &'static str {
+    match event.info() {
+        Some(WALK_EVENT_INFO | WALK_OLD_EVENT_INFO) => "walk",
+        _ => "watch",
+    }
+}
+
+/// The state shared by the commands of a spy.
+#[derive(Clone)]
+struct Shared {
+    cache: state::Cache,
+    /// The last failures by limitkey, for `failure_cooldown_secs`.
+    failures: state::Cache,
+    markers: Markers,
+    quarantine: Option<Arc<Quarantine>>,
+    loop_detector: Option<Arc<Mutex<LoopDetector>>>,
+    metrics: Arc<SpyMetrics>,
+}
+
+/// The events of a spy, handled by `watcher` and `SyncRunner` alike.
+struct Handler {
+    spy: Spy,
+    context: Context,
+    owners: Option<Arc<Vec<Spy>>>,
+    /// Forgotten `marker_ttl` after the removal of the marker.
+    marker_ttl: Duration,
+    renames: Option<RenameAsCreate>,
+    shared: Shared,
+}
+
+impl Handler {
+    fn new(
+        spy: Spy,
+        mut context: Context,
+        owners: Option<Arc<Vec<Spy>>>,
+        cache: state::Cache,
+        loop_guard: Option<LoopGuard>,
+    ) -> Result<Self> {
+        insert_spy_context(&mut context, &spy)?;
+        let marker_ttl = spy
+            .skip_marker_ttl
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_SKIP_MARKER_TTL)
+            + spy
+                .poll
+                .as_ref()
+                .map(|poll| Duration::from_millis(poll.interval))
+                .unwrap_or_default();
+        let quarantine_ttl = spy
+            .quarantine_ttl
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_QUARANTINE_TTL);
+        let shared = Shared {
+            cache,
+            failures: state::Cache::default(),
+            markers: Markers::default(),
+            quarantine: spy
+                .max_failures
+                .map(|max| Arc::new(Quarantine::new(max, quarantine_ttl))),
+            loop_detector: loop_guard.map(|guard| Arc::new(Mutex::new(LoopDetector::new(guard)))),
+            metrics: metrics::spy(&spy.name),
+        };
+        let renames = spy
+            .treat_rename_as_create
+            .unwrap_or_default()
+            .then(RenameAsCreate::default);
+        Ok(Self {
+            spy,
+            context,
+            owners,
+            marker_ttl,
+            renames,
+            shared,
+        })
+    }
+
+    /// The checks of `event` before the dispatch, then the job of its command.
+    /// Err with the reason and the reply of the skip. The reason is None if no command is for `event`.
+    fn handle(&mut self, event: Event) -> Result<Job, (Option<SkipReason>, String)> {
+        let (event, event_path_from) = match self.renames.as_mut() {
+            Some(renames) => match renames.classify(event) {
+                Some(classified) => classified,
+                None => return Err((None, "skipped: the rename is already handled".to_string())),
+            },
+            None => (event, None),
+        };
+        let spy = &self.spy;
+        if is_skip_marker(&event, &self.shared.markers, self.marker_ttl) {
+            debug!("[{}] skip_marker: {:?}", &spy.name, &event.paths);
+            return Err((None, "skipped: skip_marker".to_string()));
+        }
+        let Some((pattern_index, pattern)) = find_pattern(&event, spy) else {
+            return Err((None, "skipped: no pattern matched".to_string()));
+        };
+        let event_path = event.paths.last().unwrap().clone();
+        // The skips before the dispatch, with the marker of `pattern`.
+        let skip = |reason: SkipReason, message: String| {
+            debug!("[{}] {}: {:?}", &spy.name, &message, &event.paths);
+            if let Some(template) = pattern
+                .skip_marker
+                .as_deref()
+                .or(spy.skip_marker.as_deref())
+            {
+                let mut context = self.context.clone();
+                insert_pattern_context(&mut context, pattern_index, &pattern, &event_path);
+                let _deadline = RenderDeadline::new(exec_options(spy, &pattern).render_timeout);
+                if let Err(e) = write_skip_marker(
+                    template,
+                    reason,
+                    None,
+                    &event_path,
+                    &context,
+                    &self.shared.markers,
+                ) {
+                    error!(
+                        "[{}] Failed to write skip_marker: {}, e: {:?}",
+                        &spy.name, template, e
+                    );
+                }
+            }
+            Err((Some(reason), format!("skipped: {}", message)))
+        };
+        if event.info() == Some(WALK_OLD_EVENT_INFO) {
+            return skip(SkipReason::MaxAge, "older than max_age_secs".to_string());
+        }
+        if let Some(quarantine) = self.shared.quarantine.as_ref() {
+            if quarantine.is_quarantined(&event_path) {
+                return skip(SkipReason::Quarantine, "quarantined".to_string());
+            }
+        }
+        if pattern.latest_only.unwrap_or_default() && !is_latest(&event_path, &pattern) {
+            return skip(SkipReason::LatestOnly, "not the latest".to_string());
+        }
+        if let Some(owner) = self.owners.as_ref().and_then(|o| find_owner(&event, o)) {
+            if owner.name != spy.name {
+                return skip(SkipReason::Owner, format!("owned by [{}]", owner.name));
+            }
+        }
+        let event_kind = event_kind_to_string(event.kind);
+        let mut context = self.context.clone();
+        context.insert("event_kind", &event_kind);
+        context.insert("event_kind_detail", &event_kind_to_detail(event.kind));
+        context.insert("event_source", event_source(&event));
+        context.insert(
+            "event_path_from",
+            &event_path_from
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        );
+        insert_pattern_context(&mut context, pattern_index, &pattern, &event_path);
+        debug!("[{}] pattern: {:?}", &spy.name, pattern);
+        let (cmd, arg) = pattern.command(&event_kind);
+        let (cmd, arg) = (cmd.to_string(), arg.to_vec());
+        Ok(Job {
+            spy: spy.clone(),
+            pattern_index,
+            pattern,
+            event_path,
+            cmd,
+            arg,
+            context,
+            shared: self.shared.clone(),
+        })
+    }
+}
+
+/// The command of an event, run on a thread of the pool or on the caller's.
+struct Job {
+    spy: Spy,
+    pattern_index: usize,
+    pattern: Pattern,
+    event_path: PathBuf,
+    cmd: String,
+    arg: Vec<String>,
+    context: Context,
+    shared: Shared,
+}
+
+impl Job {
+    /// Prepares and executes the command, replies its result and gives its status to `done`.
+    /// With `timer` the debounce window is waited on it, then the command is executed on `pool`.
+    /// Without it, on this thread.
+    fn run<F>(self, timer: Option<(Arc<Timer>, Arc<ThreadPool>)>, reply: Reply, done: F)
+    where
+        F: FnOnce(String, Result<CommandResult>) + Send + 'static,
+    {
+        let Job {
+            spy,
+            pattern_index,
+            pattern,
+            event_path,
+            cmd,
+            arg,
+            mut context,
+            shared,
+        } = self;
+        let in_flight = shared.metrics.start();
+        let exec_id = new_exec_id();
+        context.insert("exec_id", &exec_id);
+        let pattern_name = pattern.name(pattern_index);
+        let span = exec_span(
+            &spy.name,
+            &exec_id,
+            &event_path,
+            pattern_index,
+            &pattern_name,
+        );
+        let _enter = span.enter();
+        let options = exec_options(&spy, &pattern);
+        let render_timeout = options.render_timeout;
+        let slow_threshold = pattern
+            .slow_threshold
+            .or(spy.slow_threshold)
+            .map(Duration::from_millis);
+        let cooldown = pattern
+            .failure_cooldown_secs
+            .or(spy.failure_cooldown_secs)
+            .map(Duration::from_secs);
+        let debounce = spy.debounce_of(Some(&pattern));
+        let throttle = spy.throttle_of(Some(&pattern));
+        let prepared = prepare_command(
+            &event_path,
+            spy.command_template(&cmd, arg),
+            &options,
+            &context,
+        );
+        let limitkey = match &prepared {
+            Ok(Prepared::Ready(cmd_info)) => Some(cmd_info.limitkey().to_string()),
+            _ => None,
+        };
+        let cooldown_key = limitkey.clone().filter(|_| cooldown.is_some());
+        let prepared = match (prepared, cooldown) {
+            (Ok(Prepared::Ready(cmd_info)), Some(cooldown))
+                if in_cooldown(cmd_info.limitkey(), cooldown, &shared.failures) =>
+            {
+                Ok(Prepared::Skip(CommandResult::skip(SkipReason::Cooldown)))
+            }
+            (prepared, _) => prepared,
+        };
+        let prepared = match (prepared, shared.loop_detector.as_ref()) {
+            (Ok(Prepared::Ready(cmd_info)), Some(loop_detector))
+                if !loop_detector.lock().unwrap().fire(cmd_info.limitkey()) =>
+            {
+                Ok(Prepared::Skip(CommandResult::skip(SkipReason::LoopGuard)))
+            }
+            (prepared, _) => prepared,
+        };
+        let progress = match (&prepared, pattern.progress_file.as_deref()) {
+            (Ok(Prepared::Ready(_)), Some(template)) => {
+                let _deadline = RenderDeadline::new(render_timeout);
+                match event_file_path("progress_file", template, &event_path, &context) {
+                    Ok(path) => Some(shared.metrics.track_progress(&exec_id, path)),
+                    Err(e) => {
+                        error!(
+                            "[{}] Failed to render progress_file: {}, e: {:?}",
+                            &spy.name, template, e
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let name = spy.name.clone();
+        let cache = shared.cache.clone();
+        let metrics = shared.metrics.clone();
+        let finish = move |status: Result<CommandResult>| {
+            // For the templates of the moves, the hooks and the skip_marker.
+            let _deadline = RenderDeadline::new(render_timeout);
+            let mut move_context = context;
+            move_context.insert("moved_to", "");
+            shared.metrics.record(&status);
+            if let Ok(result) = &status {
+                result.log_finished(slow_threshold);
+                if let Some(key) = cooldown_key.filter(|_| !result.skipped()) {
+                    record_failure(&key, result.success(), &shared.failures);
+                }
+                let quarantined = shared
+                    .quarantine
+                    .filter(|_| !result.skipped())
+                    .is_some_and(|q| q.record(&event_path, result.success()));
+                if let Some(template) = spy.quarantine_move.as_deref().filter(|_| quarantined) {
+                    move_event_file(&spy.name, &event_path, template, &mut move_context);
+                }
+                if !result.skipped() {
+                    if let Some(template) = pattern.move_to(result.success()) {
+                        move_event_file(&spy.name, &event_path, template, &mut move_context);
+                    }
+                    run_hook(&spy, &pattern, result, &event_path, &options, &move_context);
+                }
+                let skip_marker = pattern
+                    .skip_marker
+                    .as_deref()
+                    .or(spy.skip_marker.as_deref());
+                if let (Some(reason), Some(template)) = (result.skip_reason(), skip_marker) {
+                    if let Err(e) = write_skip_marker(
+                        template,
+                        reason,
+                        limitkey.as_deref(),
+                        &event_path,
+                        &move_context,
+                        &shared.markers,
+                    ) {
+                        error!(
+                            "[{}] Failed to write skip_marker: {}, e: {:?}",
+                            &spy.name, template, e
+                        );
+                    }
+                }
+            }
+            reply.send(match &status {
+                Ok(result) if result.skipped() => result.to_string(),
+                Ok(result) => format!("exec_id: {}, {}", exec_id, result),
+                Err(e) => format!("error: {:?}", e),
+            });
+            drop(progress);
+            done(pattern_name, status);
+        };
+        let status = match prepared {
+            Ok(Prepared::Ready(cmd_info)) if debounce.is_zero() => {
+                let limitkey = cmd_info.limitkey().to_string();
+                throttle_command(cmd_info, throttle, &limitkey, &cache)
+            }
+            Ok(Prepared::Ready(cmd_info)) => match timer {
+                // Wait for the debounce window on the timer, not on this pool thread.
+                Some((timer, pool)) => {
+                    let marked = debounce_mark(cmd_info.limitkey(), &cache);
+                    let span = span.clone();
+                    timer.schedule(debounce, move || {
+                        pool.spawn(move || {
+                            pool::set_current_spy(&name);
+                            let in_flight = metrics.start();
+                            let _enter = span.enter();
+                            let status = debounce_check(cmd_info, marked, &cache);
+                            drop(in_flight);
+                            finish(status);
+                        })
+                    });
+                    return;
+                }
+                None => {
+                    let limitkey = cmd_info.limitkey().to_string();
+                    debounce_command(cmd_info, debounce, &limitkey, &cache)
+                }
+            },
+            Ok(Prepared::Skip(result) | Prepared::Failed(result)) => Ok(result),
+            Err(e) => Err(e),
+        };
+        drop(in_flight);
+        finish(status);
+    }
+}
+
+/// Handles the events of a spy on the current thread, for tests and embedding.
+///
+/// The same as the watcher, from `condition` to the hooks, e.g. `failure_cooldown_secs`, `max_failures`
+/// and `skip_marker` across the events. The debounce window is waited on the current thread.
+pub struct SyncRunner {
+    handler: Handler,
+}
+
+impl SyncRunner {
+    /// `spy` is by `Spy::builder` or `Settings::rebuild`, which compile its patterns.
+    pub fn new(spy: &Spy, vars: &Vars) -> Result<Self> {
+        if spy.matcher.is_none() {
+            bail!(
+                "[{}] not built, by Spy::builder or Settings::rebuild",
+                &spy.name
+            );
+        }
+        let mut context = Context::new();
+        vars.iter()
+            .for_each(|(key, value)| context.insert(key, value));
+        let handler = Handler::new(spy.clone(), context, None, state::Cache::default(), None)?;
+        Ok(Self { handler })
+    }
+
+    /// Suspends a limitkey fired too often, as `cfg.loop_guard`.
+    pub fn loop_guard(mut self, guard: LoopGuard) -> Self {
+        self.handler.shared.loop_detector = Some(Arc::new(Mutex::new(LoopDetector::new(guard))));
+        self
+    }
+
+    /// Handles `event`, and returns the result of its command.
+    /// None if no command is for `event`, e.g. no pattern matches or it is of a `skip_marker`.
+    pub fn run(&mut self, event: &SpyEvent) -> Result<Option<CommandResult>> {
+        let job = match self.handler.handle(event.0.clone()) {
+            Ok(job) => job,
+            Err((reason, message)) => {
+                debug!("[{}] {}", &self.handler.spy.name, message);
+                return Ok(reason.map(CommandResult::skip));
+            }
+        };
+        let (tx, rx) = mpsc::channel();
+        job.run(None, Reply::default(), move |_, status| {
+            tx.send(status).unwrap();
+        });
+        rx.recv()?.map(Some)
+    }
+}
+
+/// Handles `event` with `spy` on the current thread by a new `SyncRunner`, see it.
+pub fn run_sync(spy: &Spy, vars: &Vars, event: &SpyEvent) -> Result<Option<CommandResult>> {
+    SyncRunner::new(spy, vars)?.run(event)
+}
+
+/// Moves the event file to `template`, and sets `{{ moved_to }}` to the moved path for the later steps.
+fn move_event_file(spy_name: &str, event_path: &Path, template: &str, context: &mut Context) {
+    match move_file(event_path, template, context) {
+        Ok(Some(moved_to)) => context.insert("moved_to", &moved_to.to_string_lossy()),
+        Ok(None) => {}
+        Err(e) => error!(
+            "[{}] Failed to move: {} to {}, e: {:?}",
+            spy_name,
+            event_path.display(),
+            template,
+            e
+        ),
+    }
+}
+
+/// Executes the `on_success` or `on_error` hook of `pattern` by `result`, after the moves.
+fn run_hook(
+    spy: &Spy,
+    pattern: &Pattern,
+    result: &CommandResult,
+    event_path: &Path,
+    options: &ExecOptions,
+    context: &Context,
+) {
+    let Some((hook_name, hook)) = pattern.hook(result.success()) else {
+        return;
+    };
+    // The pattern's `script` is its command, not the hook's.
+    let options = ExecOptions {
+        script: None,
+        ..options.clone()
+    };
+    let status = render_command_info(
+        event_path,
+        CommandTemplate {
+            name: &format!("{}_{}", spy.name, hook_name),
+            ..spy.command_template(&hook.cmd, hook.arg.clone())
+        },
+        &options,
+        context,
+    )
+    .and_then(exec);
+    match status {
+        Ok(status) if status.success() => {
+            info!("[{}] {} hook finished: {}", &spy.name, hook_name, status)
+        }
+        Ok(status) => error!("[{}] {} hook failed: {}", &spy.name, hook_name, status),
+        Err(e) => error!("[{}] {} hook error: {:?}", &spy.name, hook_name, e),
+    }
+}
+
+#[tracing::instrument(skip(handler), fields(spy = %handler.spy.name))]
+#[logfn(Debug)]
+fn watcher(
+    mut handler: Handler,
+    max_events: Option<MaxEvents>,
+    mut startup: Startup,
+    exec_pool: Arc<ThreadPool>,
+) -> Result<(std::thread::JoinHandle<String>, MessageSender)> {
+    let (tx_events, rx) = mpsc::channel();
+    let (tx_controls, rx_controls) = mpsc::channel();
+    let tx = MessageSender::new(tx_events.clone(), tx_controls);
+    let (tx_execute, rx_execute) = mpsc::channel::<(String, Result<CommandResult>)>();
+    let tx_clone = tx_events.clone();
+    let spy = &handler.spy;
+    info!("[watcher] watch start: {}", &spy.name);
+    let exec_pool = match spy.max_threads {
+        Some(n) => Arc::new(pool::build_spy(&spy.name, n)?),
+        None => exec_pool,
+    };
+    // Patterns may be reloaded to debounce, then the timer is started.
+    let mut timer = debounce_timer(spy)?;
+    let queue = spy.queue_size.map(|size| {
+        Arc::new(Queue::new(
+            &spy.name,
+            size,
+            spy.queue_policy.unwrap_or_default(),
+        ))
+    });
+    let builder = thread::Builder::new().name(format!("spyrun-watch-{}", spy.name));
+    // Patterns are swapped by `Message::ReloadPatterns`.
+    let handle = builder.spawn(move || -> String {
+        let spy = &mut handler.spy;
+        startup.wait(&spy.name);
+        if spy.input_policy == Some(InputPolicy::Wait) {
+            // Not to block the startup until the input appears. A watch error after is only logged.
+            startup.register(&spy.name, Ok(()));
+            if !spy.wait_input(&rx_controls) {
+                info!("[{}] stopped while waiting for input", &spy.name);
+                startup.ready.set();
+                return handler.spy.name;
+            }
+        }
+        if let Some(ref _walk) = spy.walk {
+            let handle = spy.walk(tx_clone.clone()).unwrap();
+            if let Err(e) = handle.join().unwrap() {
+                error!("[watcher] walk error: {}, e: {:?}", &spy.name, e);
+            }
+        }
+        if spy.scan_existing.unwrap_or_default() {
+            match spy.scan(tx_clone.clone()) {
+                Ok(handle) => handle.join().unwrap(),
+                Err(e) => error!("[watcher] scan error: {}, e: {:?}", &spy.name, e),
+            }
+        }
+        // Dropping the watcher stops it.
+        let _watcher = match spy.watch(EventSender::new(tx_clone, queue.clone())) {
+            Ok(watcher) => {
+                info!("[watcher] watch ok: {}", &spy.name);
+                startup.register(&spy.name, Ok(()));
+                watcher
+            }
+            Err(e) => {
+                error!("[watcher] watch error: {}, e: {:?}", &spy.name, e);
+                let message = format!("watch error: {}, e: {:?}", spy.name, e);
+                startup.register(&spy.name, Err(e));
+                startup.ready.set();
+                drop(tx_execute);
+                return message;
+            }
+        };
+        startup.ready.set();
+        let spy_clone = spy.clone();
+        let handle_execute_wait = thread::spawn(move || {
+            let mut stats = DurationStats::default();
+            let mut failed_to_start = 0;
+            let mut executions = BTreeMap::new();
+            rx_execute.into_iter().for_each(|(pattern_name, status)| {
+                debug!(
+                    "[{}] rx_execute received: pattern: {}, {:?}",
+                    &spy_clone.name, pattern_name, status
+                );
+                match status {
+                    Ok(s) if s.failed_to_start() => {
+                        error!("[{}] Command failed to start: {:?}", &spy_clone.name, s);
+                        failed_to_start += 1;
+                    }
+                    Ok(s) => {
+                        debug!("[{}] Command success status: {:?}", &spy_clone.name, s);
+                        match s.skip_reason() {
+                            Some(reason) => {
+                                debug!("[{}] Command skipped by {:?}", &spy_clone.name, reason)
+                            }
+                            None => {
+                                stats.add(s.duration());
+                                *executions.entry(pattern_name).or_insert(0) += 1;
+                            }
+                        }
+                        if let Some(max_events) = max_events.as_ref().filter(|_| !s.skipped()) {
+                            max_events.executed();
+                        }
+                    }
+                    Err(e) => error!("[{}] Command error status: {:?}", &spy_clone.name, e),
+                }
+            });
+            info!(
+                "[{}] duration stats: {}, failed to start: {}, panics: {}, executions per pattern: {:?}",
+                &spy_clone.name, &stats, failed_to_start, pool::panics(&spy_clone.name), &executions
+            );
+        });
+        let spy_metrics = handler.shared.metrics.clone();
+        let mut pause = Pause::new(spy.pause_policy.unwrap_or_default());
+        loop {
+            // The controls first, so Stop is not behind the events. The events are received
+            // only when none is pending, so the pending ones are only those held while paused.
+            let message = match rx_controls.try_recv() {
+                Ok(message) => Some(message),
+                Err(_) if pause.is_ready() => None,
+                Err(_) => match rx.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => break,
+                },
+            };
+            let (event, reply, slot) = match message {
+                None => {
+                    let event = pause.next().unwrap();
+                    // Freed when the event is skipped, or its command finished.
+                    let slot = queued(&queue, &event).map(|queue| queue.slot());
+                    (event, Reply::default(), slot)
+                }
+                Some(Message::Event(event)) => {
+                    spy_metrics.event();
+                    let counted = queued(&queue, &event);
+                    if !pause.push(event) {
+                        if let Some(queue) = counted {
+                            queue.release();
+                        }
+                    }
+                    continue;
+                }
+                // Requested by the operator, so handled even while paused.
+                Some(Message::Inject(event, reply)) => {
+                    info!("[{}] injected: {:?}", &handler.spy.name, &event);
+                    spy_metrics.event();
+                    (event, Reply(Some(reply)), None)
+                }
+                Some(Message::Pause) => {
+                    info!("[{}] paused: {:?}", &handler.spy.name, handler.spy.pause_policy);
+                    pause.paused = true;
+                    continue;
+                }
+                Some(Message::Resume) => {
+                    info!(
+                        "[{}] resumed: {} pending events",
+                        &handler.spy.name,
+                        pause.pending.len()
+                    );
+                    pause.paused = false;
+                    continue;
+                }
+                Some(Message::Rescan) => {
+                    let (tx_walk, rx_walk) = mpsc::channel();
+                    if let Err(e) = handler.spy.rescan(tx_walk) {
+                        error!("[{}] rescan error: {:?}", &handler.spy.name, e);
+                    }
+                    // Behind the received events, as the watched ones.
+                    let mut count = 0;
+                    for msg in rx_walk {
+                        if let Message::Event(_) = msg {
+                            tx_events.send(msg).unwrap();
+                            count += 1;
+                        }
+                    }
+                    info!("[{}] rescanned: {} events", &handler.spy.name, count);
+                    continue;
+                }
+                Some(Message::DumpStats(reply)) => {
+                    let stats = SpyStats {
+                        paused: pause.paused,
+                        pending: pause.pending.len(),
+                        dropped: pause.dropped,
+                        queue_peak: queue.as_ref().map_or(0, |queue| queue.peak()),
+                        queue_dropped: queue.as_ref().map_or(0, |queue| queue.dropped()),
+                        ..spy_metrics.snapshot()
+                    };
+                    if reply.send(stats).is_err() {
+                        debug!("[{}] stats requester is gone", &handler.spy.name);
+                    }
+                    continue;
+                }
+                Some(Message::ReloadPatterns(patterns)) => {
+                    let mut reloaded = handler.spy.clone();
+                    reloaded.patterns = Some(patterns);
+                    match reloaded.validate().and_then(|_| Matcher::new(&reloaded)) {
+                        Ok(matcher) => {
+                            info!(
+                                "[{}] patterns reloaded: {:?}",
+                                &handler.spy.name, &reloaded.patterns
+                            );
+                            handler.spy.patterns = reloaded.patterns;
+                            handler.spy.matcher = Some(Arc::new(matcher));
+                            if timer.is_none() {
+                                timer = debounce_timer(&handler.spy).unwrap_or_else(|e| {
+                                    error!("[{}] Failed to start the timer: {:?}", &handler.spy.name, e);
+                                    None
+                                });
+                            }
+                            if let Err(e) = insert_spy_context(&mut handler.context, &handler.spy) {
+                                error!("[{}] Failed to update spy context: {:?}", &handler.spy.name, e);
+                            }
+                        }
+                        Err(e) => error!(
+                            "[{}] Failed to reload patterns, keep the current: {:?}",
+                            &handler.spy.name, e
+                        ),
+                    }
+                    continue;
+                }
+                Some(Message::Stop) => {
+                    info!("[{}] watch stop !", &handler.spy.name);
+                    break;
+                }
+                // Only to receive the controls.
+                Some(Message::Wake) => continue,
+            };
+            match handler.handle(event) {
+                Ok(job) => {
+                    let tx_execute = tx_execute.clone();
+                    let timer = timer.clone().map(|timer| (timer, exec_pool.clone()));
+                    let name = handler.spy.name.clone();
+                    exec_pool.spawn(move || {
+                        pool::set_current_spy(&name);
+                        job.run(timer, reply, move |pattern_name, status| {
+                            drop(slot);
+                            tx_execute.send((pattern_name, status)).unwrap();
+                        });
+                    });
+                }
+                Err((_, message)) => reply.send(message),
+            }
+        }
+        if let Some(queue) = queue.as_ref() {
+            queue.close();
+        }
+        let discarded = pause.pending.len()
+            + rx
+                .try_iter()
+                .filter(|msg| matches!(msg, Message::Event(_)))
+                .count();
+        if discarded > 0 {
+            warn!(
+                "[{}] {} pending events discarded",
+                &handler.spy.name, discarded
+            );
+        }
+        info!("[{}] channel closed", &handler.spy.name);
+        drop(tx_execute);
+        handle_execute_wait.join().unwrap();
+        handler.spy.name
+    })?;
+
+    Ok((handle, tx))
+}
+
+/// The queue of `queue_size` that counts `event`, only the watched events are.
+fn queued<'a>(queue: &'a Option<Arc<Queue>>, event: &Event) -> Option<&'a Arc<Queue>> {
+    queue.as_ref().filter(|_| event_source(event) == "watch")
+}
+
+/// The timer of the debounce windows, if any pattern of `spy` debounces.
+fn debounce_timer(spy: &Spy) -> Result<Option<Arc<Timer>>> {
+    let debounces = spy
+        .patterns
+        .iter()
+        .flatten()
+        .any(|p| !spy.debounce_of(Some(p)).is_zero());
+    match debounces {
+        true => Ok(Some(Arc::new(Timer::new(&spy.name)?))),
+        false => Ok(None),
+    }
+}
+
+/// Runs the init command, retrying `init.retries` times while it fails.
+/// Bails on the final failure if `init.error_stop`.
+fn run_init(init: &Init, context: &Context) -> Result<()> {
+    let retries = init.retries.unwrap_or_default();
+    let retry_delay = Duration::from_millis(init.retry_delay.unwrap_or_default());
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            warn!(
+                "Init command retry {}/{} after {:?}",
+                attempt, retries, retry_delay
+            );
+            thread::sleep(retry_delay);
+        }
+        let exec_id = new_exec_id();
+        let mut context = context.clone();
+        context.insert("exec_id", &exec_id);
+        let span = exec_span("init", &exec_id, &env::current_exe()?, 0, "init");
+        let _enter = span.enter();
+        let status = execute_command(
+            &(env::current_exe()?),
+            CommandTemplate {
+                name: "init",
+                input: "input",
+                output: context.get("log_dir").unwrap().as_str().unwrap(),
+                cmd: &init.cmd,
+                arg: init.arg.clone(),
+                ..Default::default()
+            },
+            Duration::from_secs(0),
+            Duration::from_secs(1),
+            &ExecOptions::default(),
+            context.clone(),
+            &Arc::new(Mutex::new(HashMap::new())),
+        );
+        let error = match status {
+            Ok(s) if s.failed_to_start() => {
+                error!("Init command failed to start: {:?}", s);
+                anyhow!("Init command failed to start: {:?}", s)
+            }
+            // Without retries, an exit failure is logged as before and does not stop.
+            Ok(s) if retries == 0 || s.success() => {
+                info!("Init command success status: {:?}", s);
+                return Ok(());
+            }
+            Ok(s) => {
+                error!("Init command failed: {:?}", s);
+                anyhow!("Init command failed: {:?}", s)
+            }
+            Err(e) => {
+                error!("Init command error status: {:?}", e);
+                e
+            }
+        };
+        if attempt == retries && init.error_stop {
+            bail!(error);
+        }
+    }
+    Ok(())
+}
+
+/// The form of a path compared to a flag: normalized, and on Windows without `\\?\` and lowercased.
+fn flag_key(path: &Path) -> String {
+    #[cfg(windows)]
+    let path = util::strip_verbatim(path);
+    let key = path.normalize().to_string_lossy().to_string();
+    #[cfg(windows)]
+    let key = key.to_lowercase();
+    key
+}
+
+/// Whether `event` is of a `skip_marker` written by the spy, never handled by it.
+/// A marker is skipped while it exists, and for `ttl` after its removal is seen, for the events queued before.
+/// Then it is forgotten, so a later file of the same path is handled.
+fn is_skip_marker(event: &Event, markers: &Markers, ttl: Duration) -> bool {
+    let Some(path) = event.paths.last() else {
+        return false;
+    };
+    let mut markers = markers.lock().unwrap();
+    let now = Instant::now();
+    markers.retain(|_, removed| removed.is_none_or(|removed| now.duration_since(removed) < ttl));
+    let Some(removed) = markers.get_mut(&flag_key(path)) else {
+        return false;
+    };
+    if removed.is_none() && !path.exists() {
+        *removed = Some(now);
+    }
+    true
+}
+
+/// Whether `event_path` is the flag file `flag`.
+/// Pre-filtered by the file name, so the events of the other files in the directory are cheap.
+fn is_flag(event_path: &Path, flag: &Path) -> bool {
+    let name_key = |p: &Path| p.file_name().map(|name| flag_key(Path::new(name)));
+    match (name_key(event_path), name_key(flag)) {
+        (Some(event_name), Some(flag_name)) if event_name == flag_name => {
+            flag_key(event_path) == flag_key(flag)
+        }
+        _ => false,
+    }
+}
+
+/// Watches the flag file `path` and sends `action` to the stop channel when it is created or modified.
+#[tracing::instrument]
+#[logfn(Debug)]
+fn watch_stop_flag(
+    path: &Path,
+    action: StopAction,
+    tx_stop: ControlSender,
+) -> Result<RecommendedWatcher> {
+    let flag = path.to_path_buf();
+    let mut watcher =
+        notify::recommended_watcher(move |res: Result<Event, notify::Error>| match res {
+            Ok(event) => {
+                let event_str = event_kind_to_string(event.kind);
+                if ["Create", "Modify"].into_iter().any(|e| e == event_str)
+                    && event.paths.last().is_some_and(|p| is_flag(p, &flag))
+                {
+                    tx_stop.send(action.into()).unwrap();
+                }
+            }
+            Err(e) => error!("{} flag watch error: {:?}", action.as_str(), e),
+        })?;
+    watcher.watch(path.parent().unwrap(), RecursiveMode::NonRecursive)?;
+    info!("watching {} flg {}", action.as_str(), path.display());
+    Ok(watcher)
+}
+
+/// Runs spyrun by `cli`: loads the config, sets the logger and the instance lock, then `run`.
+///
+/// The logger is set once per process, so a second `run_cli` in the same process keeps logging by the
+/// `[log]` of the first, and its own `[log]` is only warned about.
+#[tracing::instrument]
+#[logfn(Debug)]
+pub fn run_cli(cli: Cli, tx_stop: ControlSender, rx_stop: ControlReceiver) -> Result<()> {
+    let mut context = build_cmd_map()?;
+    debug!("{:?}", &context);
+    debug!("{:?}", &cli);
+
+    let error_log_path =
+        Path::new(context.get("cmd_dir").unwrap().as_str().unwrap()).join("error.log");
+
+    let mut load_error = String::new();
+    let mut backup_report = vec![];
+    let settings = Settings::new(&cli.config, true, &mut context);
+    let settings = match settings {
+        Ok(s) => s.rebuild()?,
+        Err(e) => {
+            load_error = format!("Failed to load toml. so use backup file. {:?}", e);
+            let mut error_file = File::create(&error_log_path)?;
+            writeln!(error_file, "{}", load_error)?;
+            error_file.flush()?;
+            println!("{}", load_error);
+            let backup_cfg_path = Settings::backup_path(&cli.config);
+            let settings = Settings::new(&backup_cfg_path, false, &mut context)?.rebuild()?;
+            // What is missing until the primary is fixed.
+            backup_report = cfg_diff::backup_report(&cli.config, &backup_cfg_path);
+            for line in &backup_report {
+                writeln!(error_file, "backup diff: {}", line)?;
+                println!("backup diff: {}", line);
+            }
+            settings
+        }
+    };
+
+    debug!("{:?}", &settings);
+
+    if cli.export_config {
+        println!("{}", settings.export(cli.format)?);
+        return Ok(());
+    }
+
+    let mut log_settings = settings.clone();
+    log_settings.log.quiet |= cli.quiet;
+    let guards = logger::init(log_settings, &mut context)?;
+    info!("==================== start ! ====================");
+    if !load_error.is_empty() {
+        error!(
+            "{} (also written to {})",
+            load_error,
+            error_log_path.display()
+        );
+        backup_report
+            .iter()
+            .for_each(|line| warn!("backup diff: {}", line));
+    }
+    defer!({
+        info!("==================== end ! ====================");
+        drop(guards);
+    });
+
+    if let Some(report) = &cli.walk_report {
+        let count = walk_report(&enabled_spys(&settings.spys), report)?;
+        info!("walk report: {} matches, {}", count, report.display());
+        return Ok(());
+    }
+
+    if let Some(sample_path) = cli.sample_path.as_deref() {
+        let spys = enabled_spys(&settings.spys)
+            .into_iter()
+            .filter(|spy| cli.spy.as_ref().is_none_or(|name| &spy.name == name))
+            .collect::<Vec<_>>();
+        if cli.render {
+            let rendered = render_sample(&spys, &context, sample_path, &cli.sample_kind)?;
+            println!("{}", rendered);
+            return Ok(());
+        }
+        if cli.run_pattern {
+            let result = run_sample(&spys, &context, sample_path, &cli.sample_kind)?;
+            println!("{}", result);
+            if !result.success() {
+                bail!("Command failed: {}, code: {:?}", result, result.code());
+            }
+            return Ok(());
+        }
+    }
+
+    let cmd_line = context.get("cmd_line").unwrap().as_str().unwrap();
+    debug!("cmd_line: {}", &cmd_line);
+    let toml_str = std::fs::read_to_string(&cli.config)?;
+    let hash = hex_digest(Algorithm::SHA256, toml_str.as_bytes());
+    let pid_path = env::temp_dir().join(format!("spyrun_{}.pid", hash));
+    #[cfg(not(target_os = "windows"))]
+    let hash = env::temp_dir().join(hash);
+    #[cfg(not(target_os = "windows"))]
+    let hash = hash.to_string_lossy();
+
+    debug!("hash: {}", &hash);
+    let lock = if settings.cfg.single_instance {
+        match instance::acquire(&hash, &pid_path, cli.force) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                let warn_msg = format!("{} [{}]", e, cmd_line);
+                warn!("{}", &warn_msg);
+                bail!(warn_msg);
+            }
+        }
+    } else {
+        info!("cfg.single_instance is false. Skip the instance lock.");
+        None
+    };
+
+    let reload = run_context(settings, context, tx_stop, rx_stop, cli.max_events)?;
+
+    if reload {
+        // Release the instance lock before the new process takes it.
+        drop(lock);
+        let child = Command::new(env::current_exe()?)
+            .args(env::args().skip(1))
+            .spawn()?;
+        info!("Reloaded. new pid: {}", child.id());
+    }
+
+    Ok(())
+}
+
+/// Runs the spys of `settings` until `Stop` or `Reload` is received by `rx_stop`. Returns true on `Reload`.
+///
+/// `settings` are rebuilt, and `vars` are added to the templates. The logs go to the subscriber of the embedder,
+/// `[log]` is not applied: the global subscriber is set once per process, e.g. by the embedder or `run_cli`.
+pub fn run(
+    settings: Settings,
+    vars: Vars,
+    tx_stop: ControlSender,
+    rx_stop: ControlReceiver,
+    max_events: Option<usize>,
+) -> Result<bool> {
+    let settings = settings.rebuild()?;
+    let context = embed_context(&settings, &vars)?;
+    run_context(settings, context, tx_stop, rx_stop, max_events)
+}
+
+/// The context of the spys given in code, like the cli's, that keeps the event vars of the settings as is.
+fn embed_context(settings: &Settings, vars: &Vars) -> Result<Context> {
+    let mut context = build_cmd_map()?;
+    vars.iter()
+        .for_each(|(key, value)| context.insert(key, value));
+    insert_default_context(&mut context);
+    insert_file_context(&settings.log.path, "log", &mut context)?;
+    Ok(context)
+}
+
+/// `run` with the rebuilt settings.
+///
+/// `context` needs the vars of `build_cmd_map`, `Settings::new` and the log (`logger::init`).
+#[tracing::instrument(skip(context))]
+#[logfn(Debug)]
+fn run_context(
+    settings: Settings,
+    mut context: Context,
+    tx_stop: ControlSender,
+    rx_stop: ControlReceiver,
+    max_events: Option<usize>,
+) -> Result<bool> {
+    let exec_pool = Arc::new(pool::build(settings.cfg.max_threads)?);
+
+    let stop_flg = if Path::new(&settings.cfg.stop_flg).is_relative() {
+        Path::join(env::current_dir()?.as_path(), &settings.cfg.stop_flg)
+    } else {
+        Path::new(&settings.cfg.stop_flg).to_path_buf()
+    };
+    insert_file_context(&stop_flg, "stop", &mut context)?;
+    let stop_force_flg = if let Some(s) = &settings.cfg.stop_force_flg {
+        if Path::new(s).is_relative() {
+            Path::join(env::current_dir()?.as_path(), s)
+        } else {
+            Path::new(s).to_path_buf()
+        }
+    } else {
+        Path::join(
+            stop_flg.parent().unwrap(),
+            format!(
+                "{}_force.{}",
+                stop_flg.file_stem().unwrap().to_string_lossy(),
+                stop_flg.extension().unwrap().to_string_lossy()
+            ),
+        )
+    };
+    insert_file_context(&stop_force_flg, "stop_force", &mut context)?;
+
+    let stop_flags = [
+        (stop_flg.clone(), StopAction::Stop),
+        (stop_force_flg.clone(), StopAction::StopForce),
+    ]
+    .into_iter()
+    .chain(settings.stop_flags.iter().map(|f| {
+        let path = Path::new(&f.path);
+        match path.is_relative() {
+            true => (env::current_dir().unwrap().join(path), f.action),
+            false => (path.to_path_buf(), f.action),
+        }
+    }))
+    .collect::<Vec<_>>();
+    let _stop_watchers = stop_flags
+        .iter()
+        .map(|(path, action)| watch_stop_flag(path, *action, tx_stop.clone()))
+        .collect::<Result<Vec<_>>>()?;
+
+    for init in settings.init.iter().filter(|i| i.phase == InitPhase::Pre) {
+        run_init(init, &context)?;
+    }
+
+    if let Some(addr) = &settings.cfg.metrics_addr {
+        metrics::serve(addr)?;
+    }
+
+    let spys = check_inputs(enabled_spys(&startup_order(&settings.spys)?))?;
+    let after_timeout = settings
+        .cfg
+        .after_timeout
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_AFTER_TIMEOUT);
+    let mut startups = Startup::new(&spys, after_timeout);
+    let owners = if settings.cfg.exclusive_events {
+        Some(Arc::new(spys.clone()))
+    } else {
+        None
+    };
+    let caches = match &settings.cfg.state_file {
+        Some(state_file) => state::load(state_file).unwrap_or_else(|e| {
+            warn!("Failed to load state file: {}, e: {:?}", state_file, e);
+            HashMap::new()
+        }),
+        None => HashMap::new(),
+    };
+    let caches = spys
+        .iter()
+        .map(|spy| {
+            let cache = caches.get(&spy.name).cloned().unwrap_or_default();
+            (spy.name.clone(), cache)
+        })
+        .collect::<HashMap<_, _>>();
+    let saver = settings.cfg.state_file.as_ref().map(|state_file| {
+        let saver = Arc::new(state::Saver::new(state_file, caches.clone()));
+        let (state_file, saver_clone) = (state_file.clone(), saver.clone());
+        thread::spawn(move || loop {
+            thread::sleep(STATE_SAVE_INTERVAL);
+            if let Err(e) = saver_clone.save() {
+                error!("Failed to save state file: {}, e: {:?}", &state_file, e);
+            }
+        });
+        saver
+    });
+
+    if let Some(max_bytes) = settings.cfg.max_output_dir_bytes {
+        thread::spawn(move || loop {
+            thread::sleep(JANITOR_INTERVAL);
+            if let Err(e) = janitor::prune(&janitor::output_dirs(), max_bytes) {
+                error!("Failed to prune output logs: {:?}", e);
+            }
+        });
+    }
+
+    let max_events = max_events.map(|max| MaxEvents {
+        max,
+        count: Arc::new(AtomicUsize::new(0)),
+        tx_stop: tx_stop.clone(),
+    });
+    let (tx_registered, rx_registered) = mpsc::channel();
+    let results = spys
+        .iter()
+        .map(|spy| {
+            let mut startup = startups.remove(&spy.name).unwrap();
+            startup.registered = Some(tx_registered.clone());
+            Handler::new(
+                spy.clone(),
+                context.clone(),
+                owners.clone(),
+                caches[&spy.name].clone(),
+                settings.cfg.loop_guard.clone(),
+            )
+            .and_then(|handler| watcher(handler, max_events.clone(), startup, exec_pool.clone()))
+            .map_err(|e| error!("watcher error: {:?}", e))
+            .ok()
+        })
+        .collect::<Vec<_>>();
+    drop(tx_registered);
+    let senders: inject::Senders = spys
+        .iter()
+        .zip(&results)
+        .filter_map(|(spy, result)| Some((spy.name.clone(), result.as_ref()?.1.clone())))
+        .collect();
+    let _inject_watcher = match &settings.cfg.inject_dir {
+        Some(dir) => Some(inject::watch(Path::new(dir), senders.clone())?),
+        None => None,
+    };
+    // Each spy reports once after its walk and watch.
+    let failures = rx_registered
+        .iter()
+        .filter_map(|(name, result)| {
+            let e = result.err()?;
+            error!("[{}] failed to start watching: {:?}", &name, e);
+            Some(format!("[{}] {}", name, e))
+        })
+        .collect::<Vec<_>>();
+    if !failures.is_empty() {
+        match settings.cfg.startup {
+            StartupMode::Strict => {
+                stop_watchers(results);
+                bail!("Failed to start watching: {}", failures.join(", "));
+            }
+            StartupMode::Lenient => warn!(
+                "{} spys are disabled by the watch errors: {}",
+                failures.len(),
+                failures.join(", ")
+            ),
+        }
+    }
+
+    // The watchers are live, so a post init failure never stops them.
+    for init in settings.init.iter().filter(|i| i.phase == InitPhase::Post) {
+        if let Err(e) = run_init(init, &context) {
+            error!("Post init command failed: {:?}", e);
+        }
+    }
+
+    // Wait stop...
+    let mut reload = false;
+    loop {
+        let (name, message) = match rx_stop.recv() {
+            Ok(Control::Stop) => {
+                info!("Received stop");
+                break;
+            }
+            Ok(Control::Reload) => {
+                info!("Received reload");
+                reload = true;
+                break;
+            }
+            Ok(Control::StopForce) => {
+                info!("Received stop_force");
+                info!("==================== end ! ====================");
+                std::process::exit(1);
+            }
+            Ok(Control::Pause(name)) => (name, Message::Pause),
+            Ok(Control::Resume(name)) => (name, Message::Resume),
+            Ok(Control::Rescan(name)) => (name, Message::Rescan),
+            Err(e) => {
+                error!("stop watch error: {:?}", e);
+                continue;
+            }
+        };
+        match senders.get(&name) {
+            Some(tx) => tx.send(message).unwrap_or_default(),
+            None => warn!("[{}] no such spy watching, ignore {:?}", &name, message),
+        }
+    }
+
+    // Recv stop_force
+    thread::spawn(move || loop {
+        match rx_stop.recv() {
+            Ok(Control::Stop | Control::StopForce) => {
+                info!("Received stop or stop_force");
+                info!("==================== end ! ====================");
+                std::process::exit(1);
+            }
+            Ok(control) => warn!("Already stopping. Ignore {:?}.", control),
+            Err(e) => {
+                error!("stop watch error: {:?}", e);
+                break;
+            }
+        }
+    });
+
+    stop_watchers(results);
+
+    if let Some(saver) = &saver {
+        saver.save()?;
+    }
+
+    Ok(reload)
+}
+
+/// Sends stop to the watchers and joins them.
+fn stop_watchers(watchers: Vec<Option<(thread::JoinHandle<String>, MessageSender)>>) {
+    watchers.into_par_iter().for_each(|result| {
+        if let Some((handle, tx)) = result {
+            // A watcher that failed to watch has already stopped.
+            tx.send(Message::Stop).unwrap_or_default();
+            match handle.join() {
+                Ok(name) => {
+                    info!("[{}] watch thread joined", name);
+                }
+                Err(e) => {
+                    error!("watch thread error: {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+/// spyrun running in a thread, for embedding. The logs go to the subscriber of the embedder, `[log]` is not
+/// applied, as `run`.
+#[derive(Debug)]
+pub struct Engine {
+    control: ControlSender,
+    handle: thread::JoinHandle<Result<bool>>,
+}
+
+impl Engine {
+    /// Runs the spys of `settings` (`Settings::load` or `Settings::with_spys`) until stopped.
+    pub fn start(settings: Settings) -> Result<Self> {
+        // Rebuilt here, to return its error.
+        let settings = settings.rebuild()?;
+        let context = embed_context(&settings, &Vars::new())?;
+        let (control, rx_control) = mpsc::channel();
+        let tx_control = control.clone();
+        let handle = thread::Builder::new()
+            .name("spyrun-engine".to_string())
+            .spawn(move || run_context(settings, context, tx_control, rx_control, None))?;
+        Ok(Self { control, handle })
+    }
+
+    /// The sender of the control channel, e.g. for a signal handler.
+    pub fn control(&self) -> ControlSender {
+        self.control.clone()
+    }
+
+    /// Waits until stopped by the control channel. Returns true on `Reload`.
+    pub fn join(self) -> Result<bool> {
+        self.handle
+            .join()
+            .map_err(|e| anyhow!("spyrun engine panicked: {:?}", e))?
+    }
+
+    /// Sends `Stop` and waits until the spys are stopped.
+    pub fn stop(self) -> Result<()> {
+        self.control.send(Control::Stop)?;
+        self.join().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        env,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use anyhow::Result;
+    use clap::Parser;
+    use notify::{
+        event::{AccessKind, AccessMode, CreateKind, ModifyKind, RemoveKind, RenameMode},
+        Event, EventKind, RecursiveMode,
+    };
+    use path_slash::PathBufExt as _;
+    use tera::{Context, Tera};
+    use tracing::info;
+    use tracing_subscriber::{fmt::Layer, prelude::*, Registry};
+
+    use super::{
+        check_inputs, enabled_spys, exec_span, execute_command, find_owner, find_pattern, flag_key,
+        insert_pattern_context, insert_spy_context, is_flag, is_latest, is_skip_marker,
+        render_sample, run_cli, run_init, run_sample, run_sync, walk_report, watch_stop_flag,
+        watcher, Cli, CommandTemplate, Control, ExecOptions, Handler, LoopDetector, Markers,
+        MaxEvents, Pause, SkipReason, SpyEvent, SyncRunner, Vars,
+    };
+    use crate::{
+        inject,
+        message::{event_kind_to_string, Message, RenameAsCreate},
+        metrics, pool,
+        queue::EventSender,
+        ready::Startup,
+        settings::{
+            Init, InputPolicy, LimitScope, LoopGuard, Pattern, PausePolicy, QueuePolicy, Spy,
+            StopAction, Walk, When,
+        },
+        source::{EventSource, Handle, TestSource},
+    };
+
+    /// Sends `count` events from its own thread, as the native watchers do.
+    #[derive(Debug)]
+    struct Flood {
+        dir: PathBuf,
+        count: usize,
+        sent: Arc<AtomicUsize>,
+    }
+
+    impl EventSource for Flood {
+        fn start(
+            &self,
+            tx: EventSender,
+            _path: &Path,
+            _recursive: RecursiveMode,
+        ) -> Result<Handle> {
+            let (dir, count, sent) = (self.dir.clone(), self.count, self.sent.clone());
+            std::thread::spawn(move || {
+                for i in 0..count {
+                    let path = dir.join(format!("{}.txt", i));
+                    if tx.send(SpyEvent::new("Create", path)).is_err() {
+                        break;
+                    }
+                    sent.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+            Ok(Box::new(()))
+        }
+    }
+
+    #[test]
+    fn test_find_owner_with_priority() {
+        let tmp = env::current_dir().unwrap().join("test");
+        let low = Spy::builder("low")
+            .input(&tmp)
+            .recursive(true)
+            .priority(1)
+            .build()
+            .unwrap();
+        let high = Spy::builder("high")
+            .input(tmp.join("overlap"))
+            .priority(10)
+            .build()
+            .unwrap();
+        let spys = vec![low, high];
+
+        let event = Event::new(EventKind::Create(CreateKind::Any))
+            .add_path(tmp.join("overlap").join("test.ps1"));
+        assert_eq!(find_owner(&event, &spys).unwrap().name, "high");
+
+        let event = Event::new(EventKind::Create(CreateKind::Any))
+            .add_path(tmp.join("other").join("test.ps1"));
+        assert_eq!(find_owner(&event, &spys).unwrap().name, "low");
+
+        let event =
+            Event::new(EventKind::Create(CreateKind::Any)).add_path(PathBuf::from("test.txt"));
+        assert!(find_owner(&event, &spys).is_none());
+    }
+
+    #[test]
+    fn test_max_events() {
+        let (tx_stop, rx_stop) = mpsc::channel();
+        let max_events = MaxEvents {
+            max: 2,
+            count: Arc::new(AtomicUsize::new(0)),
+            tx_stop,
+        };
+
+        max_events.clone().executed();
+        assert!(rx_stop.try_recv().is_err());
+        max_events.clone().executed();
+        assert_eq!(
+            rx_stop.recv_timeout(Duration::from_secs(1)).unwrap(),
+            Control::Stop
+        );
+        max_events.executed();
+        assert!(rx_stop.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_pattern_when() {
+        let tmp = env::current_dir().unwrap().join("test");
+        let spy = Spy::builder("when")
+            .input(&tmp)
+            .event("Create")
+            .event("Remove")
+            .add_pattern(Pattern {
+                pattern: r"\.txt$".to_string(),
+                cmd: "create".to_string(),
+                arg: vec!["{{ event_path }}".to_string()],
+                when: [(
+                    "Remove".to_string(),
+                    When {
+                        cmd: "cleanup".to_string(),
+                        arg: vec![],
+                    },
+                )]
+                .into(),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        let path = tmp.join("when.txt");
+        let create = Event::new(EventKind::Create(CreateKind::Any)).add_path(path.clone());
+        let remove = Event::new(EventKind::Remove(RemoveKind::Any)).add_path(path);
+
+        let (_, pattern) = find_pattern(&create, &spy).unwrap();
+        let (cmd, arg) = pattern.command(&event_kind_to_string(create.kind));
+        assert_eq!(cmd, "create");
+        assert_eq!(arg, ["{{ event_path }}"]);
+
+        let (_, pattern) = find_pattern(&remove, &spy).unwrap();
+        let (cmd, arg) = pattern.command(&event_kind_to_string(remove.kind));
+        assert_eq!(cmd, "cleanup");
+        assert!(arg.is_empty());
+    }
+
+    #[test]
+    fn test_find_pattern_extensions() {
+        let tmp = env::current_dir().unwrap().join("test");
+        let pattern = Pattern {
+            extensions: Some(vec!["csv".to_string(), ".txt".to_string()]),
+            cmd: "echo".to_string(),
+            ..Default::default()
+        };
+        let builder = |pattern: &Pattern| {
+            Spy::builder("extensions")
+                .input(&tmp)
+                .event("Create")
+                .add_pattern(pattern.clone())
+        };
+        let spy = builder(&pattern).build().unwrap();
+        let event =
+            |name: &str| Event::new(EventKind::Create(CreateKind::File)).add_path(tmp.join(name));
+
+        assert!(find_pattern(&event("a.CSV"), &spy).is_some());
+        assert!(find_pattern(&event("a.csv"), &spy).is_some());
+        assert!(find_pattern(&event("a.Txt"), &spy).is_some());
+        assert!(find_pattern(&event("a.log"), &spy).is_none());
+        assert!(find_pattern(&event("a.csv.log"), &spy).is_none());
+        assert!(find_pattern(&event("csv"), &spy).is_none());
+
+        let pattern = Pattern {
+            pattern: r"\.csv$".to_string(),
+            ..pattern
+        };
+        let e = builder(&pattern).build().unwrap_err().to_string();
+        assert!(e.contains("mutually exclusive"), "{}", e);
+    }
+
+    #[test]
+    fn test_find_pattern_any() {
+        let tmp = env::current_dir().unwrap().join("test");
+        let spy = |event: &str| {
+            Spy::builder("any")
+                .input(&tmp)
+                .event(event)
+                .pattern(r"\.txt$", "echo", &[])
+                .build()
+                .unwrap()
+        };
+        let any = spy("Any");
+
+        for kind in [
+            EventKind::Any,
+            EventKind::Access(AccessKind::Any),
+            EventKind::Create(CreateKind::File),
+            EventKind::Modify(ModifyKind::Any),
+            EventKind::Remove(RemoveKind::Any),
+            EventKind::Other,
+        ] {
+            let event = Event::new(kind).add_path(tmp.join("any.txt"));
+            assert!(find_pattern(&event, &any).is_some(), "{:?}", kind);
+        }
+
+        let spy = spy("Other");
+        let other = Event::new(EventKind::Other).add_path(tmp.join("any.txt"));
+        let create = Event::new(EventKind::Create(CreateKind::File)).add_path(tmp.join("any.txt"));
+        assert!(find_pattern(&other, &spy).is_some());
+        assert!(find_pattern(&create, &spy).is_none());
+    }
+
+    #[test]
+    fn test_treat_rename_as_create() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_treat_rename_as_create");
+        // Write to a temp name, then rename to the final name, as inotify delivers.
+        let (part, csv) = (tmp.join("data.tmp"), tmp.join("data.csv"));
+        let rename = |mode| EventKind::Modify(ModifyKind::Name(mode));
+        let source = TestSource::new(vec![
+            SpyEvent::new("Create", &part),
+            SpyEvent::new("Modify", &part),
+            SpyEvent(Event::new(rename(RenameMode::From)).add_path(part.clone())),
+            SpyEvent(Event::new(rename(RenameMode::To)).add_path(csv.clone())),
+            SpyEvent(
+                Event::new(rename(RenameMode::Both))
+                    .add_path(part.clone())
+                    .add_path(csv.clone()),
+            ),
+        ]);
+        std::fs::create_dir_all(&tmp).unwrap();
+        let spy = Spy::builder("rename")
+            .input(&tmp)
+            .event("Create")
+            .pattern(r"\.csv$", "import", &[])
+            .source(source)
+            .build()
+            .unwrap();
+        let (tx, rx) = mpsc::channel();
+        let _watcher = spy.watch(tx.into()).unwrap();
+
+        // The source drops its sender after the replay, so this ends.
+        let mut renames = RenameAsCreate::default();
+        let matched = rx
+            .into_iter()
+            .filter_map(|msg| match msg {
+                Message::Event(event) => renames.classify(event),
+                _ => None,
+            })
+            .filter(|(event, _)| find_pattern(event, &spy).is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(matched.len(), 1, "{:?}", matched);
+        let (event, from) = &matched[0];
+        assert_eq!(event.paths.last().unwrap(), &csv);
+        assert_eq!(from.as_ref(), Some(&part));
+    }
+
+    #[test]
+    fn test_find_pattern_fast_path() {
+        let tmp = env::current_dir().unwrap().join("test");
+        let spy = Spy::builder("fast")
+            .input(&tmp)
+            .event("Create")
+            .build()
+            .unwrap();
+        let spys = vec![spy.clone()];
+
+        let event = Event::new(EventKind::Access(AccessKind::Any)).add_path(tmp.join("test.cmd"));
+        // Commands are spawned to rayon only for the events find_pattern returns.
+        assert!(find_pattern(&event, &spy).is_none());
+        assert!(find_owner(&event, &spys).is_none());
+
+        let event = Event::new(EventKind::Create(CreateKind::Any)).add_path(tmp.join("test.cmd"));
+        assert_eq!(find_pattern(&event, &spy).unwrap().0, 1);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_watcher_access_flood() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_access_flood");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let path = tmp.join("input").join("a.txt");
+        // Every read of a matching file, then the one event to execute.
+        let access = EventKind::Access(AccessKind::Close(AccessMode::Read));
+        let events = (0..100_000)
+            .map(|_| SpyEvent(Event::new(access).add_path(path.clone())))
+            .chain([SpyEvent::new("Create", &path)])
+            .collect();
+        let spy = Spy::builder("test_watcher_access_flood")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .pattern(r"\.txt$", "/bin/sh", &["-c", "exit 0"])
+            .source(TestSource::new(events))
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                .remove(&spy.name)
+                .unwrap(),
+            Arc::new(pool::build(Some(1)).unwrap()),
+        )
+        .unwrap();
+
+        // The Create is behind the flood, and a task of an Access would finish before its own.
+        let spy_metrics = metrics::spy("test_watcher_access_flood");
+        while spy_metrics.snapshot().executions == 0 {
+            assert!(start.elapsed() < Duration::from_secs(30));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        let stats = spy_metrics.snapshot();
+        assert_eq!(stats.events, 100_001);
+        assert_eq!(stats.executions, 1);
+        assert_eq!(stats.skip_debounce + stats.skip_throttle, 0);
+
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_enabled_spys() {
+        let spys = vec![
+            Spy::builder("enabled").build().unwrap(),
+            Spy::builder("disabled").enabled(false).build().unwrap(),
+        ];
+
+        let watched = enabled_spys(&spys);
+        assert_eq!(watched.len(), 1);
+        assert_eq!(watched[0].name, "enabled");
+    }
+
+    #[test]
+    fn test_walk_report() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_walk_report");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("sub")).unwrap();
+        for name in ["a.cmd", "sub/b.ps1", "sub/c.txt"] {
+            std::fs::write(tmp.join(name), "").unwrap();
+        }
+        let spy = Spy::builder("report")
+            .input(&tmp)
+            .walk(Walk::default())
+            .build()
+            .unwrap();
+        let report = tmp.join("report.ndjson");
+
+        assert_eq!(walk_report(&[spy], &report).unwrap(), 2);
+        let mut lines = std::fs::read_to_string(&report)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        lines.sort_by_key(|l| l["path"].as_str().unwrap().to_string());
+        assert_eq!(lines[0]["spy"], "report");
+        assert!(lines[0]["path"].as_str().unwrap().ends_with("/a.cmd"));
+        assert_eq!(lines[0]["pattern"], r"\.cmd$");
+        assert!(lines[1]["path"].as_str().unwrap().ends_with("/sub/b.ps1"));
+        assert_eq!(lines[1]["pattern"], r"\.ps1$");
+    }
+
+    #[test]
+    fn test_watcher_after() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_after");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        let spy = |name: &str, walk_delay| {
+            std::fs::create_dir_all(tmp.join(name)).unwrap();
+            Spy::builder(name)
+                .input(tmp.join(name))
+                .walk(Walk::default().delay(Duration::from_millis(walk_delay), None))
+        };
+        let unzip = spy("unzip", 500).build().unwrap();
+        let process = spy("process", 0).after("unzip").build().unwrap();
+        let spys = vec![unzip, process];
+        let mut startups = Startup::new(&spys, Duration::from_secs(10));
+        let (unzip_ready, process_ready) = (
+            startups["unzip"].ready.clone(),
+            startups["process"].ready.clone(),
+        );
+
+        // Start the dependent first, it still waits for its prerequisite.
+        let watchers = spys
+            .iter()
+            .rev()
+            .map(|spy| {
+                watcher(
+                    Handler::new(spy.clone(), Context::new(), None, Default::default(), None)
+                        .unwrap(),
+                    None,
+                    startups.remove(&spy.name).unwrap(),
+                    Arc::new(pool::build(Some(2)).unwrap()),
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        // The walk of process would be done at once without `after`.
+        assert!(!process_ready.wait(Duration::from_millis(200)));
+        assert!(!unzip_ready.wait(Duration::ZERO));
+        assert!(unzip_ready.wait(Duration::from_secs(10)));
+        assert!(process_ready.wait(Duration::from_secs(10)));
+
+        for (handle, tx) in watchers {
+            tx.send(Message::Stop).unwrap();
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_watcher_debounce_not_starved() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_debounce_not_starved");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        let (input, output, events) = (tmp.join("input"), tmp.join("output"), tmp.join("events"));
+        std::fs::create_dir_all(&input).unwrap();
+        std::fs::create_dir_all(&events).unwrap();
+        let count = 20;
+        let paths = (0..count)
+            .map(|i| {
+                let path = events.join(format!("{}.txt", i));
+                std::fs::write(&path, "").unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        // The second event of each path is in the window, so one command per path.
+        let source = TestSource::new(
+            paths
+                .iter()
+                .chain(paths.iter())
+                .map(|path| SpyEvent::new("Create", path))
+                .collect(),
+        );
+        let spy = Spy::builder("debounce")
+            .input(&input)
+            .output(&output)
+            .debounce(Duration::from_millis(500))
+            // An empty limitkey is the rendered command, one per path.
+            .limitkey("")
+            .pattern(r"\.txt$", "/bin/sh", &["-c", "echo {{ event_stem }}"])
+            .source(source)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(&[spy], Duration::from_secs(10))
+                .remove("debounce")
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let outputs = || {
+            walkdir::WalkDir::new(&output)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name().to_string_lossy().contains("_stdout_"))
+                .count()
+        };
+        while outputs() < count && start.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        // Sleeping on the 2 pool threads would take 10 rounds of the window.
+        let elapsed = start.elapsed();
+        assert_eq!(outputs(), count);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+        assert_eq!(outputs(), count);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_event_source() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_event_source");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        let (input, output) = (tmp.join("input"), tmp.join("output"));
+        std::fs::create_dir_all(&input).unwrap();
+        // Exists before watching, so found by the walk.
+        std::fs::write(input.join("old.txt"), "").unwrap();
+        let spy = Spy::builder("test_event_source")
+            .input(&input)
+            .output(&output)
+            .walk(Walk::default())
+            .pattern(
+                r"\.txt$",
+                "/bin/sh",
+                &["-c", "echo {{ event_source }} {{ event_stem }}"],
+            )
+            .build()
+            .unwrap();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                .remove(&spy.name)
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let executions = || metrics::spy("test_event_source").snapshot().executions;
+        let wait_executions = |count| {
+            let start = Instant::now();
+            while executions() < count && start.elapsed() < Duration::from_secs(10) {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+        wait_executions(1);
+        let new = input.join("new.txt");
+        std::fs::write(&new, "").unwrap();
+        tx.send(Message::Event(
+            Event::new(EventKind::Create(CreateKind::File)).add_path(new),
+        ))
+        .unwrap();
+        wait_executions(2);
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+
+        let mut outputs = walkdir::WalkDir::new(&output)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().contains("_stdout_"))
+            .filter_map(|e| std::fs::read_to_string(e.path()).ok())
+            .map(|s| s.trim().to_string())
+            .collect::<Vec<_>>();
+        outputs.sort();
+        // The watched new.txt may also be seen by the notify watcher, but never as walk.
+        outputs.dedup();
+        assert_eq!(outputs, ["walk old", "watch new"]);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_watcher_control() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_control");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        let (input, output, events) = (tmp.join("input"), tmp.join("output"), tmp.join("events"));
+        std::fs::create_dir_all(&input).unwrap();
+        std::fs::create_dir_all(&events).unwrap();
+        // Exists before watching, so only found by Rescan.
+        std::fs::write(input.join("rescan.txt"), "").unwrap();
+        let spy = Spy::builder("test_watcher_control")
+            .input(&input)
+            .output(&output)
+            .pattern(r"\.txt$", "/bin/sh", &["-c", "echo txt {{ event_stem }}"])
+            // The events are only sent by the test, inotify would report the Rescan listing the input.
+            .source(TestSource::new(vec![]))
+            .build()
+            .unwrap();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(&[spy], Duration::from_secs(10))
+                .remove("test_watcher_control")
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let send = |name: &str| {
+            let path = events.join(name);
+            std::fs::write(&path, "").unwrap();
+            tx.send(Message::Event(
+                Event::new(EventKind::Create(CreateKind::File)).add_path(path),
+            ))
+            .unwrap();
+        };
+        let stats = || {
+            let (reply, rx) = mpsc::channel();
+            tx.send(Message::DumpStats(reply)).unwrap();
+            rx.recv_timeout(Duration::from_secs(10)).unwrap()
+        };
+        let outputs = || {
+            let mut outputs = walkdir::WalkDir::new(&output)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name().to_string_lossy().contains("_stdout_"))
+                .filter_map(|e| std::fs::read_to_string(e.path()).ok())
+                .map(|s| s.trim().to_string())
+                .collect::<Vec<_>>();
+            outputs.sort();
+            outputs
+        };
+        let wait_executions = |count| {
+            let start = Instant::now();
+            while stats().executions < count && start.elapsed() < Duration::from_secs(10) {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+
+        tx.send(Message::Pause).unwrap();
+        send("a.txt");
+        std::thread::sleep(Duration::from_millis(300));
+        let paused = stats();
+        assert!(paused.paused);
+        assert_eq!((paused.events, paused.pending), (1, 1));
+        assert!(outputs().is_empty());
+
+        tx.send(Message::Resume).unwrap();
+        wait_executions(1);
+        assert_eq!(outputs(), vec!["txt a"]);
+
+        tx.send(Message::Rescan).unwrap();
+        wait_executions(2);
+        assert_eq!(outputs(), vec!["txt a", "txt rescan"]);
+
+        tx.send(Message::ReloadPatterns(vec![Pattern {
+            pattern: r"\.csv$".to_string(),
+            cmd: "/bin/sh".to_string(),
+            arg: vec!["-c".to_string(), "echo csv {{ event_stem }}".to_string()],
+            ..Default::default()
+        }]))
+        .unwrap();
+        // Invalid, so the csv pattern is kept.
+        tx.send(Message::ReloadPatterns(vec![Pattern {
+            pattern: "(".to_string(),
+            cmd: "/bin/sh".to_string(),
+            ..Default::default()
+        }]))
+        .unwrap();
+        send("b.txt");
+        send("c.csv");
+        wait_executions(3);
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(outputs(), vec!["csv c", "txt a", "txt rescan"]);
+
+        let stats = stats();
+        assert!(!stats.paused);
+        assert_eq!(
+            (stats.events, stats.executions, stats.pending, stats.dropped),
+            (4, 3, 0, 0)
+        );
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_watcher_stop_priority() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_stop_priority");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        // Held by `after` until the backlog and Stop are queued.
+        let spy = Spy::builder("test_watcher_stop_priority")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .pattern(r"\.txt$", "echo", &["{{ event_stem }}"])
+            .after("gate")
+            .build()
+            .unwrap();
+        let gate = Spy::builder("gate").build().unwrap();
+        let mut startups = Startup::new(&[gate, spy.clone()], Duration::from_secs(10));
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            startups.remove(&spy.name).unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+
+        let count = 10000;
+        for i in 0..count {
+            let path = tmp.join("input").join(format!("{}.txt", i));
+            tx.send(Message::Event(
+                Event::new(EventKind::Create(CreateKind::File)).add_path(path),
+            ))
+            .unwrap();
+        }
+        let (reply, rx_stats) = mpsc::channel();
+        tx.send(Message::DumpStats(reply)).unwrap();
+        tx.send(Message::Stop).unwrap();
+        let start = Instant::now();
+        startups["gate"].ready.set();
+        // Not drained into the pending events before the controls.
+        let stats = rx_stats.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!((stats.events, stats.pending), (0, 0));
+        handle.join().unwrap();
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            start.elapsed()
+        );
+
+        // Stop is received first, so none of the events is.
+        let stats = metrics::spy("test_watcher_stop_priority").snapshot();
+        assert_eq!(stats.events, 0);
+        assert_eq!(stats.executions + stats.skip_debounce + stats.failures, 0);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_watcher_inject() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_inject");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let spy = Spy::builder("test_watcher_inject")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .pattern(r"\.txt$", "/bin/sh", &["-c", "echo {{ event_stem }}"])
+            .build()
+            .unwrap();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                .remove(&spy.name)
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let senders = HashMap::from([(spy.name.clone(), tx.clone())]);
+
+        // Never written, the pattern matches the path only.
+        let never = tmp.join("input").join("never.txt");
+        let reply = inject::inject(
+            &format!("INJECT test_watcher_inject Create {}", never.display()),
+            &senders,
+        )
+        .unwrap();
+        assert!(reply.starts_with("exec_id: "), "{}", reply);
+        assert!(!never.exists());
+        let stdout = walkdir::WalkDir::new(tmp.join("output"))
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_name().to_string_lossy().contains("_stdout_"))
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(stdout.path()).unwrap().trim(),
+            "never"
+        );
+
+        let reply = inject::inject(
+            &format!(
+                "INJECT test_watcher_inject Create {}",
+                tmp.join("a.csv").display()
+            ),
+            &senders,
+        )
+        .unwrap();
+        assert_eq!(reply, "skipped: no pattern matched");
+        assert!(inject::inject("INJECT unknown Create a.txt", &senders).is_err());
+
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_watcher_failure_cooldown() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_failure_cooldown");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let spy = Spy::builder("test_watcher_failure_cooldown")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .pattern(r"\.txt$", "/bin/sh", &["-c", "exit 1"])
+            // An empty limitkey is the rendered command, one per path.
+            .limitkey("")
+            .failure_cooldown(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                .remove(&spy.name)
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let senders = HashMap::from([(spy.name.clone(), tx.clone())]);
+        let line = format!(
+            "INJECT test_watcher_failure_cooldown Create {}",
+            tmp.join("input").join("a.txt").display()
+        );
+
+        // The reply is sent after the failure is recorded.
+        let reply = inject::inject(&line, &senders).unwrap();
+        assert!(reply.starts_with("exec_id: "), "{}", reply);
+        let reply = inject::inject(&line, &senders).unwrap();
+        assert_eq!(reply, "skipped: Cooldown");
+        // Another limitkey is not affected.
+        let reply = inject::inject(&line.replace("a.txt", "b.txt"), &senders).unwrap();
+        assert!(reply.starts_with("exec_id: "), "{}", reply);
+
+        let stats = metrics::spy("test_watcher_failure_cooldown").snapshot();
+        assert_eq!(stats.executions, 2);
+        assert_eq!(stats.failures, 2);
+        assert_eq!(stats.skip_cooldown, 1);
+
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_watcher_moved_to() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_moved_to");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        for name in ["a.txt", "b.csv"] {
+            std::fs::write(tmp.join("input").join(name), name).unwrap();
+        }
+        let hook = When {
+            cmd: "/bin/sh".to_string(),
+            arg: vec![
+                "-c".to_string(),
+                "echo '{{ moved_to }}' > '{{ event_dir }}/../{{ event_stem }}.hook'".to_string(),
+            ],
+        };
+        let pattern = |pattern: &str, exit: &str| Pattern {
+            pattern: pattern.to_string(),
+            cmd: "/bin/sh".to_string(),
+            arg: vec!["-c".to_string(), exit.to_string()],
+            on_success_move: Some("{{ event_dir }}/done/".to_string()),
+            on_error_move: Some("{{ event_dir }}/failed/".to_string()),
+            on_success: Some(hook.clone()),
+            on_error: Some(hook.clone()),
+            ..Default::default()
+        };
+        // Not to watch the moves.
+        let spy = Spy::builder("test_watcher_moved_to")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .add_pattern(pattern(r"\.txt$", "exit 0"))
+            .add_pattern(pattern(r"\.csv$", "exit 1"))
+            .source(TestSource::new(vec![]))
+            .build()
+            .unwrap();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                .remove(&spy.name)
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let senders = HashMap::from([(spy.name.clone(), tx.clone())]);
+        let hooked = |name: &str| {
+            let line = format!(
+                "INJECT test_watcher_moved_to Create {}",
+                tmp.join("input").join(name).display()
+            );
+            // The reply is sent after the hook.
+            let reply = inject::inject(&line, &senders).unwrap();
+            assert!(reply.starts_with("exec_id: "), "{}", reply);
+            let stem = Path::new(name).file_stem().unwrap().to_string_lossy();
+            std::fs::read_to_string(tmp.join(format!("{}.hook", stem)))
+                .unwrap()
+                .trim()
+                .to_string()
+        };
+
+        let moved_to = tmp.join("input").join("done").join("a.txt");
+        assert_eq!(hooked("a.txt"), moved_to.to_string_lossy());
+        assert!(moved_to.is_file());
+        let moved_to = tmp.join("input").join("failed").join("b.csv");
+        assert_eq!(hooked("b.csv"), moved_to.to_string_lossy());
+        assert!(moved_to.is_file());
+        // Moved already, so nothing is moved.
+        assert_eq!(hooked("a.txt"), "");
+
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_watcher_loop_guard() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_loop_guard");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let spy = Spy::builder("test_watcher_loop_guard")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .pattern(r"\.txt$", "/bin/sh", &["-c", "exit 0"])
+            .limitkey("{{ event_stem }}")
+            .skip_marker("{{ event_dir }}/../{{ event_stem }}.skipped.json")
+            .build()
+            .unwrap();
+        let loop_guard = LoopGuard {
+            max: 2,
+            window: 60_000,
+            cooldown: 60_000,
+        };
+        let (handle, tx) = watcher(
+            Handler::new(
+                spy.clone(),
+                Context::new(),
+                None,
+                Default::default(),
+                Some(loop_guard),
+            )
+            .unwrap(),
+            None,
+            Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                .remove(&spy.name)
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let senders = HashMap::from([(spy.name.clone(), tx.clone())]);
+        let inject = |name: &str| {
+            let line = format!(
+                "INJECT test_watcher_loop_guard Create {}",
+                tmp.join("input").join(name).display()
+            );
+            inject::inject(&line, &senders).unwrap()
+        };
+
+        for _ in 0..2 {
+            let reply = inject("a.txt");
+            assert!(reply.starts_with("exec_id: "), "{}", reply);
+        }
+        assert_eq!(inject("a.txt"), "skipped: LoopGuard");
+        // Suspended by the rendered limitkey, another one is not.
+        let reply = inject("b.txt");
+        assert!(reply.starts_with("exec_id: "), "{}", reply);
+
+        let marker = serde_json::from_str::<serde_json::Value>(
+            &std::fs::read_to_string(tmp.join("a.skipped.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(marker["reason"], "loop_guard");
+        assert_eq!(marker["limitkey"], "a");
+        assert!(!tmp.join("b.skipped.json").exists());
+        let stats = metrics::spy("test_watcher_loop_guard").snapshot();
+        assert_eq!(stats.executions, 3);
+        assert_eq!(stats.skip_loop_guard, 1);
+
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_watcher_progress_file() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_progress_file");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let progress = tmp.join("progress.txt");
+        let script = format!(
+            "for i in 1 2 3; do echo $i/3 >> {}; sleep 0.3; done; sleep 0.5",
+            progress.display()
+        );
+        let spy = Spy::builder("test_watcher_progress_file")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .add_pattern(Pattern {
+                pattern: r"\.txt$".to_string(),
+                cmd: "/bin/sh".to_string(),
+                arg: vec!["-c".to_string(), script],
+                progress_file: Some("{{ event_dir }}/../progress.txt".to_string()),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                .remove(&spy.name)
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let stats = || {
+            let (reply, rx) = mpsc::channel();
+            tx.send(Message::DumpStats(reply)).unwrap();
+            rx.recv_timeout(Duration::from_secs(10)).unwrap()
+        };
+        tx.send(Message::Event(
+            Event::new(EventKind::Create(CreateKind::File))
+                .add_path(tmp.join("input").join("a.txt")),
+        ))
+        .unwrap();
+
+        let mut seen = vec![];
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            let stats = stats();
+            if let Some(line) = stats.progress.values().next() {
+                if !seen.contains(line) {
+                    seen.push(line.clone());
+                }
+            } else if stats.executions > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(seen.contains(&"3/3".to_string()), "{:?}", seen);
+        assert!(seen.len() >= 2, "{:?}", seen);
+        // Forgotten when the command finished.
+        assert!(stats().progress.is_empty());
+
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_watcher_queue() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_queue");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let count = 40;
+        let flood = |policy: QueuePolicy| {
+            let sent = Arc::new(AtomicUsize::new(0));
+            let spy = Spy::builder(&format!("test_watcher_queue_{:?}", policy))
+                .input(tmp.join("input"))
+                .output(tmp.join("output"))
+                .pattern(r"\.txt$", "/bin/sh", &["-c", "sleep 0.05"])
+                .queue(4, policy)
+                .source(Flood {
+                    dir: tmp.join("input"),
+                    count,
+                    sent: sent.clone(),
+                })
+                .build()
+                .unwrap();
+            let (handle, tx) = watcher(
+                Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+                None,
+                Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                    .remove(&spy.name)
+                    .unwrap(),
+                Arc::new(pool::build(Some(2)).unwrap()),
+            )
+            .unwrap();
+            let start = Instant::now();
+            let stats = loop {
+                let (reply, rx) = mpsc::channel();
+                tx.send(Message::DumpStats(reply)).unwrap();
+                let stats = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+                let handled = stats.executions + stats.queue_dropped;
+                if sent.load(Ordering::Relaxed) == count && handled == count as u64 {
+                    break stats;
+                }
+                assert!(start.elapsed() < Duration::from_secs(30), "{:?}", stats);
+                std::thread::sleep(Duration::from_millis(50));
+            };
+            tx.send(Message::Stop).unwrap();
+            handle.join().unwrap();
+            stats
+        };
+
+        // The source waits while 4 events are queued or running, none is lost.
+        let stats = flood(QueuePolicy::Block);
+        assert_eq!((stats.events, stats.executions), (40, 40));
+        assert_eq!((stats.queue_peak, stats.queue_dropped), (4, 0));
+
+        // The source never waits, the events over 4 are dropped.
+        let stats = flood(QueuePolicy::Drop);
+        assert!(stats.queue_dropped > 0, "{:?}", stats);
+        assert_eq!(stats.events, stats.executions);
+        assert_eq!(stats.queue_peak, 4);
+    }
+
+    #[test]
+    fn test_watcher_skip_marker() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watcher_skip_marker");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let spy = Spy::builder("test_watcher_skip_marker")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .add_pattern(Pattern {
+                pattern: r"\.json$".to_string(),
+                cmd: "/bin/sh".to_string(),
+                arg: vec!["-c".to_string(), "exit 0".to_string()],
+                throttle: Some(60_000),
+                debounce: Some(0),
+                ..Default::default()
+            })
+            .pattern(r"\.csv$", "/bin/sh", &["-c", "exit 1"])
+            .skip_marker("{{ event_dir }}/{{ event_stem }}.skipped.json")
+            .skip_marker_ttl(Duration::from_millis(100))
+            .quarantine(1, None)
+            .build()
+            .unwrap();
+        let (handle, tx) = watcher(
+            Handler::new(spy.clone(), Context::new(), None, Default::default(), None).unwrap(),
+            None,
+            Startup::new(std::slice::from_ref(&spy), Duration::from_secs(10))
+                .remove(&spy.name)
+                .unwrap(),
+            Arc::new(pool::build(Some(2)).unwrap()),
+        )
+        .unwrap();
+        let inject = |path: &Path| {
+            let (reply, rx) = mpsc::channel();
+            let event = Event::new(EventKind::Create(CreateKind::File)).add_path(path.into());
+            tx.send(Message::Inject(event, reply)).unwrap();
+            rx.recv_timeout(Duration::from_secs(10)).unwrap()
+        };
+        let input = tmp.join("input").join("data.json");
+        assert!(inject(&input).starts_with("exec_id: "));
+        assert_eq!(inject(&input), "skipped: Throttle");
+
+        let marker = tmp.join("input").join("data.skipped.json");
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&marker).unwrap()).unwrap();
+        assert_eq!(json["reason"], "throttle");
+        assert_eq!(json["event_path"], input.to_string_lossy().as_ref());
+        assert!(json["limitkey"].as_str().is_some_and(|key| !key.is_empty()));
+        assert!(json["timestamp"].as_str().is_some());
+        // The marker matches the pattern, but never triggers the spy while it exists.
+        assert_eq!(inject(&marker), "skipped: skip_marker");
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(inject(&marker), "skipped: skip_marker");
+        // Nor for the ttl after its removal, then a file of the same path is handled.
+        std::fs::remove_file(&marker).unwrap();
+        assert_eq!(inject(&marker), "skipped: skip_marker");
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(inject(&marker).starts_with("exec_id: "));
+
+        // The skips before the dispatch write the marker too, without the limitkey.
+        let bad = tmp.join("input").join("bad.csv");
+        assert!(inject(&bad).starts_with("exec_id: "));
+        assert_eq!(inject(&bad), "skipped: quarantined");
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(tmp.join("input").join("bad.skipped.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["reason"], "quarantine");
+        assert_eq!(json["event_path"], bad.to_string_lossy().as_ref());
+        assert!(json["limitkey"].is_null());
+
+        tx.send(Message::Stop).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_is_skip_marker() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_is_skip_marker");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(&tmp).unwrap();
+        let marker = tmp.join("data.skipped.json");
+        let event = Event::new(EventKind::Create(CreateKind::File)).add_path(marker.clone());
+        let markers = Markers::default();
+        let ttl = Duration::from_millis(10);
+        assert!(!is_skip_marker(&event, &markers, ttl));
+        std::fs::write(&marker, "{}").unwrap();
+        markers.lock().unwrap().insert(flag_key(&marker), None);
+        assert!(is_skip_marker(&event, &markers, ttl));
+
+        // Not expired while it exists.
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(is_skip_marker(&event, &markers, ttl));
+
+        // Expired after the ttl from its removal, then a file of the same path is handled again.
+        std::fs::remove_file(&marker).unwrap();
+        assert!(is_skip_marker(&event, &markers, ttl));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!is_skip_marker(&event, &markers, ttl));
+        assert!(markers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_flag() {
+        let flag = env::current_dir().unwrap().join("stop.flg");
+        assert!(is_flag(&flag, &flag));
+        assert!(is_flag(
+            &flag.parent().unwrap().join("x/../stop.flg"),
+            &flag
+        ));
+        // The unrelated siblings.
+        assert!(!is_flag(&flag.with_file_name("stop.flg.tmp"), &flag));
+        assert!(!is_flag(&flag.with_file_name("other.txt"), &flag));
+        assert!(!is_flag(&flag.join("stop.flg"), &flag));
+        assert!(!is_flag(flag.parent().unwrap(), &flag));
+
+        let upper = flag.with_file_name("STOP.FLG");
+        #[cfg(windows)]
+        {
+            assert!(is_flag(&upper, &flag));
+            let upper_dir = PathBuf::from(flag.to_string_lossy().to_uppercase());
+            assert!(is_flag(&upper_dir, &flag));
+            let verbatim = PathBuf::from(format!(r"\\?\{}", flag.display()));
+            assert!(is_flag(&verbatim, &flag));
+            assert!(is_flag(&flag, &verbatim));
+            assert!(!is_flag(&verbatim.with_file_name("stop.flg.tmp"), &flag));
+        }
+        #[cfg(not(windows))]
+        assert!(!is_flag(&upper, &flag));
+    }
+
+    #[test]
+    fn test_pause() {
+        let event = |name: &str| Event::new(EventKind::Any).add_path(PathBuf::from(name));
+        let mut pause = Pause::new(PausePolicy::Buffer);
+        pause.push(event("a"));
+        assert_eq!(pause.next(), Some(event("a")));
+        pause.paused = true;
+        pause.push(event("b"));
+        assert_eq!(pause.next(), None);
+        pause.paused = false;
+        assert_eq!(pause.next(), Some(event("b")));
+        assert_eq!(pause.next(), None);
+
+        let mut pause = Pause::new(PausePolicy::Drop);
+        pause.paused = true;
+        pause.push(event("c"));
+        pause.paused = false;
+        assert_eq!(pause.next(), None);
+        assert_eq!(pause.dropped, 1);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_run_init_retries() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_run_init_retries");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(&tmp).unwrap();
+        let count = tmp.join("count");
+        let mut context = Context::new();
+        context.insert("log_dir", &tmp.to_string_lossy());
+        // Fails twice, then succeeds.
+        let init = |retries| Init {
+            cmd: "/bin/sh".to_string(),
+            arg: vec![
+                "-c".to_string(),
+                format!(
+                    "n=$(($(cat {0} 2>/dev/null || echo 0) + 1)); echo $n > {0}; [ $n -ge 3 ]",
+                    count.display()
+                ),
+            ],
+            error_stop: true,
+            retries: Some(retries),
+            retry_delay: Some(10),
+            phase: Default::default(),
+        };
+        let attempts = || std::fs::read_to_string(&count).unwrap().trim().to_string();
+
+        run_init(&init(1), &context).unwrap_err();
+        assert_eq!(attempts(), "2");
+
+        std::fs::remove_file(&count).unwrap();
+        run_init(&init(3), &context).unwrap();
+        assert_eq!(attempts(), "3");
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_run_init_phase() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_run_init_phase");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(&tmp).unwrap();
+        let config = tmp.join("spyrun.toml");
+        // Listed post first: the phase decides the order, not the position.
+        std::fs::write(
+            &config,
+            format!(
+                r#"
+[cfg]
+stop_flg = '{0}/stop.flg'
+max_threads = 2
+single_instance = false
+
+[log]
+path = '{0}/spyrun.log'
+stdout = false
+
+[[init]]
+cmd = '/bin/sh'
+arg = ['-c', 'echo post >> {0}/order; touch {0}/input/post.txt']
+phase = 'post'
+
+[[init]]
+cmd = '/bin/sh'
+arg = ['-c', 'echo pre >> {0}/order; mkdir -p {0}/input']
+
+[[spys]]
+name = 'phase'
+input = '{0}/input'
+output = '{0}/output'
+[[spys.patterns]]
+pattern = 'post\.txt$'
+cmd = '/bin/sh'
+arg = ['-c', 'echo spy >> {0}/order']
+"#,
+                tmp.to_slash_lossy()
+            ),
+        )
+        .unwrap();
+
+        let cli = Cli::parse_from(["spyrun", "--config", config.to_str().unwrap()]);
+        let (tx_stop, rx_stop) = mpsc::channel();
+        let (tx, order) = (tx_stop.clone(), tmp.join("order"));
+        let stopper = std::thread::spawn(move || {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(10) {
+                let order = std::fs::read_to_string(&order).unwrap_or_default();
+                if order.contains("spy") {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            tx.send(Control::Stop).unwrap();
+        });
+        run_cli(cli, tx_stop, rx_stop).unwrap();
+        stopper.join().unwrap();
+        let order = std::fs::read_to_string(tmp.join("order")).unwrap();
+        assert_eq!(order.lines().collect::<Vec<_>>(), ["pre", "post", "spy"]);
+    }
+
+    #[test]
+    fn test_run_twice() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_run_twice");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(&tmp).unwrap();
+        let config = tmp.join("spyrun.toml");
+        std::fs::write(
+            &config,
+            format!(
+                r#"
+spys = []
+
+[cfg]
+stop_flg = '{0}/stop.flg'
+max_threads = 2
+single_instance = false
+
+[log]
+path = '{0}/spyrun.log'
+stdout = false
+"#,
+                tmp.to_slash_lossy()
+            ),
+        )
+        .unwrap();
+
+        // An embedder calls run again in the same process, e.g. to restart.
+        for _ in 0..2 {
+            let cli = Cli::parse_from(["spyrun", "--config", config.to_str().unwrap()]);
+            let (tx_stop, rx_stop) = mpsc::channel();
+            tx_stop.send(Control::Stop).unwrap();
+            run_cli(cli, tx_stop, rx_stop).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_run_startup_strict() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_run_startup_strict");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let config = tmp.join("spyrun.toml");
+        let write_config = |startup: &str| {
+            std::fs::write(
+                &config,
+                format!(
+                    r#"
+[cfg]
+stop_flg = '{0}/stop.flg'
+max_threads = 2
+single_instance = false
+startup = '{1}'
+
+[log]
+path = '{0}/spyrun.log'
+stdout = false
+
+[[spys]]
+name = 'blind'
+input = '{0}/input'
+# Not available on linux, so the watch fails.
+watch_backend = 'fsevent'
+"#,
+                    tmp.to_slash_lossy(),
+                    startup
+                ),
+            )
+            .unwrap();
+        };
+        let run = || {
+            let cli = Cli::parse_from(["spyrun", "--config", config.to_str().unwrap()]);
+            let (tx_stop, rx_stop) = mpsc::channel();
+            tx_stop.send(Control::Stop).unwrap();
+            run_cli(cli, tx_stop, rx_stop)
+        };
+
+        write_config("strict");
+        let message = format!("{:?}", run().unwrap_err());
+        assert!(
+            message.contains("Failed to start watching: [blind]"),
+            "{}",
+            message
+        );
+
+        // Lenient runs without the spy.
+        write_config("lenient");
+        run().unwrap();
+    }
+
+    #[test]
+    fn test_render_sample() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_render_sample");
+        let spy = Spy::builder("render")
+            .input(tmp.join("input"))
+            .output(tmp.join("output").join("{{ event_stem }}"))
+            .event("Create")
+            .add_pattern(Pattern {
+                name: Some("csv".to_string()),
+                pattern: r"\.csv$".to_string(),
+                cmd: "import".to_string(),
+                arg: vec![
+                    "{{ event_name }}".to_string(),
+                    "{{ event_kind }}".to_string(),
+                    "{{ event_kind_detail }}".to_string(),
+                    "{{ pattern_name }}".to_string(),
+                ],
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let sample = tmp.join("input").join("data.csv");
+
+        let rendered = render_sample(
+            std::slice::from_ref(&spy),
+            &Context::new(),
+            &sample,
+            "Create",
+        )
+        .unwrap();
+        assert!(
+            rendered.starts_with("[render] pattern: csv, "),
+            "{}",
+            rendered
+        );
+        assert!(
+            rendered.contains(r#"arg: ["data.csv", "Create", "Create(Any)", "csv"]"#),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("cmd: import"), "{}", rendered);
+        assert!(
+            rendered.contains(&format!(
+                "output: {}",
+                tmp.join("output").join("data").display()
+            )),
+            "{}",
+            rendered
+        );
+
+        assert!(render_sample(
+            std::slice::from_ref(&spy),
+            &Context::new(),
+            &sample,
+            "Remove"
+        )
+        .is_err());
+        let txt = tmp.join("input").join("data.txt");
+        assert!(render_sample(&[spy], &Context::new(), &txt, "Create").is_err());
+    }
+
+    #[test]
+    fn test_run_sample() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_run_sample");
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", vec!["/c", "exit {{ event_stem }}"]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("/bin/sh", vec!["-c", "exit {{ event_stem }}"]);
+        let spy = Spy::builder("run")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .add_pattern(Pattern {
+                name: Some("exit".to_string()),
+                pattern: r"\.csv$".to_string(),
+                cmd: cmd.to_string(),
+                arg: arg.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let spys = [spy];
+        let run = |name: &str| {
+            let sample = tmp.join("input").join(name);
+            run_sample(&spys, &Context::new(), &sample, "Create").unwrap()
+        };
+
+        let result = run("0.csv");
+        assert!(result.success(), "{}", result);
+        assert_eq!(result.code(), Some(0));
+        let result = run("3.csv");
+        assert!(!result.success(), "{}", result);
+        assert_eq!(result.code(), Some(3));
+        assert!(result.to_string().starts_with("status: "), "{}", result);
+
+        let txt = tmp.join("input").join("0.txt");
+        assert!(run_sample(&spys, &Context::new(), &txt, "Create").is_err());
+    }
+
+    #[test]
+    fn test_check_inputs() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_check_inputs");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        let spy = |name: &str, policy| {
+            Spy::builder(name)
+                .input(tmp.join(name).join("nested"))
+                .input_policy(policy)
+                .build()
+                .unwrap()
+        };
+
+        let checked = check_inputs(vec![
+            spy("create", InputPolicy::Create),
+            spy("wait", InputPolicy::Wait),
+            spy("ignore", InputPolicy::Ignore),
+        ])
+        .unwrap();
+        let names = checked.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["create", "wait"]);
+        assert!(tmp.join("create").join("nested").is_dir());
+        assert!(!tmp.join("wait").exists());
+
+        let e = check_inputs(vec![spy("error", InputPolicy::Error)]).unwrap_err();
+        assert!(e.to_string().contains("[error] input not found"), "{}", e);
+        assert!(!tmp.join("error").exists());
+    }
+
+    #[test]
+    fn test_loop_detector() {
+        let mut loop_detector = LoopDetector::new(LoopGuard {
+            max: 5,
+            window: 1000,
+            cooldown: 200,
+        });
+
+        // A command writing into its own input fires the same key again and again.
+        let fires = (0..10)
+            .map(|_| loop_detector.fire("self"))
+            .collect::<Vec<_>>();
+        assert_eq!(fires.iter().filter(|f| **f).count(), 5);
+        assert!(fires[..5].iter().all(|f| *f));
+        // Only the looping key is suspended.
+        assert!(loop_detector.fire("other"));
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(loop_detector.fire("self"));
+        assert!(loop_detector.fire("other"));
+    }
+
+    #[test]
+    fn test_matched_pattern() {
+        let tmp = env::current_dir().unwrap().join("test");
+        let spy = Spy::builder("matched").input(&tmp).build().unwrap();
+        let event = Event::new(EventKind::Create(CreateKind::Any)).add_path(tmp.join("test.cmd"));
+
+        let (pattern_index, pattern) = find_pattern(&event, &spy).unwrap();
+        let mut context = Context::new();
+        insert_pattern_context(&mut context, pattern_index, &pattern, &tmp.join("test.cmd"));
+        let rendered = Tera::one_off(
+            "{{ pattern_index }}: {{ matched_pattern }}",
+            &context,
+            false,
+        )
+        .unwrap();
+        assert_eq!(rendered, r"1: \.cmd$");
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_run_sync() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_run_sync");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let spy = Spy::builder("test_run_sync")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .pattern(
+                r"\.txt$",
+                "/bin/sh",
+                &["-c", "echo {{ greeting }} {{ event_stem }}; exit 3"],
+            )
+            .build()
+            .unwrap();
+        let event = |name: &str| SpyEvent::new("Create", tmp.join("input").join(name));
+        let vars = Vars::from([("greeting".to_string(), "hi".to_string())]);
+
+        let result = run_sync(&spy, &vars, &event("a.txt")).unwrap().unwrap();
+        assert!(!result.skipped());
+        assert_eq!(result.code(), Some(3));
+        let stdout = walkdir::WalkDir::new(tmp.join("output"))
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .find(|e| e.file_name().to_string_lossy().contains("_stdout_"))
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(stdout.path()).unwrap().trim(),
+            "hi a"
+        );
+
+        assert!(run_sync(&spy, &vars, &event("a.csv")).unwrap().is_none());
+        let e = run_sync(&Spy::new("unbuilt".to_string()), &vars, &event("a.txt")).unwrap_err();
+        assert!(e.to_string().contains("not built"), "{}", e);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_run_sync_failed_to_start() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_run_sync_failed_to_start");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let hooked = tmp.join("hooked.txt");
+        let spy = Spy::builder("test_run_sync_failed_to_start")
+            .input(tmp.join("input"))
+            .output(tmp.join("output"))
+            .add_pattern(Pattern {
+                pattern: r"\.txt$".to_string(),
+                cmd: "spyrun_not_found_command".to_string(),
+                on_success: Some(When {
+                    cmd: "/bin/sh".to_string(),
+                    arg: vec!["-c".to_string(), "exit 0".to_string()],
+                }),
+                on_error: Some(When {
+                    cmd: "/bin/sh".to_string(),
+                    arg: vec![
+                        "-c".to_string(),
+                        format!("echo {{{{ event_stem }}}} > '{}'", hooked.display()),
+                    ],
+                }),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let event = SpyEvent::new("Create", tmp.join("input").join("a.txt"));
+
+        let result = run_sync(&spy, &Vars::new(), &event).unwrap().unwrap();
+        assert!(result.failed_to_start());
+        assert!(
+            result
+                .to_string()
+                .contains("failed to start: spyrun_not_found_command, kind: NotFound"),
+            "{}",
+            result
+        );
+        // The start failure is an error of the command, so `on_error` runs.
+        assert_eq!(std::fs::read_to_string(&hooked).unwrap().trim(), "a");
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_sync_runner() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_sync_runner");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(tmp.join("input")).unwrap();
+        let build = |name: &str| {
+            Spy::builder(name)
+                .input(tmp.join("input"))
+                .output(tmp.join("output"))
+                .debounce(Duration::ZERO)
+                .limitkey("{{ event_stem }}")
+                .pattern(r"\.txt$", "/bin/sh", &["-c", "exit 3"])
+        };
+        let event = |name: &str| SpyEvent::new("Create", tmp.join("input").join(name));
+        let vars = Vars::new();
+
+        let spy = build("test_sync_runner_cooldown")
+            .failure_cooldown(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let mut runner = SyncRunner::new(&spy, &vars).unwrap();
+        assert_eq!(
+            runner.run(&event("a.txt")).unwrap().unwrap().code(),
+            Some(3)
+        );
+        let result = runner.run(&event("a.txt")).unwrap().unwrap();
+        assert_eq!(result.skip_reason(), Some(SkipReason::Cooldown));
+        assert!(!runner.run(&event("b.txt")).unwrap().unwrap().skipped());
+
+        std::fs::write(tmp.join("input").join("c.txt"), "c").unwrap();
+        let spy = build("test_sync_runner_quarantine")
+            .quarantine(1, None)
+            .build()
+            .unwrap();
+        let mut runner = SyncRunner::new(&spy, &vars).unwrap();
+        assert!(!runner.run(&event("c.txt")).unwrap().unwrap().skipped());
+        let result = runner.run(&event("c.txt")).unwrap().unwrap();
+        assert_eq!(result.skip_reason(), Some(SkipReason::Quarantine));
+    }
+
+    #[test]
+    fn test_spy_context() {
+        let spy = Spy::builder("spy_context")
+            .input("input")
+            .throttle(Duration::from_secs(15))
+            .pattern(r"\.csv$", "upload", &["{{ enc(arg='secret') }}"])
+            .recursive(true)
+            .event("Create")
+            .event("Modify")
+            .build()
+            .unwrap();
+        let mut context = Context::new();
+        insert_spy_context(&mut context, &spy).unwrap();
+        let rendered = Tera::one_off(
+            "{{ spy_throttle }} {{ spy_debounce }} {{ spy_recursive }} {{ spy_events }} {{ spy_patterns_count }}",
+            &context,
+            false,
+        )
+        .unwrap();
+        assert_eq!(rendered, "15000 50 true Create,Modify 1");
+
+        // Rendered as the JSON text, the templates in it are not rendered.
+        let json = Tera::one_off("{{ spy_config_json }}", &context, false).unwrap();
+        let config = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        assert_eq!(config["name"], "spy_context");
+        assert_eq!(config["throttle"], 15000);
+        assert_eq!(config["recursive"], true);
+        assert_eq!(config["patterns"][0]["arg"][0], "{{ enc(arg='secret') }}");
+        assert!(config.get("source").is_none());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_limitkey_by_captures() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_limitkey_by_captures");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        let spy = Spy::builder("captures")
+            .input(&tmp)
+            .pattern(
+                r"order_(?P<customer>[a-z]+)_(\d+)\.csv$",
+                "/bin/sh",
+                &["-c", "echo {{ match_customer }} {{ match_2 }}"],
+            )
+            .build()
+            .unwrap();
+        let cache = Arc::new(Mutex::new(HashMap::new()));
+        let results = ["order_acme_1.csv", "order_acme_2.csv", "order_beta_1.csv"]
+            .iter()
+            .map(|file| {
+                let event_path = tmp.join(file);
+                let event =
+                    Event::new(EventKind::Create(CreateKind::Any)).add_path(event_path.clone());
+                let (pattern_index, pattern) = find_pattern(&event, &spy).unwrap();
+                let mut context = Context::new();
+                insert_pattern_context(&mut context, pattern_index, &pattern, &event_path);
+                execute_command(
+                    &event_path,
+                    CommandTemplate {
+                        name: &spy.name,
+                        input: "input",
+                        output: tmp.join("output").to_str().unwrap(),
+                        cmd: &pattern.cmd,
+                        arg: pattern.arg.clone(),
+                        limitkey: "{{ match_customer }}",
+                    },
+                    Duration::ZERO,
+                    Duration::from_secs(10),
+                    &ExecOptions::default(),
+                    context,
+                    &cache,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        // Throttled per customer, not per file.
+        let skipped = results.iter().map(|r| r.skipped()).collect::<Vec<_>>();
+        assert_eq!(skipped, vec![false, true, false]);
+        let mut outputs = walkdir::WalkDir::new(tmp.join("output"))
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().contains("_stdout_"))
+            .filter_map(|e| std::fs::read_to_string(e.path()).ok())
+            .map(|s| s.trim().to_string())
+            .collect::<Vec<_>>();
+        outputs.sort();
+        assert_eq!(outputs, vec!["acme 1", "beta 1"]);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_limit_scope() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_limit_scope");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        let files = ["a/x.txt", "a/y.txt", "b/x.txt", "a/x.txt"];
+        for (scope, expected) in [
+            (LimitScope::Spy, vec![false, true, true, true]),
+            (LimitScope::Dir, vec![false, true, false, true]),
+            (LimitScope::Path, vec![false, false, false, true]),
+        ] {
+            let spy = Spy::builder(&format!("{:?}", scope))
+                .input(&tmp)
+                .output(tmp.join("output"))
+                .limit_scope(scope)
+                .pattern(r"\.txt$", "/bin/sh", &["-c", "exit 0"])
+                .build()
+                .unwrap();
+            let cache = Arc::new(Mutex::new(HashMap::new()));
+            let skipped = files
+                .iter()
+                .map(|file| {
+                    execute_command(
+                        &tmp.join(file),
+                        spy.command_template(
+                            "/bin/sh",
+                            vec!["-c".to_string(), "exit 0".to_string()],
+                        ),
+                        Duration::ZERO,
+                        Duration::from_secs(10),
+                        &ExecOptions::default(),
+                        Context::new(),
+                        &cache,
+                    )
+                    .unwrap()
+                    .skipped()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(skipped, expected, "{:?}", scope);
+        }
+
+        // An explicit limitkey wins.
+        let explicit = |limitkey: &str| {
+            Spy::builder("explicit")
+                .limit_scope(LimitScope::Spy)
+                .limitkey(limitkey)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(
+            explicit("{{ event_stem }}").limitkey_template(),
+            "{{ event_stem }}"
+        );
+        assert_eq!(explicit("").limitkey_template(), "{{ spy_name }}");
+    }
+
+    #[test]
+    fn test_is_latest() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_is_latest");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(&tmp).unwrap();
+        let now = SystemTime::now();
+        let touch = |name: &str, modified: SystemTime| {
+            let path = tmp.join(name);
+            std::fs::File::create(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            path
+        };
+        let old = touch("app.log.1", now - Duration::from_secs(3600));
+        let new = touch("app.log", now);
+        // Newer, but not matching.
+        touch("other.txt", now + Duration::from_secs(60));
+        let pattern = Pattern {
+            pattern: r"app\.log".to_string(),
+            latest_only: Some(true),
+            ..Default::default()
+        };
+        assert!(is_latest(&new, &pattern));
+        assert!(!is_latest(&old, &pattern));
+        assert!(!is_latest(&tmp.join("removed.log"), &pattern));
+
+        // The same mtime are both the newest.
+        touch("app.log.1", now);
+        assert!(is_latest(&old, &pattern));
+        assert!(is_latest(&new, &pattern));
+    }
+
+    #[test]
+    fn test_pattern_name() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_pattern_name");
+        std::fs::create_dir_all(&tmp).unwrap();
+        let pattern = |name: &str, pattern: &str| Pattern {
+            name: Some(name.to_string()),
+            pattern: pattern.to_string(),
+            cmd: "cmd".to_string(),
+            ..Default::default()
+        };
+        let spy = Spy::builder("named")
+            .input(&tmp)
+            .add_pattern(pattern("text", r"\.txt$"))
+            .add_pattern(pattern("csv", r"\.csv$"))
+            .build()
+            .unwrap();
+
+        let log_path = tmp.join("test.log");
+        let layer = Layer::default()
+            .with_writer(Mutex::new(std::fs::File::create(&log_path).unwrap()))
+            .with_ansi(false);
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for (file, name, index) in [("test.txt", "text", 0), ("test.csv", "csv", 1)] {
+                let event_path = tmp.join(file);
+                let event =
+                    Event::new(EventKind::Create(CreateKind::Any)).add_path(event_path.clone());
+                let (pattern_index, pattern) = find_pattern(&event, &spy).unwrap();
+                let mut context = Context::new();
+                insert_pattern_context(&mut context, pattern_index, &pattern, &event_path);
+                assert_eq!(context.get("pattern_name").unwrap(), name);
+                assert_eq!(context.get("pattern_index").unwrap(), index);
+
+                let span = exec_span(
+                    &spy.name,
+                    "id",
+                    event.paths.last().unwrap(),
+                    pattern_index,
+                    &pattern.name(pattern_index),
+                );
+                let _enter = span.enter();
+                info!("Finished {}", file);
+            }
+        });
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        // find_pattern logs at TRACE too.
+        let lines = log
+            .lines()
+            .filter(|l| l.contains("Finished"))
+            .collect::<Vec<_>>();
+        assert!(lines[0].contains("pattern_name=text"), "{}", log);
+        assert!(lines[0].contains("Finished test.txt"), "{}", log);
+        assert!(lines[1].contains("pattern_name=csv"), "{}", log);
+        assert!(lines[1].contains("Finished test.csv"), "{}", log);
+    }
+
+    #[test]
+    fn test_watch_stop_flag() {
+        let tmp = env::current_dir()
+            .unwrap()
+            .join("test")
+            .join("test_watch_stop_flag");
+        std::fs::remove_dir_all(&tmp).unwrap_or_default();
+        std::fs::create_dir_all(&tmp).unwrap();
+        let flag = tmp.join("reload.flg");
+        let (tx_stop, rx_stop) = mpsc::channel();
+        let _watcher = watch_stop_flag(&flag, StopAction::Reload, tx_stop).unwrap();
+
+        std::fs::write(tmp.join("other.flg"), "").unwrap();
+        std::fs::write(&flag, "").unwrap();
+        assert_eq!(
+            rx_stop.recv_timeout(Duration::from_secs(10)).unwrap(),
+            Control::Reload
+        );
+    }
+}
@@ -5,96 +5,757 @@
 // =============================================================================
 
 use std::{
-    env, fs,
-    fs::create_dir_all,
+    env, fmt, fs,
+    fs::{create_dir_all, File},
+    io::{self, BufReader, BufWriter},
     path::{Path, PathBuf},
+    thread,
+    time::Duration,
 };
 
-use anyhow::Result;
-use chrono::Local;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, Utc};
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+use path_slash::PathBufExt as _;
 use tera::Context;
-use time::UtcOffset;
-use tracing_appender::non_blocking;
+use time::{format_description::well_known::Iso8601, UtcOffset};
+use tracing::{error, level_filters::LevelFilter, warn, Subscriber};
+use tracing_appender::{
+    non_blocking,
+    non_blocking::WorkerGuard,
+    rolling::{self, RollingFileAppender},
+};
 use tracing_log::LogTracer;
 use tracing_subscriber::{
-    fmt::{time::OffsetTime, writer::BoxMakeWriter, Layer},
+    fmt::{
+        format::Writer,
+        time::{FormatTime, OffsetTime},
+        writer::BoxMakeWriter,
+        Layer,
+    },
     prelude::*,
     EnvFilter, Registry,
 };
+#[cfg(windows)]
+use windows_sys::Win32::System::Console::GetConsoleWindow;
+
+use super::{
+    dedup::Dedup,
+    settings::{Log, Rotation, Settings},
+    util::insert_file_context,
+};
+
+const COMPRESS_INTERVAL: Duration = Duration::from_secs(60);
+const SWITCH_RETRY_COUNT: u32 = 5;
+const SWITCH_RETRY_WAIT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub enum Timer {
+    Offset(OffsetTime<Iso8601>),
+    Format(String),
+}
+
+impl Timer {
+    pub fn new(log: &Log) -> Self {
+        match &log.time_format {
+            Some(format) => Timer::Format(format.clone()),
+            None => {
+                // let timer = LocalTime::new(time_format); // issues: https://github.com/tokio-rs/tracing/issues/2715
+                let offset = UtcOffset::from_hms(9, 0, 0).unwrap();
+                Timer::Offset(OffsetTime::new(offset, Iso8601::DEFAULT))
+            }
+        }
+    }
+}
+
+impl FormatTime for Timer {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        match self {
+            Timer::Offset(timer) => timer.format_time(w),
+            Timer::Format(format) => write!(w, "{}", Local::now().format(format)),
+        }
+    }
+}
+
+fn rolling_appender(dir: &str, name: &str, rotation: Rotation) -> RollingFileAppender {
+    match rotation {
+        Rotation::Daily => rolling::daily(dir, name),
+        Rotation::Hourly => rolling::hourly(dir, name),
+        Rotation::Minutely => rolling::minutely(dir, name),
+        Rotation::Never => rolling::never(dir, name),
+    }
+}
+
+/// The file name the rolling appender currently writes to.
+fn rolling_file_name(name: &str, rotation: Rotation) -> String {
+    // tracing_appender names the files with UTC.
+    let now = Utc::now();
+    match rotation {
+        Rotation::Daily => format!("{}.{}", name, now.format("%Y-%m-%d")),
+        Rotation::Hourly => format!("{}.{}", name, now.format("%Y-%m-%d-%H")),
+        Rotation::Minutely => format!("{}.{}", name, now.format("%Y-%m-%d-%H-%M")),
+        Rotation::Never => name.to_string(),
+    }
+}
+
+/// Rename `from` to `to`, retrying while another process (e.g. the previous instance's writer)
+/// still holds `from`.
+fn rename_with_retry(from: &Path, to: &Path) -> io::Result<()> {
+    let mut wait = SWITCH_RETRY_WAIT;
+    for _ in 1..SWITCH_RETRY_COUNT {
+        match fs::rename(from, to) {
+            // 32: ERROR_SHARING_VIOLATION
+            Err(e)
+                if e.kind() == io::ErrorKind::PermissionDenied || e.raw_os_error() == Some(32) =>
+            {
+                thread::sleep(wait);
+                wait *= 2;
+            }
+            r => return r,
+        }
+    }
+    fs::rename(from, to)
+}
+
+/// Rename the log files of periods strictly older than the current one to
+/// `{stem}_{now}.{ext}`, appending `_2`, `_3`, ... if the name is already taken.
+/// The file of the current period is left to the appender.
+fn switch_logs(
+    dir: &Path,
+    name: &str,
+    stem: &str,
+    ext: &str,
+    now: &str,
+    rotation: Rotation,
+) -> Result<Vec<PathBuf>> {
+    let current = rolling_file_name(name, rotation);
+    let today = Utc::now().date_naive();
+    let mut old_logs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        if !path.is_file() {
+            continue;
+        }
+        let is_old = match rotation {
+            Rotation::Never => {
+                file_name == current
+                    && DateTime::<Utc>::from(fs::metadata(&path)?.modified()?).date_naive() < today
+            }
+            _ => file_name
+                .strip_prefix(&format!("{}.", name))
+                .zip(current.strip_prefix(&format!("{}.", name)))
+                .is_some_and(|(suffix, current)| suffix.len() == current.len() && suffix < current),
+        };
+        if is_old {
+            old_logs.push(path);
+        }
+    }
+    old_logs.sort();
+
+    let mut renamed = vec![];
+    for old_log in old_logs {
+        let mut rename_log_path = dir.join(format!("{}_{}.{}", stem, now, ext));
+        let mut count = 2;
+        while rename_log_path.exists() {
+            rename_log_path = dir.join(format!("{}_{}_{}.{}", stem, now, count, ext));
+            count += 1;
+        }
+        rename_with_retry(&old_log, &rename_log_path)?;
+        renamed.push(rename_log_path);
+    }
+    Ok(renamed)
+}
+
+/// Gzip `path` to `path.gz` and remove the original.
+///
+/// The archive is written to a temporary name and verified before it replaces the original, so
+/// an interrupted compression never loses the log.
+fn compress(path: &Path) -> Result<PathBuf> {
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let tmp_path = PathBuf::from(format!("{}.gz.tmp", path.display()));
 
-use super::{settings::Settings, util::insert_file_context};
+    let mut encoder = GzEncoder::new(
+        BufWriter::new(File::create(&tmp_path)?),
+        Compression::default(),
+    );
+    io::copy(&mut BufReader::new(File::open(path)?), &mut encoder)?;
+    encoder.finish()?.into_inner()?.sync_all()?;
+
+    // GzDecoder checks the crc and size in the trailer while reading.
+    let decoded_len = io::copy(
+        &mut GzDecoder::new(BufReader::new(File::open(&tmp_path)?)),
+        &mut io::sink(),
+    )?;
+    if decoded_len != fs::metadata(path)?.len() {
+        fs::remove_file(&tmp_path)?;
+        bail!("Failed to verify compressed log: {}", tmp_path.display());
+    }
+
+    fs::rename(&tmp_path, &gz_path)?;
+    fs::remove_file(path)?;
+    Ok(gz_path)
+}
 
-pub fn init(
+/// Whether `file_name` is a log rolled by `tracing_appender`: `{name}.{date}`.
+fn is_rolled(file_name: &str, name: &str) -> bool {
+    file_name
+        .strip_prefix(&format!("{}.", name))
+        .is_some_and(|date| {
+            !date.is_empty() && date.chars().all(|c| c.is_ascii_digit() || c == '-')
+        })
+}
+
+/// Whether `file_name` is a log renamed by `switch_logs`: `{stem}_{now}.{ext}` or
+/// `{stem}_{now}_{count}.{ext}`.
+fn is_switched(file_name: &str, stem: &str) -> bool {
+    file_name
+        .strip_prefix(&format!("{}_", stem))
+        .and_then(|rest| rest.split('.').next())
+        .is_some_and(|now| !now.is_empty() && now.chars().all(|c| c.is_ascii_digit() || c == '_'))
+}
+
+/// Compress the rotated log files in `dir`, leaving the one currently written to untouched.
+///
+/// `error_name` is the error log if it is written in `dir` too, its current file is left as well.
+fn compress_rotated(
+    dir: &Path,
+    name: &str,
+    stem: &str,
+    error_name: Option<&str>,
+    rotation: Rotation,
+) -> Result<Vec<PathBuf>> {
+    let current = [Some(name), error_name]
+        .into_iter()
+        .flatten()
+        .map(|name| rolling_file_name(name, rotation))
+        .collect::<Vec<_>>();
+    let mut compressed = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        if !path.is_file()
+            || current.contains(&file_name)
+            || file_name.ends_with(".gz")
+            || file_name.ends_with(".gz.tmp")
+            || !(is_rolled(&file_name, name) || is_switched(&file_name, stem))
+        {
+            continue;
+        }
+        compressed.push(compress(&path)?);
+    }
+    Ok(compressed)
+}
+
+#[cfg(windows)]
+fn has_console() -> bool {
+    unsafe { !GetConsoleWindow().is_null() }
+}
+
+#[cfg(not(windows))]
+fn has_console() -> bool {
+    true
+}
+
+/// Sets the global logger by `settings.log`. A process has only one: if it's already set, e.g. by
+/// the first `run_cli` of the process or by the embedder, it's kept with a warning, and `settings.log`
+/// (the path, the level, the rotation, ...) is not applied. The guards are of the files not used then.
+pub fn init(settings: Settings, context: &mut Context) -> Result<Vec<WorkerGuard>> {
+    let tracer = LogTracer::init();
+
+    let (subscriber, guards) = build(settings, context)?;
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        warn!("The global logger is already set, keep it. e: {}", e);
+    }
+    if let Err(e) = tracer {
+        warn!("The log tracer is already set, keep it. e: {}", e);
+    }
+
+    Ok(guards)
+}
+
+fn build(
     settings: Settings,
     context: &mut Context,
-) -> Result<(
-    tracing_appender::non_blocking::WorkerGuard,
-    tracing_appender::non_blocking::WorkerGuard,
-)> {
-    LogTracer::init()?;
-
+) -> Result<(impl Subscriber + Send + Sync, Vec<WorkerGuard>)> {
     insert_file_context(&settings.log.path, "log", context)?;
+    let error_log = match &settings.log.error_path {
+        Some(error_path) => {
+            insert_file_context(error_path, "error_log", context)?;
+            let error_dir = context.get("error_log_dir").unwrap().as_str().unwrap();
+            let error_name = context.get("error_log_name").unwrap().as_str().unwrap();
+            create_dir_all(error_dir)?;
+            let level = settings
+                .log
+                .error_level
+                .as_deref()
+                .unwrap_or("error")
+                .parse::<LevelFilter>()?;
+            Some((error_dir.to_string(), error_name.to_string(), level))
+        }
+        None => None,
+    };
+
+    // tracing_appender writes to `{log_name}.{suffix}`, so `log_path` points at the current
+    // rolling file. It is not refreshed when the file rolls over at the period boundary.
+    let log_base_path = context.get("log_path").unwrap().clone();
+    let log_path =
+        Path::new(context.get("log_dir").unwrap().as_str().unwrap()).join(rolling_file_name(
+            context.get("log_name").unwrap().as_str().unwrap(),
+            settings.log.rotation,
+        ));
+    context.insert("log_base_path", &log_base_path);
+    context.insert("log_path", &log_path.to_slash_lossy());
 
     let log_dir = context.get("log_dir").unwrap().as_str().unwrap();
     let log_name = context.get("log_name").unwrap().as_str().unwrap();
     create_dir_all(log_dir)?;
 
     if settings.log.switch {
-        let old_log_path = Path::join(
-            &PathBuf::from(log_dir),
-            format!(
-                "{}.{}",
-                &log_name,
-                &Local::now().format("%Y-%m-%d").to_string()
-            ),
-        );
-        let rename_log_path = Path::join(
-            &PathBuf::from(log_dir),
-            format!(
-                "{}_{}.{}",
-                context.get("log_stem").unwrap().as_str().unwrap(),
-                context.get("now").unwrap().as_str().unwrap(),
-                context.get("log_ext").unwrap().as_str().unwrap()
-            ),
-        );
-
-        if old_log_path.is_file() {
-            fs::rename(old_log_path, rename_log_path)?;
-        }
+        switch_logs(
+            Path::new(log_dir),
+            log_name,
+            context.get("log_stem").unwrap().as_str().unwrap(),
+            context.get("log_ext").unwrap().as_str().unwrap(),
+            context.get("now").unwrap().as_str().unwrap(),
+            settings.log.rotation,
+        )?;
     }
 
-    let time_format = time::format_description::well_known::Iso8601::DEFAULT;
-    // let timer = LocalTime::new(time_format); // issues: https://github.com/tokio-rs/tracing/issues/2715
-    let offset = UtcOffset::from_hms(9, 0, 0).unwrap();
-    let timer = OffsetTime::new(offset, time_format);
+    if settings.log.compress {
+        let dir = PathBuf::from(log_dir);
+        let name = log_name.to_string();
+        let stem = context
+            .get("log_stem")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+        let error_name = error_log
+            .as_ref()
+            .filter(|(error_dir, _, _)| Path::new(error_dir) == dir)
+            .map(|(_, error_name, _)| error_name.clone());
+        let rotation = settings.log.rotation;
+        thread::spawn(move || loop {
+            if let Err(e) = compress_rotated(&dir, &name, &stem, error_name.as_deref(), rotation) {
+                error!("Failed to compress rotated logs: {:?}, e: {:?}", &dir, e);
+            }
+            thread::sleep(COMPRESS_INTERVAL);
+        });
+    }
 
-    let file_appender = non_blocking(tracing_appender::rolling::daily(log_dir, log_name));
-    let stdout_appender = non_blocking(std::io::stdout());
+    let timer = Timer::new(&settings.log);
 
+    let file_appender = non_blocking(rolling_appender(log_dir, log_name, settings.log.rotation));
     let file_writer = BoxMakeWriter::new(file_appender.0);
-    let stdout_writer = BoxMakeWriter::new(stdout_appender.0);
+    let mut guards = vec![file_appender.1];
 
     let file_layer = Layer::default()
         .with_writer(file_writer)
         .with_timer(timer.clone())
+        .with_target(settings.log.show_target)
+        .with_thread_ids(settings.log.show_thread)
+        .with_thread_names(settings.log.show_thread)
         // .json()
-        .with_ansi(false)
-        .with_filter(EnvFilter::new(
-            env::var("SPYRUN_LOG_FILE").unwrap_or(settings.log.level),
-        ))
-        .boxed();
-    let stdout_layer = Layer::default()
-        .with_writer(stdout_writer)
-        .with_timer(timer.clone())
-        .pretty()
-        .with_file(false)
-        .with_filter(EnvFilter::new(
-            env::var("SPYRUN_LOG_STDOUT").unwrap_or_else(|_| "info".to_string()),
-        ))
-        .boxed();
+        .with_ansi(false);
+    let file_layer = match settings.log.dedup_window {
+        Some(window) => Dedup::new(file_layer, Duration::from_secs(window)).boxed(),
+        None => file_layer.boxed(),
+    }
+    .with_filter(EnvFilter::new(match settings.log.quiet {
+        true => "error".to_string(),
+        false => env::var("SPYRUN_LOG_FILE").unwrap_or(settings.log.level),
+    }))
+    .boxed();
+    let stdout_layer = if settings.log.stdout && has_console() {
+        let stdout_appender = non_blocking(std::io::stdout());
+        let stdout_writer = BoxMakeWriter::new(stdout_appender.0);
+        guards.push(stdout_appender.1);
+        Some(
+            Layer::default()
+                .with_writer(stdout_writer)
+                .with_timer(timer.clone())
+                .with_target(settings.log.show_target)
+                .with_thread_ids(settings.log.show_thread)
+                .with_thread_names(settings.log.show_thread)
+                .pretty()
+                .with_file(false)
+                .with_filter(EnvFilter::new(match settings.log.quiet {
+                    true => "error".to_string(),
+                    false => env::var("SPYRUN_LOG_STDOUT").unwrap_or_else(|_| "info".to_string()),
+                }))
+                .boxed(),
+        )
+    } else {
+        None
+    };
+
+    let error_layer = error_log.map(|(error_dir, error_name, level)| {
+        let error_appender = non_blocking(rolling_appender(
+            &error_dir,
+            &error_name,
+            settings.log.rotation,
+        ));
+        guards.push(error_appender.1);
+        Layer::default()
+            .with_writer(BoxMakeWriter::new(error_appender.0))
+            .with_timer(timer.clone())
+            .with_target(settings.log.show_target)
+            .with_thread_ids(settings.log.show_thread)
+            .with_thread_names(settings.log.show_thread)
+            .with_ansi(false)
+            .with_filter(level)
+            .boxed()
+    });
+
+    let registry = Registry::default()
+        .with(file_layer)
+        .with(stdout_layer)
+        .with(error_layer);
+
+    Ok((registry, guards))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, File},
+        io::{Read, Write},
+        path::Path,
+        sync::Mutex,
+    };
+
+    use anyhow::Result;
+    use chrono::Local;
+    use flate2::read::GzDecoder;
+    use path_slash::PathBufExt as _;
+    use regex::Regex;
+    use tera::{Context, Tera};
+    use tracing::{error, info, warn};
+    use tracing_subscriber::{fmt::Layer, prelude::*, Registry};
+
+    use super::{build, compress_rotated, rolling_appender, rolling_file_name, switch_logs, Timer};
+    use crate::settings::{Log, Rotation, Settings};
+
+    #[test]
+    fn test_custom_time_format() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_custom_time_format");
+        create_dir_all(&tmp)?;
+        let log_path = tmp.join("test.log");
+        let log = Log {
+            path: log_path.to_string_lossy().to_string(),
+            level: "info".to_string(),
+            switch: false,
+            rotation: Rotation::Daily,
+            compress: false,
+            error_path: None,
+            error_level: None,
+            dedup_window: None,
+            quiet: false,
+            time_format: Some("[%Y/%m/%d]".to_string()),
+            show_thread: false,
+            show_target: false,
+            stdout: true,
+        };
+        let layer = Layer::default()
+            .with_writer(Mutex::new(File::create(&log_path)?))
+            .with_timer(Timer::new(&log))
+            .with_target(log.show_target)
+            .with_ansi(false);
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || info!("custom time format"));
+
+        let line = read_to_string(&log_path)?;
+        let expected = Local::now().format("[%Y/%m/%d]").to_string();
+        assert!(line.starts_with(&expected), "{}", line);
+        assert!(!line.contains("spyrun::logger"), "{}", line);
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_without_stdout() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_init_without_stdout");
+        remove_dir_all(&tmp).unwrap_or_default();
+        let settings: Settings = toml::from_str(&format!(
+            r#"
+spys = []
+
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = '{}'
+stdout = false
+"#,
+            tmp.join("spyrun.log").to_string_lossy()
+        ))?;
+        let (subscriber, guards) = build(settings, &mut Context::new())?;
+        assert_eq!(guards.len(), 1);
+        tracing::subscriber::with_default(subscriber, || info!("without stdout"));
+        drop(guards);
+
+        let log = read_dir(&tmp)?
+            .map(|e| read_to_string(e?.path()))
+            .collect::<Result<String, _>>()?;
+        assert!(log.contains("without stdout"), "{}", log);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotation() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_rotation");
+        for (rotation, shape) in [
+            (Rotation::Daily, r"^spyrun\.log\.\d{4}-\d{2}-\d{2}$"),
+            (Rotation::Hourly, r"^spyrun\.log\.\d{4}-\d{2}-\d{2}-\d{2}$"),
+            (
+                Rotation::Minutely,
+                r"^spyrun\.log\.\d{4}-\d{2}-\d{2}-\d{2}-\d{2}$",
+            ),
+            (Rotation::Never, r"^spyrun\.log$"),
+        ] {
+            let dir = tmp.join(format!("{:?}", rotation));
+            remove_dir_all(&dir).unwrap_or_default();
+            create_dir_all(&dir)?;
+            let mut appender = rolling_appender(dir.to_str().unwrap(), "spyrun.log", rotation);
+            appender.write_all(b"rotation")?;
+            appender.flush()?;
+
+            let names = read_dir(&dir)?
+                .map(|e| Ok(e?.file_name().to_string_lossy().to_string()))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(names.len(), 1, "{:?}", names);
+            assert!(Regex::new(shape)?.is_match(&names[0]), "{:?}", names);
+            let current = rolling_file_name("spyrun.log", rotation);
+            assert_eq!(names[0], current);
+        }
+        Ok(())
+    }
 
-    let registry = Registry::default().with(file_layer).with(stdout_layer);
-    tracing::subscriber::set_global_default(registry)?;
+    #[test]
+    fn test_compress_rotated() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_compress_rotated");
+        remove_dir_all(&tmp).unwrap_or_default();
+        create_dir_all(&tmp)?;
+        let content = "rotated log\n".repeat(1000);
+        let current = tmp.join(rolling_file_name("spyrun.log", Rotation::Daily));
+        let rotated = tmp.join("spyrun.log.2000-01-01");
+        let switched = tmp.join("spyrun_20000101_000000000.log");
+        // `error_path` next to `path`, with the stem of the log as its prefix.
+        let error = tmp.join(rolling_file_name("spyrun_error.log", Rotation::Daily));
+        std::fs::write(&current, "current")?;
+        std::fs::write(&rotated, &content)?;
+        std::fs::write(&switched, &content)?;
+        std::fs::write(&error, "error")?;
 
-    Ok((file_appender.1, stdout_appender.1))
+        let compress = || {
+            compress_rotated(
+                &tmp,
+                "spyrun.log",
+                "spyrun",
+                Some("spyrun_error.log"),
+                Rotation::Daily,
+            )
+        };
+        let compressed = compress()?;
+        assert_eq!(compressed.len(), 2);
+        assert!(current.is_file());
+        assert!(error.is_file());
+        assert!(!rotated.exists());
+        assert!(!switched.exists());
+        for gz in [
+            tmp.join("spyrun.log.2000-01-01.gz"),
+            tmp.join("spyrun_20000101_000000000.log.gz"),
+        ] {
+            let mut decoded = String::new();
+            GzDecoder::new(File::open(&gz)?).read_to_string(&mut decoded)?;
+            assert_eq!(decoded, content);
+        }
+
+        // Already compressed files are skipped.
+        assert!(compress()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_log() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_error_log");
+        remove_dir_all(&tmp).unwrap_or_default();
+        let settings: Settings = toml::from_str(&format!(
+            r#"
+spys = []
+
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = '{}'
+error_path = '{}'
+rotation = 'never'
+stdout = false
+"#,
+            tmp.join("spyrun.log").to_string_lossy(),
+            tmp.join("error").join("spyrun_error.log").to_string_lossy()
+        ))?;
+        let (subscriber, guards) = build(settings, &mut Context::new())?;
+        tracing::subscriber::with_default(subscriber, || {
+            info!("info record");
+            error!("error record");
+        });
+        drop(guards);
+
+        let log = read_to_string(tmp.join("spyrun.log"))?;
+        assert!(log.contains("info record"), "{}", log);
+        assert!(log.contains("error record"), "{}", log);
+        let error_log = read_to_string(tmp.join("error").join("spyrun_error.log"))?;
+        assert!(!error_log.contains("info record"), "{}", error_log);
+        assert!(error_log.contains("error record"), "{}", error_log);
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_logs() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_switch_logs");
+        remove_dir_all(&tmp).unwrap_or_default();
+        create_dir_all(&tmp)?;
+        let current = tmp.join(rolling_file_name("spyrun.log", Rotation::Daily));
+        std::fs::write(&current, "same day")?;
+        std::fs::write(tmp.join("spyrun.log.2000-01-01"), "old 1")?;
+        std::fs::write(tmp.join("spyrun.log.2000-01-02"), "old 2")?;
+        std::fs::write(tmp.join("spyrun_20000103.log"), "taken")?;
+
+        let renamed = switch_logs(
+            &tmp,
+            "spyrun.log",
+            "spyrun",
+            "log",
+            "20000103",
+            Rotation::Daily,
+        )?;
+        assert_eq!(
+            renamed,
+            [
+                tmp.join("spyrun_20000103_2.log"),
+                tmp.join("spyrun_20000103_3.log")
+            ]
+        );
+        assert_eq!(read_to_string(&renamed[0])?, "old 1");
+        assert_eq!(read_to_string(&renamed[1])?, "old 2");
+        assert_eq!(read_to_string(tmp.join("spyrun_20000103.log"))?, "taken");
+        // The file of the current day is kept for the appender.
+        assert_eq!(read_to_string(&current)?, "same day");
+
+        // Starting again on the same day renames nothing.
+        assert!(switch_logs(
+            &tmp,
+            "spyrun.log",
+            "spyrun",
+            "log",
+            "20000104",
+            Rotation::Daily
+        )?
+        .is_empty());
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_rename_with_retry_locked() -> Result<()> {
+        use std::{fs::OpenOptions, os::windows::fs::OpenOptionsExt, thread, time::Duration};
+
+        use super::rename_with_retry;
+
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_rename_with_retry_locked");
+        remove_dir_all(&tmp).unwrap_or_default();
+        create_dir_all(&tmp)?;
+        let from = tmp.join("spyrun.log.2000-01-01");
+        let to = tmp.join("spyrun_20000101.log");
+        std::fs::write(&from, "locked")?;
+
+        // No sharing: rename fails with ERROR_SHARING_VIOLATION until the handle is dropped.
+        let locked = OpenOptions::new().read(true).share_mode(0).open(&from)?;
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(locked);
+        });
+        rename_with_retry(&from, &to)?;
+        handle.join().unwrap();
+        assert_eq!(read_to_string(&to)?, "locked");
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_path_context() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_log_path_context");
+        remove_dir_all(&tmp).unwrap_or_default();
+        let settings: Settings = toml::from_str(&format!(
+            r#"
+spys = []
+
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = '{}'
+rotation = 'hourly'
+stdout = false
+"#,
+            tmp.join("spyrun.log").to_slash_lossy()
+        ))?;
+        let mut context = Context::new();
+        let (subscriber, guards) = build(settings, &mut context)?;
+        tracing::subscriber::with_default(subscriber, || info!("first log line"));
+        drop(guards);
+
+        let log_path = Tera::one_off("{{ log_path }}", &context, false)?;
+        assert!(Path::new(&log_path).is_file(), "{}", log_path);
+        assert!(read_to_string(&log_path)?.contains("first log line"));
+        let log_base_path = Tera::one_off("{{ log_base_path }}", &context, false)?;
+        assert_eq!(log_base_path, tmp.join("spyrun.log").to_slash_lossy());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quiet() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_quiet");
+        remove_dir_all(&tmp).unwrap_or_default();
+        let mut settings: Settings = toml::from_str(&format!(
+            r#"
+spys = []
+
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = '{}'
+level = 'trace'
+rotation = 'never'
+stdout = false
+"#,
+            tmp.join("spyrun.log").to_slash_lossy()
+        ))?;
+        settings.log.quiet = true;
+        let (subscriber, guards) = build(settings, &mut Context::new())?;
+        tracing::subscriber::with_default(subscriber, || {
+            info!("info record");
+            warn!("warn record");
+            error!("error record");
+        });
+        drop(guards);
+
+        let log = read_to_string(tmp.join("spyrun.log"))?;
+        assert_eq!(log.lines().count(), 1, "{}", log);
+        assert!(log.contains("error record"), "{}", log);
+        Ok(())
+    }
 }
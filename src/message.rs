@@ -4,8 +4,450 @@
 // Last Change : 2023/10/08 16:17:24.
 // =============================================================================
 
-#[derive(Debug, Clone, PartialEq)]
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc,
+};
+
+use log_derive::logfn;
+use notify::{
+    event::{
+        AccessKind, AccessMode, CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind,
+        RenameMode,
+    },
+    Event, EventKind,
+};
+
+use crate::{
+    metrics::SpyStats,
+    settings::{Pattern, StopAction},
+};
+
+/// What the watcher of a spy receives: the events, and the controls.
+#[derive(Debug, Clone)]
 pub enum Message {
     Event(notify::Event),
     Stop,
+    /// Holds the events until `Resume`, or drops them, by `pause_policy`.
+    Pause,
+    Resume,
+    /// Walks `input` now, by `walk` or like `scan_existing`.
+    Rescan,
+    /// Replies the counters of the spy.
+    DumpStats(mpsc::Sender<SpyStats>),
+    /// Replaces the patterns. The current ones are kept if they are invalid.
+    ReloadPatterns(Vec<Pattern>),
+    /// An event injected by `cfg.inject_dir`. Replies the exec_id, or why it was not executed.
+    Inject(notify::Event, mpsc::Sender<String>),
+    /// Wakes the watcher waiting for the events, to receive a control sent by `MessageSender`.
+    Wake,
+}
+
+/// The sender to the watcher of a spy. The controls have their own channel, not to be behind the events.
+#[derive(Debug, Clone)]
+pub struct MessageSender {
+    events: mpsc::Sender<Message>,
+    controls: mpsc::Sender<Message>,
+}
+
+impl MessageSender {
+    pub fn new(events: mpsc::Sender<Message>, controls: mpsc::Sender<Message>) -> Self {
+        Self { events, controls }
+    }
+
+    /// Sends an event to the events, and the others to the controls.
+    pub fn send(&self, message: Message) -> Result<(), mpsc::SendError<Message>> {
+        match message {
+            Message::Event(_) | Message::Wake => self.events.send(message),
+            control => {
+                self.controls.send(control)?;
+                // Not woken while paused, the watcher waits for the controls then.
+                self.events.send(Message::Wake).unwrap_or_default();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What the control channel of `run` and `Engine` receives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    Stop,
+    /// Exits without waiting for the watchers.
+    StopForce,
+    /// Stops, and `run` returns true to load the config again.
+    Reload,
+    /// Holds the events of the spy by name, or drops them, by its `pause_policy`.
+    Pause(String),
+    Resume(String),
+    /// Walks `input` of the spy by name now, by `walk` or like `scan_existing`.
+    Rescan(String),
+}
+
+impl From<StopAction> for Control {
+    fn from(action: StopAction) -> Self {
+        match action {
+            StopAction::Stop => Control::Stop,
+            StopAction::StopForce => Control::StopForce,
+            StopAction::Reload => Control::Reload,
+        }
+    }
+}
+
+/// An event of a path, for `run_sync`, `TestSource` and the `EventSource` given in code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpyEvent(pub(crate) Event);
+
+impl SpyEvent {
+    /// An event of `kind` by the name in `spys.events`, e.g. `Create`, or the detailed one, e.g. `Create(File)`, on `path`.
+    /// An unknown name is `Modify`, as `string_to_event_kind`.
+    pub fn new<P: AsRef<Path>>(kind: &str, path: P) -> Self {
+        Self(Event::new(string_to_event_kind(kind)).add_path(path.as_ref().to_path_buf()))
+    }
+
+    pub fn kind(&self) -> String {
+        event_kind_to_string(self.0.kind)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.0.paths.last().map(PathBuf::as_path)
+    }
+}
+
+/// The name of `kind` used in `spys.events`, `{{ event_kind }}` and `patterns.when`.
+#[tracing::instrument]
+#[logfn(Trace)]
+pub fn event_kind_to_string(kind: EventKind) -> String {
+    match kind {
+        EventKind::Any => "Any".to_string(),
+        EventKind::Create(_) => "Create".to_string(),
+        EventKind::Remove(_) => "Remove".to_string(),
+        EventKind::Modify(_) => "Modify".to_string(),
+        EventKind::Access(_) => "Access".to_string(),
+        EventKind::Other => "Other".to_string(),
+    }
+}
+
+/// The detailed name of `kind`, e.g. `Modify(Name(To))`, used in `{{ event_kind_detail }}`.
+/// The names are notify's, `string_to_event_kind` parses them back to the same kind.
+#[tracing::instrument]
+#[logfn(Trace)]
+pub fn event_kind_to_detail(kind: EventKind) -> String {
+    format!("{:?}", kind)
+}
+
+/// The kind of the name by `event_kind_to_string` or `event_kind_to_detail`. Unknown names are handled as `Modify`.
+#[tracing::instrument]
+#[logfn(Trace)]
+pub fn string_to_event_kind(str: &str) -> EventKind {
+    parse_event_kind(str).unwrap_or(EventKind::Modify(ModifyKind::Any))
+}
+
+/// The kind of a coarse name, e.g. `Create`, or a detailed one, e.g. `Create(File)`. None if unknown.
+pub fn parse_event_kind(name: &str) -> Option<EventKind> {
+    let (kind, sub) = split_kind(name)?;
+    match (kind, sub) {
+        ("Any", None) => Some(EventKind::Any),
+        ("Other", None) => Some(EventKind::Other),
+        ("Create", sub) => parse_sub(sub, CreateKind::Any, |sub| match sub {
+            "File" => Some(CreateKind::File),
+            "Folder" => Some(CreateKind::Folder),
+            "Other" => Some(CreateKind::Other),
+            _ => None,
+        })
+        .map(EventKind::Create),
+        ("Remove", sub) => parse_sub(sub, RemoveKind::Any, |sub| match sub {
+            "File" => Some(RemoveKind::File),
+            "Folder" => Some(RemoveKind::Folder),
+            "Other" => Some(RemoveKind::Other),
+            _ => None,
+        })
+        .map(EventKind::Remove),
+        ("Modify", sub) => {
+            parse_sub(sub, ModifyKind::Any, parse_modify_kind).map(EventKind::Modify)
+        }
+        ("Access", sub) => {
+            parse_sub(sub, AccessKind::Any, parse_access_kind).map(EventKind::Access)
+        }
+        _ => None,
+    }
+}
+
+/// `Kind(Sub)` to `Kind` and `Sub`, or `Kind` to `Kind` and None.
+fn split_kind(name: &str) -> Option<(&str, Option<&str>)> {
+    match name.split_once('(') {
+        Some((kind, sub)) => Some((kind, Some(sub.strip_suffix(')')?))),
+        None => Some((name, None)),
+    }
+}
+
+/// `any` if there is no sub kind, as the coarse names are.
+fn parse_sub<T>(sub: Option<&str>, any: T, parse: impl Fn(&str) -> Option<T>) -> Option<T> {
+    match sub {
+        None | Some("Any") => Some(any),
+        Some(sub) => parse(sub),
+    }
+}
+
+fn parse_modify_kind(name: &str) -> Option<ModifyKind> {
+    let (kind, sub) = split_kind(name)?;
+    match (kind, sub) {
+        ("Other", None) => Some(ModifyKind::Other),
+        ("Data", sub) => parse_sub(sub, DataChange::Any, |sub| match sub {
+            "Size" => Some(DataChange::Size),
+            "Content" => Some(DataChange::Content),
+            "Other" => Some(DataChange::Other),
+            _ => None,
+        })
+        .map(ModifyKind::Data),
+        ("Metadata", sub) => parse_sub(sub, MetadataKind::Any, |sub| match sub {
+            "AccessTime" => Some(MetadataKind::AccessTime),
+            "WriteTime" => Some(MetadataKind::WriteTime),
+            "Permissions" => Some(MetadataKind::Permissions),
+            "Ownership" => Some(MetadataKind::Ownership),
+            "Extended" => Some(MetadataKind::Extended),
+            "Other" => Some(MetadataKind::Other),
+            _ => None,
+        })
+        .map(ModifyKind::Metadata),
+        ("Name", sub) => parse_sub(sub, RenameMode::Any, |sub| match sub {
+            "To" => Some(RenameMode::To),
+            "From" => Some(RenameMode::From),
+            "Both" => Some(RenameMode::Both),
+            "Other" => Some(RenameMode::Other),
+            _ => None,
+        })
+        .map(ModifyKind::Name),
+        _ => None,
+    }
+}
+
+fn parse_access_kind(name: &str) -> Option<AccessKind> {
+    let (kind, sub) = split_kind(name)?;
+    let mode = |sub| {
+        parse_sub(sub, AccessMode::Any, |sub| match sub {
+            "Execute" => Some(AccessMode::Execute),
+            "Read" => Some(AccessMode::Read),
+            "Write" => Some(AccessMode::Write),
+            "Other" => Some(AccessMode::Other),
+            _ => None,
+        })
+    };
+    match (kind, sub) {
+        ("Read", None) => Some(AccessKind::Read),
+        ("Other", None) => Some(AccessKind::Other),
+        ("Open", sub) => mode(sub).map(AccessKind::Open),
+        ("Close", sub) => mode(sub).map(AccessKind::Close),
+        _ => None,
+    }
+}
+
+/// Classifies the rename-to events as `Create`, for `treat_rename_as_create`.
+///
+/// inotify delivers `From`, `To` and then `Both` for one rename, and Windows `From` and `To`.
+/// Only `To` (or `Both` without `To`) becomes `Create`, so a rename fires once.
+#[derive(Debug, Default)]
+pub struct RenameAsCreate {
+    from: Option<PathBuf>,
+    to: Option<PathBuf>,
+}
+
+impl RenameAsCreate {
+    /// The event to handle and the renamed from path, or None to drop the event.
+    pub fn classify(&mut self, event: Event) -> Option<(Event, Option<PathBuf>)> {
+        let create =
+            |path: &PathBuf| Event::new(EventKind::Create(CreateKind::Any)).add_path(path.clone());
+        let EventKind::Modify(ModifyKind::Name(mode)) = event.kind else {
+            return Some((event, None));
+        };
+        match (mode, event.paths.as_slice()) {
+            (RenameMode::From, [from]) => {
+                self.from = Some(from.clone());
+                Some((event, None))
+            }
+            (RenameMode::To, [to]) => {
+                self.to = Some(to.clone());
+                Some((create(to), self.from.take()))
+            }
+            (RenameMode::Both, [from, to]) => {
+                if self.to.take().as_ref() == Some(to) {
+                    return None;
+                }
+                Some((create(to), Some(from.clone())))
+            }
+            // A rename without the side, e.g. FSEvents. The existing path is the new name.
+            (_, [path]) if path.exists() => Some((create(path), None)),
+            _ => Some((event, None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use notify::{
+        event::{
+            AccessKind, AccessMode, CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind,
+            RenameMode,
+        },
+        Event, EventKind,
+    };
+
+    use super::{
+        event_kind_to_detail, event_kind_to_string, parse_event_kind, string_to_event_kind,
+        RenameAsCreate,
+    };
+
+    /// Every kind of notify.
+    fn all_event_kinds() -> Vec<EventKind> {
+        let access_modes = [
+            AccessMode::Any,
+            AccessMode::Execute,
+            AccessMode::Read,
+            AccessMode::Write,
+            AccessMode::Other,
+        ];
+        let mut kinds = vec![EventKind::Any, EventKind::Other];
+        for kind in [
+            CreateKind::Any,
+            CreateKind::File,
+            CreateKind::Folder,
+            CreateKind::Other,
+        ] {
+            kinds.push(EventKind::Create(kind));
+        }
+        for kind in [
+            RemoveKind::Any,
+            RemoveKind::File,
+            RemoveKind::Folder,
+            RemoveKind::Other,
+        ] {
+            kinds.push(EventKind::Remove(kind));
+        }
+        kinds.extend(
+            [AccessKind::Any, AccessKind::Read, AccessKind::Other]
+                .into_iter()
+                .chain(access_modes.into_iter().map(AccessKind::Open))
+                .chain(access_modes.into_iter().map(AccessKind::Close))
+                .map(EventKind::Access),
+        );
+        kinds.extend(
+            [ModifyKind::Any, ModifyKind::Other]
+                .into_iter()
+                .chain(
+                    [
+                        DataChange::Any,
+                        DataChange::Size,
+                        DataChange::Content,
+                        DataChange::Other,
+                    ]
+                    .map(ModifyKind::Data),
+                )
+                .chain(
+                    [
+                        MetadataKind::Any,
+                        MetadataKind::AccessTime,
+                        MetadataKind::WriteTime,
+                        MetadataKind::Permissions,
+                        MetadataKind::Ownership,
+                        MetadataKind::Extended,
+                        MetadataKind::Other,
+                    ]
+                    .map(ModifyKind::Metadata),
+                )
+                .chain(
+                    [
+                        RenameMode::Any,
+                        RenameMode::To,
+                        RenameMode::From,
+                        RenameMode::Both,
+                        RenameMode::Other,
+                    ]
+                    .map(ModifyKind::Name),
+                )
+                .map(EventKind::Modify),
+        );
+        kinds
+    }
+
+    #[test]
+    fn test_event_kind_round_trip() {
+        let kinds = all_event_kinds();
+        assert_eq!(kinds.len(), 41);
+        for kind in kinds {
+            let detail = event_kind_to_detail(kind);
+            assert_eq!(string_to_event_kind(&detail), kind, "{}", detail);
+            assert_eq!(parse_event_kind(&detail), Some(kind), "{}", detail);
+
+            // The coarse name is the same after a round trip.
+            let name = event_kind_to_string(kind);
+            assert_eq!(event_kind_to_string(string_to_event_kind(&name)), name);
+        }
+        assert_eq!(
+            event_kind_to_detail(EventKind::Modify(ModifyKind::Name(RenameMode::To))),
+            "Modify(Name(To))"
+        );
+        assert_eq!(
+            string_to_event_kind("Access(Close(Write))"),
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+        );
+        assert_eq!(
+            string_to_event_kind("Create"),
+            EventKind::Create(CreateKind::Any)
+        );
+
+        for unknown in [
+            "Unknown",
+            "Modify(Name(Sideways))",
+            "Create(File",
+            "Any(File)",
+        ] {
+            assert_eq!(parse_event_kind(unknown), None, "{}", unknown);
+            assert_eq!(
+                string_to_event_kind(unknown),
+                EventKind::Modify(ModifyKind::Any)
+            );
+        }
+    }
+
+    #[test]
+    fn test_rename_as_create() {
+        let rename = |mode| EventKind::Modify(ModifyKind::Name(mode));
+        let (tmp, csv) = (PathBuf::from("file.tmp"), PathBuf::from("file.csv"));
+        let create = EventKind::Create(CreateKind::Any);
+
+        // inotify: From, To and Both.
+        let mut renames = RenameAsCreate::default();
+        let events = [
+            Event::new(rename(RenameMode::From)).add_path(tmp.clone()),
+            Event::new(rename(RenameMode::To)).add_path(csv.clone()),
+            Event::new(rename(RenameMode::Both))
+                .add_path(tmp.clone())
+                .add_path(csv.clone()),
+        ]
+        .into_iter()
+        .filter_map(|e| renames.classify(e))
+        .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0.kind, rename(RenameMode::From));
+        assert_eq!(events[1].0.kind, create);
+        assert_eq!(events[1].0.paths, std::slice::from_ref(&csv));
+        assert_eq!(events[1].1, Some(tmp.clone()));
+
+        // Both only.
+        let mut renames = RenameAsCreate::default();
+        let both = Event::new(rename(RenameMode::Both))
+            .add_path(tmp.clone())
+            .add_path(csv.clone());
+        let (event, from) = renames.classify(both).unwrap();
+        assert_eq!(event.kind, create);
+        assert_eq!(from, Some(tmp));
+
+        // Other events are kept.
+        let modify = Event::new(EventKind::Modify(ModifyKind::Any)).add_path(csv);
+        let (event, from) = renames.classify(modify.clone()).unwrap();
+        assert_eq!(event, modify);
+        assert_eq!(from, None);
+    }
 }
@@ -0,0 +1,357 @@
+// =============================================================================
+// File        : metrics.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 16:32:08.
+// =============================================================================
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use tiny_http::{Header, Response, Server};
+use tracing::{debug, error, info};
+
+use crate::command::{CommandResult, SkipReason};
+
+/// How often the `progress_file` of a running command is read.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A counter of the spy metrics.
+type Counter = fn(&SpyMetrics) -> &AtomicU64;
+
+/// The metrics of all spys by name.
+static METRICS: LazyLock<Mutex<BTreeMap<String, Arc<SpyMetrics>>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// The counters of a spy, updated by the watcher and the command tasks.
+#[derive(Debug, Default)]
+pub struct SpyMetrics {
+    events: AtomicU64,
+    executions: AtomicU64,
+    failures: AtomicU64,
+    skip_debounce: AtomicU64,
+    skip_throttle: AtomicU64,
+    skip_condition: AtomicU64,
+    skip_guard: AtomicU64,
+    skip_cooldown: AtomicU64,
+    skip_loop_guard: AtomicU64,
+    in_flight: AtomicI64,
+    /// The last line of the `progress_file` by exec_id, while the command runs.
+    progress: Mutex<BTreeMap<String, String>>,
+}
+
+/// The counters of a spy at a point, replied to `Message::DumpStats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpyStats {
+    pub events: u64,
+    pub executions: u64,
+    pub failures: u64,
+    pub skip_debounce: u64,
+    pub skip_throttle: u64,
+    pub skip_condition: u64,
+    pub skip_guard: u64,
+    pub skip_cooldown: u64,
+    pub skip_loop_guard: u64,
+    pub in_flight: i64,
+    /// Paused by `Message::Pause`.
+    pub paused: bool,
+    /// The events waiting to be handled, e.g. buffered while paused.
+    pub pending: usize,
+    /// The events dropped while paused.
+    pub dropped: u64,
+    /// The most watched events queued at once, with `queue_size`.
+    pub queue_peak: usize,
+    /// The watched events dropped while the queue was full, by `queue_policy = "drop"`.
+    pub queue_dropped: u64,
+    /// The last line of the `progress_file` of each running command, by exec_id.
+    pub progress: BTreeMap<String, String>,
+}
+
+/// Reads the `progress_file` of a command until dropped, then forgets its progress.
+#[derive(Debug)]
+pub struct Progress {
+    metrics: Arc<SpyMetrics>,
+    exec_id: String,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.metrics.progress.lock().unwrap().remove(&self.exec_id);
+    }
+}
+
+/// The last line of `path` if it's written after `since`.
+fn last_line(path: &Path, since: SystemTime) -> Option<String> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    if modified < since {
+        return None;
+    }
+    fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| line.trim().to_string())
+}
+
+/// Counts a running command until dropped.
+#[derive(Debug)]
+pub struct InFlight(Arc<SpyMetrics>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl SpyMetrics {
+    pub fn event(&self) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn start(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+
+    /// The counters now. The watcher fills its own state, e.g. `paused`.
+    pub fn snapshot(&self) -> SpyStats {
+        SpyStats {
+            events: self.events.load(Ordering::Relaxed),
+            executions: self.executions.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            skip_debounce: self.skip_debounce.load(Ordering::Relaxed),
+            skip_throttle: self.skip_throttle.load(Ordering::Relaxed),
+            skip_condition: self.skip_condition.load(Ordering::Relaxed),
+            skip_guard: self.skip_guard.load(Ordering::Relaxed),
+            skip_cooldown: self.skip_cooldown.load(Ordering::Relaxed),
+            skip_loop_guard: self.skip_loop_guard.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            progress: self.progress.lock().unwrap().clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads `path` written by the command `exec_id` periodically, and keeps its last line as the progress.
+    /// A file older than now is ignored, e.g. left by the previous run.
+    pub fn track_progress(self: &Arc<Self>, exec_id: &str, path: PathBuf) -> Progress {
+        let stop = Arc::new(AtomicBool::new(false));
+        let since = SystemTime::now();
+        let (metrics, id, stopped) = (self.clone(), exec_id.to_string(), stop.clone());
+        thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                if let Some(line) = last_line(&path, since) {
+                    let mut progress = metrics.progress.lock().unwrap();
+                    // Not to resurrect the progress forgotten by the drop.
+                    if !stopped.load(Ordering::Relaxed) {
+                        progress.insert(id.clone(), line);
+                    }
+                }
+                thread::sleep(PROGRESS_INTERVAL);
+            }
+        });
+        Progress {
+            metrics: self.clone(),
+            exec_id: exec_id.to_string(),
+            stop,
+        }
+    }
+
+    pub fn record(&self, status: &Result<CommandResult>) {
+        let counter = match status {
+            Ok(result) => match result.skip_reason() {
+                Some(SkipReason::Debounce) => &self.skip_debounce,
+                Some(SkipReason::Throttle) => &self.skip_throttle,
+                Some(SkipReason::Condition) => &self.skip_condition,
+                Some(SkipReason::Guard) => &self.skip_guard,
+                Some(SkipReason::Cooldown) => &self.skip_cooldown,
+                Some(SkipReason::LoopGuard) => &self.skip_loop_guard,
+                // Skipped before the dispatch, not recorded.
+                Some(
+                    SkipReason::Quarantine
+                    | SkipReason::LatestOnly
+                    | SkipReason::Owner
+                    | SkipReason::MaxAge,
+                ) => return,
+                None => {
+                    self.executions.fetch_add(1, Ordering::Relaxed);
+                    if result.success() {
+                        return;
+                    }
+                    &self.failures
+                }
+            },
+            Err(_) => &self.failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The metrics of the spy `name`.
+pub fn spy(name: &str) -> Arc<SpyMetrics> {
+    METRICS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .clone()
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn label(spy: &str) -> String {
+    spy.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut text = String::new();
+    let counters: [(&str, &str, Counter); 3] = [
+        ("spyrun_events_total", "Events received.", |m| &m.events),
+        ("spyrun_executions_total", "Commands executed.", |m| {
+            &m.executions
+        }),
+        (
+            "spyrun_failures_total",
+            "Commands failed or failed to start.",
+            |m| &m.failures,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        header(&mut text, name, "counter", help);
+        for (spy, m) in metrics.iter() {
+            let value = counter(m).load(Ordering::Relaxed);
+            writeln!(text, "{}{{spy=\"{}\"}} {}", name, label(spy), value).unwrap();
+        }
+    }
+
+    header(
+        &mut text,
+        "spyrun_skips_total",
+        "counter",
+        "Commands skipped by reason.",
+    );
+    for (spy, m) in metrics.iter() {
+        for (reason, count) in [
+            ("debounce", &m.skip_debounce),
+            ("throttle", &m.skip_throttle),
+            ("condition", &m.skip_condition),
+            ("guard", &m.skip_guard),
+            ("cooldown", &m.skip_cooldown),
+            ("loop_guard", &m.skip_loop_guard),
+        ] {
+            writeln!(
+                text,
+                "spyrun_skips_total{{spy=\"{}\",reason=\"{}\"}} {}",
+                label(spy),
+                reason,
+                count.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+    }
+
+    header(
+        &mut text,
+        "spyrun_in_flight",
+        "gauge",
+        "Commands running now.",
+    );
+    for (spy, m) in metrics.iter() {
+        let value = m.in_flight.load(Ordering::Relaxed);
+        writeln!(text, "spyrun_in_flight{{spy=\"{}\"}} {}", label(spy), value).unwrap();
+    }
+    text
+}
+
+/// Serves `/metrics` on `addr` in a background thread. Returns the bound address.
+pub fn serve(addr: &str) -> Result<SocketAddr> {
+    let server =
+        Server::http(addr).map_err(|e| anyhow!("Failed to listen metrics: {}, e: {}", addr, e))?;
+    let bound = server
+        .server_addr()
+        .to_ip()
+        .ok_or_else(|| anyhow!("Failed to get metrics address: {}", addr))?;
+    info!("metrics listening: http://{}/metrics", bound);
+    let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+    thread::Builder::new()
+        .name("spyrun-metrics".to_string())
+        .spawn(move || {
+            for request in server.incoming_requests() {
+                debug!("metrics request: {}", request.url());
+                let response = if request.url() == "/metrics" {
+                    Response::from_string(render()).with_header(content_type.clone())
+                } else {
+                    Response::from_string("not found").with_status_code(404)
+                };
+                if let Err(e) = request.respond(response) {
+                    error!("Failed to respond metrics: {:?}", e);
+                }
+            }
+        })?;
+    Ok(bound)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use anyhow::{anyhow, Result};
+
+    use super::{serve, spy};
+    use crate::command::{CommandResult, SkipReason};
+
+    #[test]
+    fn test_metrics_endpoint() -> Result<()> {
+        let metrics = spy("test_metrics");
+        (0..3).for_each(|_| metrics.event());
+        metrics.record(&Ok(CommandResult::skip(SkipReason::Throttle)));
+        metrics.record(&Ok(CommandResult::skip(SkipReason::Throttle)));
+        metrics.record(&Err(anyhow!("render error")));
+        let in_flight = metrics.start();
+
+        let addr = serve("127.0.0.1:0")?;
+        let scrape = |path: &str| -> Result<String> {
+            let mut stream = TcpStream::connect(addr)?;
+            write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        let response = scrape("/metrics")?;
+        assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+        assert!(response.contains("# TYPE spyrun_events_total counter"));
+        assert!(response.contains("spyrun_events_total{spy=\"test_metrics\"} 3"));
+        assert!(response.contains("spyrun_executions_total{spy=\"test_metrics\"} 0"));
+        assert!(response.contains("spyrun_failures_total{spy=\"test_metrics\"} 1"));
+        assert!(response.contains("spyrun_skips_total{spy=\"test_metrics\",reason=\"throttle\"} 2"));
+        assert!(response.contains("spyrun_skips_total{spy=\"test_metrics\",reason=\"guard\"} 0"));
+        assert!(response.contains("# TYPE spyrun_in_flight gauge"));
+        assert!(response.contains("spyrun_in_flight{spy=\"test_metrics\"} 1"));
+
+        drop(in_flight);
+        assert!(scrape("/metrics")?.contains("spyrun_in_flight{spy=\"test_metrics\"} 0"));
+        assert!(scrape("/other")?.starts_with("HTTP/1.0 404"));
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+// =============================================================================
+// File        : mover.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 14:12:40.
+// =============================================================================
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use log_derive::logfn;
+use tera::Context;
+use tracing::{info, warn};
+
+use crate::util::{insert_file_context, new_tera, render};
+
+/// `path`, or with a counter appended to the stem (`_2`, `_3`, ...) if it's taken.
+pub fn available_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (2..)
+        .map(|i| path.with_file_name(format!("{}_{}{}", stem, i, ext)))
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+/// The path to move `src` to. `dest` is a directory if it exists as one or ends with a separator.
+fn destination(src: &Path, dest: &str) -> PathBuf {
+    let is_dir = dest.ends_with('/') || dest.ends_with('\\') || Path::new(dest).is_dir();
+    let dest = if is_dir {
+        Path::new(dest).join(src.file_name().unwrap_or_default())
+    } else {
+        PathBuf::from(dest)
+    };
+    available_path(&dest)
+}
+
+/// Renames `src` to `dest`, or copies and removes it if rename fails (e.g. across devices).
+fn rename_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if let Err(e) = fs::rename(src, dest) {
+        warn!(
+            "Failed to rename: {} -> {}, e: {:?}. Copy and remove instead",
+            src.display(),
+            dest.display(),
+            e
+        );
+        fs::copy(src, dest)?;
+        fs::remove_file(src)?;
+    }
+    Ok(())
+}
+
+/// Moves the event file `src` to `template` rendered with `context`, creating the destination dir.
+///
+/// Returns the moved path, or None if `src` no longer exists.
+#[tracing::instrument(skip(context))]
+#[logfn(Debug)]
+pub fn move_file(src: &Path, template: &str, context: &Context) -> Result<Option<PathBuf>> {
+    if !src.is_file() {
+        info!("Skip move, the file no longer exists: {}", src.display());
+        return Ok(None);
+    }
+    let mut context = context.clone();
+    insert_file_context(src, "event", &mut context)?;
+    let dest = new_tera("move", template)
+        .and_then(|tera| render(&tera, "move", &context))
+        .with_context(|| format!("Failed to render move destination: {}", template))?;
+    let dest = destination(src, &dest);
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    rename_or_copy(src, &dest)?;
+    info!("Moved: {} -> {}", src.display(), dest.display());
+    Ok(Some(dest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, read_to_string, remove_dir_all, write},
+    };
+
+    use anyhow::Result;
+    use tera::Context;
+
+    use super::move_file;
+
+    #[test]
+    fn test_move_file() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_move_file");
+        remove_dir_all(&tmp).unwrap_or_default();
+        create_dir_all(&tmp)?;
+        let context = Context::new();
+
+        // Into a directory.
+        let src = tmp.join("ok.txt");
+        write(&src, "ok")?;
+        let moved = move_file(&src, "{{ event_dir }}/done/", &context)?.unwrap();
+        assert_eq!(moved, tmp.join("done").join("ok.txt"));
+        assert!(!src.exists());
+
+        // To a full path.
+        let src = tmp.join("ng.txt");
+        write(&src, "ng")?;
+        let moved = move_file(
+            &src,
+            "{{ event_dir }}/failed/{{ event_stem }}.bad",
+            &context,
+        )?;
+        assert_eq!(moved.unwrap(), tmp.join("failed").join("ng.bad"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_file_collision() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_move_file_collision");
+        remove_dir_all(&tmp).unwrap_or_default();
+        create_dir_all(tmp.join("done"))?;
+        let context = Context::new();
+
+        for i in 0..3 {
+            let src = tmp.join("data.csv");
+            write(&src, i.to_string())?;
+            move_file(&src, "{{ event_dir }}/done", &context)?;
+        }
+        let done = tmp.join("done");
+        assert_eq!(read_to_string(done.join("data.csv"))?, "0");
+        assert_eq!(read_to_string(done.join("data_2.csv"))?, "1");
+        assert_eq!(read_to_string(done.join("data_3.csv"))?, "2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_file_missing_source() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_move_file_missing");
+        let moved = move_file(
+            &tmp.join("missing.txt"),
+            "{{ event_dir }}/done/",
+            &Context::new(),
+        )?;
+        assert!(moved.is_none());
+        assert!(!tmp.join("done").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+// =============================================================================
+// File        : pool.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 16:05:31.
+// =============================================================================
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::Result;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use tracing::{error, info};
+
+thread_local! {
+    /// The spy of the task running on this rayon thread.
+    static CURRENT_SPY: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The panicked tasks per spy.
+static PANICS: LazyLock<Mutex<HashMap<String, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Marks the task running on this thread as the spy `name`, for the panic handler.
+pub fn set_current_spy(name: &str) {
+    CURRENT_SPY.with(|spy| *spy.borrow_mut() = Some(name.to_string()));
+}
+
+/// The count of the panicked tasks of the spy `name`.
+pub fn panics(name: &str) -> usize {
+    PANICS
+        .lock()
+        .unwrap()
+        .get(name)
+        .copied()
+        .unwrap_or_default()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Logs a panicked task instead of aborting the process.
+fn panic_handler(payload: Box<dyn Any + Send>) {
+    let spy = CURRENT_SPY
+        .with(|spy| spy.borrow().clone())
+        .unwrap_or_default();
+    let thread = std::thread::current();
+    error!(
+        "[{}] task panicked on {}: {}",
+        &spy,
+        thread.name().unwrap_or_default(),
+        panic_message(payload.as_ref())
+    );
+    *PANICS.lock().unwrap().entry(spy).or_insert(0) += 1;
+}
+
+/// The rayon pool builder with the thread names and the panic handler.
+pub fn builder(num_threads: Option<usize>) -> ThreadPoolBuilder {
+    ThreadPoolBuilder::new()
+        .num_threads(num_threads.unwrap_or_default())
+        .thread_name(|i| format!("spyrun-exec-{}", i))
+        .panic_handler(panic_handler)
+}
+
+/// Builds the pool to execute the commands. `num_threads` is `cfg.max_threads`, or the CPU count if None.
+///
+/// Owned by each `run` instead of the global pool, so `run` can be called again in the same process.
+pub fn build(num_threads: Option<usize>) -> Result<ThreadPool> {
+    let pool = builder(num_threads).build()?;
+    info!("rayon threads: {}", pool.current_num_threads());
+    Ok(pool)
+}
+
+/// Builds the dedicated pool of the spy `name` by its `max_threads`.
+pub fn build_spy(name: &str, num_threads: usize) -> Result<ThreadPool> {
+    let prefix = format!("spyrun-{}-exec", name);
+    let pool = builder(Some(num_threads))
+        .thread_name(move |i| format!("{}-{}", prefix, i))
+        .build()?;
+    info!("[{}] rayon threads: {}", name, pool.current_num_threads());
+    Ok(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, read_to_string, File},
+        sync::{mpsc, Mutex},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use tracing_subscriber::{fmt::Layer, prelude::*, Registry};
+
+    use super::{build, build_spy, builder, panics, set_current_spy};
+
+    #[test]
+    fn test_panic_handler() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_panic_handler");
+        create_dir_all(&tmp)?;
+        let log_path = tmp.join("test.log");
+        let layer = Layer::default()
+            .with_writer(Mutex::new(File::create(&log_path)?))
+            .with_ansi(false);
+        let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+        let pool = builder(Some(1))
+            .start_handler(move |_| {
+                // Keep the dispatcher for the lifetime of the pool thread.
+                std::mem::forget(tracing::dispatcher::set_default(&dispatch));
+            })
+            .build()?;
+
+        pool.spawn(|| {
+            set_current_spy("test_panic_handler");
+            panic!("controlled panic");
+        });
+        // The pool survives the panic and runs the next task.
+        let (tx, rx) = mpsc::channel();
+        pool.spawn(move || {
+            tx.send(std::thread::current().name().map(String::from))
+                .unwrap();
+        });
+        let name = rx.recv_timeout(Duration::from_secs(10))?;
+        assert_eq!(name.as_deref(), Some("spyrun-exec-0"));
+        assert_eq!(panics("test_panic_handler"), 1);
+
+        let log = read_to_string(&log_path)?;
+        assert!(
+            log.contains("[test_panic_handler] task panicked on spyrun-exec-0: controlled panic"),
+            "{}",
+            log
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_twice() -> Result<()> {
+        // Each run builds its own pool, so both coexist.
+        let first = build(Some(2))?;
+        let second = build(Some(3))?;
+        assert_eq!(first.current_num_threads(), 2);
+        assert_eq!(second.current_num_threads(), 3);
+        let name = second.install(|| std::thread::current().name().map(String::from));
+        assert!(name.unwrap().starts_with("spyrun-exec-"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_spy() -> Result<()> {
+        let pool = build_spy("heavy", 2)?;
+        assert_eq!(pool.current_num_threads(), 2);
+        let name = pool.install(|| std::thread::current().name().map(String::from));
+        assert!(name.unwrap().starts_with("spyrun-heavy-exec-"));
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+// =============================================================================
+// File        : quarantine.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 14:40:18.
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crypto_hash::{hex_digest, Algorithm};
+use tracing::{info, warn};
+
+/// How long the failures of a path are kept after its last failure by default.
+pub const DEFAULT_QUARANTINE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The size and the modified time, the same content unless they changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stat {
+    len: u64,
+    modified: SystemTime,
+}
+
+impl Stat {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Fingerprint {
+    stat: Option<Stat>,
+    hash: String,
+}
+
+impl Fingerprint {
+    /// Whether `stat` is of the same content, without reading the file.
+    fn same_stat(&self, stat: &Option<Stat>) -> bool {
+        stat.is_some() && &self.stat == stat
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    fingerprint: Fingerprint,
+    count: u32,
+    /// The last failure. The entry expires `ttl` after it.
+    failed: Instant,
+}
+
+/// Counts the failed executions per event path, and quarantines the paths failing `max_failures`
+/// times in a row. The count is reset when the content of the file changes, or `ttl` after the last failure.
+///
+/// The content is compared by the size and the modified time, and hashed only if they changed, outside the lock.
+#[derive(Debug)]
+pub struct Quarantine {
+    max_failures: u32,
+    ttl: Duration,
+    failures: Mutex<HashMap<PathBuf, Failures>>,
+}
+
+fn content_hash(path: &Path) -> String {
+    fs::read(path)
+        .map(|content| hex_digest(Algorithm::SHA256, &content))
+        .unwrap_or_default()
+}
+
+impl Quarantine {
+    pub fn new(max_failures: u32, ttl: Duration) -> Self {
+        Self {
+            max_failures,
+            ttl,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `path` is quarantined. A quarantined path is released if its content changed.
+    pub fn is_quarantined(&self, path: &Path) -> bool {
+        let stat = Stat::of(path);
+        let hash = {
+            let mut failures = self.failures.lock().unwrap();
+            match failures.get(path) {
+                Some(f) if f.failed.elapsed() >= self.ttl => {
+                    failures.remove(path);
+                    return false;
+                }
+                Some(f) if f.count >= self.max_failures => {
+                    if f.fingerprint.same_stat(&stat) {
+                        return true;
+                    }
+                    f.fingerprint.hash.clone()
+                }
+                _ => return false,
+            }
+        };
+        // e.g. touched by a cleanup, still the same content.
+        let same = content_hash(path) == hash;
+        let mut failures = self.failures.lock().unwrap();
+        if same {
+            if let Some(f) = failures.get_mut(path) {
+                f.fingerprint.stat = stat;
+            }
+            return true;
+        }
+        info!(
+            "[quarantine] released, the content changed: {}",
+            path.display()
+        );
+        failures.remove(path);
+        false
+    }
+
+    /// Records the result of an execution for `path`. Returns true if `path` is newly quarantined.
+    pub fn record(&self, path: &Path, success: bool) -> bool {
+        if success {
+            self.failures.lock().unwrap().remove(path);
+            return false;
+        }
+        let stat = Stat::of(path);
+        let last = self
+            .failures
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|f| f.fingerprint.clone());
+        let fingerprint = match last {
+            Some(last) if last.same_stat(&stat) => last,
+            _ => Fingerprint {
+                stat,
+                hash: content_hash(path),
+            },
+        };
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        // The paths not failing any more, e.g. moved or removed.
+        failures.retain(|_, f| now.duration_since(f.failed) < self.ttl);
+        let f = failures
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Failures {
+                fingerprint: fingerprint.clone(),
+                count: 0,
+                failed: now,
+            });
+        if f.fingerprint.hash != fingerprint.hash {
+            f.count = 0;
+        }
+        f.fingerprint = fingerprint;
+        f.failed = now;
+        f.count += 1;
+        if f.count == self.max_failures {
+            warn!(
+                "[quarantine] failed {} times, stop executing for: {}",
+                f.count,
+                path.display()
+            );
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, write, File},
+        process::Command,
+        thread::sleep,
+        time::{Duration, SystemTime},
+    };
+
+    use anyhow::Result;
+
+    use super::{Quarantine, DEFAULT_QUARANTINE_TTL};
+
+    #[test]
+    fn test_quarantine() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_quarantine");
+        create_dir_all(&tmp)?;
+        let path = tmp.join("malformed.csv");
+        write(&path, "malformed")?;
+        #[cfg(windows)]
+        let (cmd, arg) = ("cmd", ["/c", "exit 1"]);
+        #[cfg(not(windows))]
+        let (cmd, arg) = ("/bin/sh", ["-c", "exit 1"]);
+
+        let quarantine = Quarantine::new(3, DEFAULT_QUARANTINE_TTL);
+        let mut executed = 0;
+        let mut quarantined = 0;
+        for _ in 0..10 {
+            if quarantine.is_quarantined(&path) {
+                continue;
+            }
+            let status = Command::new(cmd).args(arg).status()?;
+            executed += 1;
+            if quarantine.record(&path, status.success()) {
+                quarantined += 1;
+            }
+        }
+        assert_eq!(executed, 3);
+        assert_eq!(quarantined, 1);
+
+        // Touched by a cleanup, the same content is still quarantined.
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+        assert!(quarantine.is_quarantined(&path));
+
+        // A fixed file is executed again.
+        write(&path, "fixed")?;
+        assert!(!quarantine.is_quarantined(&path));
+        assert!(!quarantine.record(&path, true));
+        assert!(!quarantine.is_quarantined(&path));
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_ttl() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_quarantine_ttl");
+        create_dir_all(&tmp)?;
+        let path = tmp.join("malformed.csv");
+        write(&path, "malformed")?;
+
+        let quarantine = Quarantine::new(2, Duration::from_millis(200));
+        assert!(!quarantine.record(&path, false));
+        assert!(quarantine.record(&path, false));
+        assert!(quarantine.is_quarantined(&path));
+
+        sleep(Duration::from_millis(300));
+        assert!(!quarantine.is_quarantined(&path));
+        // Counted from zero again.
+        assert!(!quarantine.record(&path, false));
+
+        // The failures of a removed path expire with the next failure of another.
+        let other = tmp.join("other.csv");
+        write(&other, "other")?;
+        sleep(Duration::from_millis(300));
+        quarantine.record(&other, false);
+        assert_eq!(quarantine.failures.lock().unwrap().len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+// =============================================================================
+// File        : queue.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 21:05:31.
+// =============================================================================
+
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    mpsc, Arc, Condvar, Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use tracing::warn;
+
+use crate::{
+    message::{Message, SpyEvent},
+    settings::QueuePolicy,
+};
+
+/// Bounds the watched events of a spy by `queue_size`, from the event source until their command finishes.
+///
+/// The walked, scanned and injected events are not counted, they are not from the event source.
+#[derive(Debug)]
+pub struct Queue {
+    name: String,
+    size: usize,
+    policy: QueuePolicy,
+    /// The queued events, and whether the watcher is gone.
+    state: Mutex<(usize, bool)>,
+    freed: Condvar,
+    peak: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl Queue {
+    pub fn new(name: &str, size: usize, policy: QueuePolicy) -> Self {
+        Self {
+            name: name.to_string(),
+            size: size.max(1),
+            policy,
+            state: Mutex::new((0, false)),
+            freed: Condvar::new(),
+            peak: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether an event may be queued. While full, blocks until freed by `block`, or drops it by `drop`.
+    pub fn admit(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.0 >= self.size && !state.1 {
+            if self.policy == QueuePolicy::Drop {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // Not to flood the log while full.
+                if dropped % 1000 == 1 {
+                    warn!(
+                        "[{}] queue is full ({}), drop the event. dropped: {}",
+                        &self.name, self.size, dropped
+                    );
+                }
+                return false;
+            }
+            state = self.freed.wait(state).unwrap();
+        }
+        if state.1 {
+            return false;
+        }
+        state.0 += 1;
+        self.peak.fetch_max(state.0, Ordering::Relaxed);
+        true
+    }
+
+    /// Frees an admitted event, when it is dropped or handled.
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = state.0.saturating_sub(1);
+        self.freed.notify_one();
+    }
+
+    /// Frees an admitted event when the slot is dropped, e.g. after its command.
+    pub fn slot(self: &Arc<Self>) -> Slot {
+        Slot(self.clone())
+    }
+
+    /// Stops admitting, the blocked event source is released.
+    pub fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.freed.notify_all();
+    }
+
+    /// The most events queued at once.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// An admitted event, freed when dropped.
+#[derive(Debug)]
+pub struct Slot(Arc<Queue>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Where an event source sends the events, through the queue with `queue_size`.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    tx: mpsc::Sender<Message>,
+    queue: Option<Arc<Queue>>,
+}
+
+impl EventSender {
+    pub(crate) fn new(tx: mpsc::Sender<Message>, queue: Option<Arc<Queue>>) -> Self {
+        Self { tx, queue }
+    }
+
+    /// Sends `event` to the watcher. Returns Ok also when it is dropped by the queue, and Err if the watcher is gone.
+    pub fn send(&self, event: SpyEvent) -> Result<()> {
+        if let Some(queue) = self.queue.as_ref() {
+            if !queue.admit() {
+                return Ok(());
+            }
+        }
+        self.tx.send(Message::Event(event.0)).map_err(|e| {
+            if let Some(queue) = self.queue.as_ref() {
+                queue.release();
+            }
+            anyhow!("Failed to send the event: {}", e)
+        })
+    }
+}
+
+impl From<mpsc::Sender<Message>> for EventSender {
+    fn from(tx: mpsc::Sender<Message>) -> Self {
+        Self::new(tx, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::Queue;
+    use crate::settings::QueuePolicy;
+
+    #[test]
+    fn test_queue() {
+        let queue = Queue::new("drop", 2, QueuePolicy::Drop);
+        assert!(queue.admit());
+        assert!(queue.admit());
+        assert!(!queue.admit());
+        queue.release();
+        assert!(queue.admit());
+        assert_eq!((queue.peak(), queue.dropped()), (2, 1));
+
+        let queue = Arc::new(Queue::new("block", 1, QueuePolicy::Block));
+        assert!(queue.admit());
+        let slot = queue.slot();
+        let blocked = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.admit())
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!blocked.is_finished());
+        drop(slot);
+        assert!(blocked.join().unwrap());
+        assert_eq!((queue.peak(), queue.dropped()), (1, 0));
+
+        // Closed while blocked.
+        let blocked = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.admit())
+        };
+        thread::sleep(Duration::from_millis(100));
+        queue.close();
+        assert!(!blocked.join().unwrap());
+    }
+}
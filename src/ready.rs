@@ -0,0 +1,110 @@
+// =============================================================================
+// File        : ready.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 15:20:42.
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::settings::Spy;
+
+/// Set when a spy is watching and its walk is completed.
+#[derive(Debug, Default)]
+pub struct Ready {
+    ready: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Ready {
+    pub fn set(&self) {
+        *self.ready.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+
+    /// Waits until set, at most `timeout`. Returns false if it timed out.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let ready = self.ready.lock().unwrap();
+        let (ready, _) = self
+            .cond
+            .wait_timeout_while(ready, timeout, |ready| !*ready)
+            .unwrap();
+        *ready
+    }
+}
+
+/// The ready signal of a spy, and the signals of the spys in its `after` to wait for.
+#[derive(Debug, Clone)]
+pub struct Startup {
+    pub ready: Arc<Ready>,
+    after: Vec<(String, Arc<Ready>)>,
+    timeout: Duration,
+    /// Where to report whether the spy started watching, see `register`.
+    pub registered: Option<mpsc::Sender<(String, Result<()>)>>,
+}
+
+impl Startup {
+    /// The startups of `spys` by name. The spys in `after` not in `spys` (disabled) are not waited.
+    pub fn new(spys: &[Spy], timeout: Duration) -> HashMap<String, Startup> {
+        let readies = spys
+            .iter()
+            .map(|spy| (spy.name.clone(), Arc::new(Ready::default())))
+            .collect::<HashMap<_, _>>();
+        spys.iter()
+            .map(|spy| {
+                let after = spy
+                    .after
+                    .iter()
+                    .flatten()
+                    .filter_map(|name| match readies.get(name) {
+                        Some(ready) => Some((name.clone(), ready.clone())),
+                        None => {
+                            warn!(
+                                "[{}] after [{}] is not running, skip waiting",
+                                &spy.name, name
+                            );
+                            None
+                        }
+                    })
+                    .collect();
+                let startup = Startup {
+                    ready: readies[&spy.name].clone(),
+                    after,
+                    timeout,
+                    registered: None,
+                };
+                (spy.name.clone(), startup)
+            })
+            .collect()
+    }
+
+    /// Reports whether the spy `name` started watching. Only the first report is sent.
+    pub fn register(&mut self, name: &str, result: Result<()>) {
+        if let Some(registered) = self.registered.take() {
+            registered
+                .send((name.to_string(), result))
+                .unwrap_or_default();
+        }
+    }
+
+    /// Waits for the spys in `after` to be ready. Starts anyway after the timeout with a warning.
+    pub fn wait(&self, name: &str) {
+        for (after, ready) in &self.after {
+            info!("[{}] waiting for [{}] to be ready", name, after);
+            if ready.wait(self.timeout) {
+                info!("[{}] [{}] is ready", name, after);
+            } else {
+                warn!(
+                    "[{}] timed out waiting for [{}] after {:?}, start anyway",
+                    name, after, self.timeout
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// =============================================================================
+// File        : service.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 11:02:18.
+// =============================================================================
+
+#[cfg(windows)]
+use std::{ffi::OsString, sync::mpsc, time::Duration};
+
+#[cfg(not(windows))]
+use anyhow::bail;
+use anyhow::Result;
+use log_derive::logfn;
+#[cfg(windows)]
+use tracing::error;
+#[cfg(windows)]
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+};
+
+#[cfg(windows)]
+use crate::message::Control;
+
+#[cfg(windows)]
+const SERVICE_NAME: &str = "spyrun";
+
+#[cfg(windows)]
+define_windows_service!(ffi_service_main, service_main);
+
+#[cfg(windows)]
+#[logfn(Debug)]
+pub fn run() -> Result<()> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+    Ok(())
+}
+
+#[cfg(not(windows))]
+#[logfn(Debug)]
+pub fn run() -> Result<()> {
+    bail!("--service is only supported on Windows");
+}
+
+#[cfg(windows)]
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        error!("service error: {:?}", e);
+    }
+}
+
+#[cfg(windows)]
+fn run_service() -> Result<()> {
+    let (tx_stop, rx_stop) = mpsc::channel();
+    let tx_stop_clone = tx_stop.clone();
+    let status_handle = service_control_handler::register(SERVICE_NAME, move |control| {
+        handle_control(control, &tx_stop_clone)
+    })?;
+
+    status_handle.set_service_status(service_status(ServiceState::Running, 0))?;
+    let result = crate::run_cli(clap::Parser::parse(), tx_stop, rx_stop);
+    let exit_code = if result.is_ok() { 0 } else { 1 };
+    status_handle.set_service_status(service_status(ServiceState::Stopped, exit_code))?;
+    result
+}
+
+/// Maps the service control requests to the stop channel.
+#[cfg(windows)]
+fn handle_control(
+    control: ServiceControl,
+    tx_stop: &mpsc::Sender<Control>,
+) -> ServiceControlHandlerResult {
+    match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Err(e) = tx_stop.send(Control::Stop) {
+                error!("service stop error: {:?}", e);
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    }
+}
+
+#[cfg(windows)]
+fn service_status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+    let controls_accepted = if state == ServiceState::Running {
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+    } else {
+        ServiceControlAccept::empty()
+    };
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use std::sync::mpsc;
+
+    use windows_service::{
+        service::ServiceControl, service_control_handler::ServiceControlHandlerResult,
+    };
+
+    use super::handle_control;
+    use crate::message::Control;
+
+    #[test]
+    fn test_handle_control() {
+        let (tx_stop, rx_stop) = mpsc::channel();
+
+        assert!(matches!(
+            handle_control(ServiceControl::Interrogate, &tx_stop),
+            ServiceControlHandlerResult::NoError
+        ));
+        assert!(rx_stop.try_recv().is_err());
+
+        assert!(matches!(
+            handle_control(ServiceControl::Stop, &tx_stop),
+            ServiceControlHandlerResult::NoError
+        ));
+        assert_eq!(rx_stop.try_recv().unwrap(), Control::Stop);
+
+        assert!(matches!(
+            handle_control(ServiceControl::Pause, &tx_stop),
+            ServiceControlHandlerResult::NotImplemented
+        ));
+        assert!(rx_stop.try_recv().is_err());
+    }
+}
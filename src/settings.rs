@@ -5,105 +5,720 @@
 // =============================================================================
 
 use std::{
-    fs,
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    mem::{discriminant, Discriminant},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Result};
 use log_derive::logfn;
-use notify::RecursiveMode;
-use serde::{Deserialize, Deserializer};
+use notify::{EventKind, RecursiveMode};
+use regex::Regex;
+use serde::{
+    de::{MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use tera::Context;
-use tracing::error;
+use tracing::{debug, error, info, warn};
 
-use crate::util::{insert_default_context, insert_file_context, new_tera, render_vars};
+use crate::{
+    message::string_to_event_kind,
+    source::EventSource,
+    util::{
+        hostname, insert_const_placeholders, insert_consts, insert_context_table,
+        insert_default_context, insert_file_context, insert_toml_context, new_tera, render,
+        render_vars, render_vars_table, take_consts, RenderDeadline, DEFAULT_RENDER_TIMEOUT,
+    },
+    Vars,
+};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Poll {
+    #[serde(deserialize_with = "deserialize_millis")]
     pub interval: u64,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// What to do at startup when `input` does not exist.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InputPolicy {
+    Error,
+    Create,
+    Wait,
+    #[default]
+    Ignore,
+}
+
+/// What to do at startup when a spy fails to start watching.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupMode {
+    /// Stop all spys and exit with the error.
+    Strict,
+    /// Log the error and run the other spys.
+    #[default]
+    Lenient,
+}
+
+/// When an init command runs.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InitPhase {
+    /// Before the watchers start, e.g. to create the input directories.
+    #[default]
+    Pre,
+    /// After all the watchers are ready. A failure is only logged.
+    Post,
+}
+
+/// The base directory of the relative `stop_flg`, `stop_force_flg` and spy `input` / `output`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PathsRelativeTo {
+    /// The current directory at launch.
+    #[default]
+    Cwd,
+    /// The directory of the config file.
+    Config,
+}
+
+/// The preset `limitkey` of a spy: the scope in which `debounce` and `throttle` coalesce the events.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitScope {
+    /// All events of the spy.
+    Spy,
+    /// The events in the same directory.
+    Dir,
+    /// The events of the same path.
+    Path,
+}
+
+impl LimitScope {
+    /// The `limitkey` template.
+    pub fn template(&self) -> &'static str {
+        match self {
+            LimitScope::Spy => "{{ spy_name }}",
+            LimitScope::Dir => "{{ spy_name }}:{{ event_dir }}",
+            LimitScope::Path => "{{ spy_name }}:{{ event_path }}",
+        }
+    }
+}
+
+/// The format of `--export-config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Toml,
+    Json,
+}
+
+/// What to do with the events received while paused by `Control::Pause`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PausePolicy {
+    /// Handle them after `Message::Resume`.
+    #[default]
+    Buffer,
+    Drop,
+}
+
+/// What to do with the watched events while the queue of `queue_size` is full.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuePolicy {
+    /// Wait in the event source until the queue is freed.
+    #[default]
+    Block,
+    /// Drop them with a warning.
+    Drop,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchBackend {
+    #[default]
+    Recommended,
+    Poll,
+    Inotify,
+    Fsevent,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub enum OutputMode {
+    /// `{name}_stdout_{now}_{exec_id}.log` per execution.
+    #[default]
+    PerExec,
+    /// `{name}_{limitkey hash}_stdout.log` appended per limitkey.
+    PerKey,
+    /// `{name}_stdout_{YYYYMMDD}.log` appended per day.
+    PerDay,
+}
+
+/// How the command is run, mainly for the `.sh` handlers on Windows.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShellKind {
+    /// The command as is.
+    #[default]
+    Native,
+    /// `wsl.exe -- <cmd>`, with the Windows paths translated to `/mnt/c/...`.
+    Wsl,
+    /// The command as is, with the Windows paths translated to `/c/...` for Git Bash.
+    GitBash,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopAction {
+    Stop,
+    StopForce,
+    Reload,
+}
+
+impl StopAction {
+    /// The name in `stop_flags` and the logs.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopAction::Stop => "stop",
+            StopAction::StopForce => "stop_force",
+            StopAction::Reload => "reload",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StopFlag {
+    pub path: String,
+    pub action: StopAction,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LoopGuard {
+    pub max: usize,
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub window: u64,
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub cooldown: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Walk {
     pub min_depth: Option<usize>,
     pub max_depth: Option<usize>,
     pub follow_symlinks: Option<bool>,
     pub pattern: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_delay",
+        serialize_with = "serialize_delay"
+    )]
     pub delay: Option<(u64, Option<u64>)>,
+    #[serde(default)]
+    pub on_error: WalkOnError,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WalkOnError {
+    #[default]
+    Skip,
+    Log,
+    Fail,
+}
+
+impl Walk {
+    pub fn min_depth(self, min_depth: usize) -> Self {
+        Self {
+            min_depth: Some(min_depth),
+            ..self
+        }
+    }
+
+    pub fn max_depth(self, max_depth: usize) -> Self {
+        Self {
+            max_depth: Some(max_depth),
+            ..self
+        }
+    }
+
+    pub fn follow_symlinks(self, follow_symlinks: bool) -> Self {
+        Self {
+            follow_symlinks: Some(follow_symlinks),
+            ..self
+        }
+    }
+
+    pub fn pattern(self, pattern: &str) -> Self {
+        Self {
+            pattern: Some(pattern.to_string()),
+            ..self
+        }
+    }
+
+    /// Sleeps `min`, or random between `min` and `max`, before each walk event.
+    pub fn delay(self, min: Duration, max: Option<Duration>) -> Self {
+        Self {
+            delay: Some(delay_millis(min, max)),
+            ..self
+        }
+    }
+
+    pub fn on_error(self, on_error: WalkOnError) -> Self {
+        Self { on_error, ..self }
+    }
+
+    pub fn max_age(self, max_age: Duration) -> Self {
+        Self {
+            max_age_secs: Some(max_age.as_secs()),
+            ..self
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Init {
     pub cmd: String,
     pub arg: Vec<String>,
     #[serde(default)]
     pub error_stop: bool,
+    pub retries: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub retry_delay: Option<u64>,
+    #[serde(default)]
+    pub phase: InitPhase,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Spy {
     pub name: String,
+    pub enabled: Option<bool>,
     #[serde(default, deserialize_with = "is_valid_event_kind")]
     pub events: Option<Vec<String>>,
     pub input: Option<String>,
+    pub input_policy: Option<InputPolicy>,
     pub output: Option<String>,
     #[serde(
         default = "default_recursive",
-        deserialize_with = "deserialize_recursive_mode"
+        deserialize_with = "deserialize_recursive_mode",
+        serialize_with = "serialize_recursive_mode"
     )]
     pub recursive: RecursiveMode,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
     pub throttle: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
     pub debounce: Option<u64>,
     pub limitkey: Option<String>,
+    pub limit_scope: Option<LimitScope>,
     pub patterns: Option<Vec<Pattern>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_delay",
+        serialize_with = "serialize_delay"
+    )]
     pub delay: Option<(u64, Option<u64>)>,
     pub poll: Option<Poll>,
+    pub watch_backend: Option<WatchBackend>,
+    pub poll_fallback: Option<bool>,
     pub walk: Option<Walk>,
+    pub scan_existing: Option<bool>,
+    pub treat_rename_as_create: Option<bool>,
+    pub priority: Option<i64>,
+    pub expand_env_in_args: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub slow_threshold: Option<u64>,
+    pub output_mode: Option<OutputMode>,
+    pub export_env: Option<bool>,
+    pub output_status_prefix: Option<bool>,
+    pub merge_stderr: Option<bool>,
+    pub nice: Option<i32>,
+    pub shell_kind: Option<ShellKind>,
+    pub max_threads: Option<usize>,
+    pub max_failures: Option<u32>,
+    pub quarantine_move: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub quarantine_ttl: Option<u64>,
+    pub skip_marker: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub skip_marker_ttl: Option<u64>,
+    pub failure_cooldown_secs: Option<u64>,
+    pub pause_policy: Option<PausePolicy>,
+    pub queue_size: Option<usize>,
+    pub queue_policy: Option<QueuePolicy>,
+    pub base: Option<String>,
+    pub after: Option<Vec<String>>,
+    /// Replaces the watcher, e.g. by `TestSource`. Only set in code.
+    #[serde(skip)]
+    pub source: Option<Arc<dyn EventSource>>,
+    /// Compiled by `Settings::rebuild` and `Spy::builder`. No event matches a spy without it.
+    #[serde(skip)]
+    pub(crate) matcher: Option<Arc<Matcher>>,
+    /// `cfg.render_timeout` in milliseconds, set by `rebuild`.
+    #[serde(skip)]
+    pub render_timeout: Option<u64>,
+}
+
+/// The `events` and `patterns` of a spy, parsed and compiled once.
+#[derive(Debug)]
+pub struct Matcher {
+    /// `events` has `Any`, which matches every kind.
+    any: bool,
+    kinds: HashSet<Discriminant<EventKind>>,
+    patterns: Vec<PathMatcher>,
+}
+
+/// How a pattern matches an event path.
+#[derive(Debug)]
+enum PathMatcher {
+    Regex(Regex),
+    /// The lowercased `extensions`, compared to the lowercased `extension()` of the path.
+    Extensions(Vec<String>),
+}
+
+impl PathMatcher {
+    fn new(pattern: &Pattern) -> Result<Self> {
+        match &pattern.extensions {
+            Some(extensions) => Ok(PathMatcher::Extensions(
+                extensions.iter().map(|e| extension_key(e)).collect(),
+            )),
+            None => Ok(PathMatcher::Regex(pattern.regex()?)),
+        }
+    }
+
+    fn is_match(&self, path: &str) -> bool {
+        match self {
+            PathMatcher::Regex(re) => re.is_match(path),
+            PathMatcher::Extensions(extensions) => Path::new(path)
+                .extension()
+                .is_some_and(|ext| extensions.contains(&extension_key(&ext.to_string_lossy()))),
+        }
+    }
+}
+
+/// An extension compared case-insensitively, `.csv` is the same as `csv`.
+fn extension_key(extension: &str) -> String {
+    extension.trim_start_matches('.').to_lowercase()
+}
+
+impl Matcher {
+    pub fn new(spy: &Spy) -> Result<Self> {
+        let any = spy.events.iter().flatten().any(|e| e == "Any");
+        let kinds = spy
+            .events
+            .iter()
+            .flatten()
+            .map(|e| discriminant(&string_to_event_kind(e)))
+            .collect();
+        let patterns = spy
+            .patterns
+            .iter()
+            .flatten()
+            .map(PathMatcher::new)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            any,
+            kinds,
+            patterns,
+        })
+    }
+
+    /// Whether the spy subscribes to events of `kind`.
+    pub fn subscribes(&self, kind: &EventKind) -> bool {
+        self.any || self.kinds.contains(&discriminant(kind))
+    }
+
+    /// The index of the first pattern matching `path`.
+    pub fn find(&self, path: &str) -> Option<usize> {
+        self.patterns.iter().position(|m| m.is_match(path))
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Daily,
+    Hourly,
+    Minutely,
+    Never,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Log {
     pub path: String,
     #[serde(default = "default_loglevel")]
     pub level: String,
     #[serde(default)]
     pub switch: bool,
+    #[serde(default)]
+    pub rotation: Rotation,
+    #[serde(default)]
+    pub compress: bool,
+    pub error_path: Option<String>,
+    pub error_level: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub dedup_window: Option<u64>,
+    #[serde(default)]
+    pub quiet: bool,
+    pub time_format: Option<String>,
+    #[serde(default)]
+    pub show_thread: bool,
+    #[serde(default = "default_true")]
+    pub show_target: bool,
+    #[serde(default = "default_true")]
+    pub stdout: bool,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Cfg {
     pub stop_flg: String,
     pub stop_force_flg: Option<String>,
     pub max_threads: Option<usize>,
+    #[serde(default)]
+    pub exclusive_events: bool,
+    pub state_file: Option<String>,
+    pub max_output_dir_bytes: Option<u64>,
+    pub loop_guard: Option<LoopGuard>,
+    #[serde(default = "default_true")]
+    pub single_instance: bool,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub after_timeout: Option<u64>,
+    pub metrics_addr: Option<String>,
+    pub inject_dir: Option<String>,
+    #[serde(default)]
+    pub default_spy_runs: bool,
+    #[serde(default)]
+    pub startup: StartupMode,
+    #[serde(default)]
+    pub paths_relative_to: PathsRelativeTo,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub render_timeout: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Pattern {
+    pub name: Option<String>,
+    #[serde(default)]
     pub pattern: String,
+    pub extensions: Option<Vec<String>>,
+    pub pattern_type: Option<PatternType>,
+    #[serde(default)]
     pub cmd: String,
+    #[serde(default)]
     pub arg: Vec<String>,
+    #[serde(default)]
+    pub when: HashMap<String, When>,
+    pub expand_env_in_args: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub slow_threshold: Option<u64>,
+    pub output_mode: Option<OutputMode>,
+    pub on_success_move: Option<String>,
+    pub on_error_move: Option<String>,
+    /// Executed after the command succeeded, and after `on_success_move`.
+    pub on_success: Option<When>,
+    /// Executed after the command failed, and after `on_error_move`.
+    pub on_error: Option<When>,
+    pub condition: Option<String>,
+    pub script: Option<String>,
+    pub script_ext: Option<String>,
+    pub keep_script: Option<bool>,
+    pub merge_stderr: Option<bool>,
+    pub guard: Option<Guard>,
+    pub nice: Option<i32>,
+    pub shell_kind: Option<ShellKind>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub throttle: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_opt_millis")]
+    pub debounce: Option<u64>,
+    pub latest_only: Option<bool>,
+    pub failure_cooldown_secs: Option<u64>,
+    pub progress_file: Option<String>,
+    pub skip_marker: Option<String>,
+}
+
+/// Checked before executing a pattern. A Tera expression, or a command whose zero exit means proceed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Guard {
+    Expr(String),
+    Cmd {
+        cmd: String,
+        #[serde(default)]
+        arg: Vec<String>,
+    },
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// How `pattern` is written.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PatternType {
+    #[default]
+    Regex,
+    /// `*`, `**`, `?`, `[...]` and `{a,b}`, matching the end of the path.
+    Glob,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct When {
+    pub cmd: String,
+    #[serde(default)]
+    pub arg: Vec<String>,
+}
+
+impl Pattern {
+    /// `pattern` compiled by `pattern_type`, or the regex of `extensions`, e.g. `(?i)\.(csv|txt)$`.
+    pub fn regex(&self) -> Result<Regex> {
+        if let Some(extensions) = &self.extensions {
+            let alternatives = extensions
+                .iter()
+                .map(|e| regex::escape(e.trim_start_matches('.')))
+                .collect::<Vec<_>>();
+            return Ok(Regex::new(&format!(
+                r"(?i)\.({})$",
+                alternatives.join("|")
+            ))?);
+        }
+        let regex = match self.pattern_type.unwrap_or_default() {
+            PatternType::Regex => Regex::new(&self.pattern)?,
+            PatternType::Glob => Regex::new(&glob_to_regex(&self.pattern)?)?,
+        };
+        Ok(regex)
+    }
+
+    /// Either `pattern` or `extensions`, and either `cmd` or `script` must be set.
+    pub fn validate(&self) -> Result<()> {
+        match (self.pattern.is_empty(), &self.extensions) {
+            (false, Some(_)) => {
+                return Err(anyhow!(
+                    "pattern {}: `pattern` and `extensions` are mutually exclusive",
+                    &self.pattern
+                ))
+            }
+            (true, None) => return Err(anyhow!("`pattern` or `extensions` is required")),
+            (_, Some(extensions)) if extensions.is_empty() => {
+                return Err(anyhow!("`extensions` is empty"))
+            }
+            _ => {}
+        }
+        match (self.cmd.is_empty(), &self.script) {
+            (false, Some(_)) => Err(anyhow!(
+                "pattern {}: `cmd` and `script` are mutually exclusive",
+                &self.pattern
+            )),
+            (true, None) => Err(anyhow!(
+                "pattern {}: `cmd` or `script` is required",
+                &self.pattern
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The move destination template after the command succeeded or not.
+    pub fn move_to(&self, success: bool) -> Option<&str> {
+        match success {
+            true => self.on_success_move.as_deref(),
+            false => self.on_error_move.as_deref(),
+        }
+    }
+
+    /// The hook after the command succeeded or not, by its name in the logs.
+    pub fn hook(&self, success: bool) -> Option<(&'static str, &When)> {
+        match success {
+            true => self.on_success.as_ref().map(|hook| ("on_success", hook)),
+            false => self.on_error.as_ref().map(|hook| ("on_error", hook)),
+        }
+    }
+
+    /// The `name`, or `index` if not set.
+    pub fn name(&self, index: usize) -> String {
+        self.name.clone().unwrap_or_else(|| index.to_string())
+    }
+
+    /// The cmd and arg for `event_kind`, falling back to the top-level ones.
+    pub fn command(&self, event_kind: &str) -> (&str, &[String]) {
+        match self.when.get(event_kind) {
+            Some(when) => (&when.cmd, &when.arg),
+            None => (&self.cmd, &self.arg),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Settings {
     pub log: Log,
     pub cfg: Cfg,
-    pub init: Option<Init>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_inits",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub init: Vec<Init>,
     pub spys: Vec<Spy>,
+    #[serde(default)]
+    pub stop_flags: Vec<StopFlag>,
 }
 
 impl Settings {
+    /// Loads the config `cfg` like the cli without the backup, with `vars` added to its templates.
+    pub fn load<P: AsRef<Path>>(cfg: P, vars: &Vars) -> Result<Self> {
+        let mut context = crate::build_cmd_map()?;
+        vars.iter()
+            .for_each(|(key, value)| context.insert(key, value));
+        Settings::new(cfg, false, &mut context)
+    }
+
     #[logfn(Debug)]
-    pub fn new<P: AsRef<Path>>(cfg: P, backup: bool, context: &mut Context) -> Result<Self> {
+    pub(crate) fn new<P: AsRef<Path>>(cfg: P, backup: bool, context: &mut Context) -> Result<Self> {
         insert_file_context(&cfg, "cfg", context)?;
         insert_default_context(context);
+        if !context.contains_key("hostname") {
+            context.insert("hostname", &hostname());
+        }
+        let hostname = context
+            .get("hostname")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
 
         let toml_str = std::fs::read_to_string(&cfg)?;
+        let (toml_str, consts) = take_consts(&toml_str)?;
+        // Once for the whole rendering of the config itself.
+        let _deadline = RenderDeadline::new(
+            raw_render_timeout(&toml_str)?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_RENDER_TIMEOUT),
+        );
         let tera = new_tera(&cfg.as_ref().to_string_lossy(), &toml_str)?;
+        insert_const_placeholders(context, &consts);
         render_vars(context, &toml_str)?;
-        let toml_str = tera.render(&cfg.as_ref().to_string_lossy(), context)?;
-        match toml::from_str(&toml_str) {
-            Ok(s) => {
+        insert_toml_context(context, &toml_str)?;
+        for (_, host) in host_sections(&toml_str, &hostname)? {
+            if let Some(vars) = host.get("vars") {
+                render_vars_table(context, vars)?;
+            }
+            if let Some(table) = host.get("context") {
+                insert_context_table(context, table)?;
+            }
+        }
+        let toml_str = render(&tera, &cfg.as_ref().to_string_lossy(), context)?;
+        insert_consts(context, &consts);
+        let value = match toml::from_str::<toml::Value>(&toml_str) {
+            Ok(v) => merge_hosts(v, host_sections(&toml_str, &hostname)?)?,
+            Err(e) => return Err(anyhow!("Failed to parse settings.toml. {:?}", e)),
+        };
+        match value.try_into::<Settings>() {
+            Ok(mut s) => {
+                if s.cfg.paths_relative_to == PathsRelativeTo::Config {
+                    let cfg_path = std::path::absolute(&cfg)?;
+                    s.resolve_paths(cfg_path.parent().unwrap_or(Path::new("")));
+                }
                 if backup {
                     Settings::backup(&cfg)?;
                 }
@@ -113,46 +728,124 @@ impl Settings {
         }
     }
 
+    /// Joins the relative `stop_flg`, `stop_force_flg` and spy `input` / `output` to `base`.
+    /// The paths starting with a template, e.g. `{{ input }}/log`, are rendered later and kept.
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut String| {
+            if Path::new(path.as_str()).is_relative() && !path.starts_with("{{") {
+                *path = base.join(path.as_str()).to_string_lossy().to_string();
+            }
+        };
+        resolve(&mut self.cfg.stop_flg);
+        self.cfg.stop_force_flg.iter_mut().for_each(&resolve);
+        for spy in self.spys.iter_mut() {
+            spy.input
+                .iter_mut()
+                .chain(spy.output.iter_mut())
+                .for_each(&resolve);
+        }
+    }
+
+    /// The settings as `format`, with the inherited values of the spys filled in by `rebuild`.
+    /// The `default` spy is annotated, as it is the template of the others.
+    pub fn export(&self, format: ExportFormat) -> Result<String> {
+        let note = match self.cfg.default_spy_runs {
+            true => "The template of the other spys, also watched.",
+            false => "The template of the other spys, not watched unless enabled = true.",
+        };
+        match format {
+            ExportFormat::Toml => Ok(toml::to_string(self)?.replacen(
+                "[[spys]]\nname = \"default\"",
+                &format!("# {}\n[[spys]]\nname = \"default\"", note),
+                1,
+            )),
+            ExportFormat::Json => {
+                let mut value = serde_json::to_value(self)?;
+                if let Some(spy) = value["spys"]
+                    .as_array_mut()
+                    .and_then(|spys| spys.iter_mut().find(|spy| spy["name"] == "default"))
+                {
+                    spy["_comment"] = note.into();
+                }
+                Ok(serde_json::to_string_pretty(&value)?)
+            }
+        }
+    }
+
+    /// The settings of `spys` without a config file, for embedding. The stop flag and the log are in `dir`.
+    pub fn with_spys<P: AsRef<Path>>(dir: P, spys: Vec<Spy>) -> Result<Self> {
+        let path =
+            |name: &str| toml::Value::String(dir.as_ref().join(name).to_string_lossy().to_string());
+        let table = |key: &str, value| toml::Table::from_iter([(key.to_string(), value)]);
+        let value = toml::Value::Table(toml::Table::from_iter([
+            (
+                "cfg".to_string(),
+                table("stop_flg", path("stop.flg")).into(),
+            ),
+            ("log".to_string(), table("path", path("spyrun.log")).into()),
+            ("spys".to_string(), toml::Value::Array(vec![])),
+        ]));
+        // The defaults of the other fields are the same as the config file.
+        let settings: Settings = value.try_into()?;
+        Ok(Settings { spys, ..settings })
+    }
+
     #[tracing::instrument]
     #[logfn(Debug)]
-    pub fn rebuild(&self) -> Settings {
-        let default_spy = Spy::default();
-        let default_spy = self
-            .spys
-            .iter()
-            .find(|spy| spy.name == "default")
-            .unwrap_or(&default_spy);
-
+    pub fn rebuild(&self) -> Result<Settings> {
         let spys = self
             .spys
             .iter()
             .map(|spy| {
-                if spy.name == "default" {
-                    spy.clone()
-                } else {
-                    Spy {
-                        name: spy.name.clone(),
-                        events: spy.events.clone().or(default_spy.events.clone()),
-                        input: spy.input.clone().or(default_spy.input.clone()),
-                        output: spy.output.clone().or(default_spy.output.clone()),
-                        recursive: spy.recursive,
-                        throttle: spy.throttle.or(default_spy.throttle),
-                        debounce: spy.debounce.or(default_spy.debounce),
-                        limitkey: spy.limitkey.clone().or(default_spy.limitkey.clone()),
-                        patterns: spy.patterns.clone().or(default_spy.patterns.clone()),
-                        delay: spy.delay.or(default_spy.delay),
-                        poll: spy.poll.clone().or(default_spy.poll.clone()),
-                        walk: spy.walk.clone().or(default_spy.walk.clone()),
+                let mut spy = self.resolve_spy(&spy.name, &mut vec![])?;
+                if spy.name == "default" && spy.enabled.is_none() {
+                    spy.enabled = Some(self.cfg.default_spy_runs);
+                    if !self.cfg.default_spy_runs {
+                        info!("[default] used as a template only, not watched");
+                        if spy.input.is_some() {
+                            warn!("[default] has input but is no longer watched unless enabled = true or cfg.default_spy_runs = true");
+                        }
                     }
                 }
+                spy.validate()?;
+                spy.matcher = Some(Arc::new(Matcher::new(&spy)?));
+                spy.render_timeout = self.cfg.render_timeout;
+                Ok(spy)
             })
-            .collect();
+            .collect::<Result<Vec<_>>>()?;
+        startup_order(&spys)?;
 
-        Settings {
+        Ok(Settings {
             log: self.log.clone(),
             cfg: self.cfg.clone(),
             init: self.init.clone(),
             spys,
+            stop_flags: self.stop_flags.clone(),
+        })
+    }
+
+    /// Resolves the spy `name` with its `base` chain. `default` is the implicit root base.
+    #[tracing::instrument]
+    #[logfn(Trace)]
+    fn resolve_spy(&self, name: &str, chain: &mut Vec<String>) -> Result<Spy> {
+        if chain.iter().any(|n| n == name) {
+            return Err(anyhow!(
+                "Cyclic spy base: {} -> {}",
+                chain.join(" -> "),
+                name
+            ));
+        }
+        let spy = match self.spys.iter().find(|spy| spy.name == name) {
+            Some(spy) => spy.clone(),
+            None if name == "default" => Spy::default(),
+            None => return Err(anyhow!("Base spy not found: {}", name)),
+        };
+        chain.push(name.to_string());
+
+        match &spy.base {
+            Some(base) => Ok(inherit(&spy, &self.resolve_spy(base, chain)?)),
+            None if name == "default" => Ok(spy),
+            None => Ok(inherit(&spy, &self.resolve_spy("default", chain)?)),
         }
     }
 
@@ -183,13 +876,16 @@ impl Default for Spy {
     fn default() -> Self {
         Self {
             name: "default".to_string(),
+            enabled: Some(true),
             events: Some(vec!["Create".to_string(), "Modify".to_string()]),
             input: Some("input".to_string()),
+            input_policy: Some(InputPolicy::Ignore),
             output: Some("output".to_string()),
             recursive: RecursiveMode::Recursive,
-            throttle: Some(0),
-            debounce: Some(50),
+            throttle: None,
+            debounce: None,
             limitkey: Some("".to_string()),
+            limit_scope: None,
             patterns: Some(vec![
                 Pattern {
                     pattern: "\\.ps1$".to_string(),
@@ -204,16 +900,17 @@ impl Default for Spy {
                     .iter()
                     .map(|s| s.to_string())
                     .collect(),
+                    ..Default::default()
                 },
                 Pattern {
                     pattern: "\\.cmd$".to_string(),
                     cmd: "{{event_path}}".to_string(),
-                    arg: vec![],
+                    ..Default::default()
                 },
                 Pattern {
                     pattern: "\\.bat$".to_string(),
                     cmd: "{{event_path}}".to_string(),
-                    arg: vec![],
+                    ..Default::default()
                 },
                 Pattern {
                     pattern: "\\.sh$".to_string(),
@@ -222,31 +919,466 @@ impl Default for Spy {
                         .iter()
                         .map(|s| s.to_string())
                         .collect(),
+                    ..Default::default()
                 },
             ]),
             delay: None,
             poll: None,
+            watch_backend: Some(WatchBackend::Recommended),
+            poll_fallback: Some(false),
             walk: None,
+            scan_existing: Some(false),
+            treat_rename_as_create: Some(false),
+            priority: Some(0),
+            expand_env_in_args: Some(false),
+            slow_threshold: None,
+            output_mode: Some(OutputMode::PerExec),
+            export_env: Some(true),
+            output_status_prefix: Some(false),
+            merge_stderr: Some(false),
+            nice: None,
+            shell_kind: None,
+            max_threads: None,
+            max_failures: None,
+            quarantine_move: None,
+            quarantine_ttl: None,
+            skip_marker: None,
+            skip_marker_ttl: None,
+            failure_cooldown_secs: None,
+            pause_policy: Some(PausePolicy::Buffer),
+            queue_size: None,
+            queue_policy: Some(QueuePolicy::Block),
+            base: None,
+            after: None,
+            source: None,
+            matcher: None,
+            render_timeout: None,
         }
     }
 }
 
+impl Spy {
+    /// Builds a spy in code instead of the toml. Unset settings are the `default` spy's.
+    pub fn builder(name: &str) -> SpyBuilder {
+        SpyBuilder {
+            spy: Spy::new(name.to_string()),
+            events: vec![],
+            patterns: vec![],
+        }
+    }
+
+    /// Checks the patterns and the delays, as `Settings::rebuild` does for each spy.
+    pub fn validate(&self) -> Result<()> {
+        self.patterns
+            .iter()
+            .flatten()
+            .try_for_each(Pattern::validate)
+            .and(validate_delay(self.delay))
+            .and(validate_delay(self.walk.as_ref().and_then(|w| w.delay)))
+            .and(validate_events(self.events.iter().flatten()))
+            .map_err(|e| anyhow!("[{}] {}", &self.name, e))
+    }
+}
+
+/// The typed setters of a `Spy`, see `Spy::builder`.
+#[derive(Debug)]
+pub struct SpyBuilder {
+    spy: Spy,
+    events: Vec<String>,
+    patterns: Vec<Pattern>,
+}
+
+impl SpyBuilder {
+    pub fn input<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.spy.input = Some(path.as_ref().to_string_lossy().to_string());
+        self
+    }
+
+    pub fn input_policy(mut self, input_policy: InputPolicy) -> Self {
+        self.spy.input_policy = Some(input_policy);
+        self
+    }
+
+    pub fn output<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.spy.output = Some(path.as_ref().to_string_lossy().to_string());
+        self
+    }
+
+    /// Subscribes to events of `kind`, by the name in `events`, e.g. `Create`. Create and Modify if never called.
+    pub fn event(mut self, kind: &str) -> Self {
+        self.events.push(kind.to_string());
+        self
+    }
+
+    /// Adds a pattern executing `cmd` with `args`. The default patterns if never called.
+    pub fn pattern(self, regex: &str, cmd: &str, args: &[&str]) -> Self {
+        self.add_pattern(Pattern {
+            pattern: regex.to_string(),
+            cmd: cmd.to_string(),
+            arg: args.iter().map(|a| a.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    /// Adds a pattern with the other settings, e.g. `condition` or `script`.
+    pub fn add_pattern(mut self, pattern: Pattern) -> Self {
+        self.patterns.push(pattern);
+        self
+    }
+
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.spy.recursive = match recursive {
+            true => RecursiveMode::Recursive,
+            false => RecursiveMode::NonRecursive,
+        };
+        self
+    }
+
+    pub fn throttle(mut self, throttle: Duration) -> Self {
+        self.spy.throttle = Some(throttle.as_millis() as u64);
+        self
+    }
+
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.spy.debounce = Some(debounce.as_millis() as u64);
+        self
+    }
+
+    pub fn limitkey(mut self, limitkey: &str) -> Self {
+        self.spy.limitkey = Some(limitkey.to_string());
+        self
+    }
+
+    pub fn limit_scope(mut self, limit_scope: LimitScope) -> Self {
+        self.spy.limit_scope = Some(limit_scope);
+        self
+    }
+
+    pub fn failure_cooldown(mut self, cooldown: Duration) -> Self {
+        self.spy.failure_cooldown_secs = Some(cooldown.as_secs());
+        self
+    }
+
+    /// Sleeps `min`, or random between `min` and `max`, before sending each event.
+    pub fn delay(mut self, min: Duration, max: Option<Duration>) -> Self {
+        self.spy.delay = Some(delay_millis(min, max));
+        self
+    }
+
+    pub fn poll(mut self, interval: Duration) -> Self {
+        self.spy.poll = Some(Poll {
+            interval: interval.as_millis() as u64,
+        });
+        self
+    }
+
+    pub fn watch_backend(mut self, watch_backend: WatchBackend) -> Self {
+        self.spy.watch_backend = Some(watch_backend);
+        self
+    }
+
+    pub fn poll_fallback(mut self, poll_fallback: bool) -> Self {
+        self.spy.poll_fallback = Some(poll_fallback);
+        self
+    }
+
+    pub fn walk(mut self, walk: Walk) -> Self {
+        self.spy.walk = Some(walk);
+        self
+    }
+
+    pub fn scan_existing(mut self, scan_existing: bool) -> Self {
+        self.spy.scan_existing = Some(scan_existing);
+        self
+    }
+
+    pub fn pause_policy(mut self, pause_policy: PausePolicy) -> Self {
+        self.spy.pause_policy = Some(pause_policy);
+        self
+    }
+
+    pub fn queue(mut self, queue_size: usize, queue_policy: QueuePolicy) -> Self {
+        self.spy.queue_size = Some(queue_size);
+        self.spy.queue_policy = Some(queue_policy);
+        self
+    }
+
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.spy.enabled = Some(enabled);
+        self
+    }
+
+    pub fn priority(mut self, priority: i64) -> Self {
+        self.spy.priority = Some(priority);
+        self
+    }
+
+    /// Starts after the spy `name` is ready, may be called for each prerequisite.
+    pub fn after(mut self, name: &str) -> Self {
+        self.spy
+            .after
+            .get_or_insert_with(Vec::new)
+            .push(name.to_string());
+        self
+    }
+
+    pub fn treat_rename_as_create(mut self, treat_rename_as_create: bool) -> Self {
+        self.spy.treat_rename_as_create = Some(treat_rename_as_create);
+        self
+    }
+
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.spy.max_threads = Some(max_threads);
+        self
+    }
+
+    /// Quarantines a file after `max_failures` failures, moved to `quarantine_move` if set.
+    pub fn quarantine(mut self, max_failures: u32, quarantine_move: Option<&str>) -> Self {
+        self.spy.max_failures = Some(max_failures);
+        self.spy.quarantine_move = quarantine_move.map(String::from);
+        self
+    }
+
+    /// Forgets the failures of a file `ttl` after its last failure, releasing it if quarantined.
+    pub fn quarantine_ttl(mut self, ttl: Duration) -> Self {
+        self.spy.quarantine_ttl = Some(ttl.as_millis() as u64);
+        self
+    }
+
+    /// Writes the skipped events to the rendered `skip_marker`, see `skip_marker_ttl`.
+    pub fn skip_marker(mut self, skip_marker: &str) -> Self {
+        self.spy.skip_marker = Some(skip_marker.to_string());
+        self
+    }
+
+    pub fn skip_marker_ttl(mut self, ttl: Duration) -> Self {
+        self.spy.skip_marker_ttl = Some(ttl.as_millis() as u64);
+        self
+    }
+
+    /// Sends the events from `source` instead of watching `input`.
+    pub fn source<S: EventSource + 'static>(mut self, source: S) -> Self {
+        self.spy.source = Some(Arc::new(source));
+        self
+    }
+
+    /// The spy validated like `Settings::rebuild`, with the patterns compiled.
+    pub fn build(self) -> Result<Spy> {
+        let mut spy = self.spy;
+        if !self.events.is_empty() {
+            spy.events = Some(self.events);
+        }
+        if !self.patterns.is_empty() {
+            spy.patterns = Some(self.patterns);
+        }
+        spy.validate()?;
+        spy.matcher = Some(Arc::new(Matcher::new(&spy)?));
+        Ok(spy)
+    }
+}
+
+fn delay_millis(min: Duration, max: Option<Duration>) -> (u64, Option<u64>) {
+    (
+        min.as_millis() as u64,
+        max.map(|max| max.as_millis() as u64),
+    )
+}
+
+#[logfn(Trace)]
+fn inherit(spy: &Spy, base: &Spy) -> Spy {
+    Spy {
+        name: spy.name.clone(),
+        enabled: spy.enabled,
+        events: spy.events.clone().or(base.events.clone()),
+        input: spy.input.clone().or(base.input.clone()),
+        input_policy: spy.input_policy.or(base.input_policy),
+        output: spy.output.clone().or(base.output.clone()),
+        recursive: spy.recursive,
+        throttle: spy.throttle.or(base.throttle),
+        debounce: spy.debounce.or(base.debounce),
+        limitkey: spy.limitkey.clone().or(base.limitkey.clone()),
+        limit_scope: spy.limit_scope.or(base.limit_scope),
+        patterns: spy.patterns.clone().or(base.patterns.clone()),
+        delay: spy.delay.or(base.delay),
+        poll: spy.poll.clone().or(base.poll.clone()),
+        watch_backend: spy.watch_backend.or(base.watch_backend),
+        poll_fallback: spy.poll_fallback.or(base.poll_fallback),
+        walk: spy.walk.clone().or(base.walk.clone()),
+        scan_existing: spy.scan_existing.or(base.scan_existing),
+        treat_rename_as_create: spy.treat_rename_as_create.or(base.treat_rename_as_create),
+        priority: spy.priority.or(base.priority),
+        expand_env_in_args: spy.expand_env_in_args.or(base.expand_env_in_args),
+        slow_threshold: spy.slow_threshold.or(base.slow_threshold),
+        output_mode: spy.output_mode.or(base.output_mode),
+        export_env: spy.export_env.or(base.export_env),
+        output_status_prefix: spy.output_status_prefix.or(base.output_status_prefix),
+        merge_stderr: spy.merge_stderr.or(base.merge_stderr),
+        nice: spy.nice.or(base.nice),
+        shell_kind: spy.shell_kind.or(base.shell_kind),
+        max_threads: spy.max_threads.or(base.max_threads),
+        max_failures: spy.max_failures.or(base.max_failures),
+        quarantine_move: spy.quarantine_move.clone().or(base.quarantine_move.clone()),
+        quarantine_ttl: spy.quarantine_ttl.or(base.quarantine_ttl),
+        skip_marker: spy.skip_marker.clone().or(base.skip_marker.clone()),
+        skip_marker_ttl: spy.skip_marker_ttl.or(base.skip_marker_ttl),
+        failure_cooldown_secs: spy.failure_cooldown_secs.or(base.failure_cooldown_secs),
+        pause_policy: spy.pause_policy.or(base.pause_policy),
+        queue_size: spy.queue_size.or(base.queue_size),
+        queue_policy: spy.queue_policy.or(base.queue_policy),
+        base: spy.base.clone(),
+        after: spy.after.clone(),
+        source: spy.source.clone().or(base.source.clone()),
+        matcher: None,
+        render_timeout: None,
+    }
+}
+
+/// Orders `spys` so that each spy comes after the spys in its `after`.
+#[logfn(Trace)]
+pub fn startup_order(spys: &[Spy]) -> Result<Vec<Spy>> {
+    fn visit(
+        name: &str,
+        spys: &[Spy],
+        chain: &mut Vec<String>,
+        order: &mut Vec<Spy>,
+    ) -> Result<()> {
+        if order.iter().any(|spy| spy.name == name) {
+            return Ok(());
+        }
+        if chain.iter().any(|n| n == name) {
+            return Err(anyhow!(
+                "Cyclic spy after: {} -> {}",
+                chain.join(" -> "),
+                name
+            ));
+        }
+        let spy = spys
+            .iter()
+            .find(|spy| spy.name == name)
+            .ok_or_else(|| anyhow!("After spy not found: {}", name))?;
+        chain.push(name.to_string());
+        for after in spy.after.iter().flatten() {
+            visit(after, spys, chain, order)?;
+        }
+        chain.pop();
+        order.push(spy.clone());
+        Ok(())
+    }
+
+    let mut order = vec![];
+    for spy in spys {
+        visit(&spy.name, spys, &mut vec![], &mut order)?;
+    }
+    Ok(order)
+}
+
+#[derive(Debug, Default)]
+struct Hosts(Vec<(String, toml::Value)>);
+
+impl<'de> Deserialize<'de> for Hosts {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct HostsVisitor;
+
+        impl<'de> Visitor<'de> for HostsVisitor {
+            type Value = Hosts;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a table of hostname patterns")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Hosts, A::Error> {
+                let mut hosts = vec![];
+                while let Some(entry) = map.next_entry()? {
+                    hosts.push(entry);
+                }
+                Ok(Hosts(hosts))
+            }
+        }
+
+        d.deserialize_map(HostsVisitor)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HostsDocument {
+    #[serde(default)]
+    hosts: Hosts,
+}
+
+/// Returns the `[hosts."PATTERN"]` sections matching `hostname`, in declaration order.
+#[logfn(Trace)]
+fn host_sections(toml_str: &str, hostname: &str) -> Result<Vec<(String, toml::Value)>> {
+    let doc: HostsDocument = toml::from_str(toml_str)?;
+    let mut hosts = vec![];
+    for (pattern, host) in doc.hosts.0 {
+        if Regex::new(&pattern)?.is_match(hostname) {
+            debug!("hostname: {} matches hosts: {}", hostname, &pattern);
+            hosts.push((pattern, host));
+        }
+    }
+    Ok(hosts)
+}
+
+#[logfn(Trace)]
+fn merge_hosts(mut value: toml::Value, hosts: Vec<(String, toml::Value)>) -> Result<toml::Value> {
+    let root = value
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("Expected a table for settings"))?;
+    root.remove("hosts");
+    for (_, host) in hosts {
+        if let Some(cfg) = host.get("cfg").and_then(|c| c.as_table()) {
+            let base = root
+                .entry("cfg")
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let Some(base) = base.as_table_mut() {
+                base.extend(cfg.clone());
+            }
+        }
+        if let Some(spys) = host.get("spys").and_then(|s| s.as_array()) {
+            let base = root
+                .entry("spys")
+                .or_insert_with(|| toml::Value::Array(vec![]))
+                .as_array_mut()
+                .ok_or_else(|| anyhow!("Expected an array for 'spys'"))?;
+            for spy in spys {
+                let name = spy.get("name").and_then(|n| n.as_str());
+                match base
+                    .iter_mut()
+                    .find(|b| b.get("name").and_then(|n| n.as_str()) == name)
+                    .and_then(|b| b.as_table_mut())
+                {
+                    Some(b) => b.extend(spy.as_table().cloned().unwrap_or_default()),
+                    None => base.push(spy.clone()),
+                }
+            }
+        }
+    }
+    Ok(value)
+}
+
+/// The names of `events`.
+const EVENT_KINDS: [&str; 6] = ["Access", "Create", "Modify", "Remove", "Other", "Any"];
+
+fn validate_events<'a>(mut events: impl Iterator<Item = &'a String>) -> Result<()> {
+    match events.find(|e| !EVENT_KINDS.contains(&e.as_str())) {
+        Some(e) => Err(anyhow!(
+            "events must be Access, Create, Modify, Remove, Other or Any, not {}",
+            e
+        )),
+        None => Ok(()),
+    }
+}
+
 #[logfn(Debug)]
 fn is_valid_event_kind<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
     let opt = Option::<Vec<String>>::deserialize(d)?;
     if let Some(v) = opt {
-        let valid = v.iter().all(|s| {
-            matches!(
-                s.as_str(),
-                "Access" | "Create" | "Modify" | "Remove" | "Any"
-            )
-        });
-        if valid {
+        if v.iter().all(|s| EVENT_KINDS.contains(&s.as_str())) {
             Ok(Some(v))
         } else {
             Err(serde::de::Error::invalid_value(
                 serde::de::Unexpected::Seq,
-                &"events must be Access, Create, Modify, Remove or Any",
+                &"events must be Access, Create, Modify, Remove, Other or Any",
             ))
         }
     } else {
@@ -264,6 +1396,218 @@ fn deserialize_recursive_mode<'de, D: Deserializer<'de>>(d: D) -> Result<Recursi
     }
 }
 
+fn serialize_recursive_mode<S: Serializer>(mode: &RecursiveMode, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_bool(*mode == RecursiveMode::Recursive)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DelayValue {
+    Millis(Vec<u64>),
+    Text(String),
+}
+
+/// Parses a duration like `250ms`, `2s`, `15m`, `1h` or `1h30m` into milliseconds. No unit means milliseconds.
+fn parse_millis(s: &str) -> Result<u64> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(anyhow!("invalid duration: {:?}", s));
+    }
+    if s.chars().all(|c| c.is_ascii_digit()) {
+        return s
+            .parse::<u64>()
+            .map_err(|_| anyhow!("duration overflow: {:?}", s));
+    }
+    let mut rest = s;
+    let mut millis: u64 = 0;
+    while !rest.is_empty() {
+        let pos = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (num, tail) = rest.split_at(pos);
+        let num = num
+            .parse::<u64>()
+            .map_err(|_| anyhow!("invalid duration: {:?}", s))?;
+        let end = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(end);
+        let scale = match unit.trim() {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            _ => return Err(anyhow!("invalid duration unit: {:?}", s)),
+        };
+        millis = num
+            .checked_mul(scale)
+            .and_then(|n| millis.checked_add(n))
+            .ok_or_else(|| anyhow!("duration overflow: {:?}", s))?;
+        rest = tail.trim_start();
+    }
+    Ok(millis)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InitValue {
+    One(Init),
+    Many(Vec<Init>),
+}
+
+/// `[init]` or `[[init]]`, each with its own phase.
+fn deserialize_inits<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Init>, D::Error> {
+    Ok(match InitValue::deserialize(d)? {
+        InitValue::One(init) => vec![init],
+        InitValue::Many(inits) => inits,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MillisValue {
+    Millis(u64),
+    Text(String),
+}
+
+/// A time setting in milliseconds, or a string parsed by `parse_millis`, e.g. `throttle = "15m"`.
+fn deserialize_millis<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    use serde::de::Error;
+    match MillisValue::deserialize(d)? {
+        MillisValue::Millis(v) => Ok(v),
+        MillisValue::Text(s) => parse_millis(&s).map_err(D::Error::custom),
+    }
+}
+
+/// `deserialize_millis` of an optional setting.
+fn deserialize_opt_millis<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    use serde::de::Error;
+    match Option::<MillisValue>::deserialize(d)? {
+        None => Ok(None),
+        Some(MillisValue::Millis(v)) => Ok(Some(v)),
+        Some(MillisValue::Text(s)) => parse_millis(&s).map(Some).map_err(D::Error::custom),
+    }
+}
+
+/// `cfg.render_timeout` of the config not rendered yet.
+fn raw_render_timeout(toml_str: &str) -> Result<Option<u64>> {
+    let table: toml::Table = toml::from_str(toml_str)?;
+    match table.get("cfg").and_then(|cfg| cfg.get("render_timeout")) {
+        Some(v) => Ok(Some(deserialize_millis(v.clone())?)),
+        None => Ok(None),
+    }
+}
+
+/// Translates the glob to a regex matching the end of a path. The braces are expanded to an alternation.
+fn glob_to_regex(glob: &str) -> Result<String> {
+    let mut re = r"(?:^|[/\\])".to_string();
+    let mut braces = 0;
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                match chars.next_if(|c| *c == '/' || *c == '\\') {
+                    Some(_) => re.push_str(r"(?:.*[/\\])?"),
+                    None => re.push_str(".*"),
+                }
+            }
+            '*' => re.push_str(r"[^/\\]*"),
+            '?' => re.push_str(r"[^/\\]"),
+            '/' | '\\' => re.push_str(r"[/\\]"),
+            '[' => {
+                re.push('[');
+                if chars.next_if(|c| *c == '!' || *c == '^').is_some() {
+                    re.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c @ ('\\' | '[')) => {
+                            re.push('\\');
+                            re.push(c);
+                        }
+                        Some(c) => re.push(c),
+                        None => return Err(anyhow!("glob {}: `[` is not closed", glob)),
+                    }
+                }
+                re.push(']');
+            }
+            '{' => {
+                braces += 1;
+                re.push_str("(?:");
+            }
+            '}' if braces > 0 => {
+                braces -= 1;
+                re.push(')');
+            }
+            ',' if braces > 0 => re.push('|'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if braces > 0 {
+        return Err(anyhow!("glob {}: `{{` is not closed", glob));
+    }
+    re.push('$');
+    Ok(re)
+}
+
+/// Parses `delay` in the string form, `500ms` or a random range `500ms..2s`.
+fn parse_delay(s: &str) -> Result<(u64, Option<u64>)> {
+    match s.split_once("..") {
+        Some((min, max)) => Ok((parse_millis(min)?, Some(parse_millis(max)?))),
+        None => Ok((parse_millis(s)?, None)),
+    }
+}
+
+/// `delay` is `[min]`, `[min, max]` in milliseconds, or a string parsed by `parse_delay`.
+fn deserialize_delay<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<(u64, Option<u64>)>, D::Error> {
+    use serde::de::Error;
+    let delay = match Option::<DelayValue>::deserialize(d)? {
+        None => return Ok(None),
+        Some(DelayValue::Millis(v)) => match v[..] {
+            [min] => (min, None),
+            [min, max] => (min, Some(max)),
+            _ => return Err(D::Error::custom("delay must be [min] or [min, max]")),
+        },
+        Some(DelayValue::Text(s)) => parse_delay(&s).map_err(D::Error::custom)?,
+    };
+    Ok(Some(delay))
+}
+
+/// `delay` as `[min]` or `[min, max]`, read back by `deserialize_delay`.
+fn serialize_delay<S: Serializer>(
+    delay: &Option<(u64, Option<u64>)>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    match delay {
+        None => s.serialize_none(),
+        Some((min, None)) => s.serialize_some(&[min]),
+        Some((min, Some(max))) => s.serialize_some(&[min, max]),
+    }
+}
+
+/// The max of `delay`, to catch the values mistaken for seconds or so.
+const MAX_DELAY: u64 = 24 * 60 * 60 * 1000;
+
+fn validate_delay(delay: Option<(u64, Option<u64>)>) -> Result<()> {
+    match delay {
+        Some((min, Some(max))) if min > max => Err(anyhow!(
+            "delay min ({}ms) must not be greater than max ({}ms)",
+            min,
+            max
+        )),
+        Some((min, max)) if max.unwrap_or(min) > MAX_DELAY => Err(anyhow!(
+            "delay ({}ms) must not be greater than {}ms",
+            max.unwrap_or(min),
+            MAX_DELAY
+        )),
+        _ => Ok(()),
+    }
+}
+
 #[logfn(Debug)]
 fn default_recursive() -> RecursiveMode {
     RecursiveMode::NonRecursive
@@ -273,3 +1617,757 @@ fn default_recursive() -> RecursiveMode {
 fn default_loglevel() -> String {
     "info".to_string()
 }
+
+#[logfn(Debug)]
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, write},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use notify::RecursiveMode;
+    use tera::Context;
+
+    use super::{
+        glob_to_regex, parse_delay, startup_order, ExportFormat, Pattern, PatternType, Settings,
+        Spy, SpyBuilder,
+    };
+
+    #[test]
+    fn test_hosts() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_hosts");
+        create_dir_all(&tmp)?;
+        let cfg = tmp.join("spyrun.toml");
+        write(
+            &cfg,
+            r#"
+[vars]
+dir = 'base'
+
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'a'
+input = '{{ dir }}'
+
+[[spys]]
+name = 'b'
+input = 'b'
+
+[hosts."^web"]
+vars = { dir = 'web' }
+[[hosts."^web".spys]]
+name = 'a'
+output = 'web'
+
+[hosts."-01$"]
+cfg = { max_threads = 2 }
+[[hosts."-01$".spys]]
+name = 'a'
+output = '01'
+"#,
+        )?;
+
+        env::set_var("SPYRUN_HOSTNAME", "web-01");
+        let settings = Settings::new(&cfg, false, &mut Context::new())?;
+        assert_eq!(settings.spys[0].input.as_deref(), Some("web"));
+        assert_eq!(settings.spys[0].output.as_deref(), Some("01"));
+        assert_eq!(settings.spys[1].input.as_deref(), Some("b"));
+        assert_eq!(settings.cfg.max_threads, Some(2));
+
+        env::set_var("SPYRUN_HOSTNAME", "web-02");
+        let settings = Settings::new(&cfg, false, &mut Context::new())?;
+        assert_eq!(settings.spys[0].output.as_deref(), Some("web"));
+        assert_eq!(settings.cfg.max_threads, None);
+
+        env::set_var("SPYRUN_HOSTNAME", "db-02");
+        let settings = Settings::new(&cfg, false, &mut Context::new())?;
+        assert_eq!(settings.spys[0].input.as_deref(), Some("base"));
+        assert_eq!(settings.spys[0].output, None);
+        env::remove_var("SPYRUN_HOSTNAME");
+
+        // The actual host name, in this test not to race with SPYRUN_HOSTNAME.
+        write(
+            &cfg,
+            "spys = []\n[cfg]\nstop_flg = 'stop.flg'\n[log]\npath = 'logs/spyrun_{{ hostname() }}.log'\n",
+        )?;
+        let settings = Settings::new(&cfg, false, &mut Context::new())?;
+        let output = std::process::Command::new("hostname").output()?;
+        let hostname = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        assert!(!hostname.is_empty());
+        assert_eq!(crate::util::hostname(), hostname);
+        assert_eq!(settings.log.path, format!("logs/spyrun_{}.log", hostname));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export() -> Result<()> {
+        let settings: Settings = toml::from_str(
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'default'
+events = ['Create']
+output = 'default'
+throttle = '1s'
+delay = [100, 200]
+patterns = [{ pattern = '.*', cmd = 'echo', arg = ['{{ event_path }}'] }]
+
+[[spys]]
+name = 'child'
+input = 'input'
+recursive = true
+"#,
+        )?;
+        let settings = settings.rebuild()?;
+
+        let toml = settings.export(ExportFormat::Toml)?;
+        assert!(
+            toml.contains("# The template of the other spys, not watched unless enabled = true.\n[[spys]]\nname = \"default\""),
+            "{}",
+            toml
+        );
+        let exported: Settings = toml::from_str(&toml)?;
+        let child = &exported.spys[1];
+        assert_eq!(child.name, "child");
+        assert_eq!(child.throttle, Some(1000));
+        assert_eq!(child.delay, Some((100, Some(200))));
+        assert_eq!(child.recursive, RecursiveMode::Recursive);
+        assert_eq!(
+            child.patterns.as_ref().unwrap()[0].arg,
+            ["{{ event_path }}"]
+        );
+        assert_eq!(exported.spys[0].enabled, Some(false));
+
+        let json = settings.export(ExportFormat::Json)?;
+        let value = serde_json::from_str::<serde_json::Value>(&json)?;
+        assert_eq!(value["spys"][1]["throttle"], 1000);
+        assert!(value["spys"][0]["_comment"].is_string());
+        assert!(value["spys"][1].get("_comment").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_table() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_context_table");
+        create_dir_all(&tmp)?;
+        let cfg = tmp.join("spyrun.toml");
+        write(
+            &cfg,
+            r#"
+[context.customer]
+name = 'acme'
+dir = '/data/acme'
+
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = '{{ context.customer.name }}'
+input = '{{ context.customer.dir }}/input'
+"#,
+        )?;
+        let mut context = Context::new();
+        let settings = Settings::new(&cfg, false, &mut context)?;
+        assert_eq!(settings.spys[0].name, "acme");
+        assert_eq!(settings.spys[0].input.as_deref(), Some("/data/acme/input"));
+        // Kept for the commands.
+        assert_eq!(context.get("context").unwrap()["customer"]["name"], "acme");
+        Ok(())
+    }
+
+    #[test]
+    fn test_consts() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_consts");
+        create_dir_all(&tmp)?;
+        let cfg = tmp.join("spyrun.toml");
+        write(
+            &cfg,
+            r#"
+[consts]
+snippet = '{{ not_a_var }} {% if x %}'
+
+[vars]
+base = '/data'
+
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'jinja'
+input = '{{ base }}/input'
+patterns = [{ pattern = '.*', cmd = 'echo', arg = ['{{ snippet }}'] }]
+"#,
+        )?;
+        let mut context = Context::new();
+        let settings = Settings::new(&cfg, false, &mut context)?;
+        assert_eq!(settings.spys[0].input.as_deref(), Some("/data/input"));
+        let arg = &settings.spys[0].patterns.as_ref().unwrap()[0].arg[0];
+        assert_eq!(arg, "{{ snippet }}");
+        // Rendered per execution with the verbatim value.
+        assert_eq!(
+            tera::Tera::one_off(arg, &context, false)?,
+            "{{ not_a_var }} {% if x %}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths_relative_to_config() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_paths_relative_to_config");
+        create_dir_all(&tmp)?;
+        let cfg = tmp.join("spyrun.toml");
+        let toml = r#"
+[cfg]
+stop_flg = 'stop.flg'
+stop_force_flg = 'flags/force.flg'
+paths_relative_to = 'config'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'relative'
+input = 'input'
+output = 'output'
+
+[[spys]]
+name = 'absolute'
+input = '/data/input'
+output = '{{ input }}/log'
+"#;
+        write(&cfg, toml)?;
+        // The current directory is not the config directory.
+        assert_ne!(env::current_dir()?, tmp);
+        let settings = Settings::new(&cfg, false, &mut Context::new())?;
+        let path = |p: &str| Some(tmp.join(p).to_string_lossy().to_string());
+        assert_eq!(Some(settings.cfg.stop_flg.clone()), path("stop.flg"));
+        assert_eq!(settings.cfg.stop_force_flg, path("flags/force.flg"));
+        assert_eq!(settings.spys[0].input, path("input"));
+        assert_eq!(settings.spys[0].output, path("output"));
+        assert_eq!(settings.spys[1].input.as_deref(), Some("/data/input"));
+        assert_eq!(settings.spys[1].output.as_deref(), Some("{{ input }}/log"));
+
+        // Kept as is by default, resolved against the current directory at run.
+        write(&cfg, toml.replace("paths_relative_to = 'config'", ""))?;
+        let settings = Settings::new(&cfg, false, &mut Context::new())?;
+        assert_eq!(settings.cfg.stop_flg, "stop.flg");
+        assert_eq!(settings.spys[0].input.as_deref(), Some("input"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_throttle_layers() -> Result<()> {
+        let settings = |default: &str| -> Result<Settings> {
+            let settings: Settings = toml::from_str(&format!(
+                r#"
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'default'
+{}
+
+[[spys]]
+name = 'inherit'
+input = 'a'
+patterns = [
+  {{ pattern = '\.csv$', cmd = 'echo' }},
+  {{ pattern = '\.txt$', cmd = 'echo', throttle = 0, debounce = '1s' }},
+]
+
+[[spys]]
+name = 'disabled'
+input = 'b'
+throttle = 0
+debounce = 0
+patterns = [
+  {{ pattern = '\.csv$', cmd = 'echo' }},
+  {{ pattern = '\.txt$', cmd = 'echo', throttle = 2000 }},
+]
+"#,
+                default
+            ))?;
+            settings.rebuild()
+        };
+        let layers = |settings: &Settings, name: &str| {
+            let spy = settings.spys.iter().find(|s| s.name == name).unwrap();
+            let patterns = spy.patterns.as_ref().unwrap();
+            [None, Some(&patterns[0]), Some(&patterns[1])].map(|p| {
+                (
+                    spy.throttle_of(p).as_millis(),
+                    spy.debounce_of(p).as_millis(),
+                )
+            })
+        };
+
+        // default spy -> spy -> pattern.
+        let loaded = settings("throttle = 5000\ndebounce = 100")?;
+        assert_eq!(
+            layers(&loaded, "inherit"),
+            [(5000, 100), (5000, 100), (0, 1000)]
+        );
+        // An explicit 0 overrides the non-zero default.
+        assert_eq!(layers(&loaded, "disabled"), [(0, 0), (0, 0), (2000, 0)]);
+
+        // Unset in all the layers, the built-in default.
+        let loaded = settings("")?;
+        let spy = loaded.spys.iter().find(|s| s.name == "inherit").unwrap();
+        assert_eq!((spy.throttle, spy.debounce), (None, None));
+        assert_eq!(layers(&loaded, "inherit"), [(0, 50), (0, 50), (0, 1000)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_with_base() -> Result<()> {
+        let settings: Settings = toml::from_str(
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'default'
+enabled = false
+events = ['Create']
+output = 'default'
+debounce = 50
+throttle = 0
+limitkey = ''
+patterns = [{ pattern = '.*', cmd = 'echo', arg = [] }]
+
+[[spys]]
+name = 'fast'
+debounce = 10
+
+[[spys]]
+name = 'slow'
+base = 'fast'
+throttle = 1000
+output = 'slow'
+
+[[spys]]
+name = 'a'
+base = 'fast'
+input = 'a'
+
+[[spys]]
+name = 'b'
+base = 'slow'
+input = 'b'
+debounce = 20
+
+[[spys]]
+name = 'c'
+input = 'c'
+"#,
+        )?;
+        let settings = settings.rebuild()?;
+        let spy = |name: &str| settings.spys.iter().find(|s| s.name == name).unwrap();
+
+        assert_eq!(spy("a").debounce, Some(10));
+        assert_eq!(spy("a").throttle, Some(0));
+        assert_eq!(spy("a").output.as_deref(), Some("default"));
+        assert_eq!(spy("b").debounce, Some(20));
+        assert_eq!(spy("b").throttle, Some(1000));
+        assert_eq!(spy("b").output.as_deref(), Some("slow"));
+        assert_eq!(spy("b").events, Some(vec!["Create".to_string()]));
+        assert_eq!(spy("c").debounce, Some(50));
+        // A disabled base is not watched, but still inherited.
+        assert!(!spy("default").is_enabled());
+        assert!(spy("c").is_enabled());
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_spys() -> Result<()> {
+        let dir = env::current_dir()?.join("test").join("test_with_spys");
+        let spy = Spy::builder("embed")
+            .debounce(Duration::from_millis(10))
+            .build()?;
+        let settings = Settings::with_spys(&dir, vec![spy])?.rebuild()?;
+
+        assert_eq!(
+            settings.cfg.stop_flg,
+            dir.join("stop.flg").to_string_lossy()
+        );
+        assert!(settings.cfg.single_instance);
+        assert_eq!(settings.log.path, dir.join("spyrun.log").to_string_lossy());
+        assert_eq!(settings.log.level, "info");
+        assert_eq!(settings.spys.len(), 1);
+        // Unset fields are inherited from the default spy.
+        assert_eq!(settings.spys[0].debounce, Some(10));
+        assert!(settings.spys[0].patterns.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spy_builder() -> Result<()> {
+        let spy = Spy::builder("built")
+            .input("in")
+            .event("Create")
+            .pattern(r"\.txt$", "echo", &["{{ event_path }}"])
+            .throttle(Duration::from_secs(1))
+            .build()?;
+        assert_eq!(spy.input.as_deref(), Some("in"));
+        assert_eq!(spy.events, Some(vec!["Create".to_string()]));
+        assert_eq!(spy.throttle, Some(1000));
+        assert_eq!(spy.patterns.as_ref().unwrap().len(), 1);
+        // The rest is the default spy's, so nothing downstream unwraps None.
+        assert_eq!(spy.output.as_deref(), Some("output"));
+        // Unset, so the built-in debounce.
+        assert_eq!(spy.debounce, None);
+        assert_eq!(spy.debounce_of(None), Duration::from_millis(50));
+        assert_eq!(spy.limitkey.as_deref(), Some(""));
+        assert!(spy.matcher.is_some());
+
+        let err = |builder: SpyBuilder| format!("{:?}", builder.build().unwrap_err());
+        assert!(err(Spy::builder("bad").pattern("(", "echo", &[])).contains("regex"));
+        assert!(err(Spy::builder("bad").pattern(r"\.txt$", "", &[]))
+            .contains("`cmd` or `script` is required"));
+        assert!(err(
+            Spy::builder("bad").delay(Duration::from_millis(300), Some(Duration::from_millis(100)))
+        )
+        .contains("[bad] delay min (300ms) must not be greater than max (100ms)"));
+        assert!(err(Spy::builder("bad").event("Created")).contains(
+            "[bad] events must be Access, Create, Modify, Remove, Other or Any, not Created"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_default_spy_template() -> Result<()> {
+        let settings = |cfg: &str, default: &str| -> Result<Settings> {
+            let toml = format!(
+                r#"
+[cfg]
+stop_flg = 'stop.flg'
+{}
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'default'
+input = 'input'
+{}
+
+[[spys]]
+name = 'a'
+input = 'a'
+"#,
+                cfg, default
+            );
+            toml::from_str::<Settings>(&toml)?.rebuild()
+        };
+        let enabled = |settings: &Settings| {
+            settings
+                .spys
+                .iter()
+                .filter(|s| s.is_enabled())
+                .map(|s| s.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // default is a template only, even with a valid input.
+        assert_eq!(enabled(&settings("", "")?), vec!["a"]);
+        assert_eq!(
+            enabled(&settings("", "enabled = true")?),
+            vec!["default", "a"]
+        );
+        assert_eq!(
+            enabled(&settings("default_spy_runs = true", "")?),
+            vec!["default", "a"]
+        );
+        assert_eq!(
+            enabled(&settings("default_spy_runs = true", "enabled = false")?),
+            vec!["a"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_with_cyclic_base() -> Result<()> {
+        let settings: Settings = toml::from_str(
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'a'
+base = 'b'
+
+[[spys]]
+name = 'b'
+base = 'a'
+"#,
+        )?;
+        let e = settings.rebuild().unwrap_err();
+        assert!(e.to_string().contains("Cyclic spy base"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn test_startup_order() -> Result<()> {
+        let settings: Settings = toml::from_str(
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'process'
+after = ['unzip']
+
+[[spys]]
+name = 'report'
+after = ['process', 'unzip']
+
+[[spys]]
+name = 'unzip'
+"#,
+        )?;
+        let settings = settings.rebuild()?;
+        let names = startup_order(&settings.spys)?
+            .into_iter()
+            .map(|spy| spy.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["unzip", "process", "report"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_with_cyclic_after() -> Result<()> {
+        let settings: Settings = toml::from_str(
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'a'
+after = ['b']
+
+[[spys]]
+name = 'b'
+after = ['a']
+"#,
+        )?;
+        let e = settings.rebuild().unwrap_err();
+        assert!(
+            e.to_string().contains("Cyclic spy after: a -> b -> a"),
+            "{}",
+            e
+        );
+
+        let settings: Settings = toml::from_str(
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'a'
+after = ['missing']
+"#,
+        )?;
+        let e = settings.rebuild().unwrap_err();
+        assert!(e.to_string().contains("After spy not found"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_pattern() -> Result<()> {
+        let spy = Spy::builder("glob")
+            .add_pattern(Pattern {
+                pattern: "*.{txt,csv}".to_string(),
+                pattern_type: Some(PatternType::Glob),
+                cmd: "echo".to_string(),
+                ..Default::default()
+            })
+            .build()?;
+        let matcher = spy.matcher.unwrap();
+        assert_eq!(matcher.find("/data/input/a.txt"), Some(0));
+        assert_eq!(matcher.find(r"C:\data\input\b.csv"), Some(0));
+        assert_eq!(matcher.find("/data/input/c.log"), None);
+        assert_eq!(matcher.find("/data/input/d.txt.log"), None);
+
+        let glob = |glob: &str, path: &str| -> Result<bool> {
+            Ok(regex::Regex::new(&glob_to_regex(glob)?)?.is_match(path))
+        };
+        assert!(glob("in/**/*.{jpg,{png,gif}}", "/data/in/a/b/c.gif")?);
+        assert!(glob("in/**/*.jpg", "/data/in/c.jpg")?);
+        assert!(!glob("in/*.jpg", "/data/in/a/c.jpg")?);
+        assert!(glob("report_[!0-9]?.csv", "/data/report_ab.csv")?);
+        assert!(!glob("report_[!0-9]?.csv", "/data/report_1b.csv")?);
+        // The regex characters are literal.
+        assert!(!glob("a+.txt", "/data/aa.txt")?);
+        assert!(glob_to_regex("*.{txt,csv").is_err());
+        assert!(glob_to_regex("[abc").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_delay() -> Result<()> {
+        assert_eq!(parse_delay("500")?, (500, None));
+        assert_eq!(parse_delay("500ms")?, (500, None));
+        assert_eq!(parse_delay("2s")?, (2000, None));
+        assert_eq!(parse_delay("1m")?, (60_000, None));
+        assert_eq!(parse_delay("500ms..2s")?, (500, Some(2000)));
+        assert_eq!(parse_delay(" 1s .. 1h ")?, (1000, Some(3_600_000)));
+        assert_eq!(parse_delay("1h30m")?, (5_400_000, None));
+        assert_eq!(parse_delay("1m 500ms")?, (60_500, None));
+        assert!(parse_delay("2d").is_err());
+        assert!(parse_delay("ms").is_err());
+        assert!(parse_delay("1s..").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_duration_strings() -> Result<()> {
+        let settings =
+            |throttle: &str, debounce: &str, interval: &str, delay: &str| -> Result<Settings> {
+                Ok(toml::from_str(&format!(
+                    r#"
+[cfg]
+stop_flg = 'stop.flg'
+after_timeout = {2}
+loop_guard = {{ max = 3, window = {0}, cooldown = {1} }}
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'a'
+throttle = {0}
+debounce = {1}
+poll = {{ interval = {2} }}
+delay = {3}
+patterns = [{{ pattern = '.*', cmd = 'echo', slow_threshold = {2} }}]
+"#,
+                    throttle, debounce, interval, delay
+                ))?)
+            };
+        let numeric = settings("900000", "250", "5400000", "'250..900000'")?;
+        let text = settings("'15m'", "'250ms'", "'1h30m'", "'250ms..15m'")?;
+        for s in [&numeric, &text] {
+            let spy = &s.spys[0];
+            assert_eq!(
+                spy.throttle.map(Duration::from_millis),
+                Some(Duration::from_secs(15 * 60))
+            );
+            assert_eq!(spy.debounce, Some(250));
+            assert_eq!(spy.poll.as_ref().unwrap().interval, 5_400_000);
+            assert_eq!(spy.delay, Some((250, Some(900_000))));
+            assert_eq!(
+                spy.patterns.as_ref().unwrap()[0].slow_threshold,
+                Some(5_400_000)
+            );
+            assert_eq!(s.cfg.after_timeout, Some(5_400_000));
+            let loop_guard = s.cfg.loop_guard.as_ref().unwrap();
+            assert_eq!((loop_guard.window, loop_guard.cooldown), (900_000, 250));
+        }
+
+        let e = settings("900000", "250", "1000", "'250..15 minutes'").unwrap_err();
+        assert!(e.to_string().contains("delay"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_with_render_timeout() -> Result<()> {
+        let settings: Settings = toml::from_str(
+            r#"
+[cfg]
+stop_flg = 'stop.flg'
+render_timeout = '1500ms'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'a'
+"#,
+        )?;
+        // Held by the spys of these settings, not by the process.
+        assert_eq!(settings.spys[0].render_timeout, None);
+        assert_eq!(settings.rebuild()?.spys[0].render_timeout, Some(1500));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_with_delay() -> Result<()> {
+        let settings = |delay: &str| -> Result<Settings> {
+            let settings: Settings = toml::from_str(&format!(
+                r#"
+[cfg]
+stop_flg = 'stop.flg'
+
+[log]
+path = 'spyrun.log'
+
+[[spys]]
+name = 'a'
+delay = {}
+"#,
+                delay
+            ))?;
+            settings.rebuild()
+        };
+        assert_eq!(settings("[5000]")?.spys[0].delay, Some((5000, None)));
+        assert_eq!(
+            settings("[5000, 10000]")?.spys[0].delay,
+            Some((5000, Some(10000)))
+        );
+        assert_eq!(
+            settings("'500ms..2s'")?.spys[0].delay,
+            Some((500, Some(2000)))
+        );
+        assert_eq!(settings("[0, 0]")?.spys[0].delay, Some((0, Some(0))));
+
+        let e = settings("[500, 100]").unwrap_err();
+        assert!(e.to_string().contains("[a] delay min"), "{}", e);
+        let e = settings("'48h'").unwrap_err();
+        assert!(e.to_string().contains("must not be greater than"), "{}", e);
+        assert!(settings("[1, 2, 3]").is_err());
+        assert!(settings("'2 days'").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+// =============================================================================
+// File        : source.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 18:40:12.
+// =============================================================================
+
+use std::{any::Any, fmt::Debug, path::Path, time::Duration};
+
+use anyhow::{bail, Result};
+use notify::{Config, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use tracing::error;
+
+use crate::{message::SpyEvent, queue::EventSender, settings::WatchBackend};
+
+/// The poll interval of `watch_backend = "poll"` without `poll`, and of `poll_fallback`.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Kept while watching, the watching stops when dropped.
+pub type Handle = Box<dyn Any>;
+
+/// Where the events of a spy come from. `Spy::watch` picks one by the settings.
+pub trait EventSource: Debug + Send + Sync {
+    /// Starts sending the events under `path` to `tx` as `Message::Event`, through the queue with `queue_size`.
+    fn start(&self, tx: EventSender, path: &Path, recursive: RecursiveMode) -> Result<Handle>;
+}
+
+/// The native watcher of `watch_backend`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NotifySource {
+    pub backend: WatchBackend,
+}
+
+impl EventSource for NotifySource {
+    fn start(&self, tx: EventSender, path: &Path, recursive: RecursiveMode) -> Result<Handle> {
+        let config = Config::default();
+        match self.backend {
+            WatchBackend::Recommended => watch::<RecommendedWatcher>(tx, path, recursive, config),
+            WatchBackend::Poll => PollSource::default().start(tx, path, recursive),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            WatchBackend::Inotify => watch::<notify::INotifyWatcher>(tx, path, recursive, config),
+            #[cfg(target_os = "macos")]
+            WatchBackend::Fsevent => watch::<notify::FsEventWatcher>(tx, path, recursive, config),
+            #[allow(unreachable_patterns)]
+            backend => bail!(
+                "watch_backend {:?} is not available on this platform",
+                backend
+            ),
+        }
+    }
+}
+
+/// Polls every `interval`, for `poll`, `watch_backend = "poll"` and `poll_fallback`.
+#[derive(Debug, Clone, Copy)]
+pub struct PollSource {
+    pub interval: Duration,
+}
+
+impl Default for PollSource {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+}
+
+impl EventSource for PollSource {
+    fn start(&self, tx: EventSender, path: &Path, recursive: RecursiveMode) -> Result<Handle> {
+        let config = Config::default().with_poll_interval(self.interval);
+        watch::<PollWatcher>(tx, path, recursive, config)
+    }
+}
+
+/// Replays the scripted events in order on start, instead of watching the filesystem.
+///
+/// `tx` is dropped after, so the receiver ends when the other senders are gone.
+/// The events are sent before the watcher receives, so not more than `queue_size` with `queue_policy = "block"`.
+#[derive(Debug, Clone, Default)]
+pub struct TestSource {
+    events: Vec<SpyEvent>,
+}
+
+impl TestSource {
+    pub fn new(events: Vec<SpyEvent>) -> Self {
+        Self { events }
+    }
+}
+
+impl EventSource for TestSource {
+    fn start(&self, tx: EventSender, _path: &Path, _recursive: RecursiveMode) -> Result<Handle> {
+        for event in &self.events {
+            tx.send(event.clone())?;
+        }
+        Ok(Box::new(()))
+    }
+}
+
+fn watch<W: Watcher + 'static>(
+    tx: EventSender,
+    path: &Path,
+    recursive: RecursiveMode,
+    config: Config,
+) -> Result<Handle> {
+    let mut watcher = W::new(
+        move |res| match res {
+            Ok(event) => tx.send(SpyEvent(event)).unwrap(),
+            Err(e) => error!("watch error: {:?}", e),
+        },
+        config,
+    )?;
+    watcher.watch(path, recursive)?;
+    Ok(Box::new(watcher))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::mpsc};
+
+    use anyhow::Result;
+    use notify::RecursiveMode;
+
+    use super::{EventSource, TestSource};
+    use crate::message::{Message, SpyEvent};
+
+    #[test]
+    fn test_test_source() -> Result<()> {
+        let events = ["a.txt", "b.txt"]
+            .iter()
+            .map(|name| SpyEvent::new("Create", name))
+            .collect::<Vec<_>>();
+        let (tx, rx) = mpsc::channel();
+        let _handle = TestSource::new(events.clone()).start(
+            tx.into(),
+            &PathBuf::from("input"),
+            RecursiveMode::Recursive,
+        )?;
+        let received = rx
+            .into_iter()
+            .map(|message| match message {
+                Message::Event(event) => SpyEvent(event),
+                message => unreachable!("{:?}", message),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(received, events);
+        Ok(())
+    }
+}
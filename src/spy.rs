@@ -5,37 +5,41 @@
 // =============================================================================
 
 use std::{
+    fs,
     path::Path,
-    sync::mpsc,
+    sync::{mpsc, Arc},
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log_derive::logfn;
 use normalize_path::NormalizePath;
-use notify::{
-    event::{AccessKind, CreateKind, EventAttributes, ModifyKind, RemoveKind},
-    recommended_watcher, Config, Event, EventKind, PollWatcher, RecommendedWatcher, Watcher,
-};
+use notify::{event::CreateKind, Event, EventKind, RecursiveMode};
 use rand::Rng;
 use regex::Regex;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 use walkdir::WalkDir;
 
-use crate::{message::Message, settings::Spy};
-
-#[tracing::instrument]
-#[logfn(Trace)]
-fn string_to_event_kind(str: &str) -> EventKind {
-    match str {
-        "Create" => EventKind::Create(CreateKind::Any),
-        "Remove" => EventKind::Remove(RemoveKind::Any),
-        "Modify" => EventKind::Modify(ModifyKind::Any),
-        "Access" => EventKind::Access(AccessKind::Any),
-        _ => EventKind::Modify(ModifyKind::Any),
-    }
-}
+use crate::{
+    command::CommandTemplate,
+    message::{string_to_event_kind, Message},
+    queue::EventSender,
+    settings::{Pattern, Spy, WalkOnError, WatchBackend},
+    source::{EventSource, Handle, NotifySource, PollSource, DEFAULT_POLL_INTERVAL},
+};
+
+/// The interval to check if `input` exists with `input_policy = "wait"`.
+const INPUT_WAIT_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// The info of the events sent by `walk` and `scan`, to tell them from the watched ones.
+pub const WALK_EVENT_INFO: &str = "spyrun:walk";
+/// A walked file older than `max_age_secs`, sent only to write its `skip_marker`.
+pub const WALK_OLD_EVENT_INFO: &str = "spyrun:walk:max_age";
+
+/// The built-in `throttle` and `debounce` in milliseconds, when unset in all the layers.
+pub const DEFAULT_THROTTLE: u64 = 0;
+pub const DEFAULT_DEBOUNCE: u64 = 50;
 
 impl Spy {
     #[tracing::instrument]
@@ -47,50 +51,61 @@ impl Spy {
         }
     }
 
-    #[tracing::instrument]
-    #[logfn(Trace)]
-    fn notify_watch(&self, tx: mpsc::Sender<Message>) -> Result<RecommendedWatcher> {
-        let spy = self.clone();
-        let mut watcher = recommended_watcher(move |res| match res {
-            Ok(event) => tx.send(Message::Event(event)).unwrap(),
-            Err(e) => error!("watch error: {:?}", e),
-        })?;
-        watcher.watch(
-            Path::new(&spy.input.unwrap()).normalize().as_path(),
-            spy.recursive,
-        )?;
-        Ok(watcher)
+    /// The `limitkey` template: `limitkey` if not empty, otherwise the template of `limit_scope`.
+    /// Empty means the whole command.
+    pub fn limitkey_template(&self) -> &str {
+        match (self.limitkey.as_deref(), self.limit_scope) {
+            (Some(limitkey), _) if !limitkey.is_empty() => limitkey,
+            (_, Some(scope)) => scope.template(),
+            _ => "",
+        }
     }
 
-    #[tracing::instrument]
-    #[logfn(Trace)]
-    fn poll_watch(&self, tx: mpsc::Sender<Message>) -> Result<PollWatcher> {
-        let spy = self.clone();
-        let mut watcher = PollWatcher::new(
-            move |res| match res {
-                Ok(event) => tx.send(Message::Event(event)).unwrap(),
-                Err(e) => error!("watch error: {:?}", e),
-            },
-            Config::default().with_poll_interval(Duration::from_millis(spy.poll.unwrap().interval)),
-        )?;
-        watcher.watch(
-            Path::new(&spy.input.unwrap()).normalize().as_path(),
-            spy.recursive,
-        )?;
-        Ok(watcher)
+    /// The templates to run `cmd` and `arg` with: the spy name, `input`, `output` and the `limitkey` template.
+    pub fn command_template<'a>(&'a self, cmd: &'a str, arg: Vec<String>) -> CommandTemplate<'a> {
+        CommandTemplate {
+            name: &self.name,
+            input: self.input.as_deref().unwrap_or_default(),
+            output: self.output.as_deref().unwrap_or_default(),
+            cmd,
+            arg,
+            limitkey: self.limitkey_template(),
+        }
+    }
+
+    /// The `throttle` of `pattern`: the pattern's, the spy's (inherited from its bases and the default spy),
+    /// then `DEFAULT_THROTTLE`. 0 disables it.
+    pub fn throttle_of(&self, pattern: Option<&Pattern>) -> Duration {
+        let millis = pattern.and_then(|p| p.throttle).or(self.throttle);
+        Duration::from_millis(millis.unwrap_or(DEFAULT_THROTTLE))
+    }
+
+    /// The `debounce` of `pattern`, resolved as `throttle_of`.
+    pub fn debounce_of(&self, pattern: Option<&Pattern>) -> Duration {
+        let millis = pattern.and_then(|p| p.debounce).or(self.debounce);
+        Duration::from_millis(millis.unwrap_or(DEFAULT_DEBOUNCE))
+    }
+
+    /// Unset `enabled` is true, except for `default` resolved by `Settings::rebuild`.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
     }
 
     #[tracing::instrument]
     fn delay(&self, d: Option<(u64, Option<u64>)>) {
         if let Some((min, max)) = d {
-            if max.is_none() {
-                thread::sleep(Duration::from_millis(min));
-            } else {
-                let max = max.unwrap();
-                let mut rng = rand::thread_rng();
-                let wait = rng.gen_range(min..=max);
-                thread::sleep(Duration::from_millis(wait));
-            }
+            let wait = match max {
+                None => min,
+                Some(max) if max < min => {
+                    warn!(
+                        "[{}] delay min ({}ms) is greater than max ({}ms), swap them",
+                        &self.name, min, max
+                    );
+                    rand::thread_rng().gen_range(max..=min)
+                }
+                Some(max) => rand::thread_rng().gen_range(min..=max),
+            };
+            thread::sleep(Duration::from_millis(wait));
         }
     }
 
@@ -108,12 +123,19 @@ impl Spy {
 
     #[tracing::instrument]
     #[logfn(Trace)]
-    pub fn walk(&self, tx: mpsc::Sender<Message>) -> Result<JoinHandle<()>> {
+    pub(crate) fn walk(&self, tx: mpsc::Sender<Message>) -> Result<JoinHandle<Result<()>>> {
         self.walk_delay();
         let spy = self.clone();
         if spy.walk.is_none() {
-            return Ok(thread::spawn(|| {}));
+            return Ok(thread::spawn(|| Ok(())));
         }
+        Ok(thread::spawn(move || spy.walk_events(tx)))
+    }
+
+    #[tracing::instrument]
+    #[logfn(Trace)]
+    fn walk_events(&self, tx: mpsc::Sender<Message>) -> Result<()> {
+        let spy = self.clone();
         let walk = spy.walk.unwrap();
         let mut walker = WalkDir::new(Path::new(&spy.input.clone().unwrap()).normalize());
 
@@ -127,54 +149,238 @@ impl Spy {
             walker = walker.follow_links(follow_symlinks);
         }
 
-        let walker = walker.into_iter();
-
         debug!("[{}] walk input: [{}]", &spy.name, &spy.input.unwrap());
         let event_kind_str = &spy
             .events
             .clone()
             .unwrap_or(vec!["Create".to_string(), "Modify".to_string()])[0];
         let event_kind = string_to_event_kind(event_kind_str);
-        let handle = thread::spawn(move || {
-            match walk.pattern {
-                Some(pattern) => {
-                    debug!("[{}] walk pattern: [{}]", &spy.name, &pattern);
-                    let re = Regex::new(&pattern).unwrap();
-                    debug!("[{}] re: [{:?}]", &spy.name, &re);
-                    walker
-                        .filter_map(|e| e.ok())
-                        .filter(|e| e.path().to_str().is_some_and(|s| re.is_match(s)))
-                        .for_each(|e| {
-                            tx.send(Message::Event(Event {
-                                kind: event_kind,
-                                paths: vec![e.path().to_path_buf()],
-                                attrs: EventAttributes::new(),
-                            }))
-                            .unwrap();
-                        });
+        let re = match walk.pattern {
+            Some(pattern) => {
+                debug!("[{}] walk pattern: [{}]", &spy.name, &pattern);
+                let re = Regex::new(&pattern).unwrap();
+                debug!("[{}] re: [{:?}]", &spy.name, &re);
+                Some(re)
+            }
+            _ => None,
+        };
+        let max_age = walk.max_age_secs.map(Duration::from_secs);
+        let marks_old = spy.skip_marker.is_some()
+            || spy
+                .patterns
+                .iter()
+                .flatten()
+                .any(|p| p.skip_marker.is_some());
+
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let path = e
+                        .path()
+                        .map(|p| p.display().to_string())
+                        .unwrap_or_default();
+                    match walk.on_error {
+                        WalkOnError::Skip => continue,
+                        WalkOnError::Log => {
+                            warn!("[{}] walk error: [{}], e: {}", &spy.name, &path, e);
+                            continue;
+                        }
+                        WalkOnError::Fail => {
+                            bail!("[{}] walk error: [{}], e: {}", &spy.name, &path, e)
+                        }
+                    }
                 }
-                _ => walker.filter_map(|e| e.ok()).for_each(|e| {
-                    tx.send(Message::Event(Event {
-                        kind: event_kind,
-                        paths: vec![e.path().to_path_buf()],
-                        attrs: EventAttributes::new(),
-                    }))
-                    .unwrap();
-                }),
             };
+            if re
+                .as_ref()
+                .is_some_and(|re| !entry.path().to_str().is_some_and(|s| re.is_match(s)))
+            {
+                continue;
+            }
+            let info = match max_age.is_some_and(|max_age| is_older(entry.path(), max_age)) {
+                true => {
+                    debug!(
+                        "[{}] ! Skip walk older than max_age_secs: [{}]",
+                        &spy.name,
+                        entry.path().display()
+                    );
+                    if !marks_old {
+                        continue;
+                    }
+                    WALK_OLD_EVENT_INFO
+                }
+                false => WALK_EVENT_INFO,
+            };
+            tx.send(Message::Event(
+                Event::new(event_kind)
+                    .add_path(entry.path().to_path_buf())
+                    .set_info(info),
+            ))
+            .unwrap();
+        }
+        Ok(())
+    }
+
+    /// Sends Create events for the existing files in `input` matching `patterns`.
+    #[tracing::instrument]
+    #[logfn(Trace)]
+    pub(crate) fn scan(&self, tx: mpsc::Sender<Message>) -> Result<JoinHandle<()>> {
+        let spy = self.clone();
+        let patterns = spy
+            .patterns
+            .iter()
+            .flatten()
+            .map(|p| p.regex())
+            .collect::<Result<Vec<_>>>()?;
+        let mut walker = WalkDir::new(Path::new(&spy.input.clone().unwrap()).normalize());
+        if spy.recursive == RecursiveMode::NonRecursive {
+            walker = walker.max_depth(1);
+        }
+
+        debug!("[{}] scan input: [{}]", &spy.name, &spy.input.unwrap());
+        let handle = thread::spawn(move || {
+            walker
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter(|e| {
+                    let path = e.path().to_string_lossy();
+                    patterns.iter().any(|re| re.is_match(&path))
+                })
+                .for_each(|e| {
+                    tx.send(Message::Event(
+                        Event::new(EventKind::Create(CreateKind::Any))
+                            .add_path(e.path().to_path_buf())
+                            .set_info(WALK_EVENT_INFO),
+                    ))
+                    .unwrap();
+                });
         });
 
         Ok(handle)
     }
 
+    /// Walks `input` now by `walk`, or like `scan_existing` without it. Returns after the walk.
+    #[tracing::instrument]
+    #[logfn(Trace)]
+    pub(crate) fn rescan(&self, tx: mpsc::Sender<Message>) -> Result<()> {
+        match self.walk {
+            Some(_) => self.walk(tx)?.join().unwrap(),
+            None => {
+                self.scan(tx)?.join().unwrap();
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits until `input` exists. Returns false if stopped while waiting.
     #[tracing::instrument]
-    pub fn watch(&self, tx: mpsc::Sender<Message>) -> Result<Box<dyn Watcher>> {
+    pub(crate) fn wait_input(&self, rx: &mpsc::Receiver<Message>) -> bool {
+        let input = Path::new(self.input.as_ref().unwrap()).normalize();
+        loop {
+            if input.exists() {
+                info!("[{}] input appeared: {}", &self.name, input.display());
+                return true;
+            }
+            if let Ok(Message::Stop) = rx.recv_timeout(INPUT_WAIT_INTERVAL) {
+                return false;
+            }
+        }
+    }
+
+    /// Watches `input` with `source`, or `watch_backend`. `poll` forces polling with its interval.
+    #[tracing::instrument]
+    pub(crate) fn watch(&self, tx: EventSender) -> Result<Handle> {
+        self.watch_with(tx, self.source().as_ref())
+    }
+
+    /// The event source by the settings.
+    pub(crate) fn source(&self) -> Arc<dyn EventSource> {
+        if let Some(source) = &self.source {
+            return source.clone();
+        }
+        match (&self.poll, self.watch_backend.unwrap_or_default()) {
+            (Some(poll), _) => Arc::new(PollSource {
+                interval: Duration::from_millis(poll.interval),
+            }),
+            (None, WatchBackend::Poll) => Arc::new(PollSource::default()),
+            (None, backend) => Arc::new(NotifySource { backend }),
+        }
+    }
+
+    /// `watch` with `source`, replaced in the tests to simulate its errors.
+    /// The inotify limits are logged with the guidance, and fall back to polling with `poll_fallback`.
+    fn watch_with(&self, tx: EventSender, source: &dyn EventSource) -> Result<Handle> {
         self.watch_delay();
-        match self.poll {
-            Some(_) => Ok(Box::new(self.poll_watch(tx)?)),
-            _ => Ok(Box::new(self.notify_watch(tx)?)),
+        let dirs = self.count_dirs();
+        debug!("[{}] watching {} directories", &self.name, dirs);
+        let path = Path::new(self.input.as_ref().unwrap()).normalize();
+        let e = match source.start(tx.clone(), &path, self.recursive) {
+            Ok(handle) => return Ok(handle),
+            Err(e) if is_watch_limit(&e) => e,
+            Err(e) => return Err(e.context(format!("[{}] watch error", self.name))),
+        };
+        error!(
+            "[{}] inotify limit reached watching {} directories, e: {:?}. fs.inotify.max_user_watches: {}, fs.inotify.max_user_instances: {}. Raise them (e.g. `sysctl fs.inotify.max_user_watches=524288`), or set `watch_backend = \"poll\"` or `poll_fallback = true`.",
+            &self.name,
+            dirs,
+            e,
+            read_inotify_limit("max_user_watches"),
+            read_inotify_limit("max_user_instances"),
+        );
+        if !self.poll_fallback.unwrap_or_default() {
+            return Err(e.context(format!("[{}] inotify limit reached", self.name)));
         }
+        warn!(
+            "[{}] poll_fallback: watch by polling every {:?}",
+            &self.name, DEFAULT_POLL_INTERVAL
+        );
+        PollSource::default().start(tx, &path, self.recursive)
     }
+
+    /// The directories to watch in `input`, each is an inotify watch when recursive.
+    fn count_dirs(&self) -> usize {
+        let mut walker = WalkDir::new(Path::new(self.input.as_ref().unwrap()).normalize());
+        if self.recursive == RecursiveMode::NonRecursive {
+            walker = walker.max_depth(0);
+        }
+        walker
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir())
+            .count()
+    }
+}
+
+/// Whether `e` is the inotify watch limit (ENOSPC) or instance limit (EMFILE).
+fn is_watch_limit(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<notify::Error>()
+        .is_some_and(|e| match &e.kind {
+            notify::ErrorKind::MaxFilesWatch => true,
+            notify::ErrorKind::Io(e) => {
+                cfg!(any(target_os = "linux", target_os = "android"))
+                    && e.raw_os_error()
+                        .is_some_and(|code| [24, 28].contains(&code))
+            }
+            _ => false,
+        })
+}
+
+/// `/proc/sys/fs/inotify/<name>`, or "unknown" if not readable (e.g. not Linux).
+fn read_inotify_limit(name: &str) -> String {
+    fs::read_to_string(Path::new("/proc/sys/fs/inotify").join(name))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Whether the mtime of `path` is older than `max_age`. A file without mtime is not older.
+fn is_older(path: &Path, max_age: Duration) -> bool {
+    path.metadata()
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > max_age)
 }
 
 #[cfg(test)]
@@ -182,29 +388,47 @@ mod tests {
     use std::{
         env,
         fs::{create_dir_all, remove_dir_all, File},
+        path::{Path, PathBuf},
         sync::mpsc,
-        time::Duration,
+        time::{Duration, Instant, SystemTime},
     };
 
     use anyhow::Result;
+    use notify::RecursiveMode;
 
-    use super::Spy;
+    use super::{is_watch_limit, Spy, WALK_EVENT_INFO, WALK_OLD_EVENT_INFO};
     use crate::{
         message::Message,
-        settings::{Poll, Walk},
+        queue::EventSender,
+        settings::{Walk, WalkOnError, WatchBackend},
+        source::{EventSource, Handle},
     };
 
+    /// Fails with ENOSPC as inotify_add_watch over fs.inotify.max_user_watches.
+    #[derive(Debug)]
+    struct Limited;
+
+    impl EventSource for Limited {
+        fn start(
+            &self,
+            _tx: EventSender,
+            _path: &Path,
+            _recursive: RecursiveMode,
+        ) -> Result<Handle> {
+            Err(notify::Error::new(notify::ErrorKind::MaxFilesWatch).into())
+        }
+    }
+
     #[test]
     fn test_watch() -> Result<()> {
         let tmp = env::current_dir()?.join("test");
         let watch_path = tmp.join("test_watch");
         let create_file = watch_path.join("test.txt");
-        let mut spy = Spy::new("test_watch".to_string());
-        spy.input = Some(watch_path.to_string_lossy().to_string());
+        let spy = Spy::builder("test_watch").input(&watch_path).build()?;
         let (tx, rx) = mpsc::channel();
         remove_dir_all(&watch_path).unwrap_or_default();
         create_dir_all(&watch_path)?;
-        let _watch = spy.watch(tx.clone())?;
+        let _watch = spy.watch(tx.clone().into())?;
         File::create(&create_file)?;
 
         match rx.recv_timeout(Duration::from_secs(10)) {
@@ -228,13 +452,14 @@ mod tests {
         let tmp = env::current_dir()?.join("test");
         let watch_path = tmp.join("test_poll_watch");
         let create_file = watch_path.join("test.txt");
-        let mut spy = Spy::new("test_poll_watch".to_string());
-        spy.input = Some(watch_path.to_string_lossy().to_string());
-        spy.poll = Some(Poll { interval: 100 });
+        let spy = Spy::builder("test_poll_watch")
+            .input(&watch_path)
+            .poll(Duration::from_millis(100))
+            .build()?;
         let (tx, rx) = mpsc::channel();
         remove_dir_all(&watch_path).unwrap_or_default();
         create_dir_all(&watch_path)?;
-        let _watch = spy.watch(tx.clone())?;
+        let _watch = spy.watch(tx.clone().into())?;
         File::create(&create_file)?;
 
         match rx.recv_timeout(Duration::from_secs(10)) {
@@ -253,18 +478,102 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_watch_backend_poll() -> Result<()> {
+        let tmp = env::current_dir()?.join("test");
+        let watch_path = tmp.join("test_watch_backend_poll");
+        let create_file = watch_path.join("test.txt");
+        let spy = Spy::builder("test_watch_backend_poll")
+            .input(&watch_path)
+            .watch_backend(WatchBackend::Poll)
+            .build()?;
+        assert!(spy.poll.is_none());
+        let (tx, rx) = mpsc::channel();
+        remove_dir_all(&watch_path).unwrap_or_default();
+        create_dir_all(&watch_path)?;
+        let _watch = spy.watch(tx.clone().into())?;
+        File::create(&create_file)?;
+
+        match rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(Message::Event(event)) => {
+                let event_path = event.paths.last().unwrap();
+                assert_eq!(event_path.to_string_lossy(), create_file.to_string_lossy());
+            }
+            Ok(message) => unreachable!("{:?}", message),
+            Err(e) => panic!("poll backend watch error: {:?}", e),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_limit() -> Result<()> {
+        let tmp = env::current_dir()?.join("test");
+        let watch_path = tmp.join("test_watch_limit");
+        let create_file = watch_path.join("test.txt");
+        remove_dir_all(&watch_path).unwrap_or_default();
+        create_dir_all(watch_path.join("sub"))?;
+        let spy = |poll_fallback| {
+            Spy::builder("test_watch_limit")
+                .input(&watch_path)
+                .poll_fallback(poll_fallback)
+                .build()
+        };
+        assert_eq!(spy(false)?.count_dirs(), 2);
+
+        let (tx, _rx) = mpsc::channel();
+        let message = format!(
+            "{:?}",
+            spy(false)?.watch_with(tx.into(), &Limited).err().unwrap()
+        );
+        assert!(
+            message.contains("[test_watch_limit] inotify limit reached"),
+            "{}",
+            message
+        );
+
+        let (tx, rx) = mpsc::channel();
+        let _watch = spy(true)?.watch_with(tx.into(), &Limited)?;
+        File::create(&create_file)?;
+        match rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(Message::Event(event)) => {
+                let event_path = event.paths.last().unwrap();
+                assert_eq!(event_path.to_string_lossy(), create_file.to_string_lossy());
+            }
+            Ok(message) => unreachable!("{:?}", message),
+            Err(e) => panic!("poll fallback watch error: {:?}", e),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_watch_limit() {
+        let io = |code| -> anyhow::Error {
+            notify::Error::io(std::io::Error::from_raw_os_error(code)).into()
+        };
+        assert!(is_watch_limit(
+            &notify::Error::new(notify::ErrorKind::MaxFilesWatch).into()
+        ));
+        assert_eq!(
+            is_watch_limit(&io(28)),
+            cfg!(any(target_os = "linux", target_os = "android"))
+        );
+        assert!(!is_watch_limit(&io(2)));
+        assert!(!is_watch_limit(&anyhow::anyhow!("other")));
+    }
+
     #[test]
     fn test_delay_watch() -> Result<()> {
         let tmp = env::current_dir()?.join("test");
         let watch_path = tmp.join("test_delay_watch");
         let create_file = watch_path.join("test.txt");
-        let mut spy = Spy::new("test_delay_watch".to_string());
-        spy.input = Some(watch_path.to_string_lossy().to_string());
-        spy.delay = Some((100, Some(300)));
+        let spy = Spy::builder("test_delay_watch")
+            .input(&watch_path)
+            .delay(Duration::from_millis(100), Some(Duration::from_millis(300)))
+            .build()?;
         let (tx, rx) = mpsc::channel();
         remove_dir_all(&watch_path).unwrap_or_default();
         create_dir_all(&watch_path)?;
-        let _watch = spy.watch(tx.clone())?;
+        let _watch = spy.watch(tx.clone().into())?;
         File::create(&create_file)?;
 
         match rx.recv_timeout(Duration::from_secs(10)) {
@@ -283,20 +592,32 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_delay_swapped() {
+        let spy = Spy::builder("test_delay_swapped").build().unwrap();
+        let start = Instant::now();
+        spy.delay(Some((300, Some(100))));
+        spy.delay(Some((0, Some(0))));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+    }
+
     #[test]
     fn test_walk() -> Result<()> {
         let tmp = env::current_dir()?.join("test");
         let watch_path = tmp.join("test_walk");
         let create_file = watch_path.join("test.txt");
-        let mut spy = Spy::new("test_walk".to_string());
-        spy.input = Some(watch_path.to_string_lossy().to_string());
-        spy.walk = Some(Walk {
-            min_depth: Some(1),
-            max_depth: Some(2),
-            follow_symlinks: Some(true),
-            pattern: Some("\\.*\\.txt".to_string()),
-            delay: None,
-        });
+        let spy = Spy::builder("test_walk")
+            .input(&watch_path)
+            .walk(
+                Walk::default()
+                    .min_depth(1)
+                    .max_depth(2)
+                    .follow_symlinks(true)
+                    .pattern("\\.*\\.txt"),
+            )
+            .build()?;
         let (tx, rx) = mpsc::channel();
         remove_dir_all(&watch_path).unwrap_or_default();
         create_dir_all(&watch_path)?;
@@ -317,7 +638,7 @@ mod tests {
             }
         }
 
-        handle.join().unwrap();
+        handle.join().unwrap()?;
         Ok(())
     }
 
@@ -326,15 +647,17 @@ mod tests {
         let tmp = env::current_dir()?.join("test");
         let watch_path = tmp.join("test_delay_walk");
         let create_file = watch_path.join("test.txt");
-        let mut spy = Spy::new("test_delay_walk".to_string());
-        spy.input = Some(watch_path.to_string_lossy().to_string());
-        spy.walk = Some(Walk {
-            min_depth: Some(1),
-            max_depth: Some(2),
-            follow_symlinks: Some(true),
-            pattern: Some("\\.*\\.txt".to_string()),
-            delay: Some((100, Some(300))),
-        });
+        let spy = Spy::builder("test_delay_walk")
+            .input(&watch_path)
+            .walk(
+                Walk::default()
+                    .min_depth(1)
+                    .max_depth(2)
+                    .follow_symlinks(true)
+                    .pattern("\\.*\\.txt")
+                    .delay(Duration::from_millis(100), Some(Duration::from_millis(300))),
+            )
+            .build()?;
         let (tx, rx) = mpsc::channel();
         remove_dir_all(&watch_path).unwrap_or_default();
         create_dir_all(&watch_path)?;
@@ -355,7 +678,147 @@ mod tests {
             }
         }
 
-        handle.join().unwrap();
+        handle.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() -> Result<()> {
+        let tmp = env::current_dir()?.join("test");
+        let watch_path = tmp.join("test_scan");
+        let backlog_file = watch_path.join("test.ps1");
+        let builder = || {
+            Spy::builder("test_scan")
+                .input(&watch_path)
+                .scan_existing(true)
+        };
+        let spy = builder().recursive(false).build()?;
+        let (tx, rx) = mpsc::channel();
+        remove_dir_all(&watch_path).unwrap_or_default();
+        create_dir_all(watch_path.join("sub"))?;
+        File::create(&backlog_file)?;
+        File::create(watch_path.join("test.txt"))?;
+        File::create(watch_path.join("sub").join("test.ps1"))?;
+        spy.scan(tx)?.join().unwrap();
+
+        let events = rx.into_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        if let Message::Event(event) = &events[0] {
+            assert_eq!(
+                event.paths.last().unwrap().to_string_lossy(),
+                backlog_file.to_string_lossy()
+            );
+        } else {
+            unreachable!();
+        }
+
+        // The sub dir is scanned too when recursive.
+        let spy = builder().recursive(true).build()?;
+        let (tx, rx) = mpsc::channel();
+        spy.scan(tx)?.join().unwrap();
+        assert_eq!(rx.into_iter().count(), 2);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_on_error() -> Result<()> {
+        use std::{os::unix::fs::symlink, sync::Mutex};
+
+        use tracing_subscriber::{fmt::Layer, prelude::*, Registry};
+
+        let tmp = env::current_dir()?.join("test").join("test_walk_on_error");
+        remove_dir_all(&tmp).unwrap_or_default();
+        create_dir_all(&tmp)?;
+        File::create(tmp.join("test.txt"))?;
+        // Following a broken symlink is a traversal error.
+        symlink(tmp.join("not_found"), tmp.join("broken"))?;
+
+        let build = |on_error| {
+            Spy::builder("test_walk_on_error")
+                .input(&tmp)
+                .walk(
+                    Walk::default()
+                        .min_depth(1)
+                        .follow_symlinks(true)
+                        .on_error(on_error),
+                )
+                .build()
+        };
+
+        let spy = build(WalkOnError::Skip)?;
+        let (tx, rx) = mpsc::channel();
+        spy.walk_events(tx)?;
+        assert_eq!(rx.into_iter().count(), 1);
+
+        let spy = build(WalkOnError::Log)?;
+        let log_path = tmp.with_file_name("test_walk_on_error.log");
+        let layer = Layer::default()
+            .with_writer(Mutex::new(File::create(&log_path)?))
+            .with_ansi(false);
+        let (tx, rx) = mpsc::channel();
+        tracing::subscriber::with_default(Registry::default().with(layer), || spy.walk_events(tx))?;
+        assert_eq!(rx.into_iter().count(), 1);
+        let log = std::fs::read_to_string(&log_path)?;
+        assert!(log.contains("WARN"), "{}", log);
+        assert!(log.contains("broken"), "{}", log);
+
+        let spy = build(WalkOnError::Fail)?;
+        let (tx, _rx) = mpsc::channel();
+        let message = format!("{:?}", spy.walk_events(tx).unwrap_err());
+        assert!(message.contains("broken"), "{}", message);
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_max_age_secs() -> Result<()> {
+        let tmp = env::current_dir()?
+            .join("test")
+            .join("test_walk_max_age_secs");
+        remove_dir_all(&tmp).unwrap_or_default();
+        create_dir_all(&tmp)?;
+        let old_file = tmp.join("old.txt");
+        let new_file = tmp.join("new.txt");
+        File::create(&old_file)?.set_modified(SystemTime::now() - Duration::from_secs(3600))?;
+        File::create(&new_file)?;
+
+        let builder = || {
+            Spy::builder("test_walk_max_age_secs").input(&tmp).walk(
+                Walk::default()
+                    .min_depth(1)
+                    .max_age(Duration::from_secs(60)),
+            )
+        };
+        let spy = builder().build()?;
+        let walk = |spy: &Spy| -> Result<Vec<(PathBuf, Option<String>)>> {
+            let (tx, rx) = mpsc::channel();
+            spy.walk_events(tx)?;
+            let mut events = rx
+                .into_iter()
+                .map(|message| match message {
+                    Message::Event(event) => {
+                        (event.paths[0].clone(), event.info().map(String::from))
+                    }
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            events.sort();
+            Ok(events)
+        };
+        assert_eq!(
+            walk(&spy)?,
+            vec![(new_file.clone(), Some(WALK_EVENT_INFO.into()))]
+        );
+
+        // Sent apart only to write the marker.
+        let spy = builder().skip_marker("{{ event_path }}.skipped").build()?;
+        assert_eq!(
+            walk(&spy)?,
+            vec![
+                (new_file, Some(WALK_EVENT_INFO.into())),
+                (old_file, Some(WALK_OLD_EVENT_INFO.into())),
+            ]
+        );
         Ok(())
     }
 }
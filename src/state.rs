@@ -0,0 +1,120 @@
+// =============================================================================
+// File        : state.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 09:12:41.
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log_derive::logfn;
+
+pub type Cache = Arc<Mutex<HashMap<String, Instant>>>;
+
+type State = HashMap<String, HashMap<String, u64>>;
+
+#[logfn(Trace)]
+fn to_unix_millis(instant: Instant) -> u64 {
+    let time = SystemTime::now() - Instant::now().saturating_duration_since(instant);
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[logfn(Trace)]
+fn from_unix_millis(millis: u64) -> Option<Instant> {
+    let time = UNIX_EPOCH + Duration::from_millis(millis);
+    let elapsed = SystemTime::now().duration_since(time).unwrap_or_default();
+    Instant::now().checked_sub(elapsed)
+}
+
+#[logfn(Debug)]
+pub fn load<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Cache>> {
+    if !path.as_ref().is_file() {
+        return Ok(HashMap::new());
+    }
+    let state: State = toml::from_str(&fs::read_to_string(path)?)?;
+    Ok(state
+        .into_iter()
+        .map(|(name, keys)| {
+            let cache = keys
+                .into_iter()
+                .filter_map(|(k, v)| from_unix_millis(v).map(|i| (k, i)))
+                .collect::<HashMap<_, _>>();
+            (name, Arc::new(Mutex::new(cache)))
+        })
+        .collect())
+}
+
+/// The caches at a time, compared to skip the saves without changes.
+type Snapshot = HashMap<String, HashMap<String, Instant>>;
+
+#[logfn(Trace)]
+fn snapshot(caches: &HashMap<String, Cache>) -> Snapshot {
+    caches
+        .iter()
+        .map(|(name, cache)| (name.clone(), cache.lock().unwrap().clone()))
+        .collect()
+}
+
+/// Saves the caches to the state file, only when they changed since the last save.
+#[derive(Debug)]
+pub struct Saver {
+    path: PathBuf,
+    caches: HashMap<String, Cache>,
+    saved: Mutex<Snapshot>,
+}
+
+impl Saver {
+    /// `caches` as loaded are not saved until they change.
+    pub fn new<P: AsRef<Path>>(path: P, caches: HashMap<String, Cache>) -> Self {
+        let saved = Mutex::new(snapshot(&caches));
+        Self {
+            path: path.as_ref().to_path_buf(),
+            caches,
+            saved,
+        }
+    }
+
+    /// Returns whether the state file was written.
+    #[logfn(Trace)]
+    pub fn save(&self) -> Result<bool> {
+        let mut saved = self.saved.lock().unwrap();
+        let snapshot = snapshot(&self.caches);
+        if snapshot == *saved {
+            return Ok(false);
+        }
+        write(&self.path, &snapshot)?;
+        *saved = snapshot;
+        Ok(true)
+    }
+}
+
+#[logfn(Trace)]
+fn write(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let state = snapshot
+        .iter()
+        .map(|(name, keys)| {
+            let keys = keys
+                .iter()
+                .map(|(k, v)| (k.clone(), to_unix_millis(*v)))
+                .collect::<HashMap<_, _>>();
+            (name.clone(), keys)
+        })
+        .collect::<State>();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Renamed over the state file, not to leave it half written.
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, toml::to_string(&state)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
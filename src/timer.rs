@@ -0,0 +1,114 @@
+// =============================================================================
+// File        : timer.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 18:05:40.
+// =============================================================================
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tracing::debug;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs the jobs after their delay on one thread, so the waiting does not park a pool thread.
+///
+/// The jobs should be short, e.g. spawning to a pool. The pending jobs are still run after dropped.
+#[derive(Debug)]
+pub struct Timer {
+    tx: mpsc::Sender<(Instant, Job)>,
+}
+
+impl Timer {
+    pub fn new(name: &str) -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<(Instant, Job)>();
+        let name = format!("spyrun-timer-{}", name);
+        thread::Builder::new().name(name.clone()).spawn(move || {
+            // The deadlines with the sequence of the jobs, the earliest first.
+            let mut queue = BinaryHeap::<Reverse<(Instant, u64)>>::new();
+            let mut jobs = HashMap::<u64, Job>::new();
+            let mut seq = 0;
+            let mut connected = true;
+            while connected || !queue.is_empty() {
+                let received = match queue.peek() {
+                    Some(Reverse((deadline, _))) if connected => {
+                        rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                    Some(Reverse((deadline, _))) => {
+                        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                        Err(RecvTimeoutError::Timeout)
+                    }
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok((deadline, job)) => {
+                        queue.push(Reverse((deadline, seq)));
+                        jobs.insert(seq, job);
+                        seq += 1;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => connected = false,
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+                let now = Instant::now();
+                while queue
+                    .peek()
+                    .is_some_and(|Reverse((deadline, _))| *deadline <= now)
+                {
+                    let Reverse((_, i)) = queue.pop().unwrap();
+                    (jobs.remove(&i).unwrap())();
+                }
+            }
+            debug!("{} stopped", name);
+        })?;
+        Ok(Self { tx })
+    }
+
+    /// Runs `job` after `delay`.
+    pub fn schedule<F: FnOnce() + Send + 'static>(&self, delay: Duration, job: F) {
+        let job: Job = Box::new(job);
+        // The thread lives until all senders are dropped.
+        self.tx.send((Instant::now() + delay, job)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+
+    use super::Timer;
+
+    #[test]
+    fn test_timer() -> Result<()> {
+        let timer = Timer::new("test_timer")?;
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        for (name, delay) in [("c", 300), ("a", 100), ("b", 200), ("a2", 100)] {
+            let tx = tx.clone();
+            timer.schedule(Duration::from_millis(delay), move || {
+                tx.send((name, start.elapsed())).unwrap()
+            });
+        }
+        // The pending jobs are run after dropped.
+        drop(timer);
+        drop(tx);
+
+        let fired = rx.into_iter().collect::<Vec<_>>();
+        let names = fired.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "a2", "b", "c"]);
+        for ((_, elapsed), min) in fired.iter().zip([100, 100, 200, 300]) {
+            assert!(*elapsed >= Duration::from_millis(min), "{:?}", fired);
+        }
+        Ok(())
+    }
+}
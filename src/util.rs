@@ -7,10 +7,15 @@
 #[cfg(windows)]
 use std::os::windows::process::CommandExt;
 use std::{
-    collections::HashMap,
-    env,
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    env, fs,
+    io::Read,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Output, Stdio},
+    sync::{Arc, LazyLock, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
 use aead::generic_array::GenericArray;
@@ -18,17 +23,52 @@ use aes_gcm_siv::{
     aead::{Aead, KeyInit},
     Aes256GcmSiv, Nonce,
 };
-use anyhow::Result;
+use anyhow::{bail, Context as _, Result};
 use base64::{engine::general_purpose, Engine as _};
 use log_derive::logfn;
 #[cfg(windows)]
 use normpath::PathExt;
 use path_slash::{PathBufExt as _, PathExt as _};
-use tera::{Context, Tera, Value};
+use regex::{Captures, Regex};
+use tera::{Context, Map, Tera, Value};
 use tracing::{debug, trace};
 #[cfg(windows)]
+use winreg::{
+    enums::{
+        RegType, HKEY_CLASSES_ROOT, HKEY_CURRENT_CONFIG, HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE,
+        HKEY_USERS,
+    },
+    RegKey,
+};
+#[cfg(windows)]
 const CREATE_NO_WINDOW: u32 = 0x08000000;
 
+type LookupTable = HashMap<String, HashMap<String, String>>;
+/// The tables by path, with the modified time read.
+type LookupCache = HashMap<PathBuf, (SystemTime, Arc<LookupTable>)>;
+
+/// The default of `cfg.render_timeout`.
+pub const DEFAULT_RENDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a `ps()` / `psf()` is checked against the render deadline.
+const RENDER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+thread_local! {
+    /// The deadline and the timeout of the renders on this thread, see `RenderDeadline`.
+    static RENDER_DEADLINE: Cell<Option<(Instant, Duration)>> = const { Cell::new(None) };
+}
+
+static LOOKUP_CACHE: LazyLock<Mutex<LookupCache>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The plaintexts returned by `dec()`, replaced by `scrub` in the logs.
+static SECRETS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// The min length of a secret replaced inside a longer value, shorter ones (e.g. `1`) would mangle any text.
+const SCRUB_MIN_LEN: usize = 4;
+
+static ENV_VAR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{(\w+)\}|\$(\w+)|%(\w+)%").unwrap());
+
 const KEY: &[u8; 32] = b"an example very very secret key.";
 const NONCE: &[u8; 12] = b"unique nonce";
 
@@ -36,32 +76,34 @@ const NONCE: &[u8; 12] = b"unique nonce";
 pub fn powershell(script: &str) -> Result<String, String> {
     let script = format!(
         "& {{ chcp 65001 | Out-Null; [Console]::OutputEncoding = [System.Text.Encoding]::GetEncoding('utf-8'); {} }}",
-        &script
+        script
     );
     debug!("{:?}", &script);
 
     #[cfg(windows)]
-    let output = Command::new("powershell")
-        .creation_flags(CREATE_NO_WINDOW)
-        .arg("-NoProfile")
-        .arg("-WindowStyle")
-        .arg("Hidden")
-        .arg("-ExecutionPolicy")
-        .arg("ByPass")
-        .arg("-Command")
-        .arg(&script)
-        .output()
-        .expect("failed to execute process !");
+    let output = output_until_deadline(
+        "ps",
+        Command::new("powershell")
+            .creation_flags(CREATE_NO_WINDOW)
+            .arg("-NoProfile")
+            .arg("-WindowStyle")
+            .arg("Hidden")
+            .arg("-ExecutionPolicy")
+            .arg("ByPass")
+            .arg("-Command")
+            .arg(&script),
+    )?;
 
     #[cfg(not(windows))]
-    let output = Command::new("pwsh")
-        .arg("-NoProfile")
-        .arg("-ExecutionPolicy")
-        .arg("ByPass")
-        .arg("-Command")
-        .arg(&script)
-        .output()
-        .expect("failed to execute process !");
+    let output = output_until_deadline(
+        "ps",
+        Command::new("pwsh")
+            .arg("-NoProfile")
+            .arg("-ExecutionPolicy")
+            .arg("ByPass")
+            .arg("-Command")
+            .arg(&script),
+    )?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -75,27 +117,29 @@ pub fn powershell(script: &str) -> Result<String, String> {
 #[logfn(Debug)]
 pub fn powershell_file(script_path: &str) -> Result<String, String> {
     #[cfg(windows)]
-    let output = Command::new("powershell")
-        .creation_flags(CREATE_NO_WINDOW)
-        .arg("-NoProfile")
-        .arg("-WindowStyle")
-        .arg("Hidden")
-        .arg("-ExecutionPolicy")
-        .arg("ByPass")
-        .arg("-File")
-        .arg(script_path)
-        .output()
-        .expect("failed to execute process !");
+    let output = output_until_deadline(
+        "psf",
+        Command::new("powershell")
+            .creation_flags(CREATE_NO_WINDOW)
+            .arg("-NoProfile")
+            .arg("-WindowStyle")
+            .arg("Hidden")
+            .arg("-ExecutionPolicy")
+            .arg("ByPass")
+            .arg("-File")
+            .arg(script_path),
+    )?;
 
     #[cfg(not(windows))]
-    let output = Command::new("pwsh")
-        .arg("-NoProfile")
-        .arg("-ExecutionPolicy")
-        .arg("ByPass")
-        .arg("-File")
-        .arg(script_path)
-        .output()
-        .expect("failed to execute process !");
+    let output = output_until_deadline(
+        "psf",
+        Command::new("pwsh")
+            .arg("-NoProfile")
+            .arg("-ExecutionPolicy")
+            .arg("ByPass")
+            .arg("-File")
+            .arg(script_path),
+    )?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -118,37 +162,68 @@ pub fn insert_file_context<P: AsRef<Path>>(
         p = std::env::current_dir()?.join(p);
     }
     #[cfg(windows)]
+    let p = strip_verbatim(&p);
+    #[cfg(windows)]
     let normpath = p.normalize_virtually()?;
     #[cfg(windows)]
     trace!("normpath: {:?}", normpath);
     #[cfg(windows)]
     let p = PathBuf::from(normpath);
-    context.insert(format!("{}_path", &prefix), &p.to_slash_lossy());
+    // A root path (`/`, `C:\`, `\\server\share`) has no parent nor file name.
+    let dir = p.parent().unwrap_or(&p);
+    context.insert(format!("{}_path", prefix), &p.to_slash_lossy());
     // context.insert(format!("{}_path", &prefix), &p.to_string_lossy());
     context.insert(
-        format!("{}_dir", &prefix),
-        &p.parent().unwrap().to_slash_lossy(),
+        format!("{}_dir", prefix),
+        &dir.to_slash_lossy(),
         // &p.parent().unwrap().to_string_lossy(),
     );
     context.insert(
-        format!("{}_dirname", &prefix),
-        &p.parent().unwrap().file_name().unwrap().to_string_lossy(),
+        format!("{}_dirname", prefix),
+        &dir.file_name().unwrap_or_default().to_string_lossy(),
     );
     context.insert(
-        format!("{}_name", &prefix),
-        &p.file_name().unwrap().to_string_lossy(),
+        format!("{}_name", prefix),
+        &p.file_name().unwrap_or_default().to_string_lossy(),
     );
     context.insert(
-        format!("{}_stem", &prefix),
-        &p.file_stem().unwrap().to_string_lossy(),
+        format!("{}_stem", prefix),
+        &p.file_stem().unwrap_or_default().to_string_lossy(),
     );
     context.insert(
-        format!("{}_ext", &prefix),
+        format!("{}_ext", prefix),
         &p.extension().unwrap_or_default().to_string_lossy(),
     );
+    context.insert(
+        format!("{}_size", prefix),
+        &fs::metadata(&p).map(|m| m.len()).unwrap_or_default(),
+    );
     Ok(())
 }
 
+/// Converts an extended-length path (`\\?\C:\...`, `\\?\UNC\server\share\...`) to its usual form.
+#[cfg(windows)]
+pub fn strip_verbatim(p: &Path) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    let mut components = p.components();
+    let prefix = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(disk) => format!("{}:", disk as char),
+            Prefix::VerbatimUNC(server, share) => format!(
+                r"\\{}\{}",
+                server.to_string_lossy(),
+                share.to_string_lossy()
+            ),
+            _ => return p.to_path_buf(),
+        },
+        _ => return p.to_path_buf(),
+    };
+    let mut stripped = PathBuf::from(format!(r"{}\", prefix));
+    stripped.extend(components.filter(|c| *c != Component::RootDir));
+    stripped
+}
+
 #[logfn(Debug)]
 pub fn insert_default_context(context: &mut Context) {
     context.insert("spy_name", "{{ spy_name }}");
@@ -156,11 +231,13 @@ pub fn insert_default_context(context: &mut Context) {
     context.insert("output", "{{ output }}");
     context.insert("event_kind", "{{ event_kind }}");
     context.insert("event_path", "{{ event_path }}");
+    context.insert("event_path_from", "{{ event_path_from }}");
     context.insert("event_dir", "{{ event_dir }}");
     context.insert("event_dirname", "{{ event_dirname }}");
     context.insert("event_name", "{{ event_name }}");
     context.insert("event_stem", "{{ event_stem }}");
     context.insert("event_ext", "{{ event_ext }}");
+    context.insert("event_size", "{{ event_size }}");
     context.insert("stop_path", "{{ stop_path }}");
     context.insert("stop_dir", "{{ stop_dir }}");
     context.insert("stop_dirname", "{{ stop_dirname }}");
@@ -174,6 +251,7 @@ pub fn insert_default_context(context: &mut Context) {
     context.insert("stop_force_stem", "{{ stop_force_stem }}");
     context.insert("stop_force_ext", "{{ stop_force_ext }}");
     context.insert("log_path", "{{ log_path }}");
+    context.insert("log_base_path", "{{ log_base_path }}");
     context.insert("log_dir", "{{ log_dir }}");
     context.insert("log_dirname", "{{ log_dirname }}");
     context.insert("log_name", "{{ log_name }}");
@@ -185,23 +263,222 @@ pub fn insert_default_context(context: &mut Context) {
 pub fn render_vars(context: &mut Context, toml_str: &str) -> Result<()> {
     let toml_value: toml::Value = toml::from_str(toml_str)?;
     if let Some(vars) = toml_value.get("vars") {
-        let table = vars
-            .as_table()
-            .ok_or_else(|| anyhow::Error::msg("Expected a table for 'vars'"))?;
-        for (k, v) in table.iter() {
-            let mut tera_key = new_tera("key", k)?;
-            let rendered_key = tera_key.render_str(k, context)?;
-            let v_str = v
-                .as_str()
-                .ok_or_else(|| anyhow::Error::msg("Expected a string for 'value'"))?;
-            let mut tera_value = new_tera("value", v_str)?;
-            let rendered_value = tera_value.render_str(v_str, context)?;
-            context.insert(rendered_key, &rendered_value);
+        render_vars_table(context, vars)?;
+    }
+    Ok(())
+}
+
+#[logfn(Trace)]
+pub fn render_vars_table(context: &mut Context, vars: &toml::Value) -> Result<()> {
+    let table = vars
+        .as_table()
+        .ok_or_else(|| anyhow::Error::msg("Expected a table for 'vars'"))?;
+    for (k, v) in table.iter() {
+        let rendered_key = render(&new_tera("key", k)?, "key", context)?;
+        let rendered_value = render_var_value(v, context)
+            .with_context(|| format!("Failed to render var `{}`", rendered_key))?;
+        // Kept through the rendering of the config, and never resolved.
+        let placeholder = format!("{{{{ {} }}}}", rendered_key);
+        if rendered_value.to_string().contains(&placeholder) {
+            bail!("var `{}` references itself: {}", &rendered_key, v);
         }
+        context.insert(rendered_key, &rendered_value);
     }
     Ok(())
 }
 
+/// Renders the strings in `v`. The other types are kept, so they can be compared in templates.
+fn render_var_value(v: &toml::Value, context: &Context) -> Result<toml::Value> {
+    Ok(match v {
+        toml::Value::String(v_str) => {
+            toml::Value::String(render(&new_tera("value", v_str)?, "value", context)?)
+        }
+        toml::Value::Array(array) => toml::Value::Array(
+            array
+                .iter()
+                .map(|v| render_var_value(v, context))
+                .collect::<Result<_>>()?,
+        ),
+        toml::Value::Table(table) => toml::Value::Table(
+            table
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), render_var_value(v, context)?)))
+                .collect::<Result<_>>()?,
+        ),
+        toml::Value::Datetime(datetime) => toml::Value::String(datetime.to_string()),
+        v => v.clone(),
+    })
+}
+
+/// Removes the `[consts]` table from `toml_str`, so its values are not rendered with the config.
+#[logfn(Trace)]
+pub fn take_consts(toml_str: &str) -> Result<(String, toml::Table)> {
+    let mut table: toml::Table = toml::from_str(toml_str)?;
+    match table.remove("consts") {
+        None => Ok((toml_str.to_string(), toml::Table::new())),
+        Some(toml::Value::Table(consts)) => Ok((toml::to_string(&table)?, consts)),
+        Some(_) => Err(anyhow::Error::msg("Expected a table for 'consts'")),
+    }
+}
+
+/// Inserts `{{ key }}` for each const, so the references are kept through the rendering of the config.
+pub fn insert_const_placeholders(context: &mut Context, consts: &toml::Table) {
+    for k in consts.keys() {
+        context.insert(k, &format!("{{{{ {} }}}}", k));
+    }
+}
+
+/// Inserts the consts verbatim, for the templates rendered per execution.
+pub fn insert_consts(context: &mut Context, consts: &toml::Table) {
+    for (k, v) in consts.iter() {
+        context.insert(k, &toml_to_value(v));
+    }
+}
+
+/// Inserts the `[context]` table as is under `context`, e.g. `{{ context.customer.name }}`.
+#[logfn(Trace)]
+pub fn insert_toml_context(context: &mut Context, toml_str: &str) -> Result<()> {
+    let toml_value: toml::Value = toml::from_str(toml_str)?;
+    if let Some(table) = toml_value.get("context") {
+        insert_context_table(context, table)?;
+    }
+    Ok(())
+}
+
+/// Merges `table` into `context` of the tera context. The nested tables are merged by key.
+#[logfn(Trace)]
+pub fn insert_context_table(context: &mut Context, table: &toml::Value) -> Result<()> {
+    if !table.is_table() {
+        return Err(anyhow::Error::msg("Expected a table for 'context'"));
+    }
+    let mut value = context
+        .get("context")
+        .cloned()
+        .unwrap_or_else(|| Value::Object(Map::new()));
+    merge_value(&mut value, toml_to_value(table));
+    context.insert("context", &value);
+    Ok(())
+}
+
+/// `v` as the tera value. The datetimes are strings.
+fn toml_to_value(v: &toml::Value) -> Value {
+    match v {
+        toml::Value::String(s) => Value::String(s.clone()),
+        toml::Value::Integer(i) => Value::from(*i),
+        toml::Value::Float(f) => Value::from(*f),
+        toml::Value::Boolean(b) => Value::Bool(*b),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) => Value::Array(array.iter().map(toml_to_value).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .iter()
+                .map(|(k, v)| (k.clone(), toml_to_value(v)))
+                .collect(),
+        ),
+    }
+}
+
+fn merge_value(base: &mut Value, value: Value) {
+    match (base, value) {
+        (Value::Object(base), Value::Object(value)) => {
+            for (k, v) in value {
+                match base.get_mut(&k) {
+                    Some(b) => merge_value(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+/// Expand `$VAR`, `${VAR}` and `%VAR%` with the environment variables. Unset variables are left as is.
+#[logfn(Trace)]
+pub fn expand_env(s: &str) -> String {
+    ENV_VAR
+        .replace_all(s, |caps: &Captures| {
+            let name = caps
+                .get(1)
+                .or(caps.get(2))
+                .or(caps.get(3))
+                .unwrap()
+                .as_str();
+            env::var(name).unwrap_or_else(|_| caps[0].to_string())
+        })
+        .to_string()
+}
+
+/// The Windows drive path `s` (`C:\dir`, `C:/dir` or `\\?\C:\dir`) as a POSIX path under `root`,
+/// e.g. `/mnt/c/dir` for WSL (`root = "/mnt/"`) or `/c/dir` for Git Bash (`root = "/"`).
+/// None if `s` is not a drive path, e.g. a relative path or an option.
+pub fn to_posix_path(s: &str, root: &str) -> Option<String> {
+    let s = s.strip_prefix(r"\\?\").unwrap_or(s);
+    let mut chars = s.chars();
+    let drive = chars.next().filter(|c| c.is_ascii_alphabetic())?;
+    if chars.next() != Some(':') {
+        return None;
+    }
+    let rest = chars.as_str();
+    if !rest.is_empty() && !rest.starts_with(['\\', '/']) {
+        return None;
+    }
+    Some(format!(
+        "{}{}{}",
+        root,
+        drive.to_ascii_lowercase(),
+        rest.replace('\\', "/")
+    ))
+}
+
+/// Returns a random (version 4) UUID string.
+#[logfn(Trace)]
+pub fn new_exec_id() -> String {
+    let mut bytes = rand::random::<[u8; 16]>();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// The host name of the system, as `hostname` prints. `SPYRUN_HOSTNAME` overrides it.
+#[logfn(Debug)]
+pub fn hostname() -> String {
+    if let Ok(hostname) = env::var("SPYRUN_HOSTNAME") {
+        return hostname;
+    }
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+/// Whether a process with `pid` is running.
+#[logfn(Trace)]
+pub fn process_exists(pid: u32) -> bool {
+    #[cfg(windows)]
+    let output = Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH", "/FO", "CSV"])
+        .output();
+    #[cfg(not(windows))]
+    let output = Command::new("ps").args(["-p", &pid.to_string()]).output();
+    match output {
+        #[cfg(windows)]
+        Ok(o) => String::from_utf8_lossy(&o.stdout).contains(&format!("\"{}\"", pid)),
+        #[cfg(not(windows))]
+        Ok(o) => o.status.success(),
+        Err(_) => false,
+    }
+}
+
 #[logfn(Trace)]
 pub fn new_tera(name: &str, content: &str) -> Result<Tera> {
     let mut tera = Tera::default();
@@ -212,70 +489,170 @@ pub fn new_tera(name: &str, content: &str) -> Result<Tera> {
     tera.register_function("dec", dec_function);
     tera.register_function("ps", powershell_function);
     tera.register_function("psf", powershell_file_function);
+    tera.register_function("reg", reg_function);
+    tera.register_function("lookup", lookup_function);
+    tera.register_function("file_exists", file_exists_function);
+    tera.register_function("hostname", hostname_function);
     Ok(tera)
 }
 
+/// Renders `name` of `tera`. Its `ps()` / `psf()` are bounded by the `RenderDeadline` of this thread.
+pub fn render(tera: &Tera, name: &str, context: &Context) -> Result<String> {
+    Ok(tera.render(name, context)?)
+}
+
+/// Bounds the `ps()` / `psf()` rendered on this thread by `cfg.render_timeout` until dropped,
+/// e.g. once for a config load or for the templates of a command.
+/// Without one, each call has `DEFAULT_RENDER_TIMEOUT`.
+#[derive(Debug)]
+pub struct RenderDeadline(Option<(Instant, Duration)>);
+
+impl RenderDeadline {
+    pub fn new(timeout: Duration) -> Self {
+        Self(RENDER_DEADLINE.replace(Some((Instant::now() + timeout, timeout))))
+    }
+}
+
+impl Drop for RenderDeadline {
+    fn drop(&mut self) {
+        RENDER_DEADLINE.set(self.0);
+    }
+}
+
+/// Reads `pipe` of a child to the end, it is closed when the child exits or is killed.
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buf).unwrap_or_default();
+        }
+        buf
+    })
+}
+
+/// Runs `command` of the function `f` to its output, killed at the render deadline, e.g. a `ps()` that never returns.
+fn output_until_deadline(f: &str, command: &mut Command) -> Result<Output, String> {
+    let (deadline, timeout) = RENDER_DEADLINE.get().unwrap_or_else(|| {
+        (
+            Instant::now() + DEFAULT_RENDER_TIMEOUT,
+            DEFAULT_RENDER_TIMEOUT,
+        )
+    });
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("{}: failed to execute powershell: {}", f, e))?;
+    let (stdout, stderr) = (
+        read_pipe(child.stdout.take()),
+        read_pipe(child.stderr.take()),
+    );
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                return Ok(Output {
+                    status,
+                    stdout: stdout.join().unwrap_or_default(),
+                    stderr: stderr.join().unwrap_or_default(),
+                })
+            }
+            Ok(None) if Instant::now() < deadline => thread::sleep(RENDER_POLL_INTERVAL),
+            Ok(None) => {
+                child.kill().unwrap_or_default();
+                child.wait().ok();
+                return Err(format!(
+                    "{}: killed after the render timeout of {:?}",
+                    f, timeout
+                ));
+            }
+            Err(e) => return Err(format!("{}: failed to wait powershell: {}", f, e)),
+        }
+    }
+}
+
 #[logfn(Trace)]
-fn env_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
-    let arg = args
-        .get("arg")
-        .ok_or_else(|| tera::Error::msg("arg is required"))?
+fn str_arg<'a>(f: &str, args: &'a HashMap<String, Value>, name: &str) -> tera::Result<&'a str> {
+    args.get(name)
+        .ok_or_else(|| tera::Error::msg(format!("{}: {} is required", f, name)))?
         .as_str()
-        .unwrap();
+        .ok_or_else(|| tera::Error::msg(format!("{}: {} must be a string", f, name)))
+}
+
+#[logfn(Trace)]
+fn env_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let arg = str_arg("env", args, "arg")?;
     Ok(Value::String(env::var(arg).unwrap_or_default()))
 }
 
 fn setenv_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
-    if let (Some(key), Some(value)) = (args.get("key"), args.get("value")) {
-        if let (Some(key_str), Some(value_str)) = (key.as_str(), value.as_str()) {
-            env::set_var(key_str, value_str);
-            return Ok(Value::String(format!("Set {} to {}", key_str, value_str)));
-        }
-    }
-    Err("Invalid arguments".into())
+    let key = str_arg("setenv", args, "key")?;
+    let value = str_arg("setenv", args, "value")?;
+    env::set_var(key, value);
+    Ok(Value::String(format!("Set {} to {}", key, value)))
 }
 
 #[logfn(Trace)]
 fn enc_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
-    let arg = args
-        .get("arg")
-        .ok_or_else(|| tera::Error::msg("arg is required"))?
-        .as_str()
-        .unwrap();
+    let arg = str_arg("enc", args, "arg")?;
 
     let bytes = arg.as_bytes();
     let key = GenericArray::from_slice(KEY);
     let cipher = Aes256GcmSiv::new(key);
     let nonce = Nonce::from_slice(NONCE);
-    let ciphertext = cipher.encrypt(nonce, bytes.as_ref()).unwrap();
+    let ciphertext = cipher
+        .encrypt(nonce, bytes.as_ref())
+        .map_err(|e| tera::Error::msg(format!("enc: failed to encrypt arg: {}", e)))?;
 
     Ok(Value::String(general_purpose::STANDARD.encode(ciphertext)))
 }
 
-#[logfn(Trace)]
+// Not `logfn`, it would log the plaintext.
 fn dec_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
-    let arg = args
-        .get("arg")
-        .ok_or_else(|| tera::Error::msg("arg is required"))?
-        .as_str()
-        .unwrap();
+    let arg = str_arg("dec", args, "arg")?;
 
-    let bytes = general_purpose::STANDARD.decode(arg).unwrap();
+    let bytes = general_purpose::STANDARD
+        .decode(arg)
+        .map_err(|e| tera::Error::msg(format!("dec: arg is not valid base64: {}", e)))?;
     let key = GenericArray::from_slice(KEY);
     let cipher = Aes256GcmSiv::new(key);
     let nonce = Nonce::from_slice(NONCE);
-    let plaintext = cipher.decrypt(nonce, bytes.as_ref()).unwrap();
+    let plaintext = cipher
+        .decrypt(nonce, bytes.as_ref())
+        .map_err(|e| tera::Error::msg(format!("dec: failed to decrypt arg: {}", e)))?;
 
-    Ok(Value::String(String::from_utf8(plaintext).unwrap()))
+    let plaintext = String::from_utf8(plaintext)
+        .map_err(|e| tera::Error::msg(format!("dec: decrypted arg is not utf-8: {}", e)))?;
+    if !plaintext.is_empty() {
+        SECRETS.lock().unwrap().insert(plaintext.clone());
+    }
+    Ok(Value::String(plaintext))
+}
+
+/// `value` for the logs, with every secret decrypted by `dec()` so far replaced by `***`.
+/// So a secret is scrubbed also when rendered through a var, or as a part of a field.
+/// A secret shorter than `SCRUB_MIN_LEN` is scrubbed only as the whole value.
+pub fn scrub(value: &str) -> String {
+    let secrets = SECRETS.lock().unwrap();
+    if secrets.contains(value) {
+        return "***".to_string();
+    }
+    let mut secrets = secrets
+        .iter()
+        .filter(|secret| secret.len() >= SCRUB_MIN_LEN && value.contains(secret.as_str()))
+        .collect::<Vec<_>>();
+    // The longest first, a secret may contain another.
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    secrets
+        .into_iter()
+        .fold(value.to_string(), |value, secret| {
+            value.replace(secret, "***")
+        })
 }
 
 #[logfn(Trace)]
 fn powershell_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
-    let arg = args
-        .get("arg")
-        .ok_or_else(|| tera::Error::msg("arg is required"))?
-        .as_str()
-        .unwrap();
+    let arg = str_arg("ps", args, "arg")?;
 
     let stdout = powershell(arg)?;
 
@@ -284,23 +661,188 @@ fn powershell_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
 
 #[logfn(Trace)]
 fn powershell_file_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
-    let arg = args
-        .get("arg")
-        .ok_or_else(|| tera::Error::msg("arg is required"))?
-        .as_str()
-        .unwrap();
+    let arg = str_arg("psf", args, "arg")?;
 
     let stdout = powershell_file(arg)?;
 
     Ok(Value::String(stdout))
 }
 
+#[logfn(Trace)]
+fn load_lookup_table(path: &Path) -> Result<LookupTable> {
+    let is_json = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"));
+    if is_json {
+        let json: HashMap<String, HashMap<String, Value>> =
+            serde_json::from_str(&fs::read_to_string(path)?)?;
+        return Ok(json
+            .into_iter()
+            .map(|(key, row)| {
+                let row = row
+                    .into_iter()
+                    .map(|(column, v)| match v {
+                        Value::String(s) => (column, s),
+                        v => (column, v.to_string()),
+                    })
+                    .collect();
+                (key, row)
+            })
+            .collect());
+    }
+
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let mut table = LookupTable::new();
+    for record in reader.records() {
+        let record = record?;
+        if let Some(key) = record.get(0) {
+            let row = headers
+                .iter()
+                .zip(record.iter())
+                .map(|(h, v)| (h.to_string(), v.to_string()))
+                .collect();
+            table.insert(key.to_string(), row);
+        }
+    }
+    Ok(table)
+}
+
+#[logfn(Trace)]
+fn lookup_table(path: &Path) -> Result<Arc<LookupTable>> {
+    let modified = fs::metadata(path)?.modified()?;
+    let mut cache = LOOKUP_CACHE.lock().unwrap();
+    if let Some((cached, table)) = cache.get(path) {
+        if cached == &modified {
+            return Ok(table.clone());
+        }
+    }
+    debug!("load lookup table: {:?}", path);
+    let table = Arc::new(load_lookup_table(path)?);
+    cache.insert(path.to_path_buf(), (modified, table.clone()));
+    Ok(table)
+}
+
+#[logfn(Trace)]
+fn file_exists_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let path = str_arg("file_exists", args, "path")?;
+    Ok(Value::Bool(Path::new(path).exists()))
+}
+
+/// `hostname()`, the same host name as `[hosts."PATTERN"]` matches.
+#[logfn(Trace)]
+fn hostname_function(_args: &HashMap<String, Value>) -> tera::Result<Value> {
+    Ok(Value::String(hostname()))
+}
+
+#[logfn(Trace)]
+fn lookup_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let file = str_arg("lookup", args, "file")?;
+    let key = str_arg("lookup", args, "key")?;
+    let column = str_arg("lookup", args, "column")?;
+
+    let table = lookup_table(Path::new(file))
+        .map_err(|e| tera::Error::msg(format!("lookup: failed to load {}: {}", file, e)))?;
+    match table.get(key).and_then(|row| row.get(column)) {
+        Some(v) => Ok(Value::String(v.clone())),
+        None => args.get("default").cloned().ok_or_else(|| {
+            tera::Error::msg(format!(
+                "lookup: key {} (column {}) is not found in {}",
+                key, column, file
+            ))
+        }),
+    }
+}
+
+#[cfg(windows)]
+#[logfn(Trace)]
+fn reg_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let key = str_arg("reg", args, "key")?;
+    let value = str_arg("reg", args, "value")?;
+
+    let (root, subkey) = key.split_once('\\').unwrap_or((key, ""));
+    let root = match root.to_uppercase().as_str() {
+        "HKLM" | "HKEY_LOCAL_MACHINE" => HKEY_LOCAL_MACHINE,
+        "HKCU" | "HKEY_CURRENT_USER" => HKEY_CURRENT_USER,
+        "HKCR" | "HKEY_CLASSES_ROOT" => HKEY_CLASSES_ROOT,
+        "HKU" | "HKEY_USERS" => HKEY_USERS,
+        "HKCC" | "HKEY_CURRENT_CONFIG" => HKEY_CURRENT_CONFIG,
+        _ => {
+            return Err(tera::Error::msg(format!(
+                "reg: unknown registry root: {}",
+                root
+            )))
+        }
+    };
+    let regkey = RegKey::predef(root)
+        .open_subkey(subkey)
+        .map_err(|e| tera::Error::msg(format!("reg: failed to open key {}: {}", key, e)))?;
+    let raw = regkey
+        .get_raw_value(value)
+        .map_err(|e| tera::Error::msg(format!("reg: failed to read {}\\{}: {}", key, value, e)))?;
+    let read_error =
+        |e: std::io::Error| tera::Error::msg(format!("reg: {}\\{}: {}", key, value, e));
+    let s = match raw.vtype {
+        RegType::REG_SZ | RegType::REG_EXPAND_SZ => {
+            regkey.get_value::<String, _>(value).map_err(read_error)?
+        }
+        RegType::REG_DWORD => regkey
+            .get_value::<u32, _>(value)
+            .map_err(read_error)?
+            .to_string(),
+        RegType::REG_QWORD => regkey
+            .get_value::<u64, _>(value)
+            .map_err(read_error)?
+            .to_string(),
+        t => {
+            return Err(tera::Error::msg(format!(
+                "reg: {}\\{} is not a string value: {:?}",
+                key, value, t
+            )))
+        }
+    };
+
+    Ok(Value::String(s))
+}
+
+#[cfg(not(windows))]
+#[logfn(Trace)]
+fn reg_function(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    str_arg("reg", args, "key")?;
+    str_arg("reg", args, "value")?;
+    Err(tera::Error::msg("reg: only supported on Windows"))
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{
+        env,
+        fs::{create_dir_all, write, File},
+        process::Command,
+        time::{Duration, Instant, SystemTime},
+    };
+
     use anyhow::Result;
     use tera::Context;
 
-    use crate::util::new_tera;
+    use crate::util::{
+        insert_context_table, insert_default_context, insert_toml_context, new_tera,
+        output_until_deadline, render, render_vars, scrub, RenderDeadline, RENDER_DEADLINE,
+    };
+
+    fn render_error(template: &str) -> String {
+        let e = new_tera("template", template)
+            .unwrap()
+            .render("template", &Context::new())
+            .unwrap_err();
+        let mut messages = vec![e.to_string()];
+        let mut source = std::error::Error::source(&e);
+        while let Some(e) = source {
+            messages.push(e.to_string());
+            source = e.source();
+        }
+        messages.join(": ")
+    }
 
     #[test]
     fn test_enc_dec() -> Result<()> {
@@ -312,4 +854,338 @@ mod tests {
         assert_eq!(result, "The encrypted text of Alice is EzB4qO+2K66gKXPBNRl7owf4EGpo\nThe decrypted text of EzB4qO+2K66gKXPBNRl7owf4EGpo is Alice");
         Ok(())
     }
+
+    #[test]
+    fn test_scrub() -> Result<()> {
+        assert_eq!(
+            scrub("user=scrub_user pass=scrub_pass"),
+            "user=scrub_user pass=scrub_pass"
+        );
+        let tera = new_tera(
+            "template",
+            "{{ dec(arg=enc(arg='scrub_pass')) }} {{ dec(arg=enc(arg='scrub_pass_long')) }}",
+        )?;
+        render(&tera, "template", &Context::new())?;
+        assert_eq!(
+            scrub("user=scrub_user pass=scrub_pass"),
+            "user=scrub_user pass=***"
+        );
+        // The longest first, not `***_long`.
+        assert_eq!(scrub("pass=scrub_pass_long"), "pass=***");
+
+        // A short secret only as the whole value.
+        let tera = new_tera("template", "{{ dec(arg=enc(arg='7')) }}")?;
+        render(&tera, "template", &Context::new())?;
+        assert_eq!(scrub("7"), "***");
+        assert_eq!(scrub("retry 7 of 17"), "retry 7 of 17");
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_reg() -> Result<()> {
+        let tera = new_tera(
+            "template",
+            r"{{ reg(key='HKLM\SOFTWARE\Microsoft\Windows\CurrentVersion', value='ProgramFilesDir') }}",
+        )?;
+        let result = tera.render("template", &Context::new())?;
+        assert_eq!(result, std::env::var("ProgramFiles")?);
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_reg_not_found() -> Result<()> {
+        let tera = new_tera(
+            "template",
+            r"{{ reg(key='HKLM\SOFTWARE\spyrun\not_found', value='not_found') }}",
+        )?;
+        assert!(tera.render("template", &Context::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_lookup");
+        create_dir_all(&tmp)?;
+        let csv = tmp.join("routes.csv");
+        let json = tmp.join("routes.json");
+        write(&csv, "code,dest\nA001,D:/a\nB002,D:/b\n")?;
+        write(&json, r#"{"A001": {"dest": "D:/a", "port": 8080}}"#)?;
+
+        let render = |template: &str| -> Result<String> {
+            Ok(new_tera("template", template)?.render("template", &Context::new())?)
+        };
+        let csv = csv.to_string_lossy();
+        let json = json.to_string_lossy();
+        assert_eq!(
+            render(&format!(
+                "{{{{ lookup(file='{}', key='B002', column='dest') }}}}",
+                csv
+            ))?,
+            "D:/b"
+        );
+        assert_eq!(
+            render(&format!(
+                "{{{{ lookup(file='{}', key='A001', column='port') }}}}",
+                json
+            ))?,
+            "8080"
+        );
+        assert_eq!(
+            render(&format!(
+                "{{{{ lookup(file='{}', key='C003', column='dest', default='D:/c') }}}}",
+                csv
+            ))?,
+            "D:/c"
+        );
+        assert!(render(&format!(
+            "{{{{ lookup(file='{}', key='C003', column='dest') }}}}",
+            csv
+        ))
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup_cache() -> Result<()> {
+        let tmp = env::current_dir()?.join("test").join("test_lookup_cache");
+        create_dir_all(&tmp)?;
+        let csv = tmp.join("routes.csv");
+        write(&csv, "code,dest\nA001,D:/old\n")?;
+        let modified = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&csv)?
+            .set_modified(modified)?;
+
+        let template = format!(
+            "{{{{ lookup(file='{}', key='A001', column='dest') }}}}",
+            csv.to_string_lossy()
+        );
+        let render = || -> Result<String> {
+            Ok(new_tera("template", &template)?.render("template", &Context::new())?)
+        };
+        assert_eq!(render()?, "D:/old");
+
+        // same mtime: cached table is used.
+        write(&csv, "code,dest\nA001,D:/new\n")?;
+        File::options()
+            .write(true)
+            .open(&csv)?
+            .set_modified(modified)?;
+        assert_eq!(render()?, "D:/old");
+
+        // mtime changed: table is reloaded.
+        File::options()
+            .write(true)
+            .open(&csv)?
+            .set_modified(SystemTime::now())?;
+        assert_eq!(render()?, "D:/new");
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_argument_errors() {
+        for (template, expected) in [
+            ("{{ env(arg=1) }}", "env: arg must be a string"),
+            ("{{ env() }}", "env: arg is required"),
+            (
+                "{{ setenv(key='a', value=1) }}",
+                "setenv: value must be a string",
+            ),
+            ("{{ enc(arg=true) }}", "enc: arg must be a string"),
+            ("{{ dec(arg=1) }}", "dec: arg must be a string"),
+            ("{{ ps(arg=1) }}", "ps: arg must be a string"),
+            ("{{ psf(arg=1) }}", "psf: arg must be a string"),
+            ("{{ reg(key=1, value='a') }}", "reg: key must be a string"),
+            (
+                "{{ lookup(file='a.csv', key=1, column='a') }}",
+                "lookup: key must be a string",
+            ),
+        ] {
+            let message = render_error(template);
+            assert!(message.contains(expected), "{}: {}", template, message);
+        }
+    }
+
+    #[test]
+    fn test_dec_malformed() {
+        let message = render_error("{{ dec(arg='not base64 !') }}");
+        assert!(
+            message.contains("dec: arg is not valid base64"),
+            "{}",
+            message
+        );
+        let message = render_error("{{ dec(arg='YWxpY2U=') }}");
+        assert!(
+            message.contains("dec: failed to decrypt arg"),
+            "{}",
+            message
+        );
+    }
+
+    #[cfg(windows)]
+    fn file_context(path: &str) -> Result<Vec<String>> {
+        let mut context = Context::new();
+        super::insert_file_context(path, "event", &mut context)?;
+        Ok(["path", "dir", "dirname", "name", "stem", "ext"]
+            .iter()
+            .map(|k| {
+                context
+                    .get(&format!("event_{}", k))
+                    .and_then(|v| v.as_str())
+                    .unwrap()
+                    .to_string()
+            })
+            .collect())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_insert_file_context_unc() -> Result<()> {
+        let expected = [
+            "//server/share/dir/file.txt",
+            "//server/share/dir",
+            "dir",
+            "file.txt",
+            "file",
+            "txt",
+        ];
+        assert_eq!(file_context(r"\\server\share\dir\file.txt")?, expected);
+        assert_eq!(
+            file_context(r"\\?\UNC\server\share\dir\file.txt")?,
+            expected
+        );
+        assert_eq!(
+            file_context(r"\\?\C:\dir\file.txt")?,
+            [
+                "C:/dir/file.txt",
+                "C:/dir",
+                "dir",
+                "file.txt",
+                "file",
+                "txt"
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_insert_file_context_drive_root() -> Result<()> {
+        assert_eq!(file_context(r"C:\")?, ["C:/", "C:/", "", "", "", ""]);
+        assert_eq!(
+            file_context(r"C:\dir")?,
+            ["C:/dir", "C:/", "", "dir", "dir", ""]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_vars_with_types() -> Result<()> {
+        let mut context = Context::new();
+        context.insert("base", "/base");
+        render_vars(
+            &mut context,
+            r#"
+[vars]
+count = 3
+debug = true
+dirs = ['{{ base }}/a', '{{ base }}/b']
+"#,
+        )?;
+        let render = |template: &str| -> Result<String> {
+            Ok(new_tera("template", template)?.render("template", &context)?)
+        };
+        assert_eq!(
+            render("{% if debug and count > 2 %}verbose{% endif %}")?,
+            "verbose"
+        );
+        assert_eq!(render("{{ count + 1 }}")?, "4");
+        assert_eq!(render("{{ dirs | join(sep=',') }}")?, "/base/a,/base/b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_vars_self_reference() {
+        let e = render_vars(&mut Context::new(), "[vars]\na = '{{ a }}x'\n").unwrap_err();
+        assert!(
+            format!("{:?}", e).contains("Failed to render var `a`"),
+            "{:?}",
+            e
+        );
+
+        // The placeholders of the default context are never resolved.
+        let mut context = Context::new();
+        insert_default_context(&mut context);
+        let e = render_vars(&mut context, "[vars]\ninput = '{{ input }}/x'\n").unwrap_err();
+        assert!(
+            e.to_string().contains("var `input` references itself"),
+            "{:?}",
+            e
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_render_deadline() {
+        let deadline = RenderDeadline::new(Duration::from_millis(200));
+        let start = Instant::now();
+        let e = output_until_deadline("ps", Command::new("sleep").arg("3")).unwrap_err();
+        assert_eq!(e, "ps: killed after the render timeout of 200ms");
+        assert!(start.elapsed() < Duration::from_secs(2));
+        drop(deadline);
+
+        // The default again.
+        assert_eq!(RENDER_DEADLINE.get(), None);
+        let output = output_until_deadline("ps", Command::new("echo").arg("fast")).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "fast\n");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_render_deadline() -> Result<()> {
+        let tera = new_tera("template", "{{ ps(arg='Start-Sleep 5') }}")?;
+        let deadline = RenderDeadline::new(Duration::from_millis(500));
+        let start = Instant::now();
+        let e = render(&tera, "template", &Context::new()).unwrap_err();
+        assert!(
+            format!("{:?}", e).contains("killed after the render timeout"),
+            "{:?}",
+            e
+        );
+        assert!(start.elapsed() < Duration::from_secs(4));
+        drop(deadline);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_toml_context() -> Result<()> {
+        let mut context = Context::new();
+        insert_toml_context(
+            &mut context,
+            r#"
+[context.customer]
+name = 'acme'
+dirs = ['in', 'out']
+
+[context.customer.limits]
+retries = 3
+"#,
+        )?;
+        // A host section overrides by key, the others are kept.
+        let host: toml::Value = toml::from_str("customer = { name = 'acme-web' }")?;
+        insert_context_table(&mut context, &host)?;
+        let render = |template: &str| -> Result<String> {
+            Ok(new_tera("template", template)?.render("template", &context)?)
+        };
+        assert_eq!(
+            render("/data/{{ context.customer.name }}/{{ context.customer.dirs[0] }}")?,
+            "/data/acme-web/in"
+        );
+        assert_eq!(render("{{ context.customer.limits.retries + 1 }}")?, "4");
+        assert!(insert_context_table(&mut context, &toml::Value::Integer(1)).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,98 @@
+// =============================================================================
+// File        : mod.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 17:10:12.
+// =============================================================================
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use spyrun::{Control, Engine, Settings, Vars};
+use walkdir::WalkDir;
+
+/// spyrun running in-process on a fixture config.
+pub struct Harness {
+    pub dir: PathBuf,
+    engine: Engine,
+}
+
+/// Copies `tests/fixtures/<name>.toml` to a fresh `test/integration/<name>/spyrun.toml`. Returns the dir.
+pub fn prepare(name: &str) -> Result<PathBuf> {
+    let dir = env::current_dir()?
+        .join("test")
+        .join("integration")
+        .join(name);
+    fs::remove_dir_all(&dir).unwrap_or_default();
+    fs::create_dir_all(&dir)?;
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(format!("{}.toml", name));
+    fs::copy(fixture, dir.join("spyrun.toml"))?;
+    Ok(dir)
+}
+
+impl Harness {
+    /// Loads `dir/spyrun.toml` like the cli and runs it in a thread.
+    pub fn start(dir: &Path) -> Result<Self> {
+        let settings = Settings::load(dir.join("spyrun.toml"), &Vars::new())?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            engine: Engine::start(settings)?,
+        })
+    }
+
+    /// The input dir of the spy `name`, created by `input_policy = 'create'`.
+    pub fn input(&self, name: &str) -> Result<PathBuf> {
+        let input = self.dir.join("input").join(name);
+        wait_until(|| Ok(input.is_dir()))?;
+        // The watcher starts after the input is created.
+        thread::sleep(Duration::from_millis(500));
+        Ok(input)
+    }
+
+    /// The stdout of the commands of the spy `name`, that have written it.
+    pub fn outputs(&self, name: &str) -> Vec<String> {
+        WalkDir::new(self.dir.join("output").join(name))
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().contains("_stdout_"))
+            .filter_map(|e| fs::read_to_string(e.path()).ok())
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    /// Waits until the spy `name` has `count` outputs.
+    pub fn wait_outputs(&self, name: &str, count: usize) -> Result<Vec<String>> {
+        wait_until(|| Ok(self.outputs(name).len() >= count))?;
+        Ok(self.outputs(name))
+    }
+
+    /// Sends `control` to the engine.
+    pub fn send(&self, control: Control) -> Result<()> {
+        Ok(self.engine.control().send(control)?)
+    }
+
+    /// Sends stop and waits for the spys to stop.
+    pub fn stop(self) -> Result<()> {
+        self.engine.stop()
+    }
+}
+
+/// Waits until `f` is true, at most 10 seconds.
+pub fn wait_until<F: Fn() -> Result<bool>>(f: F) -> Result<()> {
+    let start = Instant::now();
+    while !f()? {
+        if start.elapsed() > Duration::from_secs(10) {
+            bail!("timed out");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+// =============================================================================
+// File        : pipeline.rs
+// Author      : yukimemi
+// Last Change : 2026/10/16 17:10:12.
+// =============================================================================
+
+//! Loads the fixture configs and runs the whole pipeline: settings, watcher and command.
+
+#![cfg(not(windows))]
+
+mod common;
+
+use std::{collections::HashMap, fs, thread, time::Duration};
+
+use anyhow::Result;
+use common::{prepare, wait_until, Harness};
+use spyrun::Control;
+
+#[test]
+fn test_inherit() -> Result<()> {
+    let harness = Harness::start(&prepare("inherit")?)?;
+    fs::write(harness.input("first")?.join("a.txt"), "")?;
+    fs::write(harness.input("second")?.join("b.csv"), "")?;
+
+    assert_eq!(
+        harness.wait_outputs("first", 1)?,
+        vec!["hello s3cret first a.txt"]
+    );
+    assert_eq!(harness.wait_outputs("second", 1)?, vec!["csv second b"]);
+    harness.stop()
+}
+
+#[test]
+fn test_control_pause() -> Result<()> {
+    let harness = Harness::start(&prepare("control_pause")?)?;
+    let input = harness.input("first")?;
+    harness.send(Control::Pause("first".to_string()))?;
+    // An unknown spy is only logged.
+    harness.send(Control::Pause("unknown".to_string()))?;
+    thread::sleep(Duration::from_millis(200));
+    fs::write(input.join("a.txt"), "")?;
+    thread::sleep(Duration::from_secs(1));
+    assert!(harness.outputs("first").is_empty());
+
+    // Buffered while paused, then handled.
+    harness.send(Control::Resume("first".to_string()))?;
+    assert_eq!(harness.wait_outputs("first", 1)?, vec!["hello first a.txt"]);
+    harness.stop()
+}
+
+#[test]
+fn test_walk_poll() -> Result<()> {
+    let dir = prepare("walk_poll")?;
+    let walk = dir.join("input").join("walk");
+    fs::create_dir_all(&walk)?;
+    fs::write(walk.join("old.txt"), "")?;
+
+    let harness = Harness::start(&dir)?;
+    assert_eq!(
+        harness.wait_outputs("walk", 1)?,
+        vec!["walk Create old.txt"]
+    );
+    fs::write(harness.input("poll")?.join("new.txt"), "")?;
+    assert_eq!(
+        harness.wait_outputs("poll", 1)?,
+        vec!["poll Create new.txt"]
+    );
+    harness.stop()
+}
+
+#[test]
+fn test_limits() -> Result<()> {
+    let harness = Harness::start(&prepare("limits")?)?;
+    let debounce = harness.input("debounce")?;
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        fs::write(debounce.join(name), name)?;
+    }
+    let throttle = harness.input("throttle")?;
+    fs::write(throttle.join("a.txt"), "1")?;
+    fs::write(throttle.join("b.txt"), "1")?;
+    thread::sleep(Duration::from_millis(200));
+    fs::write(throttle.join("a.txt"), "2")?;
+
+    assert_eq!(harness.wait_outputs("debounce", 1)?.len(), 1);
+    let mut outputs = harness.wait_outputs("throttle", 2)?;
+    // The later events are skipped, not only delayed.
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(harness.outputs("debounce").len(), 1);
+    outputs.sort();
+    assert_eq!(outputs, vec!["throttle a.txt", "throttle b.txt"]);
+    assert_eq!(harness.outputs("throttle").len(), 2);
+    harness.stop()
+}
+
+#[test]
+fn test_mutex_key() -> Result<()> {
+    let dir = prepare("mutex_key")?;
+    fs::create_dir_all(dir.join("input").join("keyed").join("sub"))?;
+    let harness = Harness::start(&dir)?;
+    let input = harness.input("keyed")?;
+    fs::write(input.join("a.txt"), "")?;
+    fs::write(input.join("b.txt"), "")?;
+    fs::write(input.join("sub").join("c.txt"), "")?;
+
+    let mut outputs = harness.wait_outputs("keyed", 2)?;
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(harness.outputs("keyed").len(), 2);
+    outputs.sort();
+    assert_eq!(outputs, vec!["keyed a.txt", "keyed c.txt"]);
+    harness.stop()?;
+
+    // A key per directory, `{{ spy_name }}:{{ event_dir }}`, saved on stop.
+    let state: HashMap<String, HashMap<String, u64>> =
+        toml::from_str(&fs::read_to_string(dir.join("state.toml"))?)?;
+    let mut keys = state["keyed"].keys().cloned().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(
+        keys,
+        vec![
+            format!("keyed:{}", input.display()),
+            format!("keyed:{}", input.join("sub").display()),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_audit() -> Result<()> {
+    let dir = prepare("audit")?;
+    let harness = Harness::start(&dir)?;
+    let input = harness.input("audit")?;
+    fs::write(input.join("a.txt"), "")?;
+    fs::write(input.join("b.txt"), "")?;
+
+    // An entry per execution, the header then the stdout.
+    harness.wait_outputs("audit", 1)?;
+    let entries = || {
+        harness.outputs("audit")[0]
+            .matches("===== exec_id: ")
+            .count()
+    };
+    wait_until(|| Ok(entries() == 2))?;
+    let log = harness.outputs("audit").remove(0);
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4, "{}", log);
+    assert!(lines[0].starts_with("===== exec_id: "), "{}", log);
+    assert!(lines[2].starts_with("===== exec_id: "), "{}", log);
+    let mut stdouts = vec![lines[1], lines[3]];
+    stdouts.sort();
+    assert_eq!(stdouts, vec!["audit a.txt", "audit b.txt"]);
+
+    // Created again within the throttle, so skipped with a marker.
+    fs::remove_file(input.join("a.txt"))?;
+    fs::write(input.join("a.txt"), "")?;
+    let marker = dir.join("skipped").join("a.json");
+    wait_until(|| Ok(marker.is_file()))?;
+    thread::sleep(Duration::from_millis(200));
+    let marker: serde_json::Value = serde_json::from_str(&fs::read_to_string(&marker)?)?;
+    let event_path = input.join("a.txt").display().to_string();
+    assert_eq!(marker["reason"], "throttle");
+    assert_eq!(marker["limitkey"], event_path.as_str());
+    assert_eq!(marker["event_path"], event_path.as_str());
+    assert!(marker["timestamp"].is_string());
+    assert_eq!(entries(), 2);
+    harness.stop()
+}